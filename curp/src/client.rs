@@ -1,20 +1,29 @@
-use std::{cmp::Ordering, collections::HashMap, fmt::Debug, iter, marker::PhantomData, sync::Arc};
+use std::{
+    cmp::Ordering,
+    collections::{BTreeSet, HashMap, HashSet},
+    fmt::Debug,
+    iter,
+    marker::PhantomData,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
 use event_listener::Event;
 use futures::{pin_mut, stream::FuturesUnordered, StreamExt};
-use parking_lot::RwLock;
+use parking_lot::{Mutex, RwLock};
 use tokio::{sync::broadcast, time::timeout};
 use tracing::{debug, instrument, warn};
 use utils::{config::ClientTimeout, parking_lot_lock::RwLockMap};
 
 use crate::{
-    cmd::{Command, ProposeId},
+    cmd::{ClientSession, Command, ProposeId},
     error::ProposeError,
+    members::ConfChange,
     rpc::{
         self,
         connect::{Connect, ConnectApi},
-        FetchLeaderRequest, FetchReadStateRequest, ProposeRequest, ReadState as PbReadState,
-        SyncError, SyncResult, WaitSyncedRequest,
+        FetchLeaderRequest, FetchReadStateRequest, ProposeConfChangeRequest, ProposeRequest,
+        ReadState as PbReadState, RegisterSessionRequest, SyncError, SyncResult, WaitSyncedRequest,
     },
     LogIndex, ServerId,
 };
@@ -23,10 +32,12 @@ use crate::{
 pub struct Client<C: Command> {
     /// Current leader and term
     state: RwLock<State>,
-    /// All servers's `Connect`
-    connects: HashMap<ServerId, Arc<Connect>>,
+    /// All voters' `Connect`, it will be updated once the membership changes
+    connects: RwLock<HashMap<ServerId, Arc<Connect>>>,
     /// Curp client timeout settings
     timeout: ClientTimeout,
+    /// The session that the commands are proposed in, it's registered on the first proposal
+    session: Mutex<Option<Session>>,
     /// To keep Command type
     phantom: PhantomData<C>,
 }
@@ -37,6 +48,7 @@ impl<C: Command> Debug for Client<C> {
         f.debug_struct("Client")
             .field("state", &self.state)
             .field("timeout", &self.timeout)
+            .field("session", &self.session)
             .finish()
     }
 }
@@ -84,6 +96,52 @@ impl State {
     }
 }
 
+/// A client session registered in the cluster, the commands proposed in it are executed at most once
+#[derive(Debug)]
+struct Session {
+    /// Client id assigned by the cluster
+    client_id: u64,
+    /// Sequence number of the next command
+    next_seq: u64,
+    /// Sequence numbers of the commands whose responses are not received yet
+    incomplete: BTreeSet<u64>,
+    /// The session will be expired by the cluster after it has been idle for the timeout
+    timeout: Duration,
+    /// When a command is proposed in the session last time
+    last_active: Instant,
+}
+
+impl Session {
+    /// Create a new session
+    fn new(client_id: u64, timeout: Duration) -> Self {
+        Self {
+            client_id,
+            next_seq: 0,
+            incomplete: BTreeSet::new(),
+            timeout,
+            last_active: Instant::now(),
+        }
+    }
+
+    /// Allocate the sequence number of the next command
+    fn next(&mut self) -> ClientSession {
+        let seq = self.next_seq;
+        self.next_seq = self.next_seq.wrapping_add(1);
+        let _ig = self.incomplete.insert(seq);
+        self.last_active = Instant::now();
+        ClientSession {
+            client_id: self.client_id,
+            seq,
+            first_incomplete: self.incomplete.first().copied().unwrap_or(seq),
+        }
+    }
+
+    /// Check if the session may be expired by the cluster soon, then a new one should be registered
+    fn is_stale(&self) -> bool {
+        self.last_active.elapsed() >= self.timeout / 2
+    }
+}
+
 /// Read state of a command
 #[derive(Debug)]
 #[non_exhaustive]
@@ -94,6 +152,17 @@ pub enum ReadState {
     CommitIndex(LogIndex),
 }
 
+/// Get the superquorum of `size` servers: the smallest number of servers whose spec pools
+/// must hold a command for it to be recovered. Any quorum that elects the next leader then
+/// holds the command in at least `quorum / 2 + 1` spec pools, which is what the leader needs
+/// to recover it
+fn superquorum(size: usize) -> usize {
+    let max_fault = size.wrapping_div(2);
+    max_fault
+        .wrapping_add(max_fault.wrapping_add(1).wrapping_div(2))
+        .wrapping_add(1)
+}
+
 impl<C> Client<C>
 where
     C: Command + 'static,
@@ -103,8 +172,9 @@ where
     pub async fn new(addrs: HashMap<ServerId, String>, timeout: ClientTimeout) -> Self {
         Self {
             state: RwLock::new(State::new()),
-            connects: rpc::connect(addrs, None).await,
+            connects: RwLock::new(rpc::connect(addrs, None).await),
             timeout,
+            session: Mutex::new(None),
             phantom: PhantomData,
         }
     }
@@ -116,11 +186,10 @@ where
         &self,
         cmd_arc: Arc<C>,
     ) -> Result<(Option<<C as Command>::ER>, bool), ProposeError> {
-        let max_fault = self.connects.len().wrapping_div(2);
+        let connects = self.all_connects();
         let req = ProposeRequest::new(cmd_arc.as_ref())?;
-        let mut rpcs: FuturesUnordered<_> = self
-            .connects
-            .values()
+        let mut rpcs: FuturesUnordered<_> = connects
+            .iter()
             .zip(iter::repeat(req))
             .map(|(connect, req_cloned)| {
                 connect.propose(req_cloned, *self.timeout.propose_timeout())
@@ -129,9 +198,7 @@ where
 
         let mut ok_cnt: usize = 0;
         let mut execute_result: Option<C::ER> = None;
-        let major_cnt = max_fault
-            .wrapping_add(max_fault.wrapping_add(1).wrapping_div(2))
-            .wrapping_add(1);
+        let major_cnt = superquorum(connects.len());
         while let Some(resp_result) = rpcs.next().await {
             let resp = match resp_result {
                 Ok(resp) => resp.into_inner(),
@@ -177,14 +244,19 @@ where
                     Ok(())
                 },
                 |err| {
-                    if let ProposeError::ExecutionError(_) = err {
-                        // Only `ProposeError::ExecutionError` will be reported to upper function
+                    if matches!(
+                        err,
+                        ProposeError::ExecutionError(_) | ProposeError::SessionExpired
+                    ) {
+                        // Only `ProposeError::ExecutionError` and `ProposeError::SessionExpired`
+                        // will be reported to upper function
                         return Err(err);
                     }
                     warn!("Propose error: {}", err);
                     Ok(())
                 },
             )??;
+            // the fast round also needs the execution result, which only the leader returns
             if (ok_cnt >= major_cnt) && execute_result.is_some() {
                 debug!("fast round succeeds");
                 return Ok((execute_result, true));
@@ -206,9 +278,8 @@ where
 
             debug!("wait synced request sent to {}", leader_id);
             let resp = match self
-                .connects
-                .get(&leader_id)
-                .unwrap_or_else(|| unreachable!("leader {leader_id} not found"))
+                .get_connect(&leader_id)
+                .await?
                 .wait_synced(
                     WaitSyncedRequest::new(cmd.id())?,
                     *self.timeout.wait_synced_timeout(),
@@ -268,9 +339,8 @@ where
             debug!("resend propose to {leader_id}");
 
             let resp = self
-                .connects
-                .get(&leader_id)
-                .unwrap_or_else(|| unreachable!("leader {leader_id} not found"))
+                .get_connect(&leader_id)
+                .await?
                 .propose(
                     ProposeRequest::new(cmd.as_ref())?,
                     *self.timeout.propose_timeout(),
@@ -290,6 +360,14 @@ where
                 }
             };
 
+            // the leader rejects the commands of an expired session, they will never be synced
+            if resp.map_or_else::<C, _, _, _>(
+                |_| false,
+                |err| matches!(err, ProposeError::SessionExpired),
+            )? {
+                return Err(ProposeError::SessionExpired);
+            }
+
             let mut state_w = self.state.write();
 
             let resp_term = resp.term();
@@ -331,9 +409,9 @@ where
     /// Note: The fetched leader may still be outdated
     async fn fetch_leader(&self) -> ServerId {
         loop {
-            let mut rpcs: FuturesUnordered<_> = self
-                .connects
-                .values()
+            let connects = self.all_connects();
+            let mut rpcs: FuturesUnordered<_> = connects
+                .iter()
                 .map(|connect| async {
                     (
                         connect.id().clone(),
@@ -345,10 +423,12 @@ where
                 .collect();
             let mut max_term = 0;
             let mut leader = None;
+            // the voters known by a server with the max term, the leader's view is preferred
+            let mut voters = None;
 
             let mut ok_cnt = 0;
             #[allow(clippy::integer_arithmetic)]
-            let majority_cnt = self.connects.len() / 2 + 1;
+            let majority_cnt = connects.len() / 2 + 1;
             while let Some((id, resp)) = rpcs.next().await {
                 let resp = match resp {
                     Ok(resp) => resp.into_inner(),
@@ -357,15 +437,26 @@ where
                         continue;
                     }
                 };
+                let resp_voters = match resp.voters() {
+                    Ok(resp_voters) => resp_voters,
+                    Err(e) => {
+                        warn!("failed to decode the voters from {id}, {e}");
+                        continue;
+                    }
+                };
                 if let Some(leader_id) = resp.leader_id {
                     #[allow(clippy::integer_arithmetic)]
                     match max_term.cmp(&resp.term) {
                         Ordering::Less => {
                             max_term = resp.term;
+                            voters = Some((id.clone(), resp_voters));
                             leader = Some(leader_id);
                             ok_cnt = 1;
                         }
                         Ordering::Equal => {
+                            if id == leader_id {
+                                voters = Some((id.clone(), resp_voters));
+                            }
                             leader = Some(leader_id);
                             ok_cnt += 1;
                         }
@@ -378,6 +469,9 @@ where
             }
 
             if let Some(leader) = leader {
+                if let Some((responder, voters)) = voters {
+                    self.refresh_connects(&responder, voters).await;
+                }
                 let mut state = self.state.write();
                 debug!("Fetch leader succeeded, leader set to {}", leader);
                 state.term = max_term;
@@ -391,6 +485,86 @@ where
         }
     }
 
+    /// Get the `Connect` of a server, the `Connect`s are refreshed by fetching the leader
+    /// if it's unknown, e.g. it has joined the cluster by the conf change of another client
+    async fn get_connect(&self, id: &ServerId) -> Result<Arc<Connect>, ProposeError> {
+        let connect = self.connects.read().get(id).map(Arc::clone);
+        if let Some(connect) = connect {
+            return Ok(connect);
+        }
+        let _leader = self.fetch_leader().await;
+        self.connects
+            .read()
+            .get(id)
+            .map(Arc::clone)
+            .ok_or_else(|| ProposeError::ProtocolError(format!("server {id} is not a voter")))
+    }
+
+    /// Get all the `Connect`s
+    fn all_connects(&self) -> Vec<Arc<Connect>> {
+        self.connects.read().values().map(Arc::clone).collect()
+    }
+
+    /// Rebuild `Connect`s by the voters after a conf change is applied, `voters` doesn't
+    /// include the leader
+    async fn update_connects(
+        &self,
+        leader_id: &ServerId,
+        change: &ConfChange,
+        voters: HashMap<ServerId, String>,
+    ) {
+        let leader_removed = match *change {
+            ConfChange::RemoveNode(ref id) => id == leader_id,
+            ConfChange::AddLearner(..)
+            | ConfChange::PromoteLearner(_)
+            | ConfChange::UpdateNode(..) => false,
+        };
+        let existing: HashSet<ServerId> = self.connects.read().keys().cloned().collect();
+        let mut new_addrs: HashMap<ServerId, String> = voters
+            .iter()
+            .filter(|&(id, _)| !existing.contains(id))
+            .map(|(id, addr)| (id.clone(), addr.clone()))
+            .collect();
+        // reconnect to the server whose address has changed
+        if let ConfChange::UpdateNode(ref id, ref addr) = *change {
+            if existing.contains(id) {
+                let _ig = new_addrs.insert(id.clone(), addr.clone());
+            }
+        }
+        let new_connects = rpc::connect(new_addrs, None).await;
+        self.connects.map_write(|mut connects_w| {
+            connects_w
+                .retain(|id, _| voters.contains_key(id) || (id == leader_id && !leader_removed));
+            connects_w.extend(new_connects);
+        });
+        if leader_removed {
+            self.state.write().leader = None;
+        }
+    }
+
+    /// Rebuild `Connect`s by the voters known by `responder`, `voters` doesn't include the
+    /// responder
+    async fn refresh_connects(&self, responder: &ServerId, voters: HashMap<ServerId, String>) {
+        let existing: HashSet<ServerId> = self.connects.read().keys().cloned().collect();
+        let new_addrs: HashMap<ServerId, String> = voters
+            .iter()
+            .filter(|&(id, _)| !existing.contains(id))
+            .map(|(id, addr)| (id.clone(), addr.clone()))
+            .collect();
+        let has_stale = existing
+            .iter()
+            .any(|id| id != responder && !voters.contains_key(id));
+        if new_addrs.is_empty() && !has_stale {
+            return;
+        }
+        debug!("client refreshes its connects by the voters known by {responder}");
+        let new_connects = rpc::connect(new_addrs, None).await;
+        self.connects.map_write(|mut connects_w| {
+            connects_w.retain(|id, _| id == responder || voters.contains_key(id));
+            connects_w.extend(new_connects);
+        });
+    }
+
     /// Get leader id from the state or fetch it from servers
     async fn get_leader_id(&self) -> ServerId {
         let notify = Arc::clone(&self.state.read().leader_notify);
@@ -405,15 +579,118 @@ where
         }
     }
 
-    /// Propose the request to servers
+    /// Register a client session to the leader
+    async fn register_session(&self) -> Result<Session, ProposeError> {
+        let retry_timeout = *self.timeout.retry_timeout();
+        loop {
+            let leader_id = self.get_leader_id().await;
+            debug!("register session to {leader_id}");
+            let resp = match self
+                .get_connect(&leader_id)
+                .await?
+                .register_session(
+                    RegisterSessionRequest::new(),
+                    *self.timeout.wait_synced_timeout(),
+                )
+                .await
+            {
+                Ok(resp) => resp.into_inner(),
+                Err(e) => {
+                    warn!("register session rpc error: {e}");
+                    tokio::time::sleep(retry_timeout).await;
+                    let _leader = self.fetch_leader().await;
+                    continue;
+                }
+            };
+
+            match resp.result()? {
+                Some(Ok(client_id)) => {
+                    debug!("client session {client_id} is registered");
+                    return Ok(Session::new(client_id, resp.session_timeout()));
+                }
+                Some(Err(ProposeError::LeaderTransferring)) => {
+                    // retry after the leadership is transferred
+                    tokio::time::sleep(retry_timeout).await;
+                    let _leader = self.fetch_leader().await;
+                }
+                Some(Err(e)) => return Err(e),
+                None => {
+                    // redirect to the new leader
+                    let term = resp.term;
+                    let new_leader = resp.leader_id.and_then(|id| {
+                        let mut state = self.state.write();
+                        (state.term <= term).then(|| {
+                            state.update_to_term(term);
+                            state.set_leader(id.clone());
+                            id
+                        })
+                    });
+                    if new_leader.is_none() {
+                        tokio::time::sleep(retry_timeout).await;
+                        let _leader = self.fetch_leader().await;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Attach the session to the command, a new session is registered if there is no
+    /// session or the current one may be expired soon
+    async fn attach_session(&self, cmd: &mut C) -> Result<ClientSession, ProposeError> {
+        let current = self
+            .session
+            .lock()
+            .as_mut()
+            .filter(|session| !session.is_stale())
+            .map(Session::next);
+        let client_session = if let Some(client_session) = current {
+            client_session
+        } else {
+            let mut session = self.register_session().await?;
+            let client_session = session.next();
+            *self.session.lock() = Some(session);
+            client_session
+        };
+        cmd.id_mut().set_session(client_session);
+        Ok(client_session)
+    }
+
+    /// Mark the command proposed in `client_session` as completed, the session is dropped
+    /// if it has been expired by the cluster
+    fn detach_session<T>(&self, client_session: ClientSession, result: &Result<T, ProposeError>) {
+        let mut session_l = self.session.lock();
+        let Some(session) = session_l.as_mut() else {
+            return;
+        };
+        if session.client_id != client_session.client_id {
+            return;
+        }
+        if matches!(*result, Err(ProposeError::SessionExpired)) {
+            *session_l = None;
+        } else {
+            let _ig = session.incomplete.remove(&client_session.seq);
+        }
+    }
+
+    /// Propose the request to servers, the command is executed at most once even if it's retried
     /// # Errors
     ///   `ProposeError::ExecutionError` if execution error is met
     ///   `ProposeError::SyncedError` error met while syncing logs to followers
+    ///   `ProposeError::SessionExpired` if the session is expired by the cluster, the command
+    ///     may have been executed, and a new session will be registered on the next proposal
     /// # Panics
     ///   If leader index is out of bound of all the connections, panic
     #[inline]
+    pub async fn propose(&self, mut cmd: C) -> Result<C::ER, ProposeError> {
+        let client_session = self.attach_session(&mut cmd).await?;
+        let result = self.propose_in_session(cmd).await;
+        self.detach_session(client_session, &result);
+        result
+    }
+
+    /// Propose the command that has been attached to a session
     #[allow(clippy::too_many_lines)] // FIXME: split to smaller functions
-    pub async fn propose(&self, cmd: C) -> Result<C::ER, ProposeError> {
+    async fn propose_in_session(&self, cmd: C) -> Result<C::ER, ProposeError> {
         let cmd_arc = Arc::new(cmd);
         let fast_round = self.fast_round(Arc::clone(&cmd_arc));
         let slow_round = self.slow_round(cmd_arc);
@@ -451,12 +728,14 @@ where
     ///   `ProposeError::SyncedError` error met while syncing logs to followers
     ///   `ProposeError::RpcError` rpc error met, usually it's network error
     ///   `ProposeError::ProtocolError` execution result is not got from the two requests
+    ///   `ProposeError::SessionExpired` if the session is expired by the cluster
     ///
     /// # Panics
     ///   If leader index is out of bound of all the connections, panic
     #[inline]
     #[allow(clippy::else_if_without_else)] // the else is redundant
-    pub async fn propose_indexed(&self, cmd: C) -> Result<(C::ER, C::ASR), ProposeError> {
+    pub async fn propose_indexed(&self, mut cmd: C) -> Result<(C::ER, C::ASR), ProposeError> {
+        let client_session = self.attach_session(&mut cmd).await?;
         let cmd_arc = Arc::new(cmd);
         let fast_round = self.fast_round(Arc::clone(&cmd_arc));
         let slow_round = self.slow_round(cmd_arc);
@@ -464,10 +743,12 @@ where
         #[allow(clippy::integer_arithmetic)] // tokio framework triggers
         let (_fast_result, slow_result) = tokio::join!(fast_round, slow_round);
 
-        match slow_result {
+        let result = match slow_result {
             Ok((asr, er)) => Ok((er, asr)),
             Err(e) => Err(e),
-        }
+        };
+        self.detach_session(client_session, &result);
+        result
     }
 
     /// Fetch Read state from leader
@@ -480,9 +761,8 @@ where
             let leader_id = self.get_leader_id().await;
             debug!("fetch read state request sent to {}", leader_id);
             let resp = match self
-                .connects
-                .get(&leader_id)
-                .unwrap_or_else(|| unreachable!("leader {leader_id} not found"))
+                .get_connect(&leader_id)
+                .await?
                 .fetch_read_state(
                     FetchReadStateRequest::new(cmd)?,
                     *self.timeout.wait_synced_timeout(),
@@ -493,6 +773,7 @@ where
                 Err(e) => {
                     warn!("fetch read state rpc error: {e}");
                     tokio::time::sleep(retry_timeout).await;
+                    let _leader = self.fetch_leader().await;
                     continue;
                 }
             };
@@ -512,6 +793,70 @@ where
         }
     }
 
+    /// Propose a change of the cluster membership to the leader, the client will only talk to
+    /// the voters after the change is applied
+    /// # Errors
+    ///   `ProposeError::InvalidConfChange` if the change can't be applied to the current membership,
+    ///     note that a change might have been applied if the previous attempt failed with a rpc error
+    ///   `ProposeError::LearnerNotCatchUp` if the learner to promote hasn't caught up with the leader
+    ///   `ProposeError::SyncedError` if the change is not applied in time
+    ///   `ProposeError::EncodeError` encoding error met while serializing the change
+    #[inline]
+    pub async fn propose_conf_change(&self, change: ConfChange) -> Result<(), ProposeError> {
+        let retry_timeout = *self.timeout.retry_timeout();
+        loop {
+            let leader_id = self.get_leader_id().await;
+            debug!("propose conf change {change:?} to {leader_id}");
+            let resp = match self
+                .get_connect(&leader_id)
+                .await?
+                .propose_conf_change(
+                    ProposeConfChangeRequest::new(&change)?,
+                    *self.timeout.wait_synced_timeout(),
+                )
+                .await
+            {
+                Ok(resp) => resp.into_inner(),
+                Err(e) => {
+                    warn!("propose conf change rpc error: {e}");
+                    tokio::time::sleep(retry_timeout).await;
+                    let _leader = self.fetch_leader().await;
+                    continue;
+                }
+            };
+
+            match resp.result()? {
+                Some(Ok(voters)) => {
+                    debug!("conf change {change:?} is applied");
+                    self.update_connects(&leader_id, &change, voters).await;
+                    return Ok(());
+                }
+                Some(Err(ProposeError::LeaderTransferring)) => {
+                    // retry after the leadership is transferred
+                    tokio::time::sleep(retry_timeout).await;
+                    let _leader = self.fetch_leader().await;
+                }
+                Some(Err(e)) => return Err(e),
+                None => {
+                    // redirect to the new leader
+                    let term = resp.term;
+                    let new_leader = resp.leader_id.and_then(|id| {
+                        let mut state = self.state.write();
+                        (state.term <= term).then(|| {
+                            state.update_to_term(term);
+                            state.set_leader(id.clone());
+                            id
+                        })
+                    });
+                    if new_leader.is_none() {
+                        tokio::time::sleep(retry_timeout).await;
+                        let _leader = self.fetch_leader().await;
+                    }
+                }
+            }
+        }
+    }
+
     /// Get the current leader.
     #[inline]
     pub fn leader(&self) -> Option<ServerId> {
@@ -543,4 +888,36 @@ mod tests {
         assert!(rx.recv().await.is_err());
         assert_eq!(rx.recv().await.unwrap().as_str(), "S3");
     }
+
+    #[test]
+    fn session_will_track_incomplete_cmds() {
+        let mut session = Session::new(1, Duration::from_secs(10));
+        let s0 = session.next();
+        let s1 = session.next();
+        assert_eq!((s0.seq, s0.first_incomplete), (0, 0));
+        assert_eq!((s1.seq, s1.first_incomplete), (1, 0));
+
+        let _ig = session.incomplete.remove(&0);
+        let s2 = session.next();
+        assert_eq!((s2.seq, s2.first_incomplete), (2, 1));
+        assert!(!session.is_stale());
+    }
+
+    #[test]
+    fn superquorum_is_correct() {
+        assert_eq!(superquorum(3), 3);
+        assert_eq!(superquorum(4), 4);
+        assert_eq!(superquorum(5), 4);
+        assert_eq!(superquorum(7), 6);
+    }
+
+    #[test]
+    fn superquorum_will_be_recovered_by_any_quorum() {
+        for size in 1..=9 {
+            let quorum = size / 2 + 1;
+            let recover_cnt = quorum / 2 + 1;
+            // the servers that hold the cmd in the worst quorum
+            assert!(superquorum(size) + quorum - size >= recover_cnt);
+        }
+    }
 }
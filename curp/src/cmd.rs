@@ -35,6 +35,9 @@ pub trait Command:
     /// Get propose id
     fn id(&self) -> &ProposeId;
 
+    /// Get mutable propose id, the client attaches its session to the id before proposing
+    fn id_mut(&mut self) -> &mut ProposeId;
+
     /// Execute the command according to the executor
     #[inline]
     async fn execute<E>(&self, e: &E) -> Result<Self::ER, E::Error>
@@ -62,15 +65,38 @@ pub trait Command:
 /// Command Id wrapper, abstracting underlying implementation
 #[allow(clippy::module_name_repetitions)] // the name is ok even with repetitions
 #[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize, Hash)]
-pub struct ProposeId(String);
+pub struct ProposeId(String, Option<ClientSession>);
 
 impl ProposeId {
     /// Create a new propose id
     #[inline]
     #[must_use]
     pub fn new(id: String) -> Self {
-        Self(id)
+        Self(id, None)
+    }
+
+    /// Get the client session that the command is proposed in
+    pub(crate) fn session(&self) -> Option<ClientSession> {
+        self.1
     }
+
+    /// Attach the command to a client session
+    pub(crate) fn set_session(&mut self, session: ClientSession) {
+        self.1 = Some(session);
+    }
+}
+
+/// The client session that a command is proposed in. A command is executed at most once in
+/// its session, the retries of an executed command get the cached response
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Serialize, Deserialize, Hash)]
+pub(crate) struct ClientSession {
+    /// Id of the client, it's assigned by the cluster when the client registers
+    pub(crate) client_id: u64,
+    /// Sequence number of the command in the session, it increases monotonically
+    pub(crate) seq: u64,
+    /// The client has received the responses of all the commands before this sequence number,
+    /// so their cached responses can be discarded
+    pub(crate) first_incomplete: u64,
 }
 
 impl Display for ProposeId {
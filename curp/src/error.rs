@@ -64,6 +64,19 @@ pub enum ProposeError {
     /// Protocol error
     #[error("protocol error {0}")]
     ProtocolError(String),
+    /// The conf change can't be applied to the current membership
+    #[error("invalid conf change: {0}")]
+    InvalidConfChange(String),
+    /// The leader is transferring its leadership and doesn't accept proposals
+    #[error("leader transfer in progress")]
+    LeaderTransferring,
+    /// The learner can't be promoted since it hasn't caught up with the leader
+    #[error("learner is not in sync with the leader")]
+    LearnerNotCatchUp,
+    /// The client session has expired or is unknown to the leader, the command may have been
+    /// executed if it was proposed before
+    #[error("client session expired")]
+    SessionExpired,
 }
 
 impl From<tonic::transport::Error> for ProposeError {
@@ -164,6 +164,9 @@ pub mod error;
 /// The command to be executed
 pub mod cmd;
 
+/// Cluster membership and its changes
+pub mod members;
+
 /// Log Entry
 mod log_entry;
 
@@ -2,6 +2,8 @@ use std::sync::Arc;
 
 use serde::{Deserialize, Serialize};
 
+use crate::members::ConfChange;
+
 /// Log Index
 pub type LogIndex = u64;
 
@@ -12,13 +14,79 @@ pub(crate) struct LogEntry<C> {
     pub(crate) term: u64,
     /// Index
     pub(crate) index: LogIndex,
-    /// The Command
-    pub(crate) cmd: Arc<C>,
+    /// Entry data
+    pub(crate) entry_data: EntryData<C>,
+}
+
+/// Data carried by a log entry
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) enum EntryData<C> {
+    /// A command to be executed
+    Command(Arc<C>),
+    /// A change of the cluster membership
+    ConfChange(ConfChange),
+    /// An empty entry appended by a new leader, it commits the entries of previous terms
+    Empty,
+    /// A client session is registered, the index of the entry is used as the client id
+    RegisterSession,
+    /// The idle client sessions are expired
+    ExpireSessions(Vec<u64>),
 }
 
 impl<C> LogEntry<C> {
-    /// Create a new `LogEntry`
+    /// Create a new `LogEntry` of a command
     pub(super) fn new(index: LogIndex, term: u64, cmd: Arc<C>) -> Self {
-        Self { term, index, cmd }
+        Self {
+            term,
+            index,
+            entry_data: EntryData::Command(cmd),
+        }
+    }
+
+    /// Create a new `LogEntry` of a conf change
+    pub(super) fn new_conf_change(index: LogIndex, term: u64, change: ConfChange) -> Self {
+        Self {
+            term,
+            index,
+            entry_data: EntryData::ConfChange(change),
+        }
+    }
+
+    /// Create a new empty `LogEntry`
+    pub(super) fn new_empty(index: LogIndex, term: u64) -> Self {
+        Self {
+            term,
+            index,
+            entry_data: EntryData::Empty,
+        }
+    }
+
+    /// Create a new `LogEntry` of a session registration
+    pub(super) fn new_register_session(index: LogIndex, term: u64) -> Self {
+        Self {
+            term,
+            index,
+            entry_data: EntryData::RegisterSession,
+        }
+    }
+
+    /// Create a new `LogEntry` of a session expiration
+    pub(super) fn new_expire_sessions(index: LogIndex, term: u64, client_ids: Vec<u64>) -> Self {
+        Self {
+            term,
+            index,
+            entry_data: EntryData::ExpireSessions(client_ids),
+        }
+    }
+
+    /// Get the command carried by the entry, return None if it's not a command
+    pub(crate) fn cmd(&self) -> Option<&Arc<C>> {
+        match self.entry_data {
+            EntryData::Command(ref cmd) => Some(cmd),
+            EntryData::ConfChange(_)
+            | EntryData::Empty
+            | EntryData::RegisterSession
+            | EntryData::ExpireSessions(_) => None,
+        }
     }
 }
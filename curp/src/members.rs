@@ -0,0 +1,283 @@
+use std::collections::{HashMap, HashSet};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{LogIndex, ServerId};
+
+/// A single-server change of the cluster membership, it's carried by a log entry
+/// and takes effect once the entry is applied
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[non_exhaustive]
+pub enum ConfChange {
+    /// Add a non-voting learner with its id and address
+    AddLearner(ServerId, String),
+    /// Promote a learner to a voting member
+    PromoteLearner(ServerId),
+    /// Remove a voting member or a learner
+    RemoveNode(ServerId),
+    /// Update the address of a member
+    UpdateNode(ServerId, String),
+}
+
+impl ConfChange {
+    /// Get the id of the server that is changed
+    #[inline]
+    #[must_use]
+    pub fn id(&self) -> &ServerId {
+        match *self {
+            Self::AddLearner(ref id, _)
+            | Self::PromoteLearner(ref id)
+            | Self::RemoveNode(ref id)
+            | Self::UpdateNode(ref id, _) => id,
+        }
+    }
+}
+
+/// Information of a cluster member
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct MemberInfo {
+    /// Id of the member
+    pub id: ServerId,
+    /// Address of the member, it's `None` for the current server since curp doesn't know it
+    pub addr: Option<String>,
+    /// Whether the member is a learner
+    pub is_learner: bool,
+}
+
+/// Membership of the curp cluster
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct Membership {
+    /// Index of the last applied conf change
+    index: LogIndex,
+    /// Voting members, including self if self is a voter
+    voters: HashSet<ServerId>,
+    /// Non-voting learners, they receive log entries but aren't counted in quorum
+    learners: HashSet<ServerId>,
+    /// Addresses of all members except self
+    addrs: HashMap<ServerId, String>,
+}
+
+impl Membership {
+    /// Create the initial membership, all servers in `others` are voters
+    pub(crate) fn new(id: &ServerId, others: HashMap<ServerId, String>, is_learner: bool) -> Self {
+        let mut voters: HashSet<ServerId> = others.keys().cloned().collect();
+        let mut learners = HashSet::new();
+        if is_learner {
+            let _ig = learners.insert(id.clone());
+        } else {
+            let _ig = voters.insert(id.clone());
+        }
+        Self {
+            index: 0,
+            voters,
+            learners,
+            addrs: others,
+        }
+    }
+
+    /// Index of the last applied conf change
+    pub(crate) fn index(&self) -> LogIndex {
+        self.index
+    }
+
+    /// Get all voters
+    pub(crate) fn voters(&self) -> &HashSet<ServerId> {
+        &self.voters
+    }
+
+    /// Check if `id` is a voter
+    pub(crate) fn is_voter(&self, id: &ServerId) -> bool {
+        self.voters.contains(id)
+    }
+
+    /// Check if `id` is a voter or a learner
+    pub(crate) fn is_member(&self, id: &ServerId) -> bool {
+        self.voters.contains(id) || self.learners.contains(id)
+    }
+
+    /// Get ids and addresses of all members except self
+    pub(crate) fn peers(&self) -> &HashMap<ServerId, String> {
+        &self.addrs
+    }
+
+    /// Get information of all members, including self
+    pub(crate) fn members(&self) -> Vec<MemberInfo> {
+        self.voters
+            .iter()
+            .chain(self.learners.iter())
+            .map(|id| MemberInfo {
+                id: id.clone(),
+                addr: self.addrs.get(id).cloned(),
+                is_learner: self.learners.contains(id),
+            })
+            .collect()
+    }
+
+    /// Get the smallest number of voters who must agree on a decision
+    pub(crate) fn quorum(&self) -> usize {
+        self.voters.len().wrapping_div(2).wrapping_add(1)
+    }
+
+    /// Check if the change can be applied to the current membership
+    pub(crate) fn validate(&self, change: &ConfChange) -> Result<(), String> {
+        match *change {
+            ConfChange::AddLearner(ref id, _) => {
+                if self.is_member(id) {
+                    return Err(format!("{id} is already a member"));
+                }
+            }
+            ConfChange::PromoteLearner(ref id) => {
+                if !self.learners.contains(id) {
+                    return Err(format!("{id} is not a learner"));
+                }
+            }
+            ConfChange::RemoveNode(ref id) => {
+                if !self.is_member(id) {
+                    return Err(format!("{id} is not a member"));
+                }
+                if self.voters.len() == 1 && self.voters.contains(id) {
+                    return Err(format!("can't remove the last voter {id}"));
+                }
+            }
+            ConfChange::UpdateNode(ref id, _) => {
+                if !self.is_member(id) {
+                    return Err(format!("{id} is not a member"));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Apply the change carried by log[`index`], return false if the change has been applied before.
+    /// The change is not validated here since it has been validated by the leader when it's proposed
+    pub(crate) fn apply(
+        &mut self,
+        self_id: &ServerId,
+        change: ConfChange,
+        index: LogIndex,
+    ) -> bool {
+        if index <= self.index {
+            return false;
+        }
+        match change {
+            ConfChange::AddLearner(id, addr) => {
+                if &id != self_id {
+                    let _ig = self.addrs.insert(id.clone(), addr);
+                }
+                let _ig = self.learners.insert(id);
+            }
+            ConfChange::PromoteLearner(id) => {
+                let _ig_learner = self.learners.remove(&id);
+                let _ig_voter = self.voters.insert(id);
+            }
+            ConfChange::RemoveNode(id) => {
+                let _ig_voter = self.voters.remove(&id);
+                let _ig_learner = self.learners.remove(&id);
+                let _ig_addr = self.addrs.remove(&id);
+            }
+            ConfChange::UpdateNode(id, addr) => {
+                if &id != self_id {
+                    let _ig = self.addrs.insert(id, addr);
+                }
+            }
+        }
+        self.index = index;
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn init_membership() -> Membership {
+        Membership::new(
+            &"S0".to_owned(),
+            HashMap::from([
+                ("S1".to_owned(), "127.0.0.1:1".to_owned()),
+                ("S2".to_owned(), "127.0.0.1:2".to_owned()),
+            ]),
+            false,
+        )
+    }
+
+    #[test]
+    fn learner_will_not_be_counted_in_quorum() {
+        let mut ms = init_membership();
+        assert_eq!(ms.quorum(), 2);
+
+        assert!(ms.apply(
+            &"S0".to_owned(),
+            ConfChange::AddLearner("S3".to_owned(), "127.0.0.1:3".to_owned()),
+            1,
+        ));
+        assert_eq!(ms.quorum(), 2);
+        assert!(ms.is_member(&"S3".to_owned()));
+        assert!(!ms.is_voter(&"S3".to_owned()));
+        assert_eq!(ms.peers().len(), 3);
+
+        assert!(ms.apply(
+            &"S0".to_owned(),
+            ConfChange::PromoteLearner("S3".to_owned()),
+            2,
+        ));
+        assert_eq!(ms.quorum(), 3);
+        assert!(ms.is_voter(&"S3".to_owned()));
+    }
+
+    #[test]
+    fn remove_node_will_succeed() {
+        let mut ms = init_membership();
+        assert!(ms.apply(&"S0".to_owned(), ConfChange::RemoveNode("S2".to_owned()), 1));
+        assert_eq!(ms.quorum(), 2);
+        assert!(!ms.is_member(&"S2".to_owned()));
+        assert_eq!(ms.peers().len(), 1);
+
+        assert!(ms.apply(&"S0".to_owned(), ConfChange::RemoveNode("S0".to_owned()), 2));
+        assert_eq!(ms.quorum(), 1);
+        assert!(!ms.is_member(&"S0".to_owned()));
+    }
+
+    #[test]
+    fn update_node_will_change_address() {
+        let mut ms = init_membership();
+        assert!(ms.apply(
+            &"S0".to_owned(),
+            ConfChange::UpdateNode("S1".to_owned(), "127.0.0.1:11".to_owned()),
+            1,
+        ));
+        assert_eq!(
+            ms.peers().get(&"S1".to_owned()).map(String::as_str),
+            Some("127.0.0.1:11")
+        );
+        assert_eq!(ms.quorum(), 2);
+        assert_eq!(ms.members().len(), 3);
+    }
+
+    #[test]
+    fn invalid_change_will_be_rejected() {
+        let ms = init_membership();
+        assert!(ms
+            .validate(&ConfChange::AddLearner(
+                "S1".to_owned(),
+                "127.0.0.1:1".to_owned()
+            ))
+            .is_err());
+        assert!(ms
+            .validate(&ConfChange::PromoteLearner("S1".to_owned()))
+            .is_err());
+        assert!(ms
+            .validate(&ConfChange::RemoveNode("S3".to_owned()))
+            .is_err());
+    }
+
+    #[test]
+    fn applied_change_will_be_ignored() {
+        let mut ms = init_membership();
+        let change = ConfChange::AddLearner("S3".to_owned(), "127.0.0.1:3".to_owned());
+        assert!(ms.apply(&"S0".to_owned(), change.clone(), 1));
+        assert!(!ms.apply(&"S0".to_owned(), change, 1));
+        assert_eq!(ms.index(), 1);
+    }
+}
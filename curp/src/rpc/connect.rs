@@ -16,8 +16,10 @@ use crate::{
     rpc::{
         proto::protocol_client::ProtocolClient, AppendEntriesRequest, AppendEntriesResponse,
         FetchLeaderRequest, FetchLeaderResponse, FetchReadStateRequest, FetchReadStateResponse,
-        InstallSnapshotRequest, InstallSnapshotResponse, ProposeRequest, ProposeResponse,
-        VoteRequest, VoteResponse, WaitSyncedRequest, WaitSyncedResponse,
+        InstallSnapshotRequest, InstallSnapshotResponse, ProposeConfChangeRequest,
+        ProposeConfChangeResponse, ProposeRequest, ProposeResponse, RegisterSessionRequest,
+        RegisterSessionResponse, TimeoutNowRequest, TimeoutNowResponse, VoteRequest, VoteResponse,
+        WaitSyncedRequest, WaitSyncedResponse,
     },
     snapshot::Snapshot,
     ServerId,
@@ -113,12 +115,15 @@ pub(crate) trait ConnectApi: Send + Sync + 'static {
         timeout: Duration,
     ) -> Result<tonic::Response<FetchLeaderResponse>, ProposeError>;
 
-    /// Send a snapshot
+    /// Send a snapshot, `membership` and `sessions` are the serialized membership and client
+    /// sessions when the snapshot is taken
     async fn install_snapshot(
         &self,
         term: u64,
         leader_id: ServerId,
         mut snapshot: Snapshot,
+        membership: Vec<u8>,
+        sessions: Vec<u8>,
     ) -> Result<tonic::Response<InstallSnapshotResponse>, ProposeError>;
 
     /// Send `FetchReadStateRequest`
@@ -127,6 +132,27 @@ pub(crate) trait ConnectApi: Send + Sync + 'static {
         request: FetchReadStateRequest,
         timeout: Duration,
     ) -> Result<tonic::Response<FetchReadStateResponse>, ProposeError>;
+
+    /// Send `ProposeConfChangeRequest`
+    async fn propose_conf_change(
+        &self,
+        request: ProposeConfChangeRequest,
+        timeout: Duration,
+    ) -> Result<tonic::Response<ProposeConfChangeResponse>, ProposeError>;
+
+    /// Send `TimeoutNowRequest`
+    async fn timeout_now(
+        &self,
+        request: TimeoutNowRequest,
+        timeout: Duration,
+    ) -> Result<tonic::Response<TimeoutNowResponse>, ProposeError>;
+
+    /// Send `RegisterSessionRequest`
+    async fn register_session(
+        &self,
+        request: RegisterSessionRequest,
+        timeout: Duration,
+    ) -> Result<tonic::Response<RegisterSessionResponse>, ProposeError>;
 }
 
 /// The connection struct to hold the real rpc connections, it may failed to connect, but it also
@@ -250,13 +276,15 @@ impl ConnectApi for Connect {
         term: u64,
         leader_id: ServerId,
         snapshot: Snapshot,
+        membership: Vec<u8>,
+        sessions: Vec<u8>,
     ) -> Result<tonic::Response<InstallSnapshotResponse>, ProposeError> {
         self.filter()?;
 
         let mut client = self.get().await?;
         client
             .install_snapshot(Request::new(install_snapshot_stream(
-                term, leader_id, snapshot,
+                term, leader_id, snapshot, membership, sessions,
             )))
             .await
             .map_err(Into::into)
@@ -275,6 +303,48 @@ impl ConnectApi for Connect {
         req.set_timeout(timeout);
         client.fetch_read_state(req).await.map_err(Into::into)
     }
+
+    /// Send `ProposeConfChangeRequest`
+    async fn propose_conf_change(
+        &self,
+        request: ProposeConfChangeRequest,
+        timeout: Duration,
+    ) -> Result<tonic::Response<ProposeConfChangeResponse>, ProposeError> {
+        self.filter()?;
+
+        let mut client = self.get().await?;
+        let mut req = tonic::Request::new(request);
+        req.set_timeout(timeout);
+        client.propose_conf_change(req).await.map_err(Into::into)
+    }
+
+    /// Send `TimeoutNowRequest`
+    async fn timeout_now(
+        &self,
+        request: TimeoutNowRequest,
+        timeout: Duration,
+    ) -> Result<tonic::Response<TimeoutNowResponse>, ProposeError> {
+        self.filter()?;
+
+        let mut client = self.get().await?;
+        let mut req = tonic::Request::new(request);
+        req.set_timeout(timeout);
+        client.timeout_now(req).await.map_err(Into::into)
+    }
+
+    /// Send `RegisterSessionRequest`
+    async fn register_session(
+        &self,
+        request: RegisterSessionRequest,
+        timeout: Duration,
+    ) -> Result<tonic::Response<RegisterSessionResponse>, ProposeError> {
+        self.filter()?;
+
+        let mut client = self.get().await?;
+        let mut req = tonic::Request::new(request);
+        req.set_timeout(timeout);
+        client.register_session(req).await.map_err(Into::into)
+    }
 }
 
 /// Generate install snapshot stream
@@ -282,6 +352,8 @@ fn install_snapshot_stream(
     term: u64,
     leader_id: ServerId,
     snapshot: Snapshot,
+    membership: Vec<u8>,
+    sessions: Vec<u8>,
 ) -> impl Stream<Item = InstallSnapshotRequest> {
     // FIXME: The following code is better. But it will result in an unknown compiling error that might origin from a compiler bug(https://github.com/rust-lang/rust/issues/102211).
     // let req_stream = futures::stream::unfold(
@@ -332,6 +404,7 @@ fn install_snapshot_stream(
                 error!("read snapshot error, {e}");
                 break;
             }
+            let done = (offset + len) == snapshot.size();
             let req = InstallSnapshotRequest {
                 term,
                 leader_id: leader_id.clone(),
@@ -339,7 +412,9 @@ fn install_snapshot_stream(
                 last_included_term: meta.last_included_term,
                 offset,
                 data,
-                done: (offset + len) == snapshot.size(),
+                done,
+                membership: if done { membership.clone() } else { vec![] },
+                sessions: if done { sessions.clone() } else { vec![] },
             };
             if let Err(e) = tx.send(req).await {
                 error!("snapshot tx error, {e}");
@@ -393,6 +468,8 @@ mod tests {
                 },
                 Box::new(snapshot),
             ),
+            vec![1],
+            vec![1],
         );
         let mut sum = 0;
         while let Some(req) = stream.next().await {
@@ -402,6 +479,8 @@ mod tests {
             assert_eq!(req.last_included_term, 1);
             sum += req.data.len() as u64;
             assert_eq!(sum == SNAPSHOT_SIZE, req.done);
+            assert_eq!(req.done, !req.membership.is_empty());
+            assert_eq!(req.done, !req.sessions.is_empty());
         }
         assert_eq!(sum, SNAPSHOT_SIZE);
     }
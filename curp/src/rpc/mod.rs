@@ -1,22 +1,27 @@
-use std::sync::Arc;
+use std::{collections::HashMap, sync::Arc, time::Duration};
 
+use clippy_utilities::NumericCast;
 use serde::{de::DeserializeOwned, Deserialize, Serialize};
 
 pub use self::proto::protocol_server::ProtocolServer;
 pub(crate) use self::proto::{
     fetch_read_state_response::ReadState,
+    propose_conf_change_response::Result as ConfChangeResult,
     propose_response::ExeResult,
     protocol_server::Protocol,
+    register_session_response::Result as RegisterSessionResult,
     wait_synced_response::{Success, SyncResult as SyncResultRaw},
     AppendEntriesRequest, AppendEntriesResponse, FetchLeaderRequest, FetchLeaderResponse,
     FetchReadStateRequest, FetchReadStateResponse, IdSet, InstallSnapshotRequest,
-    InstallSnapshotResponse, ProposeRequest, ProposeResponse, VoteRequest, VoteResponse,
-    WaitSyncedRequest, WaitSyncedResponse,
+    InstallSnapshotResponse, ProposeConfChangeRequest, ProposeConfChangeResponse, ProposeRequest,
+    ProposeResponse, RegisterSessionRequest, RegisterSessionResponse, TimeoutNowRequest,
+    TimeoutNowResponse, VoteRequest, VoteResponse, WaitSyncedRequest, WaitSyncedResponse,
 };
 use crate::{
     cmd::{Command, ProposeId},
     error::ProposeError,
     log_entry::LogEntry,
+    members::ConfChange,
     LogIndex, ServerId,
 };
 
@@ -48,9 +53,22 @@ impl FetchLeaderRequest {
 }
 
 impl FetchLeaderResponse {
-    /// Create a new `FetchLeaderResponse`
-    pub(crate) fn new(leader_id: Option<ServerId>, term: u64) -> Self {
-        Self { leader_id, term }
+    /// Create a new `FetchLeaderResponse`, `voters` doesn't include the responder
+    pub(crate) fn new(
+        leader_id: Option<ServerId>,
+        term: u64,
+        voters: &HashMap<ServerId, String>,
+    ) -> bincode::Result<Self> {
+        Ok(Self {
+            leader_id,
+            term,
+            voters: bincode::serialize(voters)?,
+        })
+    }
+
+    /// Get the voters known by the responder
+    pub(crate) fn voters(&self) -> bincode::Result<HashMap<ServerId, String>> {
+        bincode::deserialize(&self.voters)
     }
 }
 
@@ -291,12 +309,16 @@ impl VoteRequest {
         candidate_id: String,
         last_log_index: LogIndex,
         last_log_term: u64,
+        is_pre_vote: bool,
+        is_leader_transfer: bool,
     ) -> Self {
         Self {
             term,
             candidate_id,
             last_log_index,
             last_log_term,
+            is_pre_vote,
+            is_leader_transfer,
         }
     }
 }
@@ -342,6 +364,146 @@ impl InstallSnapshotResponse {
     }
 }
 
+impl TimeoutNowRequest {
+    /// Create a new `TimeoutNow` request
+    pub(crate) fn new(term: u64, leader_id: ServerId) -> Self {
+        Self { term, leader_id }
+    }
+}
+
+impl TimeoutNowResponse {
+    /// Create a new `TimeoutNow` response
+    pub(crate) fn new(term: u64) -> Self {
+        Self { term }
+    }
+}
+
+impl ProposeConfChangeRequest {
+    /// Create a new `ProposeConfChange` request
+    pub(crate) fn new(change: &ConfChange) -> bincode::Result<Self> {
+        Ok(Self {
+            conf_change: bincode::serialize(change)?,
+        })
+    }
+
+    /// Get the conf change
+    pub(crate) fn conf_change(&self) -> bincode::Result<ConfChange> {
+        bincode::deserialize(&self.conf_change)
+    }
+}
+
+impl ProposeConfChangeResponse {
+    /// Create a response with the voters after the change is applied
+    pub(crate) fn new_voters(
+        leader_id: Option<ServerId>,
+        term: u64,
+        voters: &HashMap<ServerId, String>,
+    ) -> bincode::Result<Self> {
+        Ok(Self {
+            leader_id,
+            term,
+            result: Some(ConfChangeResult::Voters(bincode::serialize(voters)?)),
+        })
+    }
+
+    /// Create an error response
+    pub(crate) fn new_error(
+        leader_id: Option<ServerId>,
+        term: u64,
+        error: &ProposeError,
+    ) -> bincode::Result<Self> {
+        Ok(Self {
+            leader_id,
+            term,
+            result: Some(ConfChangeResult::Error(bincode::serialize(error)?)),
+        })
+    }
+
+    /// Create a response which tells the client to redirect to the leader
+    pub(crate) fn new_redirect(leader_id: Option<ServerId>, term: u64) -> Self {
+        Self {
+            leader_id,
+            term,
+            result: None,
+        }
+    }
+
+    /// Get the result, return `Ok(None)` if the client should redirect to the leader
+    pub(crate) fn result(
+        &self,
+    ) -> bincode::Result<Option<Result<HashMap<ServerId, String>, ProposeError>>> {
+        match self.result {
+            Some(ConfChangeResult::Voters(ref voters)) => {
+                Ok(Some(Ok(bincode::deserialize(voters)?)))
+            }
+            Some(ConfChangeResult::Error(ref e)) => Ok(Some(Err(bincode::deserialize(e)?))),
+            None => Ok(None),
+        }
+    }
+}
+
+impl RegisterSessionRequest {
+    /// Create a new `RegisterSession` request
+    pub(crate) fn new() -> Self {
+        Self {}
+    }
+}
+
+impl RegisterSessionResponse {
+    /// Create a response with the client id assigned to the session
+    pub(crate) fn new_client_id(
+        leader_id: Option<ServerId>,
+        term: u64,
+        client_id: u64,
+        session_timeout: Duration,
+    ) -> Self {
+        Self {
+            leader_id,
+            term,
+            result: Some(RegisterSessionResult::ClientId(client_id)),
+            session_timeout_ms: session_timeout.as_millis().numeric_cast(),
+        }
+    }
+
+    /// Create an error response
+    pub(crate) fn new_error(
+        leader_id: Option<ServerId>,
+        term: u64,
+        error: &ProposeError,
+    ) -> bincode::Result<Self> {
+        Ok(Self {
+            leader_id,
+            term,
+            result: Some(RegisterSessionResult::Error(bincode::serialize(error)?)),
+            session_timeout_ms: 0,
+        })
+    }
+
+    /// Create a response which tells the client to redirect to the leader
+    pub(crate) fn new_redirect(leader_id: Option<ServerId>, term: u64) -> Self {
+        Self {
+            leader_id,
+            term,
+            result: None,
+            session_timeout_ms: 0,
+        }
+    }
+
+    /// Get the result, return `Ok(None)` if the client should redirect to the leader
+    pub(crate) fn result(&self) -> bincode::Result<Option<Result<u64, ProposeError>>> {
+        match self.result {
+            Some(RegisterSessionResult::ClientId(client_id)) => Ok(Some(Ok(client_id))),
+            Some(RegisterSessionResult::Error(ref e)) => Ok(Some(Err(bincode::deserialize(e)?))),
+            None => Ok(None),
+        }
+    }
+
+    /// Get the session timeout
+    pub(crate) fn session_timeout(&self) -> Duration {
+        Duration::from_millis(self.session_timeout_ms)
+    }
+}
+
 impl IdSet {
     /// Create a new `IdSet`
     pub fn new(ids: Vec<ProposeId>) -> bincode::Result<Self> {
@@ -9,6 +9,7 @@ use clippy_utilities::NumericCast;
 use mockall::automock;
 use tokio::{sync::oneshot, task::JoinHandle};
 use tracing::{debug, error};
+use utils::parking_lot_lock::RwLockMap;
 
 use self::conflict_checked_mpmc::Task;
 use super::raw_curp::RawCurp;
@@ -55,12 +56,14 @@ impl<C: Command> Debug for CEEvent<C> {
     }
 }
 
-/// Worker that execute commands
+/// Worker that execute commands, the node is shut down if the command executor fails to be
+/// reset, since its state is unknown then
 async fn cmd_worker<C: Command + 'static, CE: 'static + CommandExecutor<C>>(
     cmd_rx: TaskRx<C>,
     done_tx: flume::Sender<(Task<C>, bool)>,
     curp: Arc<RawCurp<C>>,
     ce: Arc<CE>,
+    shutdown_trigger: Arc<event_listener::Event>,
 ) {
     let cb = curp.cmd_board();
     let id = curp.id();
@@ -82,7 +85,8 @@ async fn cmd_worker<C: Command + 'static, CE: 'static + CommandExecutor<C>>(
                         .reset(Some((snapshot.into_inner(), meta.last_included_index)))
                         .await
                     {
-                        error!("reset failed, {e}");
+                        error!("{id} failed to reset the command executor by a snapshot, shutting down, {e}");
+                        shutdown_trigger.notify(usize::MAX);
                     } else {
                         debug_assert_eq!(
                             ce.last_applied()
@@ -92,12 +96,19 @@ async fn cmd_worker<C: Command + 'static, CE: 'static + CommandExecutor<C>>(
                         );
                         debug!("{id}'s command executor has been reset by a snapshot");
                         curp.reset_by_snapshot(meta);
+                        // the snapshot is consumed by the reset, a copy of it is retained instead
+                        // so that the command executor can be rebuilt from it again
+                        if curp.is_snapshot_expected(meta.last_included_index) {
+                            retain_snapshot_copy(curp.as_ref(), ce.as_ref(), meta).await;
+                        }
                     }
                 } else {
                     if let Err(e) = ce.reset(None).await {
-                        error!("reset failed, {e}");
+                        error!("{id} failed to reset the command executor, shutting down, {e}");
+                        shutdown_trigger.notify(usize::MAX);
+                    } else {
+                        debug!("{id}'s command executor has been restored to the initial state");
                     }
-                    debug!("{id}'s command executor has been restored to the initial state");
                 }
                 let _ig = finish_tx.send(());
                 true
@@ -105,7 +116,8 @@ async fn cmd_worker<C: Command + 'static, CE: 'static + CommandExecutor<C>>(
             #[allow(clippy::unwrap_used)]
             TaskType::Snapshot(meta, tx) => match ce.snapshot().await {
                 Ok(snapshot) => {
-                    debug_assert_eq!(ce.last_applied().unwrap(), meta.last_included_index); // sanity check
+                    // log entries of conf changes are not applied to the command executor
+                    debug_assert!(ce.last_applied().unwrap() <= meta.last_included_index); // sanity check
                     if tx.send(Snapshot::new(meta, snapshot)).is_err() {
                         error!("snapshot oneshot closed");
                     }
@@ -125,6 +137,27 @@ async fn cmd_worker<C: Command + 'static, CE: 'static + CommandExecutor<C>>(
     error!("cmd worker exits unexpectedly");
 }
 
+/// Take a copy of the snapshot at `meta` right after the command executor is reset by it,
+/// and retain it if it's still expected
+async fn retain_snapshot_copy<C: Command + 'static, CE: 'static + CommandExecutor<C>>(
+    curp: &RawCurp<C>,
+    ce: &CE,
+    meta: SnapshotMeta,
+) {
+    let snapshot = match ce.snapshot().await {
+        Ok(snapshot) => Snapshot::new(meta, snapshot),
+        Err(e) => {
+            error!("failed to take a copy of the snapshot, {e}");
+            return;
+        }
+    };
+    if let Some(stale) = curp.retain_expected_snapshot(snapshot) {
+        if let Err(e) = stale.into_inner().clean().await {
+            error!("failed to clean the snapshot, {e}");
+        }
+    }
+}
+
 /// Worker that execute `after_sync`
 async fn as_worker<C: Command + 'static, CE: 'static + CommandExecutor<C>>(
     as_task_rx: TaskRx<C>,
@@ -136,16 +169,24 @@ async fn as_worker<C: Command + 'static, CE: 'static + CommandExecutor<C>>(
     let id = curp.id();
     while let Ok(mut task) = as_task_rx.recv().await {
         let succeeded = if let TaskType::AS(cmd, index) = task.take() {
-            let need_run = cb
-                .read()
-                .er_buffer
-                .get(cmd.id())
-                .map_or(false, Result::is_ok);
+            let session = cmd.id().session();
+            let (need_run, er) = cb.map_read(|cb_r| {
+                let er = cb_r.er_buffer.get(cmd.id());
+                // the execution result is only kept for the commands proposed in sessions
+                (
+                    er.map_or(false, Result::is_ok),
+                    session.and_then(|_| er.cloned()),
+                )
+            });
             let asr = ce
                 .after_sync(cmd.as_ref(), index, need_run)
                 .await
                 .map_err(|e| e.to_string());
             let asr_ok = asr.is_ok();
+            // the response is cached in the session for the retries of the client
+            if let (Some(session), Some(er)) = (session, er) {
+                curp.record_session_response(session, (er, need_run.then(|| asr.clone())));
+            }
             if need_run {
                 cb.write().insert_asr(cmd.id(), asr);
             }
@@ -249,13 +290,24 @@ pub(super) fn start_bg_workers<C: Command + 'static, CE: 'static + CommandExecut
     let ce = Arc::new(cmd_executor);
     let as_done_tx = done_tx.clone();
     #[allow(clippy::shadow_unrelated)] // false positive
-    let cmd_worker_handles: Vec<JoinHandle<_>> =
-        iter::repeat((task_rx, done_tx, Arc::clone(&curp), Arc::clone(&ce)))
-            .take(n_workers)
-            .map(|(task_rx, done_tx, curp, ce)| {
-                tokio::spawn(cmd_worker(TaskRx(task_rx), done_tx, curp, ce))
-            })
-            .collect();
+    let cmd_worker_handles: Vec<JoinHandle<_>> = iter::repeat((
+        task_rx,
+        done_tx,
+        Arc::clone(&curp),
+        Arc::clone(&ce),
+        Arc::clone(&shutdown_trigger),
+    ))
+    .take(n_workers)
+    .map(|(task_rx, done_tx, curp, ce, shutdown_trigger)| {
+        tokio::spawn(cmd_worker(
+            TaskRx(task_rx),
+            done_tx,
+            curp,
+            ce,
+            shutdown_trigger,
+        ))
+    })
+    .collect();
     let as_worker_handles = tokio::spawn(as_worker(TaskRx(as_rx), as_done_tx, curp, ce));
     let _ig = tokio::spawn(async move {
         shutdown_trigger.listen().await;
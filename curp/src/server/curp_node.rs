@@ -1,18 +1,24 @@
-use std::{collections::HashMap, fmt::Debug, io, sync::Arc, time::Duration};
+use std::{
+    collections::HashMap,
+    fmt::Debug,
+    io,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
 use clippy_utilities::NumericCast;
 use event_listener::Event;
 use futures::{pin_mut, stream::FuturesUnordered, Stream, StreamExt};
-use itertools::Itertools;
 use madsim::rand::{thread_rng, Rng};
 use parking_lot::{Mutex, RwLock};
 use thiserror::Error;
 use tokio::{
-    sync::{broadcast, mpsc},
+    sync::{broadcast, broadcast::error::RecvError, mpsc},
+    task::JoinHandle,
     time::MissedTickBehavior,
 };
 use tracing::{debug, error, info, warn};
-use utils::config::CurpConfig;
+use utils::config::{CurpConfig, CurpStorageBackend};
 
 use super::{
     cmd_board::{CmdBoardRef, CommandBoard},
@@ -21,20 +27,31 @@ use super::{
     raw_curp::{AppendEntries, RawCurp, Vote},
     spec_pool::{SpecPoolRef, SpeculativePool},
     storage::{StorageApi, StorageError},
+    NodeStatus,
 };
 use crate::{
     cmd::{Command, CommandExecutor, ProposeId},
     error::ProposeError,
     log_entry::LogEntry,
+    members::{MemberInfo, Membership},
     rpc::{
-        self, connect::ConnectApi, AppendEntriesRequest, AppendEntriesResponse, FetchLeaderRequest,
-        FetchLeaderResponse, FetchReadStateRequest, FetchReadStateResponse, InstallSnapshotRequest,
-        InstallSnapshotResponse, ProposeRequest, ProposeResponse, VoteRequest, VoteResponse,
-        WaitSyncedRequest, WaitSyncedResponse,
+        self,
+        connect::{Connect, ConnectApi},
+        AppendEntriesRequest, AppendEntriesResponse, FetchLeaderRequest, FetchLeaderResponse,
+        FetchReadStateRequest, FetchReadStateResponse, InstallSnapshotRequest,
+        InstallSnapshotResponse, ProposeConfChangeRequest, ProposeConfChangeResponse,
+        ProposeRequest, ProposeResponse, RegisterSessionRequest, RegisterSessionResponse,
+        TimeoutNowRequest, TimeoutNowResponse, VoteRequest, VoteResponse, WaitSyncedRequest,
+        WaitSyncedResponse,
+    },
+    server::{
+        cmd_worker::CEEventTxApi,
+        raw_curp::SyncAction,
+        session::SessionTable,
+        storage::{rocksdb::RocksDBStorage, wal::WalStorage},
     },
-    server::{cmd_worker::CEEventTxApi, raw_curp::SyncAction, storage::rocksdb::RocksDBStorage},
     snapshot::{Snapshot, SnapshotMeta},
-    ServerId, TxFilter,
+    LogIndex, ServerId, TxFilter,
 };
 
 /// Uncommitted pool type
@@ -43,6 +60,12 @@ pub(super) type UncommittedPool<C> = HashMap<ProposeId, Arc<C>>;
 /// Reference to uncommitted pool
 pub(super) type UncommittedPoolRef<C> = Arc<Mutex<UncommittedPool<C>>>;
 
+/// Interval to retry persisting a batch of log entries after a storage error
+const LOG_PERSIST_RETRY_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Connects to other members, shared by the election task and the membership task
+type ConnectsRef<Conn> = Arc<RwLock<HashMap<ServerId, Arc<Conn>>>>;
+
 /// Curp error
 #[derive(Debug, Error)]
 pub(super) enum CurpError {
@@ -61,6 +84,9 @@ pub(super) enum CurpError {
     /// Internal error
     #[error("internal error, {0}")]
     Internal(String),
+    /// Self is not the leader or the leadership can't be confirmed
+    #[error("not leader, {0}")]
+    NotLeader(String),
 }
 
 /// Internal error encountered when sending `append_entries`
@@ -89,6 +115,9 @@ enum SendSnapshotError {
     /// Transport
     #[error("transport error, {0}")]
     Transport(#[from] ProposeError),
+    /// Encode/Decode error
+    #[error("encode or decode error")]
+    EncodeDecode(#[from] bincode::Error),
 }
 
 /// `CurpNode` represents a single node of curp cluster
@@ -105,6 +134,8 @@ pub(super) struct CurpNode<C: Command> {
     ce_event_tx: CEEventTx<C>,
     /// Storage
     storage: Arc<dyn StorageApi<Command = C>>,
+    /// Connects to other members, used to broadcast votes once a `timeout_now` is received
+    connects: ConnectsRef<Connect>,
 }
 
 // handlers
@@ -113,8 +144,31 @@ impl<C: 'static + Command> CurpNode<C> {
     pub(super) async fn propose(&self, req: ProposeRequest) -> Result<ProposeResponse, CurpError> {
         let cmd: Arc<C> = Arc::new(req.cmd()?);
 
+        // a retry of the command in a client session must not be proposed again
+        if let Some(session) = cmd.id().session() {
+            let ((leader_id, term), result) = self.curp.handle_propose_session(session);
+            match result {
+                Ok(None) => {}
+                Ok(Some((Ok(er), _))) => {
+                    return Ok(ProposeResponse::new_result::<C>(leader_id, term, &er)?);
+                }
+                Ok(Some((Err(err), _))) => {
+                    return Ok(ProposeResponse::new_error(
+                        leader_id,
+                        term,
+                        &ProposeError::ExecutionError(err),
+                    )?);
+                }
+                Err(err) => return Ok(ProposeResponse::new_error(leader_id, term, &err)?),
+            }
+        }
+
         // handle proposal
         let ((leader_id, term), result) = self.curp.handle_propose(Arc::clone(&cmd));
+        // the witnessed cmd must be durable before it's acked in the fast path
+        if self.curp.cfg().persist_spec_pool && self.spec_pool.lock().pool.contains_key(cmd.id()) {
+            self.storage.put_spec_pool_cmd(cmd.as_ref()).await?;
+        }
         let resp = match result {
             Ok(true) => match CommandBoard::wait_for_er(&self.cmd_board, cmd.id()).await {
                 Ok(er) => ProposeResponse::new_result::<C>(leader_id, term, &er)?,
@@ -129,12 +183,15 @@ impl<C: 'static + Command> CurpNode<C> {
         Ok(resp)
     }
 
-    /// Handle `AppendEntries` requests
-    pub(super) fn append_entries(
+    /// Handle `AppendEntries` requests, the entries are acked only after they are persisted
+    pub(super) async fn append_entries(
         &self,
         req: AppendEntriesRequest,
     ) -> Result<AppendEntriesResponse, CurpError> {
         let entries = req.entries()?;
+        // the leader takes the follower as matched up to the last entry of the request
+        #[allow(clippy::integer_arithmetic)] // won't overflow
+        let last_index = req.prev_log_index + entries.len().numeric_cast::<LogIndex>();
 
         let result = self.curp.handle_append_entries(
             req.term,
@@ -144,6 +201,10 @@ impl<C: 'static + Command> CurpNode<C> {
             entries,
             req.leader_commit,
         );
+        let result = match result {
+            Ok(term) => self.wait_log_persisted(term, last_index).await,
+            Err(e) => Err(e),
+        };
         let resp = match result {
             Ok(term) => AppendEntriesResponse::new_accept(term),
             Err((term, hint)) => AppendEntriesResponse::new_reject(term, hint),
@@ -160,10 +221,15 @@ impl<C: 'static + Command> CurpNode<C> {
             req.candidate_id.clone(),
             req.last_log_index,
             req.last_log_term,
+            req.is_pre_vote,
+            req.is_leader_transfer,
         );
         let resp = match result {
             Ok((term, sp)) => {
-                self.storage.flush_voted_for(term, req.candidate_id).await?;
+                // a pre-vote is not binding, so it's not persisted
+                if !req.is_pre_vote {
+                    self.storage.flush_voted_for(term, req.candidate_id).await?;
+                }
                 VoteResponse::new_accept(term, sp)?
             }
             Err(term) => VoteResponse::new_reject(term),
@@ -180,7 +246,11 @@ impl<C: 'static + Command> CurpNode<C> {
         let id = req.id()?;
         debug!("{} get wait synced request for cmd({id})", self.curp.id());
 
-        let (er, asr) = CommandBoard::wait_for_er_asr(&self.cmd_board, &id).await;
+        // the response of a retried command may only be cached in its session
+        let (er, asr) = match self.curp.session_response(&id) {
+            Some(resp) => resp,
+            None => CommandBoard::wait_for_er_asr(&self.cmd_board, &id).await,
+        };
         let resp = WaitSyncedResponse::new_from_result::<C>(Some(er), asr)?;
 
         debug!("{} wait synced for cmd({id}) finishes", self.curp.id());
@@ -188,13 +258,20 @@ impl<C: 'static + Command> CurpNode<C> {
     }
 
     /// Handle fetch leader requests
-    #[allow(clippy::unnecessary_wraps, clippy::needless_pass_by_value)] // To keep type consistent with other request handlers
+    #[allow(clippy::needless_pass_by_value)] // To keep type consistent with other request handlers
     pub(super) fn fetch_leader(
         &self,
         _req: FetchLeaderRequest,
     ) -> Result<FetchLeaderResponse, CurpError> {
         let (leader_id, term) = self.curp.leader();
-        Ok(FetchLeaderResponse::new(leader_id, term))
+        let ms = self.curp.membership();
+        let voters = ms
+            .peers()
+            .iter()
+            .filter(|&(id, _)| ms.is_voter(id))
+            .map(|(id, addr)| (id.clone(), addr.clone()))
+            .collect();
+        Ok(FetchLeaderResponse::new(leader_id, term, &voters)?)
     }
 
     /// Install snapshot
@@ -234,17 +311,40 @@ impl<C: 'static + Command> CurpNode<C> {
                     last_included_index: req.last_included_index,
                     last_included_term: req.last_included_term,
                 };
-                let snapshot = Snapshot::new(meta, snapshot);
-                self.ce_event_tx
-                    .send_reset(Some(snapshot))
-                    .await
-                    .map_err(|err| {
-                        let err = CurpError::Internal(format!(
-                            "failed to reset the command executor by snapshot, {err}"
-                        ));
-                        error!("{err}");
-                        err
-                    })?;
+                let membership: Membership = bincode::deserialize(&req.membership)?;
+                let sessions: SessionTable<C> = bincode::deserialize(&req.sessions)?;
+                if self.curp.cfg().is_witness {
+                    // a witness has no state machine, only the log is reset
+                    if let Err(err) = snapshot.clean().await {
+                        warn!("failed to clean the snapshot, {err}");
+                    }
+                    self.curp.reset_by_snapshot(meta);
+                } else {
+                    // the cmd worker retains a copy of the snapshot once the reset is done
+                    if let Some(stale) = self.curp.expect_snapshot(meta, sessions.clone()) {
+                        Self::clean_snapshot(stale).await;
+                    }
+                    let snapshot = Snapshot::new(meta, snapshot);
+                    self.ce_event_tx
+                        .send_reset(Some(snapshot))
+                        .await
+                        .map_err(|err| {
+                            let err = CurpError::Internal(format!(
+                                "failed to reset the command executor by snapshot, {err}"
+                            ));
+                            error!("{err}");
+                            err
+                        })?;
+                }
+                // conf changes and sessions included in the snapshot won't be applied from the log
+                self.curp.reset_membership(membership);
+                self.curp.reset_sessions(sessions);
+                // entries included in the snapshot are no longer needed
+                self.storage.flush_sessions(&self.curp.sessions()).await?;
+                self.storage
+                    .flush_membership(&self.curp.membership())
+                    .await?;
+                self.storage.compact(meta, &[]).await?;
                 return Ok(InstallSnapshotResponse::new(self.curp.term()));
             }
         }
@@ -253,25 +353,172 @@ impl<C: 'static + Command> CurpNode<C> {
         ))
     }
 
-    /// Handle fetch read state requests
+    /// Handle fetch read state requests, the read state is returned after the leadership
+    /// is confirmed by a quorum of heartbeats
     #[allow(clippy::needless_pass_by_value)] // To keep type consistent with other request handlers
-    pub(super) fn fetch_read_state(
+    pub(super) async fn fetch_read_state(
         &self,
         req: FetchReadStateRequest,
     ) -> Result<FetchReadStateResponse, CurpError> {
         let cmd = req.cmd()?;
-        let state = self.curp.handle_fetch_read_state(&cmd)?;
-        Ok(FetchReadStateResponse::new(state))
+        let Some(pending) = self.curp.handle_fetch_read_state(&cmd)? else {
+            return Err(CurpError::NotLeader(format!(
+                "{} can't serve the read as a leader",
+                self.curp.id()
+            )));
+        };
+        if pending.in_lease {
+            return Ok(FetchReadStateResponse::new(pending.state));
+        }
+
+        let read_event = self.curp.read_event();
+        let wait_confirmed = async {
+            loop {
+                // grab the listener before checking to prevent missing acks
+                let listener = read_event.listen();
+                match self.curp.read_confirmed(pending.term, pending.seq) {
+                    Ok(true) => return true,
+                    Ok(false) => listener.await,
+                    Err(()) => return false,
+                }
+            }
+        };
+        // the leader may have been partitioned from the majority, give up after an election timeout
+        let cfg = self.curp.cfg();
+        let election_timeout = cfg
+            .heartbeat_interval
+            .saturating_mul(cfg.follower_timeout_ticks.into());
+        match tokio::time::timeout(election_timeout, wait_confirmed).await {
+            Ok(true) => Ok(FetchReadStateResponse::new(pending.state)),
+            Ok(false) | Err(_) => Err(CurpError::NotLeader(format!(
+                "{} failed to confirm its leadership",
+                self.curp.id()
+            ))),
+        }
+    }
+
+    /// Handle `ProposeConfChange` requests, wait until the change is applied
+    pub(super) async fn propose_conf_change(
+        &self,
+        req: ProposeConfChangeRequest,
+    ) -> Result<ProposeConfChangeResponse, CurpError> {
+        let change = req.conf_change()?;
+        let ((leader_id, term), result) = self.curp.handle_propose_conf_change(change);
+        let index = match result {
+            Ok(Some(index)) => index,
+            Ok(None) => return Ok(ProposeConfChangeResponse::new_redirect(leader_id, term)),
+            Err(err) => return Ok(ProposeConfChangeResponse::new_error(leader_id, term, &err)?),
+        };
+
+        let membership_event = self.curp.membership_event();
+        let wait_applied = async {
+            loop {
+                let listener = membership_event.listen();
+                if let Some(applied) = self.curp.entry_state(index, term) {
+                    break applied;
+                }
+                listener.await;
+            }
+        };
+        let resp =
+            match tokio::time::timeout(self.curp.cfg().wait_synced_timeout, wait_applied).await {
+                Ok(true) => {
+                    let ms = self.curp.membership();
+                    let voters = ms
+                        .peers()
+                        .iter()
+                        .filter(|&(id, _)| ms.is_voter(id))
+                        .map(|(id, addr)| (id.clone(), addr.clone()))
+                        .collect();
+                    ProposeConfChangeResponse::new_voters(leader_id, term, &voters)?
+                }
+                Ok(false) => ProposeConfChangeResponse::new_error(
+                    leader_id,
+                    term,
+                    &ProposeError::ProtocolError(
+                        "the conf change has been overwritten by another leader".to_owned(),
+                    ),
+                )?,
+                Err(_elapsed) => ProposeConfChangeResponse::new_error(
+                    leader_id,
+                    term,
+                    &ProposeError::SyncedError(
+                        "wait for the conf change to be applied timeout".to_owned(),
+                    ),
+                )?,
+            };
+        Ok(resp)
+    }
+
+    /// Handle `RegisterSession` requests, wait until the registration is applied
+    pub(super) async fn register_session(
+        &self,
+        _req: RegisterSessionRequest,
+    ) -> Result<RegisterSessionResponse, CurpError> {
+        let ((leader_id, term), result) = self.curp.handle_register_session();
+        let index = match result {
+            Ok(Some(index)) => index,
+            Ok(None) => return Ok(RegisterSessionResponse::new_redirect(leader_id, term)),
+            Err(err) => return Ok(RegisterSessionResponse::new_error(leader_id, term, &err)?),
+        };
+
+        let session_event = self.curp.session_event();
+        let wait_applied = async {
+            loop {
+                let listener = session_event.listen();
+                if let Some(applied) = self.curp.entry_state(index, term) {
+                    break applied;
+                }
+                listener.await;
+            }
+        };
+        let resp =
+            match tokio::time::timeout(self.curp.cfg().wait_synced_timeout, wait_applied).await {
+                Ok(true) => RegisterSessionResponse::new_client_id(
+                    leader_id,
+                    term,
+                    index,
+                    self.curp.cfg().session_timeout,
+                ),
+                Ok(false) => RegisterSessionResponse::new_error(
+                    leader_id,
+                    term,
+                    &ProposeError::ProtocolError(
+                        "the registration has been overwritten by another leader".to_owned(),
+                    ),
+                )?,
+                Err(_elapsed) => RegisterSessionResponse::new_error(
+                    leader_id,
+                    term,
+                    &ProposeError::SyncedError(
+                        "wait for the registration to be applied timeout".to_owned(),
+                    ),
+                )?,
+            };
+        Ok(resp)
+    }
+
+    /// Handle `TimeoutNow` requests, start an election right away if self is the leader transferee
+    #[allow(clippy::unnecessary_wraps, clippy::needless_pass_by_value)] // To keep type consistent with other request handlers
+    pub(super) fn timeout_now(
+        &self,
+        req: TimeoutNowRequest,
+    ) -> Result<TimeoutNowResponse, CurpError> {
+        if let Some(vote) = self.curp.handle_timeout_now(req.term, &req.leader_id) {
+            let curp = Arc::clone(&self.curp);
+            let voters = Self::voter_connects(curp.as_ref(), &self.connects);
+            let _ig = tokio::spawn(async move {
+                let _ig = Self::bcast_vote(curp.as_ref(), &voters, vote).await;
+            });
+        }
+        Ok(TimeoutNowResponse::new(self.curp.term()))
     }
 }
 
 /// Spawned tasks
 impl<C: 'static + Command> CurpNode<C> {
     /// Tick periodically
-    async fn election_task(
-        curp: Arc<RawCurp<C>>,
-        connects: HashMap<ServerId, Arc<impl ConnectApi>>,
-    ) {
+    async fn election_task(curp: Arc<RawCurp<C>>, connects: ConnectsRef<impl ConnectApi>) {
         let heartbeat_interval = curp.cfg().heartbeat_interval;
         // wait for some random time before tick starts to minimize vote split possibility
         let rand = thread_rng()
@@ -283,8 +530,13 @@ impl<C: 'static + Command> CurpNode<C> {
         ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
         loop {
             let _now = ticker.tick().await;
-            if let Some(vote) = curp.tick_election() {
-                Self::bcast_vote(curp.as_ref(), &connects, vote).await;
+            let Some(mut vote) = curp.tick_election() else {
+                continue;
+            };
+            let voters = Self::voter_connects(curp.as_ref(), &connects);
+            // a real election follows a successful pre-vote round right away
+            while let Some(next_vote) = Self::bcast_vote(curp.as_ref(), &voters, vote).await {
+                vote = next_vote;
             }
         }
     }
@@ -306,20 +558,97 @@ impl<C: 'static + Command> CurpNode<C> {
                 Arc::clone(&sync_event),
             )
             .await;
+            if !curp.is_member(connect.id()) {
+                debug!("{} has been removed, stop syncing", connect.id());
+                return;
+            }
+        }
+    }
+
+    /// Keep connections and sync tasks consistent with the membership, and persist the membership once it changes
+    async fn membership_task(
+        curp: Arc<RawCurp<C>>,
+        storage: Arc<dyn StorageApi<Command = C>>,
+        connects: ConnectsRef<Connect>,
+        tx_filter: Option<Box<dyn TxFilter>>,
+        shutdown_trigger: Arc<Event>,
+    ) {
+        let membership_event = curp.membership_event();
+        let mut shutdown_listener = shutdown_trigger.listen();
+        // sync tasks and the addresses they are connected to
+        let mut sync_task_daemons: HashMap<ServerId, (String, JoinHandle<()>)> = HashMap::new();
+        #[allow(clippy::integer_arithmetic)] // tokio select internal triggered
+        loop {
+            // grab the listener in the beginning to prevent missing membership events
+            let listener = membership_event.listen();
+            let ms = curp.membership();
+            if let Err(err) = storage.flush_membership(&ms).await {
+                error!("storage error, {err}");
+            }
+
+            // stop syncing with removed members and members whose address has changed
+            sync_task_daemons.retain(|id, &mut (ref addr, ref daemon)| {
+                let is_valid = ms.peers().get(id) == Some(addr);
+                if !is_valid {
+                    daemon.abort();
+                    let _ig = connects.write().remove(id);
+                }
+                is_valid
+            });
+            // establish connection with new members
+            let new_peers = ms
+                .peers()
+                .iter()
+                .filter(|&(id, _)| {
+                    sync_task_daemons
+                        .get(id)
+                        .map_or(true, |&(_, ref daemon)| daemon.is_finished())
+                })
+                .map(|(id, addr)| (id.clone(), addr.clone()))
+                .collect();
+            let new_connects =
+                rpc::connect(new_peers, tx_filter.as_ref().map(|f| f.boxed_clone())).await;
+            for (id, connect) in new_connects {
+                let (Some(sync_event), Some(addr)) = (curp.sync_event(&id), ms.peers().get(&id)) else {
+                    continue;
+                };
+                let _ig = connects.write().insert(id.clone(), Arc::clone(&connect));
+                let daemon = tokio::spawn(Self::sync_follower_daemon(
+                    Arc::clone(&curp),
+                    connect,
+                    sync_event,
+                ));
+                if let Some((_, prev)) = sync_task_daemons.insert(id, (addr.clone(), daemon)) {
+                    prev.abort();
+                }
+            }
+
+            tokio::select! {
+                _ = listener => {}
+                _ = &mut shutdown_listener => {
+                    for &(_, ref daemon) in sync_task_daemons.values() {
+                        daemon.abort();
+                    }
+                    return;
+                }
+            }
         }
     }
 
     /// Leader use this task to keep a follower up-to-date, will return if self is no longer leader
+    /// The batches are pipelined, at most `max_inflight_batches` of them are sent without waiting for the responses
     async fn sync_follower_task(
         curp: Arc<RawCurp<C>>,
         connect: Arc<impl ConnectApi>,
         sync_event: Arc<Event>,
     ) {
         let mut hb_opt = false;
+        let mut sent_read_seq = 0;
         let mut ticker = tokio::time::interval(curp.cfg().heartbeat_interval);
         ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
         let id = connect.id();
         let batch_timeout = curp.cfg().batch_timeout;
+        let mut inflights = FuturesUnordered::new();
 
         #[allow(clippy::integer_arithmetic, clippy::unwrap_used)]
         // tokio select internal triggered,
@@ -327,46 +656,63 @@ impl<C: 'static + Command> CurpNode<C> {
             // grab the listener in the beginning to prevent missing sync events
             let listener: event_listener::EventListener = sync_event.listen();
 
-            let Ok(sync_action) = curp.sync(id) else {
-                return;
-            };
+            // keep sending until the inflight window is full or there are no more entries
+            loop {
+                let Ok(sync_action) = curp.sync(id) else {
+                    return;
+                };
 
-            match sync_action {
-                SyncAction::AppendEntries(ae) => {
-                    // (hb_opt, entries) status combination
-                    // (false, empty) => send heartbeat to followers
-                    // (true, empty) => indicates that `batch_timeout` expired, and during this period there is not any log generated. Do nothing
-                    // (true | false, not empty) => send append entries
-                    if !hb_opt || !ae.entries.is_empty() {
-                        let result = Self::send_ae(connect.as_ref(), curp.as_ref(), ae).await;
-                        if let Err(err) = result {
-                            warn!("ae to {} failed, {err}", connect.id());
-                            if matches!(err, SendAEError::NotLeader) {
-                                return;
-                            }
-                        } else {
+                match sync_action {
+                    SyncAction::AppendEntries(ae) => {
+                        // (hb_opt, entries) status combination
+                        // (false, empty) => send heartbeat to followers
+                        // (true, empty) => indicates that `batch_timeout` expired, and during this period there is not any log generated. Do nothing
+                        // (true | false, not empty) => send append entries
+                        // a pending read also requires a heartbeat to confirm the leadership
+                        let has_entries = !ae.entries.is_empty();
+                        if !hb_opt || has_entries || ae.read_seq > sent_read_seq {
+                            sent_read_seq = ae.read_seq;
                             hb_opt = true;
+                            inflights.push(Self::send_ae(connect.as_ref(), curp.as_ref(), ae));
+                        }
+                        if !has_entries {
+                            break;
                         }
                     }
-                }
-                SyncAction::Snapshot(rx) => match rx.await {
-                    Ok(snapshot) => {
-                        let result =
-                            Self::send_snapshot(connect.as_ref(), curp.as_ref(), snapshot).await;
-                        if let Err(err) = result {
-                            warn!("snapshot to {} failed, {err}", connect.id());
-                            if matches!(err, SendSnapshotError::NotLeader) {
-                                return;
+                    SyncAction::Snapshot(rx, membership, sessions) => {
+                        match rx.await {
+                            Ok(snapshot) => {
+                                let result = Self::send_snapshot(
+                                    connect.as_ref(),
+                                    curp.as_ref(),
+                                    snapshot,
+                                    &membership,
+                                    &sessions,
+                                )
+                                .await;
+                                if let Err(err) = result {
+                                    warn!("snapshot to {} failed, {err}", connect.id());
+                                    if matches!(err, SendSnapshotError::NotLeader) {
+                                        return;
+                                    }
+                                }
+                            }
+                            Err(err) => {
+                                warn!("failed to receive snapshot result, {err}");
                             }
                         }
+                        break;
                     }
-                    Err(err) => {
-                        warn!("failed to receive snapshot result, {err}");
-                    }
-                },
+                }
+            }
+
+            // the leader transferee has caught up, ask it to start an election right away
+            if let Some(term) = curp.leader_transfer_ready(id) {
+                Self::send_timeout_now(connect.as_ref(), curp.as_ref(), term).await;
             }
 
-            // a sync is either triggered by an heartbeat timeout event or when new log entries arrive
+            // a sync is either triggered by an heartbeat timeout event, when new log entries arrive,
+            // or when an inflight batch is acked
             tokio::select! {
                 _now = ticker.tick() => {
                     hb_opt = false;
@@ -376,6 +722,15 @@ impl<C: 'static + Command> CurpNode<C> {
                         hb_opt = true;
                     }
                 }
+                Some(result) = inflights.next() => {
+                    if let Err(err) = result {
+                        warn!("ae to {} failed, {err}", connect.id());
+                        if matches!(err, SendAEError::NotLeader) {
+                            return;
+                        }
+                        hb_opt = false;
+                    }
+                }
             }
         }
     }
@@ -393,101 +748,144 @@ impl<C: 'static + Command> CurpNode<C> {
         curp_cfg: Arc<CurpConfig>,
         tx_filter: Option<Box<dyn TxFilter>>,
     ) -> Result<Self, CurpError> {
-        let sync_events = others
-            .keys()
-            .map(|server_id| (server_id.clone(), Arc::new(Event::new())))
-            .collect();
         let (log_tx, log_rx) = mpsc::unbounded_channel();
         let shutdown_trigger = Arc::new(Event::new());
         let cmd_board = Arc::new(RwLock::new(CommandBoard::new()));
         let spec_pool = Arc::new(Mutex::new(SpeculativePool::new()));
         let uncommitted_pool = Arc::new(Mutex::new(UncommittedPool::new()));
-        let last_applied = cmd_executor
-            .last_applied()
-            .map_err(|e| CurpError::Internal(format!("get applied index error, {e}")))?;
+        if curp_cfg.is_witness && is_leader {
+            return Err(CurpError::Internal(
+                "a witness can't be the initial leader".to_owned(),
+            ));
+        }
+        // a witness never runs the command executor, its entries are applied without any state
+        let last_applied = if curp_cfg.is_witness {
+            0
+        } else {
+            cmd_executor
+                .last_applied()
+                .map_err(|e| CurpError::Internal(format!("get applied index error, {e}")))?
+        };
         let (ce_event_tx, task_rx, as_rx, done_tx) = conflict_checked_mpmc::channel();
 
-        let storage = Arc::new(RocksDBStorage::new(&curp_cfg.data_dir)?);
+        let storage: Arc<dyn StorageApi<Command = C>> = match curp_cfg.storage_backend {
+            CurpStorageBackend::RocksDB => Arc::new(RocksDBStorage::new(&curp_cfg.data_dir)?),
+            CurpStorageBackend::Wal => Arc::new(WalStorage::new(
+                &curp_cfg.data_dir,
+                curp_cfg.wal_segment_size,
+            )?),
+            _ => unreachable!("unknown curp storage backend"),
+        };
 
         // create curp state machine
-        let (voted_for, entries) = storage.recover().await?;
-        let curp = if voted_for.is_none() && entries.is_empty() {
+        let (voted_for, membership, log_base, entries) = storage.recover().await?;
+        let mut spec_pool_cmds = storage.spec_pool_cmds().await?;
+        let sessions = storage.sessions().await?;
+        if !curp_cfg.persist_spec_pool && !spec_pool_cmds.is_empty() {
+            // the cmds were persisted before the persistence is disabled, they are outdated
+            let ids: Vec<_> = spec_pool_cmds
+                .drain(..)
+                .map(|cmd| cmd.id().clone())
+                .collect();
+            storage.remove_spec_pool_cmds(&ids).await?;
+        }
+        let curp = if voted_for.is_none()
+            && membership.is_none()
+            && log_base.is_none()
+            && entries.is_empty()
+            && spec_pool_cmds.is_empty()
+        {
             Arc::new(RawCurp::new(
-                id,
-                others.keys().cloned().collect(),
+                id.clone(),
+                Membership::new(&id, others, curp_cfg.is_learner),
                 is_leader,
                 Arc::clone(&cmd_board),
                 Arc::clone(&spec_pool),
                 uncommitted_pool,
                 Arc::clone(&curp_cfg),
                 Box::new(ce_event_tx.clone()),
-                sync_events,
                 log_tx,
             ))
         } else {
             info!(
-                "{} recovered voted_for({voted_for:?}), entries from {:?} to {:?}",
+                "{} recovered voted_for({voted_for:?}), membership({membership:?}), log_base({log_base:?}), entries from {:?} to {:?}, {} spec pool cmds",
                 id,
                 entries.first(),
-                entries.last()
+                entries.last(),
+                spec_pool_cmds.len()
             );
+            // the initial membership is used if no conf change has been applied before
+            let membership =
+                membership.unwrap_or_else(|| Membership::new(&id, others, curp_cfg.is_learner));
             Arc::new(RawCurp::recover_from(
                 id,
-                others.keys().cloned().collect(),
+                membership,
                 is_leader,
                 Arc::clone(&cmd_board),
                 Arc::clone(&spec_pool),
                 uncommitted_pool,
                 &curp_cfg,
                 Box::new(ce_event_tx.clone()),
-                sync_events,
                 log_tx,
                 voted_for,
+                log_base,
                 entries,
+                spec_pool_cmds.into_iter().map(Arc::new).collect(),
+                sessions,
                 last_applied,
             ))
         };
 
-        start_bg_workers(
-            cmd_executor,
-            Arc::clone(&curp),
-            task_rx,
-            as_rx,
-            done_tx,
-            Arc::clone(&shutdown_trigger),
-        );
+        if !curp_cfg.is_witness {
+            start_bg_workers(
+                cmd_executor,
+                Arc::clone(&curp),
+                task_rx,
+                as_rx,
+                done_tx,
+                Arc::clone(&shutdown_trigger),
+            );
+        }
         run_gc_tasks(
             Arc::clone(&cmd_board),
             Arc::clone(&spec_pool),
+            curp_cfg.persist_spec_pool.then(|| Arc::clone(&storage)),
             curp_cfg.gc_interval,
         );
 
+        // connections with other servers are established by the membership task
+        let connects = Arc::new(RwLock::new(HashMap::new()));
         let curp_c = Arc::clone(&curp);
         let shutdown_trigger_c = Arc::clone(&shutdown_trigger);
         let storage_c = Arc::clone(&storage);
+        let connects_c = Arc::clone(&connects);
         let _ig = tokio::spawn(async move {
-            // establish connection with other servers
-            let connects = rpc::connect(others.clone(), tx_filter).await;
-            let election_task =
-                tokio::spawn(Self::election_task(Arc::clone(&curp_c), connects.clone()));
-            let sync_task_daemons = connects
-                .into_iter()
-                .map(|(server_id, connect)| {
-                    tokio::spawn(Self::sync_follower_daemon(
-                        Arc::clone(&curp_c),
-                        connect,
-                        curp_c.sync_event(&server_id),
-                    ))
-                })
-                .collect_vec();
+            let election_task = tokio::spawn(Self::election_task(
+                Arc::clone(&curp_c),
+                Arc::clone(&connects_c),
+            ));
+            // the membership task aborts all sync tasks by itself on shutdown
+            let _membership_task = tokio::spawn(Self::membership_task(
+                Arc::clone(&curp_c),
+                Arc::clone(&storage_c),
+                connects_c,
+                tx_filter,
+                Arc::clone(&shutdown_trigger_c),
+            ));
 
-            let log_persist_task = tokio::spawn(Self::log_persist_task(log_rx, storage_c));
+            let log_persist_task = tokio::spawn(Self::log_persist_task(
+                log_rx,
+                Arc::clone(&storage_c),
+                Arc::clone(&curp_c),
+            ));
+            let log_compact_task =
+                tokio::spawn(Self::log_compact_task(Arc::clone(&curp_c), storage_c));
+            let session_expire_task = tokio::spawn(Self::session_expire_task(curp_c));
             shutdown_trigger_c.listen().await;
             election_task.abort();
-            for sync_task in sync_task_daemons {
-                sync_task.abort();
-            }
             log_persist_task.abort();
+            log_compact_task.abort();
+            session_expire_task.abort();
         });
 
         Ok(Self {
@@ -497,15 +895,31 @@ impl<C: 'static + Command> CurpNode<C> {
             shutdown_trigger,
             ce_event_tx,
             storage,
+            connects,
         })
     }
 
+    /// Get the connects to the voters, only voters are asked for votes
+    fn voter_connects<Conn>(
+        curp: &RawCurp<C>,
+        connects: &ConnectsRef<Conn>,
+    ) -> HashMap<ServerId, Arc<Conn>> {
+        let ms = curp.membership();
+        connects
+            .read()
+            .iter()
+            .filter(|&(id, _)| ms.is_voter(id))
+            .map(|(id, connect)| (id.clone(), Arc::clone(connect)))
+            .collect()
+    }
+
     /// Candidate broadcasts votes
+    /// Return `Some(vote)` if the pre-vote is granted and a real election should be started with `vote`
     async fn bcast_vote(
         curp: &RawCurp<C>,
         connects: &HashMap<ServerId, Arc<impl ConnectApi>>,
         vote: Vote,
-    ) {
+    ) -> Option<Vote> {
         debug!("{} broadcasts votes to all servers", curp.id());
         let rpc_timeout = curp.cfg().rpc_timeout;
         let resps = connects
@@ -516,6 +930,8 @@ impl<C: 'static + Command> CurpNode<C> {
                     vote.candidate_id.clone(),
                     vote.last_log_index,
                     vote.last_log_term,
+                    vote.is_pre_vote,
+                    vote.is_leader_transfer,
                 );
                 async move {
                     let resp = connect.vote(req, rpc_timeout).await;
@@ -534,6 +950,13 @@ impl<C: 'static + Command> CurpNode<C> {
             });
         pin_mut!(resps);
         while let Some((id, resp)) = resps.next().await {
+            if vote.is_pre_vote {
+                match curp.handle_pre_vote_resp(&id, resp.term, resp.vote_granted) {
+                    Ok(None) => continue,
+                    Ok(Some(next_vote)) => return Some(next_vote),
+                    Err(()) => return None,
+                }
+            }
             // collect follower spec pool
             let follower_spec_pool = match resp.spec_pool() {
                 Err(e) => {
@@ -546,9 +969,10 @@ impl<C: 'static + Command> CurpNode<C> {
                 curp.handle_vote_resp(&id, resp.term, resp.vote_granted, follower_spec_pool);
             match result {
                 Ok(false) => {}
-                Ok(true) | Err(()) => return,
+                Ok(true) | Err(()) => return None,
             }
         }
+        None
     }
 
     /// Get a rx for leader changes
@@ -556,19 +980,184 @@ impl<C: 'static + Command> CurpNode<C> {
         self.curp.leader_rx()
     }
 
-    /// Log persist task
+    /// Get information of all members
+    pub(super) fn members(&self) -> Vec<MemberInfo> {
+        self.curp.membership().members()
+    }
+
+    /// Get a snapshot of the current node status
+    pub(super) fn status(&self) -> NodeStatus {
+        self.curp.status()
+    }
+
+    /// Transfer the leadership to `target`, wait until `target` becomes the leader
+    pub(super) async fn move_leader(&self, target: &ServerId) -> Result<(), ProposeError> {
+        // subscribe before the transfer starts to prevent missing leader changes
+        let mut leader_rx = self.curp.leader_rx();
+        if !self.curp.handle_move_leader(target)? {
+            return Ok(());
+        }
+
+        let wait_transferred = async {
+            while self.curp.leader().0.as_ref() != Some(target) {
+                if let Err(RecvError::Closed) = leader_rx.recv().await {
+                    break;
+                }
+            }
+        };
+        // the transfer is aborted if the transferee can't become the leader within an election timeout
+        let cfg = self.curp.cfg();
+        let election_timeout = cfg
+            .heartbeat_interval
+            .saturating_mul(cfg.follower_timeout_ticks.into());
+        if tokio::time::timeout(election_timeout, wait_transferred)
+            .await
+            .is_err()
+        {
+            self.curp.abort_leader_transfer(target);
+            return Err(ProposeError::SyncedError(format!(
+                "transfer the leadership to {target} timeout"
+            )));
+        }
+        Ok(())
+    }
+
+    /// Wait until the log entries up to log[`index`] are persisted
+    /// Return `Ok(term)` if they are persisted in `term`
+    /// Return `Err(term, hint_index)` if the term has changed while waiting
+    async fn wait_log_persisted(&self, term: u64, index: LogIndex) -> Result<u64, (u64, LogIndex)> {
+        let persist_event = self.curp.persist_event();
+        loop {
+            // grab the listener before checking to prevent missing the notification
+            let listener = persist_event.listen();
+            if self.curp.log_persisted_to(term, index)? {
+                return Ok(term);
+            }
+            listener.await;
+        }
+    }
+
+    /// Log persist task, the entries queued up while the previous batch is being persisted
+    /// are written and fsynced together as the next batch. A failed batch is retried until
+    /// it's persisted, so that the entries after it are never acked before it
     pub(super) async fn log_persist_task(
         mut log_rx: mpsc::UnboundedReceiver<LogEntry<C>>,
         storage: Arc<dyn StorageApi<Command = C>>,
+        curp: Arc<RawCurp<C>>,
     ) {
         while let Some(e) = log_rx.recv().await {
-            if let Err(err) = storage.put_log_entry(e).await {
-                error!("storage error, {err}");
+            let mut entries = vec![e];
+            while let Ok(e) = log_rx.try_recv() {
+                entries.push(e);
+            }
+            while let Err(err) = storage.put_log_entries(&entries).await {
+                error!("storage error, failed to persist log entries, {err}");
+                tokio::time::sleep(LOG_PERSIST_RETRY_INTERVAL).await;
+            }
+            if let Some(last) = entries.last() {
+                curp.on_log_persisted(last.index, last.term);
             }
         }
         error!("log persist task exits unexpectedly");
     }
 
+    /// Log compaction task, compact the applied log entries once the log grows beyond `log_entries_cap`,
+    /// the latest snapshot is kept to rebuild the command executor when the leader retires
+    async fn log_compact_task(curp: Arc<RawCurp<C>>, storage: Arc<dyn StorageApi<Command = C>>) {
+        let mut ticker = tokio::time::interval(curp.cfg().log_compact_interval);
+        ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        loop {
+            let _now = ticker.tick().await;
+            if curp.cfg().is_witness {
+                if let Some((meta, compacted)) = curp.witness_compact_log() {
+                    Self::compact_storage(
+                        storage.as_ref(),
+                        &curp.sessions(),
+                        &curp.membership(),
+                        meta,
+                        &compacted,
+                    )
+                    .await;
+                }
+                continue;
+            }
+            if let Some((rx, sessions)) = curp.snapshot_for_retirement() {
+                match rx.await {
+                    Ok(snapshot) => {
+                        if let Some(stale) = curp.retain_snapshot(snapshot, sessions) {
+                            Self::clean_snapshot(stale).await;
+                        }
+                    }
+                    Err(err) => warn!("failed to receive snapshot result, {err}"),
+                }
+            }
+            let Some((rx, sessions)) = curp.snapshot_for_compaction() else {
+                continue;
+            };
+            let snapshot = match rx.await {
+                Ok(snapshot) => snapshot,
+                Err(err) => {
+                    warn!("failed to receive snapshot result, {err}");
+                    continue;
+                }
+            };
+            let meta = snapshot.meta;
+            let (compacted, stale) = curp.compact_log(snapshot, sessions.clone());
+            if let Some(stale) = stale {
+                Self::clean_snapshot(stale).await;
+            }
+            Self::compact_storage(
+                storage.as_ref(),
+                &sessions,
+                &curp.membership(),
+                meta,
+                &compacted,
+            )
+            .await;
+        }
+    }
+
+    /// Compact the persisted log, the client sessions and the membership are flushed before
+    /// it since the compacted entries can't rebuild them anymore
+    async fn compact_storage(
+        storage: &dyn StorageApi<Command = C>,
+        sessions: &SessionTable<C>,
+        membership: &Membership,
+        meta: SnapshotMeta,
+        compacted: &[ProposeId],
+    ) {
+        if let Err(err) = storage.flush_sessions(sessions).await {
+            error!("failed to flush the client sessions, {err}");
+            return;
+        }
+        if let Err(err) = storage.flush_membership(membership).await {
+            error!("failed to flush the membership, {err}");
+            return;
+        }
+        if let Err(err) = storage.compact(meta, compacted).await {
+            error!("storage error, {err}");
+        }
+    }
+
+    /// Clean the files of a snapshot that is no longer needed
+    async fn clean_snapshot(snapshot: Snapshot) {
+        if let Err(err) = snapshot.into_inner().clean().await {
+            warn!("failed to clean the snapshot, {err}");
+        }
+    }
+
+    /// Session expiration task, the leader expires the client sessions that have been idle for `session_timeout`
+    async fn session_expire_task(curp: Arc<RawCurp<C>>) {
+        let mut ticker = tokio::time::interval(curp.cfg().gc_interval);
+        ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        loop {
+            let _now = ticker.tick().await;
+            if let Some(index) = curp.expire_sessions() {
+                debug!("{} expires idle client sessions in log[{index}]", curp.id());
+            }
+        }
+    }
+
     /// Send `append_entries` request
     #[allow(clippy::integer_arithmetic)] // won't overflow
     async fn send_ae(
@@ -576,8 +1165,11 @@ impl<C: 'static + Command> CurpNode<C> {
         curp: &RawCurp<C>,
         ae: AppendEntries<C>,
     ) -> Result<(), SendAEError> {
-        let last_sent_index = (!ae.entries.is_empty())
-            .then(|| ae.prev_log_index + ae.entries.len().numeric_cast::<u64>());
+        // a successful heartbeat also proves that the follower has matched `prev_log_index`
+        let prev_log_index = ae.prev_log_index;
+        let last_sent_index = prev_log_index + ae.entries.len().numeric_cast::<u64>();
+        let read_seq = ae.read_seq;
+        let sent_at = Instant::now();
         let req = AppendEntriesRequest::new(
             ae.term,
             ae.leader_id,
@@ -588,20 +1180,26 @@ impl<C: 'static + Command> CurpNode<C> {
         )?;
 
         debug!("{} send ae to {}", curp.id(), connect.id());
-        let resp = connect
-            .append_entries(req, curp.cfg().rpc_timeout)
-            .await?
-            .into_inner();
+        let resp = match connect.append_entries(req, curp.cfg().rpc_timeout).await {
+            Ok(resp) => resp.into_inner(),
+            Err(err) => {
+                curp.report_unreachable(connect.id());
+                return Err(err.into());
+            }
+        };
 
         let succeeded = curp
             .handle_append_entries_resp(
                 connect.id(),
+                prev_log_index,
                 last_sent_index,
                 resp.term,
                 resp.success,
                 resp.hint_index,
             )
             .map_err(|_e| SendAEError::NotLeader)?;
+        // the follower still recognizes self as the leader even if it rejects the entries
+        curp.ack_heartbeat(connect.id(), read_seq, sent_at);
 
         if succeeded {
             Ok(())
@@ -610,15 +1208,34 @@ impl<C: 'static + Command> CurpNode<C> {
         }
     }
 
-    /// Send snapshot
+    /// Send `timeout_now` to the leader transferee
+    async fn send_timeout_now(connect: &impl ConnectApi, curp: &RawCurp<C>, term: u64) {
+        debug!("{} send timeout_now to {}", curp.id(), connect.id());
+        let req = TimeoutNowRequest::new(term, curp.id().clone());
+        if let Err(err) = connect.timeout_now(req, curp.cfg().rpc_timeout).await {
+            warn!("timeout_now to {} failed, {err}", connect.id());
+        }
+    }
+
+    /// Send snapshot, attached with the membership and the client sessions when the snapshot is taken
     async fn send_snapshot(
         connect: &impl ConnectApi,
         curp: &RawCurp<C>,
         snapshot: Snapshot,
+        membership: &Membership,
+        sessions: &SessionTable<C>,
     ) -> Result<(), SendSnapshotError> {
         let meta = snapshot.meta;
+        let membership = bincode::serialize(membership)?;
+        let sessions = bincode::serialize(sessions)?;
         let resp = connect
-            .install_snapshot(curp.term(), curp.id().clone(), snapshot)
+            .install_snapshot(
+                curp.term(),
+                curp.id().clone(),
+                snapshot,
+                membership,
+                sessions,
+            )
             .await?
             .into_inner();
         curp.handle_snapshot_resp(connect.id(), meta, resp.term)
@@ -708,10 +1325,10 @@ mod tests {
 
         tokio::spawn(CurpNode::election_task(
             Arc::clone(&curp),
-            HashMap::from([
+            Arc::new(RwLock::new(HashMap::from([
                 ("S1".to_owned(), Arc::new(mock_connect1)),
                 ("S2".to_owned(), Arc::new(mock_connect2)),
-            ]),
+            ]))),
         ));
         sleep_secs(3).await;
         assert!(curp.is_leader());
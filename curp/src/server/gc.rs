@@ -1,36 +1,76 @@
-use std::{collections::HashSet, time::Duration};
+use std::{collections::HashSet, sync::Arc, time::Duration};
 
+use tracing::warn;
 use utils::parking_lot_lock::MutexMap;
 
-use super::spec_pool::SpecPoolRef;
+use super::{
+    spec_pool::SpecPoolRef,
+    storage::{StorageApi, StorageError},
+};
 use crate::{
     cmd::{Command, ProposeId},
     server::cmd_board::CmdBoardRef,
 };
 
-/// Run background GC tasks for Curp server
+/// Run background GC tasks for Curp server, the persisted spec pool is cleaned up
+/// together with the spec pool if `storage` is given
 pub(super) fn run_gc_tasks<C: Command + 'static>(
     cmd_board: CmdBoardRef<C>,
     spec: SpecPoolRef<C>,
+    storage: Option<Arc<dyn StorageApi<Command = C>>>,
     gc_interval: Duration,
 ) {
-    let _spec_pool_gc = tokio::spawn(gc_spec_pool(spec, gc_interval));
+    let _spec_pool_gc = tokio::spawn(gc_spec_pool(spec, storage, gc_interval));
     let _cmd_board_gc = tokio::spawn(gc_cmd_board(cmd_board, gc_interval));
 }
 
 /// Cleanup spec pool
-async fn gc_spec_pool<C: Command + 'static>(sp: SpecPoolRef<C>, interval: Duration) {
+async fn gc_spec_pool<C: Command + 'static>(
+    sp: SpecPoolRef<C>,
+    storage: Option<Arc<dyn StorageApi<Command = C>>>,
+    interval: Duration,
+) {
     let mut last_check: HashSet<ProposeId> =
         sp.map_lock(|sp_l| sp_l.pool.keys().cloned().collect());
+    let mut last_persisted: HashSet<ProposeId> = HashSet::new();
     loop {
         tokio::time::sleep(interval).await;
-        let mut sp = sp.lock();
-        sp.pool.retain(|k, _v| !last_check.contains(k));
+        let live = sp.map_lock(|mut sp_l| {
+            sp_l.pool.retain(|k, _v| !last_check.contains(k));
+            last_check = sp_l.pool.keys().cloned().collect();
+            last_check.clone()
+        });
 
-        last_check = sp.pool.keys().cloned().collect();
+        if let Some(ref storage) = storage {
+            if let Err(e) =
+                gc_persisted_spec_pool(storage.as_ref(), &live, &mut last_persisted).await
+            {
+                warn!("failed to cleanup the persisted spec pool, {e}");
+            }
+        }
     }
 }
 
+/// Remove the persisted cmds that are no longer in the spec pool. A cmd is inserted into the
+/// spec pool before it's persisted, so only the ones persisted before the last check are removed
+async fn gc_persisted_spec_pool<C: Command + 'static>(
+    storage: &dyn StorageApi<Command = C>,
+    live: &HashSet<ProposeId>,
+    last_persisted: &mut HashSet<ProposeId>,
+) -> Result<(), StorageError> {
+    let removed: Vec<_> = last_persisted.difference(live).cloned().collect();
+    if !removed.is_empty() {
+        storage.remove_spec_pool_cmds(&removed).await?;
+    }
+    *last_persisted = storage
+        .spec_pool_cmds()
+        .await?
+        .iter()
+        .map(|cmd| cmd.id().clone())
+        .collect();
+    Ok(())
+}
+
 /// Cleanup cmd board
 async fn gc_cmd_board<C: Command + 'static>(cmd_board: CmdBoardRef<C>, interval: Duration) {
     let mut last_check_len_er = 0;
@@ -67,6 +107,7 @@ mod tests {
     use std::{sync::Arc, time::Duration};
 
     use parking_lot::{Mutex, RwLock};
+    use tokio::fs::remove_dir_all;
 
     use super::*;
     use crate::{
@@ -75,8 +116,9 @@ mod tests {
             cmd_board::{CmdBoardRef, CommandBoard},
             gc::gc_cmd_board,
             spec_pool::{SpecPoolRef, SpeculativePool},
+            storage::rocksdb::RocksDBStorage,
         },
-        test_utils::{sleep_secs, test_cmd::TestCommand},
+        test_utils::{random_id, sleep_secs, test_cmd::TestCommand},
     };
 
     #[tokio::test]
@@ -133,7 +175,11 @@ mod tests {
     #[tokio::test]
     async fn spec_gc_test() {
         let spec: SpecPoolRef<TestCommand> = Arc::new(Mutex::new(SpeculativePool::new()));
-        tokio::spawn(gc_spec_pool(Arc::clone(&spec), Duration::from_millis(500)));
+        tokio::spawn(gc_spec_pool(
+            Arc::clone(&spec),
+            None,
+            Duration::from_millis(500),
+        ));
 
         tokio::time::sleep(Duration::from_millis(100)).await;
         let cmd1 = Arc::new(TestCommand::default());
@@ -182,10 +228,46 @@ mod tests {
             .pool
             .insert(cmd2.id().clone(), Arc::clone(&cmd3));
 
-        tokio::spawn(gc_spec_pool(Arc::clone(&spec), Duration::from_millis(500)));
+        tokio::spawn(gc_spec_pool(
+            Arc::clone(&spec),
+            None,
+            Duration::from_millis(500),
+        ));
 
         spec.lock().remove(cmd2.id());
 
         sleep_secs(1).await;
     }
+
+    #[tokio::test]
+    async fn persisted_spec_gc_test() {
+        let db_dir = format!("/tmp/curp-{}", random_id());
+        let storage: Arc<dyn StorageApi<Command = TestCommand>> =
+            Arc::new(RocksDBStorage::new(&db_dir).unwrap());
+        let spec: SpecPoolRef<TestCommand> = Arc::new(Mutex::new(SpeculativePool::new()));
+        tokio::spawn(gc_spec_pool(
+            Arc::clone(&spec),
+            Some(Arc::clone(&storage)),
+            Duration::from_millis(500),
+        ));
+
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let cmd1 = Arc::new(TestCommand::default());
+        let _ig = spec.lock().insert(Arc::clone(&cmd1));
+        storage.put_spec_pool_cmd(cmd1.as_ref()).await.unwrap();
+
+        // at 600ms, cmd1 is synced
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        spec.lock().remove(cmd1.id());
+        let cmd2 = Arc::new(TestCommand::new_get(vec![2]));
+        let _ig = spec.lock().insert(Arc::clone(&cmd2));
+        storage.put_spec_pool_cmd(cmd2.as_ref()).await.unwrap();
+
+        // at 1100ms, cmd1 should be removed from storage
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        let persisted = storage.spec_pool_cmds().await.unwrap();
+        assert_eq!(persisted, vec![cmd2.as_ref().clone()]);
+
+        remove_dir_all(db_dir).await.unwrap();
+    }
 }
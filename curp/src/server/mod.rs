@@ -10,14 +10,17 @@ use utils::{config::CurpConfig, tracing::Extract};
 use self::curp_node::{CurpError, CurpNode};
 use crate::{
     cmd::{Command, CommandExecutor},
-    error::ServerError,
+    error::{ProposeError, ServerError},
+    members::MemberInfo,
     rpc::{
         AppendEntriesRequest, AppendEntriesResponse, FetchLeaderRequest, FetchLeaderResponse,
         FetchReadStateRequest, FetchReadStateResponse, InstallSnapshotRequest,
-        InstallSnapshotResponse, ProposeRequest, ProposeResponse, ProtocolServer, VoteRequest,
-        VoteResponse, WaitSyncedRequest, WaitSyncedResponse,
+        InstallSnapshotResponse, ProposeConfChangeRequest, ProposeConfChangeResponse,
+        ProposeRequest, ProposeResponse, ProtocolServer, RegisterSessionRequest,
+        RegisterSessionResponse, TimeoutNowRequest, TimeoutNowResponse, VoteRequest, VoteResponse,
+        WaitSyncedRequest, WaitSyncedResponse,
     },
-    ServerId, TxFilter,
+    LogIndex, ServerId, TxFilter,
 };
 
 /// Command worker to do execution and after sync
@@ -41,9 +44,30 @@ mod curp_node;
 /// Storage
 mod storage;
 
+/// Client sessions
+mod session;
+
 /// Default server serving port
 static DEFAULT_SERVER_PORT: u16 = 12345;
 
+/// Read-only status of a curp node
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct NodeStatus {
+    /// Id of the leader known by this node
+    pub leader: Option<ServerId>,
+    /// Current term
+    pub term: u64,
+    /// Index of the last committed log entry
+    pub commit_index: LogIndex,
+    /// Index of the last applied log entry
+    pub applied_index: LogIndex,
+    /// Whether this node is a learner
+    pub is_learner: bool,
+    /// Whether this node is a witness
+    pub is_witness: bool,
+}
+
 /// The Rpc Server to handle rpc requests
 /// This Wrapper is introduced due to the `MadSim` rpc lib
 #[derive(Clone, Debug)]
@@ -82,7 +106,7 @@ impl<C: 'static + Command> crate::rpc::Protocol for Rpc<C> {
         request: tonic::Request<AppendEntriesRequest>,
     ) -> Result<tonic::Response<AppendEntriesResponse>, tonic::Status> {
         Ok(tonic::Response::new(
-            self.inner.append_entries(request.into_inner())?,
+            self.inner.append_entries(request.into_inner()).await?,
         ))
     }
 
@@ -125,7 +149,37 @@ impl<C: 'static + Command> crate::rpc::Protocol for Rpc<C> {
         request: tonic::Request<FetchReadStateRequest>,
     ) -> Result<tonic::Response<FetchReadStateResponse>, tonic::Status> {
         Ok(tonic::Response::new(
-            self.inner.fetch_read_state(request.into_inner())?,
+            self.inner.fetch_read_state(request.into_inner()).await?,
+        ))
+    }
+
+    #[instrument(skip_all, name = "curp_propose_conf_change")]
+    async fn propose_conf_change(
+        &self,
+        request: tonic::Request<ProposeConfChangeRequest>,
+    ) -> Result<tonic::Response<ProposeConfChangeResponse>, tonic::Status> {
+        Ok(tonic::Response::new(
+            self.inner.propose_conf_change(request.into_inner()).await?,
+        ))
+    }
+
+    #[instrument(skip_all, name = "curp_timeout_now")]
+    async fn timeout_now(
+        &self,
+        request: tonic::Request<TimeoutNowRequest>,
+    ) -> Result<tonic::Response<TimeoutNowResponse>, tonic::Status> {
+        Ok(tonic::Response::new(
+            self.inner.timeout_now(request.into_inner())?,
+        ))
+    }
+
+    #[instrument(skip_all, name = "curp_register_session")]
+    async fn register_session(
+        &self,
+        request: tonic::Request<RegisterSessionRequest>,
+    ) -> Result<tonic::Response<RegisterSessionResponse>, tonic::Status> {
+        Ok(tonic::Response::new(
+            self.inner.register_session(request.into_inner()).await?,
         ))
     }
 }
@@ -262,6 +316,32 @@ impl<C: Command + 'static> Rpc<C> {
     pub fn leader_rx(&self) -> broadcast::Receiver<Option<ServerId>> {
         self.inner.leader_rx()
     }
+
+    /// Get information of all members in the cluster
+    #[inline]
+    #[must_use]
+    pub fn members(&self) -> Vec<MemberInfo> {
+        self.inner.members()
+    }
+
+    /// Get the status of the current node, it's read locally and may lag behind the leader
+    #[inline]
+    #[must_use]
+    pub fn status(&self) -> NodeStatus {
+        self.inner.status()
+    }
+
+    /// Transfer the leadership of the current node to `target`, the leader stops accepting
+    /// proposals until `target` becomes the leader or the transfer times out
+    ///
+    /// # Errors
+    ///   `ProposeError::ProtocolError` if the current node is not the leader or `target` is not a voter
+    ///   `ProposeError::LeaderTransferring` if another transfer is in progress
+    ///   `ProposeError::SyncedError` if `target` doesn't become the leader within an election timeout
+    #[inline]
+    pub async fn move_leader(&self, target: &ServerId) -> Result<(), ProposeError> {
+        self.inner.move_leader(target).await
+    }
 }
 
 impl From<CurpError> for tonic::Status {
@@ -9,7 +9,8 @@ use tracing::error;
 
 use crate::{
     cmd::{Command, ProposeId},
-    log_entry::LogEntry,
+    log_entry::{EntryData, LogEntry},
+    members::ConfChange,
     snapshot::SnapshotMeta,
     LogIndex,
 };
@@ -31,6 +32,9 @@ pub(super) struct Log<C: Command> {
     pub(super) base_term: u64,
     /// Index of highest log entry known to be committed
     pub(super) commit_index: LogIndex,
+    /// Index of highest log entry known to be durably persisted, the entries after it may be
+    /// lost on crash
+    pub(super) persisted_index: LogIndex,
     /// Index of highest log entry applied to state machine
     /// Note this `last_applied` is a little bit different from the one in command executor
     /// in that it means the index of the last log sent to the `cmd_worker`(may not be executed yet)
@@ -51,18 +55,23 @@ impl<C: Command> Debug for Log<C> {
             .field("base_index", &self.base_index)
             .field("base_term", &self.base_term)
             .field("commit_index", &self.commit_index)
+            .field("persisted_index", &self.persisted_index)
             .field("last_applied", &self.last_applied)
             .finish()
     }
 }
 
 impl<C: 'static + Command> Log<C> {
-    /// Recover log from the given entires
+    /// Recover log from the given entires, `base` is the last compacted entry if the log has been compacted before
     pub(super) fn recover(
         log_tx: mpsc::UnboundedSender<LogEntry<C>>,
+        base: Option<SnapshotMeta>,
         entries: Vec<LogEntry<C>>,
         batch_limit: u64,
     ) -> Self {
+        let (base_index, base_term) = base.map_or((0, 0), |meta| {
+            (meta.last_included_index, meta.last_included_term)
+        });
         let mut batch_index = Vec::with_capacity(entries.capacity());
         batch_index.push(0);
         for entry in &entries {
@@ -75,12 +84,15 @@ impl<C: 'static + Command> Log<C> {
             }
         }
 
+        // the recovered entries have been persisted
+        let persisted_index = entries.last().map_or(base_index, |entry| entry.index);
         Self {
-            entries, // a fake log[0]
-            commit_index: 0,
-            base_index: 0,
-            base_term: 0,
-            last_applied: 0,
+            entries,
+            commit_index: base_index,
+            persisted_index,
+            base_index,
+            base_term,
+            last_applied: base_index,
             log_tx,
             batch_index,
             batch_limit,
@@ -95,6 +107,7 @@ impl<C: 'static + Command> Log<C> {
         Self {
             entries, // a fake log[0]
             commit_index: 0,
+            persisted_index: 0,
             base_index: 0,
             base_term: 0,
             last_applied: 0,
@@ -170,6 +183,8 @@ impl<C: 'static + Command> Log<C> {
 
             self.entries.truncate(pi);
             self.batch_index.truncate(pi.overflow_add(1));
+            // the persisted entries after `li - 1` are replaced
+            self.persisted_index = self.persisted_index.min(li - 1);
 
             self.entries.push(entry.clone());
             let pre_entry_size = if let Some(&last_entry_size) = self.batch_index.last() {
@@ -205,10 +220,45 @@ impl<C: 'static + Command> Log<C> {
 
     /// Pack the cmd into a log entry and push it to the end of the log, return its index
     pub(super) fn push_cmd(&mut self, term: u64, cmd: Arc<C>) -> Result<LogIndex, bincode::Error> {
-        assert_eq!(self.batch_index.len(), self.entries.len() + 1);
+        let index = self.last_log_index() + 1;
+        self.push_entry(LogEntry::new(index, term, cmd))
+    }
+
+    /// Pack the conf change into a log entry and push it to the end of the log, return its index
+    pub(super) fn push_conf_change(
+        &mut self,
+        term: u64,
+        change: ConfChange,
+    ) -> Result<LogIndex, bincode::Error> {
+        let index = self.last_log_index() + 1;
+        self.push_entry(LogEntry::new_conf_change(index, term, change))
+    }
+
+    /// Push an empty entry to the end of the log, return its index
+    pub(super) fn push_empty(&mut self, term: u64) -> Result<LogIndex, bincode::Error> {
+        let index = self.last_log_index() + 1;
+        self.push_entry(LogEntry::new_empty(index, term))
+    }
 
+    /// Push a session registration to the end of the log, its index is the client id
+    pub(super) fn push_register_session(&mut self, term: u64) -> Result<LogIndex, bincode::Error> {
         let index = self.last_log_index() + 1;
-        let entry = LogEntry::new(index, term, cmd);
+        self.push_entry(LogEntry::new_register_session(index, term))
+    }
+
+    /// Push a session expiration to the end of the log, return its index
+    pub(super) fn push_expire_sessions(
+        &mut self,
+        term: u64,
+        client_ids: Vec<u64>,
+    ) -> Result<LogIndex, bincode::Error> {
+        let index = self.last_log_index() + 1;
+        self.push_entry(LogEntry::new_expire_sessions(index, term, client_ids))
+    }
+
+    /// Push a new entry to the end of the log, return its index
+    fn push_entry(&mut self, entry: LogEntry<C>) -> Result<LogIndex, bincode::Error> {
+        assert_eq!(self.batch_index.len(), self.entries.len() + 1);
 
         let entry_size = serialized_size(&entry)?;
         let pre_entry_size = if let Some(&last_entry_size) = self.batch_index.last() {
@@ -256,7 +306,27 @@ impl<C: 'static + Command> Log<C> {
 
     /// Get existing cmd ids
     pub(super) fn get_cmd_ids(&self) -> HashSet<&ProposeId> {
-        self.entries.iter().map(|entry| entry.cmd.id()).collect()
+        self.entries
+            .iter()
+            .filter_map(|entry| entry.cmd().map(|cmd| cmd.id()))
+            .collect()
+    }
+
+    /// Get the index of the last command at or before log[`index`], the entries after it don't change
+    /// the state machine. Return the base index if there is no such command in the log
+    pub(super) fn last_cmd_index_to(&self, index: LogIndex) -> LogIndex {
+        let end = self.li_to_pi(index + 1).min(self.entries.len());
+        self.entries
+            .get(..end)
+            .and_then(|entries| entries.iter().rev().find(|entry| entry.cmd().is_some()))
+            .map_or(self.base_index, |entry| entry.index)
+    }
+
+    /// Check if there is a conf change that hasn't been applied
+    pub(super) fn has_pending_conf_change(&self) -> bool {
+        self.entries.iter().any(|entry| {
+            entry.index > self.last_applied && matches!(entry.entry_data, EntryData::ConfChange(_))
+        })
     }
 
     /// Get previous log entry's term and index
@@ -282,7 +352,67 @@ impl<C: 'static + Command> Log<C> {
         self.base_term = meta.last_included_term;
         self.last_applied = meta.last_included_index.numeric_cast();
         self.commit_index = meta.last_included_index.numeric_cast();
+        self.persisted_index = meta.last_included_index.numeric_cast();
         self.entries.clear();
+        self.batch_index.clear();
+        self.batch_index.push(0);
+    }
+
+    /// Get the number of log entries that haven't been compacted
+    pub(super) fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Compact the log entries up to `index`(inclusive), only applied entries can be compacted.
+    /// Return the ids of the compacted cmds
+    pub(super) fn compact(&mut self, index: LogIndex) -> Vec<ProposeId> {
+        if index <= self.base_index {
+            return vec![];
+        }
+        assert!(
+            index <= self.last_applied,
+            "can't compact log[{index}] which hasn't been applied, last_applied: {}",
+            self.last_applied
+        );
+        let pi = self.li_to_pi(index);
+        let base_term = self.entries.get(pi).map_or_else(
+            || {
+                unreachable!(
+                    "system corrupted, compact log[{index}] when we only have {} log entries",
+                    self.last_log_index()
+                )
+            },
+            |entry| entry.term,
+        );
+        let compacted = self
+            .entries
+            .drain(..=pi)
+            .filter_map(|entry| entry.cmd().map(|cmd| cmd.id().clone()))
+            .collect();
+        // the prefix sum of the compacted entries becomes the new batch_index[0]
+        let _compacted_size = self.batch_index.drain(..=pi);
+        if let Some(&base_size) = self.batch_index.first() {
+            self.batch_index
+                .iter_mut()
+                .for_each(|size| *size = size.overflow_sub(base_size));
+        }
+        self.base_index = index;
+        self.base_term = base_term;
+        // the compacted entries are included in the snapshot
+        self.persisted_index = self.persisted_index.max(index);
+        compacted
+    }
+
+    /// Update `persisted_index` once the entries up to log[`index`] of `term` are persisted,
+    /// return `false` if the entry has been replaced or compacted and the update is ignored
+    pub(super) fn persisted_to(&mut self, index: LogIndex, term: u64) -> bool {
+        // the entries are persisted in order, so the entries before log[index] have been
+        // persisted if log[index] is still the one that was persisted
+        if self.get(index).map_or(true, |entry| entry.term != term) {
+            return false;
+        }
+        self.persisted_index = self.persisted_index.max(index);
+        true
     }
 }
 
@@ -358,6 +488,38 @@ mod tests {
         assert_eq!(log[2].term, 2);
     }
 
+    #[test]
+    fn persisted_index_will_fall_back_when_entries_are_replaced() {
+        let (log_tx, _log_rx) = mpsc::unbounded_channel();
+        let mut log = Log::<TestCommand>::new(log_tx, default_batch_max_size());
+        let result = log.try_append_entries(
+            vec![
+                LogEntry::new(1, 1, Arc::new(TestCommand::default())),
+                LogEntry::new(2, 1, Arc::new(TestCommand::default())),
+                LogEntry::new(3, 1, Arc::new(TestCommand::default())),
+            ],
+            0,
+            0,
+        );
+        assert!(result.is_ok());
+        assert!(log.persisted_to(3, 1));
+        assert_eq!(log.persisted_index, 3);
+
+        let result = log.try_append_entries(
+            vec![LogEntry::new(2, 2, Arc::new(TestCommand::default()))],
+            1,
+            1,
+        );
+        assert!(result.is_ok());
+        assert_eq!(log.persisted_index, 1);
+        // the persistence of the replaced entries is ignored
+        assert!(!log.persisted_to(3, 1));
+        assert!(!log.persisted_to(2, 1));
+        assert_eq!(log.persisted_index, 1);
+        assert!(log.persisted_to(2, 2));
+        assert_eq!(log.persisted_index, 2);
+    }
+
     #[test]
     fn try_append_entries_will_not_append() {
         let (log_tx, _log_rx) = mpsc::unbounded_channel();
@@ -473,7 +635,7 @@ mod tests {
             .map(|(idx, cmd)| LogEntry::new((idx + 1).numeric_cast(), 0, cmd))
             .collect::<Vec<LogEntry<TestCommand>>>();
         let (tx, _rx) = mpsc::unbounded_channel();
-        let log = Log::recover(tx, entries, default_batch_max_size());
+        let log = Log::recover(tx, None, entries, default_batch_max_size());
         assert_eq!(log.entries.len(), 10);
         assert_eq!(log.batch_index.len(), 11);
         assert_eq!(log.batch_index[0], 0);
@@ -483,4 +645,115 @@ mod tests {
             .enumerate()
             .for_each(|(idx, &size)| assert_eq!(size, entry_size * idx.numeric_cast::<u64>()));
     }
+
+    #[test]
+    fn compact_log_should_success() {
+        let entry_size =
+            serialized_size(&LogEntry::new(0, 0, Arc::new(TestCommand::default()))).unwrap();
+        let (tx, _rx) = mpsc::unbounded_channel();
+        let mut log = Log::new(tx, default_batch_max_size());
+        for term in 1..=10 {
+            let _index = log
+                .push_cmd(term, Arc::new(TestCommand::default()))
+                .unwrap();
+        }
+        log.commit_index = 6;
+        log.last_applied = 6;
+
+        let compacted = log.compact(4);
+        assert_eq!(compacted.len(), 4);
+        assert_eq!(log.base_index, 4);
+        assert_eq!(log.base_term, 4);
+        assert_eq!(log.len(), 6);
+        assert_eq!(log.last_log_index(), 10);
+        assert!(log.get(4).is_none());
+        assert_eq!(log[5].index, 5);
+        assert_eq!(log.get_prev_entry_info(5), (4, 4));
+        assert_eq!(log.batch_index.len(), 7);
+        log.batch_index
+            .iter()
+            .enumerate()
+            .for_each(|(idx, &size)| assert_eq!(size, entry_size * idx.numeric_cast::<u64>()));
+
+        // compacting the compacted entries takes no effect
+        assert!(log.compact(3).is_empty());
+        assert_eq!(log.base_index, 4);
+
+        assert_eq!(log.compact(6).len(), 2);
+        assert_eq!(log.base_index, 6);
+        assert_eq!(log.base_term, 6);
+        assert_eq!(log.len(), 4);
+        assert_eq!(log.get_from(7).unwrap().len(), 4);
+    }
+
+    #[test]
+    #[should_panic]
+    fn compact_unapplied_log_should_panic() {
+        let (tx, _rx) = mpsc::unbounded_channel();
+        let mut log = Log::new(tx, default_batch_max_size());
+        for _ in 0..5 {
+            let _index = log.push_cmd(1, Arc::new(TestCommand::default())).unwrap();
+        }
+        log.last_applied = 2;
+        let _compacted = log.compact(3);
+    }
+
+    #[test]
+    fn recover_compacted_log_should_success() {
+        let entries = repeat(Arc::new(TestCommand::default()))
+            .enumerate()
+            .take(5)
+            .map(|(idx, cmd)| LogEntry::new((idx + 6).numeric_cast(), 2, cmd))
+            .collect::<Vec<LogEntry<TestCommand>>>();
+        let (tx, _rx) = mpsc::unbounded_channel();
+        let base = SnapshotMeta {
+            last_included_index: 5,
+            last_included_term: 1,
+        };
+        let log = Log::recover(tx, Some(base), entries, default_batch_max_size());
+        assert_eq!(log.base_index, 5);
+        assert_eq!(log.base_term, 1);
+        assert_eq!(log.last_applied, 5);
+        assert_eq!(log.last_log_index(), 10);
+        assert_eq!(log[6].term, 2);
+        assert_eq!(log.get_prev_entry_info(6), (5, 1));
+    }
+
+    #[test]
+    fn pending_conf_change_will_be_found() {
+        let (tx, _rx) = mpsc::unbounded_channel();
+        let mut log = Log::<TestCommand>::new(tx, default_batch_max_size());
+        let _index = log.push_cmd(1, Arc::new(TestCommand::default())).unwrap();
+        assert!(!log.has_pending_conf_change());
+
+        let index = log
+            .push_conf_change(1, ConfChange::RemoveNode("S1".to_owned()))
+            .unwrap();
+        assert_eq!(index, 2);
+        assert!(log.has_pending_conf_change());
+        assert!(log.get_cmd_ids().len() == 1);
+
+        log.commit_index = 2;
+        log.last_applied = 2;
+        assert!(!log.has_pending_conf_change());
+    }
+
+    #[test]
+    fn last_cmd_index_will_skip_non_cmd_entries() {
+        let (tx, _rx) = mpsc::unbounded_channel();
+        let mut log = Log::<TestCommand>::new(tx, default_batch_max_size());
+        assert_eq!(log.last_cmd_index_to(0), 0);
+
+        let _index = log.push_cmd(1, Arc::new(TestCommand::default())).unwrap();
+        let _index = log
+            .push_conf_change(1, ConfChange::RemoveNode("S1".to_owned()))
+            .unwrap();
+        let index = log.push_empty(2).unwrap();
+        assert_eq!(index, 3);
+        assert_eq!(log.last_cmd_index_to(3), 1);
+
+        let index = log.push_cmd(2, Arc::new(TestCommand::default())).unwrap();
+        assert_eq!(log.last_cmd_index_to(index), index);
+        assert_eq!(log.last_cmd_index_to(index - 1), 1);
+    }
 }
@@ -5,6 +5,8 @@
 //!     1. self.st
 //!     2. self.cst
 //!     3. self.log
+//!     4. self.ms
+//!     5. self.ctx.sync_events
 
 #![allow(clippy::similar_names)] // st, lst, cst is similar but not confusing
 #![allow(clippy::integer_arithmetic)] // u64 is large enough and won't overflow
@@ -17,6 +19,7 @@ use std::{
         atomic::{AtomicU8, Ordering},
         Arc,
     },
+    time::Instant,
 };
 
 use clippy_utilities::NumericCast;
@@ -25,8 +28,9 @@ use itertools::Itertools;
 use parking_lot::{Mutex, RwLock, RwLockUpgradableReadGuard};
 use tokio::sync::{broadcast, mpsc, oneshot};
 use tracing::{
-    debug, error,
+    debug, error, info,
     log::{log_enabled, Level},
+    warn,
 };
 use utils::{
     config::CurpConfig,
@@ -35,15 +39,20 @@ use utils::{
 
 use self::{
     log::Log,
-    state::{CandidateState, LeaderState, State},
+    state::{CandidateState, LeaderState, ProgressState, State},
+};
+use super::{
+    cmd_worker::CEEventTxApi,
+    curp_node::UncommittedPoolRef,
+    session::{CachedResponse, SessionTable},
 };
-use super::{cmd_worker::CEEventTxApi, curp_node::UncommittedPoolRef};
 use crate::{
-    cmd::{Command, ProposeId},
+    cmd::{ClientSession, Command, ProposeId},
     error::ProposeError,
-    log_entry::LogEntry,
+    log_entry::{EntryData, LogEntry},
+    members::{ConfChange, Membership},
     rpc::{IdSet, ReadState},
-    server::{cmd_board::CmdBoardRef, spec_pool::SpecPoolRef},
+    server::{cmd_board::CmdBoardRef, spec_pool::SpecPoolRef, NodeStatus},
     snapshot::{Snapshot, SnapshotMeta},
     LogIndex, ServerId,
 };
@@ -65,16 +74,19 @@ pub(super) struct RawCurp<C: Command> {
     cst: Mutex<CandidateState<C>>,
     /// Curp logs
     log: RwLock<Log<C>>,
+    /// Cluster membership
+    ms: RwLock<Membership>,
     /// Relevant context
     ctx: Context<C>,
 }
 
 /// Actions of syncing
-pub(super) enum SyncAction<C> {
+pub(super) enum SyncAction<C: Command> {
     /// Use append entires to calibrate
     AppendEntries(AppendEntries<C>),
-    /// Use snapshot to calibrate
-    Snapshot(oneshot::Receiver<Snapshot>),
+    /// Use snapshot to calibrate, attached with the membership and the client sessions when
+    /// the snapshot is taken
+    Snapshot(oneshot::Receiver<Snapshot>, Membership, SessionTable<C>),
 }
 
 /// Invoked by candidates to gather votes
@@ -88,6 +100,11 @@ pub(super) struct Vote {
     pub(super) last_log_index: LogIndex,
     /// Candidate's last log term
     pub(super) last_log_term: u64,
+    /// Whether it's a pre-vote, which won't change the state of the voters
+    pub(super) is_pre_vote: bool,
+    /// Whether the election is started by a leader transfer, the voters will vote even if they
+    /// believe the leader is alive
+    pub(super) is_leader_transfer: bool,
 }
 
 /// Invoked by leader to replicate log entries; also used as heartbeat
@@ -104,6 +121,20 @@ pub(super) struct AppendEntries<C> {
     pub(super) leader_commit: LogIndex,
     /// New entries to be appended to the follower
     pub(super) entries: Vec<LogEntry<C>>,
+    /// Sequence number of the latest read when the request is built, it's acked together with the request
+    pub(super) read_seq: u64,
+}
+
+/// A read that can only be served after the leadership is confirmed
+pub(super) struct PendingRead {
+    /// Read state of the command
+    pub(super) state: ReadState,
+    /// Term in which the read is issued
+    pub(super) term: u64,
+    /// Sequence number of the read
+    pub(super) seq: u64,
+    /// Whether the leadership is confirmed by the lease, no heartbeat is needed then
+    pub(super) in_lease: bool,
 }
 
 /// Curp Role
@@ -111,18 +142,30 @@ pub(super) struct AppendEntries<C> {
 enum Role {
     /// Follower
     Follower,
+    /// Pre-candidate, which is collecting pre-votes before it starts a real election
+    PreCandidate,
     /// Candidate
     Candidate,
     /// Leader
     Leader,
 }
 
+/// A snapshot that includes no speculatively executed cmds, it's kept with the client sessions
+/// at its index, so that the cmds after it are re-executed at most once when they are replayed
+struct RetainedSnapshot<C: Command> {
+    /// Meta of the snapshot
+    meta: SnapshotMeta,
+    /// The snapshot of the command executor, it's `None` while the command executor is being
+    /// reset by it, the cmd worker retains a new one taken right after the reset
+    snapshot: Option<Snapshot>,
+    /// The client sessions that have applied the log up to the snapshot
+    sessions: SessionTable<C>,
+}
+
 /// Relevant context for Curp
 struct Context<C: Command> {
     /// Id of the server
     id: ServerId,
-    /// Other server ids
-    others: HashSet<ServerId>,
     /// Config
     cfg: Arc<CurpConfig>,
     /// Cmd board for tracking the cmd sync results
@@ -138,16 +181,28 @@ struct Context<C: Command> {
     /// Tx to send cmds to execute and do after sync
     cmd_tx: Box<dyn CEEventTxApi<C>>,
     /// Followers sync event trigger
-    sync_events: HashMap<ServerId, Arc<Event>>,
+    sync_events: RwLock<HashMap<ServerId, Arc<Event>>>,
     /// Become leader event
     leader_event: Arc<Event>,
+    /// Membership change event
+    membership_event: Arc<Event>,
+    /// Read ack event, triggered when a follower acks a read or the leader retires
+    read_event: Arc<Event>,
+    /// Log persist event, triggered when log entries are persisted or the term changes
+    persist_event: Arc<Event>,
+    /// Client sessions, they are used to execute the commands at most once
+    sessions: Mutex<SessionTable<C>>,
+    /// Session event, triggered when a session registration is applied
+    session_event: Arc<Event>,
+    /// The latest snapshot that includes no speculatively executed cmds, a retiring leader
+    /// rebuilds the command executor from it once the log has been compacted
+    snapshot: Mutex<Option<RetainedSnapshot<C>>>,
 }
 
 impl<C: Command> Debug for Context<C> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("Context")
             .field("id", &self.id)
-            .field("others", &self.others)
             .field("config", &self.cfg)
             .field("cb", &self.cb)
             .field("sp", &self.sp)
@@ -161,22 +216,72 @@ impl<C: Command> Debug for Context<C> {
 impl<C: 'static + Command> RawCurp<C> {
     /// Tick
     pub(super) fn tick_election(&self) -> Option<Vote> {
+        // only voters can start an election, and a witness can't serve as the leader
+        if !self.ms.read().is_voter(self.id()) || self.cfg().is_witness || !self.can_rebuild() {
+            return None;
+        }
         let timeout = {
             let st_r = self.st.read();
             match st_r.role {
                 Role::Follower => st_r.follower_timeout_ticks,
-                Role::Candidate => st_r.candidate_timeout_ticks,
-                Role::Leader => return None,
+                Role::PreCandidate | Role::Candidate => st_r.candidate_timeout_ticks,
+                Role::Leader => {
+                    drop(st_r);
+                    self.tick_check_quorum();
+                    return None;
+                }
             }
         };
         let tick = self.ctx.election_tick.fetch_add(1, Ordering::AcqRel);
         if tick < timeout {
             return None;
         }
-        let vote =
-            self.become_candidate(&mut self.st.write(), &mut self.cst.lock(), &self.log.read());
+        let (mut st_w, mut cst_l, log_r) = (self.st.write(), self.cst.lock(), self.log.read());
+        let vote = if self.cfg().pre_vote {
+            self.become_pre_candidate(&mut st_w, &mut cst_l, &log_r)
+        } else {
+            self.become_candidate(&mut st_w, &mut cst_l, &log_r)
+        };
         Some(vote)
     }
+
+    /// The leader checks whether it has heard from the majority of voters every election timeout,
+    /// it steps down if it hasn't
+    fn tick_check_quorum(&self) {
+        if !self.cfg().check_quorum {
+            return;
+        }
+        let tick = self.ctx.election_tick.fetch_add(1, Ordering::AcqRel);
+        if tick < self.cfg().follower_timeout_ticks {
+            return;
+        }
+        self.reset_election_tick();
+        let recent_active = self.lst.take_recent_active();
+
+        let mut st_w = self.st.write();
+        if st_w.role != Role::Leader {
+            return;
+        }
+        let ms_r = self.ms.read();
+        let active_cnt = ms_r
+            .voters()
+            .iter()
+            .filter(|&id| id == self.id() || recent_active.contains(id))
+            .count();
+        if active_cnt >= ms_r.quorum() {
+            return;
+        }
+        drop(ms_r);
+        warn!(
+            "{} hasn't heard from the majority within an election timeout, steps down",
+            self.id()
+        );
+        // keep the vote of the current term, so that self won't vote twice in it
+        let voted_for = st_w.voted_for.take();
+        let term = st_w.term;
+        self.update_to_term_and_become_follower(&mut st_w, term);
+        st_w.voted_for = voted_for;
+    }
 }
 
 // Curp handlers
@@ -210,6 +315,14 @@ impl<C: 'static + Command> RawCurp<C> {
             );
         }
 
+        // the leader stops accepting proposals while it is transferring the leadership
+        if st_r.leader_transferee.is_some() {
+            if !conflict {
+                self.ctx.sp.map_lock(|mut sp_l| sp_l.remove(cmd.id()));
+            }
+            return (info, Err(ProposeError::LeaderTransferring));
+        }
+
         if !self
             .ctx
             .cb
@@ -240,7 +353,7 @@ impl<C: 'static + Command> RawCurp<C> {
             self.ctx.cmd_tx.send_sp_exe(cmd);
         }
 
-        self.ctx.sync_events.iter().for_each(|(id, event)| {
+        self.ctx.sync_events.read().iter().for_each(|(id, event)| {
             let next = self.lst.get_next_index(id);
             if log_w.has_next_batch(next) {
                 event.notify(1);
@@ -286,6 +399,8 @@ impl<C: 'static + Command> RawCurp<C> {
             std::cmp::Ordering::Equal => {
                 if st_r.leader_id.is_none() {
                     let mut st_w = RwLockUpgradableReadGuard::upgrade(st_r);
+                    // a (pre-)candidate steps down once it finds the leader of its term
+                    st_w.role = Role::Follower;
                     st_w.leader_id = Some(leader_id.clone());
                     let _ig = self.ctx.leader_tx.send(Some(leader_id)).ok();
                 }
@@ -298,15 +413,20 @@ impl<C: 'static + Command> RawCurp<C> {
 
         // append log entries
         let mut log_w = self.log.write();
+        let last_new_index = prev_log_index + entries.len().numeric_cast::<LogIndex>();
         let append_succeeded = log_w
             .try_append_entries(entries, prev_log_index, prev_log_term)
             .is_ok();
 
-        // update commit index
+        // update commit index, the pipelined requests may arrive out of order, so it only
+        // moves forward, and the entries after the appended ones may not match the leader's
         let prev_commit_index = log_w.commit_index;
-        log_w.commit_index = min(leader_commit, log_w.last_log_index());
-        if prev_commit_index < log_w.commit_index {
-            self.apply(&mut *log_w);
+        if append_succeeded {
+            let commit_index = min(leader_commit, last_new_index);
+            if prev_commit_index < commit_index {
+                log_w.commit_index = commit_index;
+                self.apply(&mut *log_w);
+            }
         }
 
         if append_succeeded {
@@ -327,7 +447,8 @@ impl<C: 'static + Command> RawCurp<C> {
     pub(super) fn handle_append_entries_resp(
         &self,
         follower_id: &ServerId,
-        last_sent_index: Option<LogIndex>, // None means the ae is a heartbeat
+        prev_log_index: LogIndex,
+        last_sent_index: LogIndex, // prev_log_index if the ae is a heartbeat
         term: u64,
         success: bool,
         hint_index: LogIndex,
@@ -342,34 +463,45 @@ impl<C: 'static + Command> RawCurp<C> {
         if cur_role != Role::Leader {
             return Err(());
         }
+        self.lst.mark_active(follower_id);
 
         if !success {
-            self.lst.update_next_index(follower_id, hint_index);
-            debug!(
-                "{} updates follower {}'s next_index to {hint_index} because it rejects ae",
-                self.id(),
-                follower_id,
-            );
+            if self.lst.reject(follower_id, prev_log_index, hint_index) {
+                debug!(
+                    "{} rolls back follower {}'s next_index because it rejects ae after log[{prev_log_index}]",
+                    self.id(),
+                    follower_id,
+                );
+            }
             return Ok(false);
         }
 
-        // if ae is a heartbeat, return
-        let Some(last_sent_index) = last_sent_index else {
-            return Ok(true);
-        };
-
         self.lst.update_match_index(follower_id, last_sent_index);
 
         // check if commit_index needs to be updated
-        if self.can_update_commit_index_to(&self.log.read(), last_sent_index, cur_term) {
+        if let Some(commit_index) = self
+            .log
+            .map_read(|log_r| self.next_commit_index(&log_r, cur_term))
+        {
             let mut log_w = self.log.write();
-            if last_sent_index > log_w.commit_index {
-                log_w.commit_index = last_sent_index;
-                debug!("{} updates commit index to {last_sent_index}", self.id());
+            if commit_index > log_w.commit_index {
+                log_w.commit_index = commit_index;
+                debug!("{} updates commit index to {commit_index}", self.id());
                 self.apply(&mut *log_w);
             }
         }
 
+        // the leader steps down once it has been removed from the cluster
+        if !self.ms.read().is_voter(self.id()) {
+            info!(
+                "{} has been removed from the cluster, steps down",
+                self.id()
+            );
+            let mut st_w = self.st.write();
+            self.update_to_term_and_become_follower(&mut st_w, cur_term);
+            return Err(());
+        }
+
         Ok(true)
     }
 
@@ -382,19 +514,40 @@ impl<C: 'static + Command> RawCurp<C> {
         candidate_id: ServerId,
         last_log_index: LogIndex,
         last_log_term: u64,
+        is_pre_vote: bool,
+        is_leader_transfer: bool,
     ) -> Result<(u64, Vec<Arc<C>>), u64> {
         debug!(
-            "{} received vote: term({}), last_log_index({}), last_log_term({}), id({})",
+            "{} received vote: term({}), last_log_index({}), last_log_term({}), id({}), pre_vote({})",
             self.id(),
             term,
             last_log_index,
             last_log_term,
-            candidate_id
+            candidate_id,
+            is_pre_vote
         );
 
+        if is_pre_vote {
+            return self.handle_pre_vote(term, &candidate_id, last_log_index, last_log_term);
+        }
+
         let mut st_w = self.st.write();
         let log_r = self.log.read();
 
+        // a non-voter can't be elected, and it should not disrupt the cluster by bumping the term
+        if !self.ms.read().is_voter(&candidate_id) {
+            return Err(st_w.term);
+        }
+
+        // the leader may be serving reads in its lease, so no one else can be elected until it expires
+        if self.cfg().lease_read
+            && !is_leader_transfer
+            && term > st_w.term
+            && self.leader_alive(&st_w)
+        {
+            return Err(st_w.term);
+        }
+
         // calibrate term
         if term < st_w.term {
             return Err(st_w.term);
@@ -430,6 +583,71 @@ impl<C: 'static + Command> RawCurp<C> {
         Ok((st_w.term, self_spec_pool))
     }
 
+    /// Handle a pre-vote, it doesn't change the state of self
+    /// Return `Ok(term, [])` if the pre-vote is granted, `term` is the one the candidate will campaign for
+    /// Return `Err(term)` if the pre-vote is rejected
+    fn handle_pre_vote(
+        &self,
+        term: u64,
+        candidate_id: &ServerId,
+        last_log_index: LogIndex,
+        last_log_term: u64,
+    ) -> Result<(u64, Vec<Arc<C>>), u64> {
+        let st_r = self.st.read();
+        let log_r = self.log.read();
+
+        if !self.ms.read().is_voter(candidate_id) || term <= st_r.term {
+            return Err(st_r.term);
+        }
+
+        // a server that believes the leader is still alive won't help the candidate to disrupt the cluster
+        if self.leader_alive(&st_r) || !log_r.log_up_to_date(last_log_term, last_log_index) {
+            return Err(st_r.term);
+        }
+
+        debug!("{} grants pre-vote to server {}", self.id(), candidate_id);
+        Ok((term, vec![]))
+    }
+
+    /// Handle `vote` responses of a pre-vote round
+    /// Return `Ok(Some(vote))` if the pre-vote is granted by the majority, self should start a real election with it
+    /// Return `Ok(None)` if the pre-vote round is still going on
+    /// Return `Err(())` if self is no longer a pre-candidate
+    pub(super) fn handle_pre_vote_resp(
+        &self,
+        id: &ServerId,
+        term: u64,
+        vote_granted: bool,
+    ) -> Result<Option<Vote>, ()> {
+        let mut st_w = self.st.write();
+        // a granted pre-vote carries the term that self will campaign for, only a rejection can bring a newer term
+        if !vote_granted && st_w.term < term {
+            self.update_to_term_and_become_follower(&mut st_w, term);
+            return Err(());
+        }
+        if st_w.role != Role::PreCandidate {
+            return Err(());
+        }
+
+        if !vote_granted || term != st_w.term + 1 || !self.ms.read().is_voter(id) {
+            return Ok(None);
+        }
+
+        let mut cst_w = self.cst.lock();
+        if cst_w.sps.insert(id.clone(), vec![]).is_some() {
+            return Ok(None);
+        }
+        debug!("{}'s pre-vote is granted by server {}", self.id(), id);
+        cst_w.votes_received += 1;
+        if cst_w.votes_received < self.quorum() {
+            return Ok(None);
+        }
+
+        // pre-vote is granted by the majority of servers, start a real election
+        let vote = self.become_candidate(&mut st_w, &mut cst_w, &self.log.read());
+        Ok(Some(vote))
+    }
+
     /// Handle `vote` responses
     /// Return `Ok(election_ends)` if succeeds
     /// Return `Err(())` if self is no longer a candidate
@@ -449,7 +667,7 @@ impl<C: 'static + Command> RawCurp<C> {
             return Err(());
         }
 
-        if !vote_granted {
+        if !vote_granted || !self.ms.read().is_voter(id) {
             return Ok(false);
         }
 
@@ -474,18 +692,27 @@ impl<C: 'static + Command> RawCurp<C> {
 
         let prev_last_log_index = log_w.last_log_index();
         self.recover_from_spec_pools(&mut st_w, &mut log_w, &spec_pools);
+        // entries of previous terms can only be committed by committing an entry of the current term
+        if log_w.commit_index < log_w.last_log_index() && log_w.last_log_term() != st_w.term {
+            #[allow(clippy::expect_used)]
+            let index = log_w
+                .push_empty(st_w.term)
+                .expect("empty entry cannot be serialized");
+            debug!("{} appends an empty entry to log[{index}]", self.id());
+        }
         let last_log_index = log_w.last_log_index();
 
         self.become_leader(&mut st_w);
 
-        // update next_index for each follower
-        for other in &self.ctx.others {
-            self.lst.update_next_index(other, last_log_index + 1); // iter from the end to front is more likely to match the follower
+        // reset the progress of each follower, including learners
+        for other in self.ms.read().peers().keys() {
+            self.lst.reset_progress(other, last_log_index + 1); // iter from the end to front is more likely to match the follower
         }
         if prev_last_log_index < last_log_index {
             // if some entries are recovered, sync with followers immediately
             self.ctx
                 .sync_events
+                .read()
                 .values()
                 .for_each(|event| event.notify(1));
         }
@@ -534,24 +761,238 @@ impl<C: 'static + Command> RawCurp<C> {
         if cur_role != Role::Leader {
             return Err(());
         }
+        self.lst.mark_active(follower_id);
         self.lst
             .update_match_index(follower_id, meta.last_included_index.numeric_cast());
         Ok(())
     }
 
-    /// Handle `fetch_read_state`
-    pub(super) fn handle_fetch_read_state(&self, cmd: &C) -> bincode::Result<ReadState> {
+    /// Handle `fetch_read_state`, heartbeats are sent right away to confirm the leadership for the read
+    /// Return `Ok(Some(pending_read))` if self is the leader
+    /// Return `Ok(None)` if self is not the leader or it hasn't committed an entry in its term
+    pub(super) fn handle_fetch_read_state(&self, cmd: &C) -> bincode::Result<Option<PendingRead>> {
         let ids = self.ctx.sp.map_lock(|sp| {
             sp.pool
                 .iter()
                 .filter_map(|(id, c)| c.is_conflict(cmd).then_some(id.clone()))
                 .collect_vec()
         });
-        if ids.is_empty() {
-            Ok(ReadState::CommitIndex(self.log.read().commit_index))
+
+        let st_r = self.st.read();
+        if st_r.role != Role::Leader {
+            return Ok(None);
+        }
+        let log_r = self.log.read();
+        // the leader doesn't know the latest commit index until it commits an entry in its term
+        if log_r
+            .get(log_r.commit_index + 1)
+            .map_or(false, |entry| entry.term != st_r.term)
+        {
+            return Ok(None);
+        }
+        let state = if ids.is_empty() {
+            ReadState::CommitIndex(log_r.last_cmd_index_to(log_r.commit_index))
         } else {
-            Ok(ReadState::Ids(IdSet::new(ids)?))
+            ReadState::Ids(IdSet::new(ids)?)
+        };
+        // the lease is broken once the leadership transfer starts
+        if self.cfg().lease_read
+            && st_r.leader_transferee.is_none()
+            && self.in_lease(Instant::now())
+        {
+            return Ok(Some(PendingRead {
+                state,
+                term: st_r.term,
+                seq: 0,
+                in_lease: true,
+            }));
         }
+        let seq = self.lst.next_read_seq();
+        self.ctx
+            .sync_events
+            .read()
+            .values()
+            .for_each(|event| event.notify(1));
+
+        Ok(Some(PendingRead {
+            state,
+            term: st_r.term,
+            seq,
+            in_lease: false,
+        }))
+    }
+
+    /// Handle `propose_conf_change`
+    /// Return `((leader_id, term), Ok(Some(index)))` if the change is appended to log[index]
+    /// Return `((leader_id, term), Ok(None))` if self is not the leader
+    /// Return `((leader_id, term), Err(ProposeError))` if the change is invalid or another change is pending
+    #[allow(clippy::type_complexity)] // it's clear
+    pub(super) fn handle_propose_conf_change(
+        &self,
+        change: ConfChange,
+    ) -> (
+        (Option<ServerId>, u64),
+        Result<Option<LogIndex>, ProposeError>,
+    ) {
+        debug!("{} gets conf change proposal {change:?}", self.id());
+        let st_r = self.st.read();
+        let info = (st_r.leader_id.clone(), st_r.term);
+        if st_r.role != Role::Leader {
+            return (info, Ok(None));
+        }
+        if st_r.leader_transferee.is_some() {
+            return (info, Err(ProposeError::LeaderTransferring));
+        }
+
+        let mut log_w = self.log.write();
+        // only one conf change can be in progress at a time
+        if log_w.has_pending_conf_change() {
+            return (
+                info,
+                Err(ProposeError::InvalidConfChange(
+                    "another conf change is in progress".to_owned(),
+                )),
+            );
+        }
+        if let Err(e) = self.ms.read().validate(&change) {
+            return (info, Err(ProposeError::InvalidConfChange(e)));
+        }
+        // a learner promoted before it catches up can't vote for the entries it lacks,
+        // which may make the cluster unavailable
+        if let ConfChange::PromoteLearner(ref id) = change {
+            if self.lst.get_match_index(id) < log_w.commit_index {
+                return (info, Err(ProposeError::LearnerNotCatchUp));
+            }
+        }
+        let index = match log_w.push_conf_change(st_r.term, change) {
+            Ok(index) => index,
+            Err(e) => return (info, Err(e.into())),
+        };
+        debug!("{} gets new log[{index}]", self.id());
+
+        self.ctx
+            .sync_events
+            .read()
+            .values()
+            .for_each(|event| event.notify(1));
+
+        (info, Ok(Some(index)))
+    }
+
+    /// Handle `register_session`
+    /// Return `((leader_id, term), Ok(Some(client_id)))` if the registration is appended to log[client_id]
+    /// Return `((leader_id, term), Ok(None))` if self is not the leader
+    /// Return `((leader_id, term), Err(ProposeError))` if the leadership is being transferred
+    #[allow(clippy::type_complexity)] // it's clear
+    pub(super) fn handle_register_session(
+        &self,
+    ) -> ((Option<ServerId>, u64), Result<Option<u64>, ProposeError>) {
+        let st_r = self.st.read();
+        let info = (st_r.leader_id.clone(), st_r.term);
+        if st_r.role != Role::Leader {
+            return (info, Ok(None));
+        }
+        if st_r.leader_transferee.is_some() {
+            return (info, Err(ProposeError::LeaderTransferring));
+        }
+
+        let mut log_w = self.log.write();
+        let index = match log_w.push_register_session(st_r.term) {
+            Ok(index) => index,
+            Err(e) => return (info, Err(e.into())),
+        };
+        debug!("{} gets new log[{index}] to register a session", self.id());
+
+        self.ctx
+            .sync_events
+            .read()
+            .values()
+            .for_each(|event| event.notify(1));
+
+        (info, Ok(Some(index)))
+    }
+
+    /// Handle a proposal in a client session before it's proposed, only the leader rejects it,
+    /// the others skip the commands that they know to be applied
+    /// Return `((leader_id, term), Ok(None))` if the command can be proposed
+    /// Return `((leader_id, term), Ok(Some(response)))` if the command has been applied and its response is cached
+    /// Return `((leader_id, term), Err(ProposeError))` if the command is duplicated or the session is expired
+    #[allow(clippy::type_complexity)] // it's clear
+    pub(super) fn handle_propose_session(
+        &self,
+        session: ClientSession,
+    ) -> (
+        (Option<ServerId>, u64),
+        Result<Option<CachedResponse<C>>, ProposeError>,
+    ) {
+        let st_r = self.st.read();
+        let info = (st_r.leader_id.clone(), st_r.term);
+        let result = self.ctx.sessions.lock().check(session);
+        if st_r.role == Role::Leader {
+            return (info, result);
+        }
+        // a non-leader may not know the session, and it never returns the response
+        match result {
+            Ok(Some(_)) | Err(ProposeError::Duplicated) => (info, Err(ProposeError::Duplicated)),
+            Ok(None) | Err(_) => (info, Ok(None)),
+        }
+    }
+
+    /// Handle `move_leader`, start transferring the leadership to `target`
+    /// Return `Ok(true)` if the transfer starts, `Ok(false)` if self is already the target
+    /// Return `Err(ProposeError)` if self is not the leader, `target` is not a voter or another transfer is in progress
+    pub(super) fn handle_move_leader(&self, target: &ServerId) -> Result<bool, ProposeError> {
+        let mut st_w = self.st.write();
+        if st_w.role != Role::Leader {
+            return Err(ProposeError::ProtocolError(format!(
+                "{} is not the leader",
+                self.id()
+            )));
+        }
+        if target == self.id() {
+            return Ok(false);
+        }
+        if st_w.leader_transferee.is_some() {
+            return Err(ProposeError::LeaderTransferring);
+        }
+        if !self.ms.read().is_voter(target) {
+            return Err(ProposeError::ProtocolError(format!(
+                "{target} is not a voter"
+            )));
+        }
+        info!(
+            "{} starts transferring the leadership to {target}",
+            self.id()
+        );
+        st_w.leader_transferee = Some(target.clone());
+        // wake up the sync task so that the transferee is caught up as soon as possible
+        if let Some(event) = self.ctx.sync_events.read().get(target) {
+            event.notify(1);
+        }
+        Ok(true)
+    }
+
+    /// Handle `timeout_now`
+    /// Return `Some(vote)` if self starts an election right away
+    pub(super) fn handle_timeout_now(&self, term: u64, leader_id: &ServerId) -> Option<Vote> {
+        let mut st_w = self.st.write();
+        // only the transferee of the current leader can start the election
+        if st_w.term != term
+            || st_w.role != Role::Follower
+            || st_w.leader_id.as_ref() != Some(leader_id)
+            || !self.ms.read().is_voter(self.id())
+            || self.cfg().is_witness
+            || !self.can_rebuild()
+        {
+            return None;
+        }
+        info!(
+            "{} receives timeout_now from {leader_id}, starts election immediately",
+            self.id()
+        );
+        let mut vote = self.become_candidate(&mut st_w, &mut self.cst.lock(), &self.log.read());
+        vote.is_leader_transfer = true;
+        Some(vote)
     }
 }
 
@@ -561,16 +1002,20 @@ impl<C: 'static + Command> RawCurp<C> {
     #[allow(clippy::too_many_arguments)] // only called once
     pub(super) fn new(
         id: ServerId,
-        others: HashSet<ServerId>,
+        membership: Membership,
         is_leader: bool,
         cmd_board: CmdBoardRef<C>,
         spec_pool: SpecPoolRef<C>,
         uncommitted_pool: UncommittedPoolRef<C>,
         cfg: Arc<CurpConfig>,
         cmd_tx: Box<dyn CEEventTxApi<C>>,
-        sync_events: HashMap<ServerId, Arc<Event>>,
         log_tx: mpsc::UnboundedSender<LogEntry<C>>,
     ) -> Self {
+        let others: HashSet<ServerId> = membership.peers().keys().cloned().collect();
+        let sync_events = others
+            .iter()
+            .map(|id| (id.clone(), Arc::new(Event::new())))
+            .collect();
         let raw_curp = Self {
             st: RwLock::new(State::new(
                 0,
@@ -580,12 +1025,12 @@ impl<C: 'static + Command> RawCurp<C> {
                 cfg.follower_timeout_ticks,
                 cfg.candidate_timeout_ticks,
             )),
-            lst: LeaderState::new(&others),
+            lst: LeaderState::new(&others, cfg.max_inflight_batches),
             cst: Mutex::new(CandidateState::new()),
             log: RwLock::new(Log::new(log_tx, cfg.batch_max_size)),
+            ms: RwLock::new(membership),
             ctx: Context {
                 id,
-                others,
                 cb: cmd_board,
                 sp: spec_pool,
                 ucp: uncommitted_pool,
@@ -593,8 +1038,14 @@ impl<C: 'static + Command> RawCurp<C> {
                 cfg,
                 election_tick: AtomicU8::new(0),
                 cmd_tx,
-                sync_events,
+                sync_events: RwLock::new(sync_events),
                 leader_event: Arc::new(Event::new()),
+                membership_event: Arc::new(Event::new()),
+                read_event: Arc::new(Event::new()),
+                persist_event: Arc::new(Event::new()),
+                sessions: Mutex::new(SessionTable::new()),
+                session_event: Arc::new(Event::new()),
+                snapshot: Mutex::new(None),
             },
         };
         if is_leader {
@@ -609,29 +1060,30 @@ impl<C: 'static + Command> RawCurp<C> {
     #[allow(clippy::too_many_arguments)] // only called once
     pub(super) fn recover_from(
         id: ServerId,
-        others: HashSet<ServerId>,
+        membership: Membership,
         is_leader: bool,
         cmd_board: CmdBoardRef<C>,
         spec_pool: SpecPoolRef<C>,
         uncommitted_pool: UncommittedPoolRef<C>,
         cfg: &Arc<CurpConfig>,
         cmd_tx: Box<dyn CEEventTxApi<C>>,
-        sync_event: HashMap<ServerId, Arc<Event>>,
         log_tx: mpsc::UnboundedSender<LogEntry<C>>,
         voted_for: Option<(u64, ServerId)>,
+        log_base: Option<SnapshotMeta>,
         entries: Vec<LogEntry<C>>,
+        spec_pool_cmds: Vec<Arc<C>>,
+        sessions: Option<SessionTable<C>>,
         last_applied: LogIndex,
     ) -> Self {
         let mut raw_curp = Self::new(
             id,
-            others,
+            membership,
             is_leader,
             cmd_board,
             spec_pool,
             uncommitted_pool,
             Arc::clone(cfg),
             cmd_tx,
-            sync_event,
             log_tx.clone(),
         );
 
@@ -642,19 +1094,60 @@ impl<C: 'static + Command> RawCurp<C> {
         } else if is_leader {
             // all uncommitted cmds should stay in ucp until they are executed
             raw_curp.ctx.ucp.map_lock(|mut ucp_l| {
-                for e in &entries {
-                    let _ig = ucp_l.insert(e.cmd.id().clone(), Arc::clone(&e.cmd));
+                for cmd in entries.iter().filter_map(LogEntry::cmd) {
+                    let _ig = ucp_l.insert(cmd.id().clone(), Arc::clone(cmd));
                 }
             });
         } else {
         }
 
+        // the persisted spec pool is replayed, except the cmds that have been synced
+        let synced_ids: HashSet<_> = entries
+            .iter()
+            .take_while(|entry| entry.index <= last_applied)
+            .filter_map(LogEntry::cmd)
+            .map(|cmd| cmd.id().clone())
+            .collect();
+        raw_curp.ctx.sp.map_lock(|mut sp_l| {
+            for cmd in spec_pool_cmds {
+                if !synced_ids.contains(cmd.id()) {
+                    let _ig = sp_l.insert(cmd);
+                }
+            }
+        });
+
+        raw_curp.log = RwLock::new(Log::recover(log_tx, log_base, entries, cfg.batch_max_size));
+
         raw_curp.log.map_write(|mut log_w| {
+            // entries before the log base have been applied
+            let last_applied = last_applied.max(log_w.base_index);
             log_w.last_applied = last_applied;
             log_w.commit_index = last_applied;
-        });
 
-        raw_curp.log = RwLock::new(Log::recover(log_tx, entries, cfg.batch_max_size));
+            // conf changes are not applied to the command executor, the applied ones may not
+            // have been persisted together with the membership
+            let next_index = log_w.last_log_index() + 1;
+            let ms_index = raw_curp.ms.read().index();
+            for i in (ms_index.max(log_w.base_index) + 1)..=last_applied {
+                let Some(entry) = log_w.get(i) else {
+                    continue;
+                };
+                if let EntryData::ConfChange(ref change) = entry.entry_data {
+                    raw_curp.apply_conf_change(change.clone(), i, next_index);
+                }
+            }
+
+            // the sessions persisted before the compaction are restored, and the entries applied
+            // after that are applied to them again, the responses of those are lost though
+            if let Some(sessions) = sessions {
+                *raw_curp.ctx.sessions.lock() = sessions;
+            }
+            for i in (log_w.base_index + 1)..=last_applied {
+                if let Some(entry) = log_w.get(i) {
+                    let _applied = raw_curp.apply_session(entry);
+                }
+            }
+        });
 
         raw_curp
     }
@@ -664,6 +1157,24 @@ impl<C: 'static + Command> RawCurp<C> {
         self.st.map_read(|st_r| (st_r.leader_id.clone(), st_r.term))
     }
 
+    /// Get a snapshot of the current node status
+    pub(super) fn status(&self) -> NodeStatus {
+        let (leader, term) = self.leader();
+        let (commit_index, applied_index) = self
+            .log
+            .map_read(|log_r| (log_r.commit_index, log_r.last_applied));
+        let is_learner = !self.ms.read().is_voter(self.id());
+        let is_witness = self.cfg().is_witness;
+        NodeStatus {
+            leader,
+            term,
+            commit_index,
+            applied_index,
+            is_learner,
+            is_witness,
+        }
+    }
+
     /// Get self's id
     pub(super) fn id(&self) -> &ServerId {
         &self.ctx.id
@@ -686,23 +1197,40 @@ impl<C: 'static + Command> RawCurp<C> {
 
         let next_index = self.lst.get_next_index(follower_id);
         let log_r = self.log.read();
+        let ms_r = self.ms.read();
+        if !ms_r.is_member(follower_id) {
+            // the follower has been removed from the cluster
+            return Err(());
+        }
         if next_index <= log_r.base_index {
             // the log has already been compacted
-            let entry = log_r.get(log_r.last_applied).unwrap_or_else(|| {
-                unreachable!(
-                    "log entry {} should not have been compacted yet, needed for snapshot",
-                    log_r.last_applied
-                )
+            let (last_included_index, last_included_term) =
+                log_r.get_prev_entry_info(log_r.last_applied + 1);
+            let rx = self.ctx.cmd_tx.send_snapshot(SnapshotMeta {
+                last_included_index,
+                last_included_term,
             });
-            Ok(SyncAction::Snapshot(self.ctx.cmd_tx.send_snapshot(
-                SnapshotMeta {
-                    last_included_index: entry.index.numeric_cast(),
-                    last_included_term: entry.term,
-                },
-            )))
+            // all applied conf changes and sessions are included in the snapshot
+            let sessions = self.ctx.sessions.lock().clone();
+            Ok(SyncAction::Snapshot(rx, ms_r.clone(), sessions))
         } else {
-            let (prev_log_index, prev_log_term) = log_r.get_prev_entry_info(next_index);
-            let entries = log_r.get_from(next_index).unwrap_or_default().to_vec();
+            let is_replicating = self.lst.get_state(follower_id) == Some(ProgressState::Replicate);
+            let entries = if self.lst.is_paused(follower_id) {
+                vec![]
+            } else {
+                log_r.get_from(next_index).unwrap_or_default().to_vec()
+            };
+            let (prev_log_index, prev_log_term) = if let Some(last) = entries.last() {
+                self.lst.sent_batch(follower_id, last.index);
+                log_r.get_prev_entry_info(next_index)
+            } else if is_replicating {
+                // `next_index` is advanced optimistically, so the heartbeats are matched at
+                // `match_index` to avoid being rejected before the inflight batches arrive
+                let match_index = self.lst.get_match_index(follower_id).max(log_r.base_index);
+                log_r.get_prev_entry_info(match_index + 1)
+            } else {
+                log_r.get_prev_entry_info(next_index)
+            };
             let ae = AppendEntries {
                 term,
                 leader_id: self.id().clone(),
@@ -710,11 +1238,111 @@ impl<C: 'static + Command> RawCurp<C> {
                 prev_log_term,
                 leader_commit: log_r.commit_index,
                 entries,
+                read_seq: self.lst.read_seq(),
             };
             Ok(SyncAction::AppendEntries(ae))
         }
     }
 
+    /// The follower is unreachable, the batches sent to it are considered lost
+    pub(super) fn report_unreachable(&self, follower_id: &ServerId) {
+        self.lst.report_unreachable(follower_id);
+    }
+
+    /// Check if the leader transferee `id` has caught up with the leader
+    /// Return `Some(term)` if `timeout_now` should be sent to it
+    pub(super) fn leader_transfer_ready(&self, id: &ServerId) -> Option<u64> {
+        let st_r = self.st.read();
+        if st_r.role != Role::Leader || st_r.leader_transferee.as_ref() != Some(id) {
+            return None;
+        }
+        (self.lst.get_match_index(id) >= self.log.read().last_log_index()).then_some(st_r.term)
+    }
+
+    /// Abort the leader transfer to `target` if it's still in progress
+    pub(super) fn abort_leader_transfer(&self, target: &ServerId) {
+        let mut st_w = self.st.write();
+        if st_w.role == Role::Leader && st_w.leader_transferee.as_ref() == Some(target) {
+            warn!(
+                "{} aborts transferring the leadership to {target}",
+                self.id()
+            );
+            st_w.leader_transferee = None;
+            // the transferee may have been granted votes regardless of the lease
+            self.lst.reset_lease();
+        }
+    }
+
+    /// Record that the follower has acked the heartbeat sent at `sent_at` for read `read_seq`
+    pub(super) fn ack_heartbeat(&self, id: &ServerId, read_seq: u64, sent_at: Instant) {
+        self.lst.ack_read(id, read_seq);
+        self.lst.ack_lease(id, sent_at);
+        self.ctx.read_event.notify(usize::MAX);
+    }
+
+    /// Check if the leadership of `term` has been confirmed for read `seq`
+    /// Return `Ok(confirmed)` if self is still the leader of `term`
+    /// Return `Err(())` if self is no longer the leader of `term`
+    pub(super) fn read_confirmed(&self, term: u64, seq: u64) -> Result<bool, ()> {
+        let st_r = self.st.read();
+        if st_r.role != Role::Leader || st_r.term != term {
+            return Err(());
+        }
+        let ms_r = self.ms.read();
+        let acked_cnt = ms_r
+            .voters()
+            .iter()
+            .filter(|&id| id == self.id() || self.lst.get_read_acked(id) >= seq)
+            .count();
+        Ok(acked_cnt >= ms_r.quorum())
+    }
+
+    /// Get read ack event
+    pub(super) fn read_event(&self) -> Arc<Event> {
+        Arc::clone(&self.ctx.read_event)
+    }
+
+    /// The log entries up to log[`index`] of `term` have been persisted, the leader counts
+    /// itself toward commit only for the persisted entries
+    pub(super) fn on_log_persisted(&self, index: LogIndex, term: u64) {
+        let st_r = self.st.read();
+        let mut log_w = self.log.write();
+        if !log_w.persisted_to(index, term) {
+            return;
+        }
+        self.ctx.persist_event.notify(usize::MAX);
+
+        if st_r.role != Role::Leader {
+            return;
+        }
+        if let Some(commit_index) = self.next_commit_index(&log_w, st_r.term) {
+            log_w.commit_index = commit_index;
+            debug!("{} updates commit index to {commit_index}", self.id());
+            self.apply(&mut *log_w);
+        }
+    }
+
+    /// Check if the log entries up to log[`index`] have been persisted in `term`
+    /// Return `Ok(persisted)` if the term hasn't changed
+    /// Return `Err(term, hint_index)` if the term has changed, the entries may have been replaced
+    pub(super) fn log_persisted_to(
+        &self,
+        term: u64,
+        index: LogIndex,
+    ) -> Result<bool, (u64, LogIndex)> {
+        let st_r = self.st.read();
+        let log_r = self.log.read();
+        if st_r.term != term {
+            return Err((st_r.term, log_r.commit_index + 1));
+        }
+        Ok(log_r.persisted_index >= index)
+    }
+
+    /// Get log persist event
+    pub(super) fn persist_event(&self) -> Arc<Event> {
+        Arc::clone(&self.ctx.persist_event)
+    }
+
     /// Get a reference to `CurpConfig`
     pub(super) fn cfg(&self) -> &CurpConfig {
         self.ctx.cfg.as_ref()
@@ -730,12 +1358,169 @@ impl<C: 'static + Command> RawCurp<C> {
         Arc::clone(&self.ctx.leader_event)
     }
 
-    /// Reset log base
+    /// Reset log base, the log is kept if the snapshot is behind it, which happens when a
+    /// retiring leader rebuilds its command executor from the snapshot
     pub(super) fn reset_by_snapshot(&self, meta: SnapshotMeta) {
         let mut log_w = self.log.write();
+        if meta.last_included_index <= log_w.last_applied {
+            return;
+        }
         log_w.reset_by_snapshot_meta(meta);
     }
 
+    /// Take a snapshot of all applied log entries if the log has grown beyond `log_entries_cap`,
+    /// the entries included in the snapshot can be compacted once the snapshot is taken. It's
+    /// attached with the client sessions when the snapshot is taken, they are persisted before
+    /// the compaction, so that the entries after the snapshot are applied to them only once
+    pub(super) fn snapshot_for_compaction(
+        &self,
+    ) -> Option<(oneshot::Receiver<Snapshot>, SessionTable<C>)> {
+        // the snapshot event must be sent under the log lock, so that it is ordered
+        // right after the after sync event of log[last_applied]
+        let log_r = self.log.read();
+        let meta = self.compaction_meta(&log_r)?;
+        debug!(
+            "{} takes a snapshot at log[{}] to compact the log",
+            self.id(),
+            meta.last_included_index
+        );
+        let sessions = self.ctx.sessions.lock().clone();
+        Some((self.ctx.cmd_tx.send_snapshot(meta), sessions))
+    }
+
+    /// Compact all applied log entries of a witness if the log has grown beyond `log_entries_cap`,
+    /// a witness has no state machine, so no snapshot is needed. Return the meta of the compacted entries
+    pub(super) fn witness_compact_log(&self) -> Option<(SnapshotMeta, Vec<ProposeId>)> {
+        let mut log_w = self.log.write();
+        let meta = self.compaction_meta(&log_w)?;
+        let compacted = log_w.compact(meta.last_included_index);
+        debug!(
+            "witness {} compacted the log up to log[{}]",
+            self.id(),
+            log_w.base_index
+        );
+        Some((meta, compacted))
+    }
+
+    /// Compact the log entries included in the snapshot, the snapshot is kept with the sessions
+    /// attached to it to rebuild the command executor when self retires. Return the ids of the
+    /// compacted cmds and the replaced snapshot
+    pub(super) fn compact_log(
+        &self,
+        snapshot: Snapshot,
+        sessions: SessionTable<C>,
+    ) -> (Vec<ProposeId>, Option<Snapshot>) {
+        let mut log_w = self.log.write();
+        let compacted = log_w.compact(snapshot.meta.last_included_index);
+        debug!(
+            "{} compacted the log up to log[{}]",
+            self.id(),
+            log_w.base_index
+        );
+        (compacted, self.retain_snapshot(snapshot, sessions))
+    }
+
+    /// Take a snapshot of all applied log entries if self has no snapshot to rebuild the command
+    /// executor from since the log was compacted, e.g. after a restart or installing a snapshot.
+    /// Only a non-leader takes it, whose command executor has no speculatively executed cmds.
+    /// It's attached with the client sessions when the snapshot is taken
+    pub(super) fn snapshot_for_retirement(
+        &self,
+    ) -> Option<(oneshot::Receiver<Snapshot>, SessionTable<C>)> {
+        let st_r = self.st.read();
+        if st_r.role == Role::Leader || self.cfg().is_witness {
+            return None;
+        }
+        // the snapshot event must be sent under the log lock, so that it is ordered
+        // right after the after sync event of log[last_applied]
+        let log_r = self.log.read();
+        let has_snapshot = self.ctx.snapshot.lock().as_ref().map_or(false, |retained| {
+            retained.snapshot.is_some() && retained.meta.last_included_index >= log_r.base_index
+        });
+        if log_r.base_index == 0 || has_snapshot {
+            return None;
+        }
+        let (last_included_index, last_included_term) =
+            log_r.get_prev_entry_info(log_r.last_applied + 1);
+        debug!(
+            "{} takes a snapshot at log[{last_included_index}] to rebuild the command executor on retirement",
+            self.id()
+        );
+        let sessions = self.ctx.sessions.lock().clone();
+        let rx = self.ctx.cmd_tx.send_snapshot(SnapshotMeta {
+            last_included_index,
+            last_included_term,
+        });
+        Some((rx, sessions))
+    }
+
+    /// Keep the newer one of the snapshot and the current one, return the other. The sessions
+    /// must have applied the log up to the snapshot
+    pub(super) fn retain_snapshot(
+        &self,
+        snapshot: Snapshot,
+        sessions: SessionTable<C>,
+    ) -> Option<Snapshot> {
+        let mut snapshot_l = self.ctx.snapshot.lock();
+        let is_newer = snapshot_l.as_ref().map_or(true, |cur| {
+            cur.snapshot.is_none()
+                || cur.meta.last_included_index < snapshot.meta.last_included_index
+        });
+        if is_newer {
+            snapshot_l
+                .replace(RetainedSnapshot {
+                    meta: snapshot.meta,
+                    snapshot: Some(snapshot),
+                    sessions,
+                })
+                .and_then(|stale| stale.snapshot)
+        } else {
+            Some(snapshot)
+        }
+    }
+
+    /// Expect the snapshot that the command executor is going to be reset by, e.g. an installed
+    /// one, the cmd worker retains a copy of it once the reset is done. Return the replaced snapshot
+    pub(super) fn expect_snapshot(
+        &self,
+        meta: SnapshotMeta,
+        sessions: SessionTable<C>,
+    ) -> Option<Snapshot> {
+        self.ctx
+            .snapshot
+            .lock()
+            .replace(RetainedSnapshot {
+                meta,
+                snapshot: None,
+                sessions,
+            })
+            .and_then(|stale| stale.snapshot)
+    }
+
+    /// Check if a copy of the snapshot at `index` is expected since the command executor is
+    /// reset by it
+    pub(super) fn is_snapshot_expected(&self, index: LogIndex) -> bool {
+        self.ctx.snapshot.lock().as_ref().map_or(false, |retained| {
+            retained.snapshot.is_none() && retained.meta.last_included_index == index
+        })
+    }
+
+    /// Retain the copy of the snapshot that the command executor has been reset by, return it
+    /// if it's no longer expected
+    pub(super) fn retain_expected_snapshot(&self, snapshot: Snapshot) -> Option<Snapshot> {
+        let mut snapshot_l = self.ctx.snapshot.lock();
+        match snapshot_l.as_mut() {
+            Some(retained)
+                if retained.snapshot.is_none()
+                    && retained.meta.last_included_index == snapshot.meta.last_included_index =>
+            {
+                retained.snapshot = Some(snapshot);
+                None
+            }
+            Some(_) | None => Some(snapshot),
+        }
+    }
+
     /// Get current term
     pub(super) fn term(&self) -> u64 {
         self.st.read().term
@@ -756,19 +1541,126 @@ impl<C: 'static + Command> RawCurp<C> {
         Arc::clone(&self.ctx.ucp)
     }
 
-    /// Get sync event
-    pub(super) fn sync_event(&self, id: &ServerId) -> Arc<Event> {
-        Arc::clone(
-            self.ctx
-                .sync_events
-                .get(id)
-                .unwrap_or_else(|| unreachable!("server id {id} not found")),
-        )
+    /// Get sync event, return None if `id` is not a member
+    pub(super) fn sync_event(&self, id: &ServerId) -> Option<Arc<Event>> {
+        self.ctx.sync_events.read().get(id).map(Arc::clone)
+    }
+
+    /// Get the current membership
+    pub(super) fn membership(&self) -> Membership {
+        self.ms.read().clone()
+    }
+
+    /// Check if `id` is a member of the cluster
+    pub(super) fn is_member(&self, id: &ServerId) -> bool {
+        self.ms.read().is_member(id)
+    }
+
+    /// Get membership change event
+    pub(super) fn membership_event(&self) -> Arc<Event> {
+        Arc::clone(&self.ctx.membership_event)
+    }
+
+    /// Reset the membership by the one attached to an installed snapshot
+    pub(super) fn reset_membership(&self, membership: Membership) {
+        let next_index = self.log.read().last_log_index() + 1;
+        let mut ms_w = self.ms.write();
+        if membership.index() <= ms_w.index() {
+            return;
+        }
+        let peers = membership.peers();
+        let mut sync_events_w = self.ctx.sync_events.write();
+        for id in ms_w.peers().keys().filter(|id| !peers.contains_key(*id)) {
+            self.lst.remove(id);
+            let _ig = sync_events_w.remove(id);
+        }
+        for id in peers.keys().filter(|id| !ms_w.peers().contains_key(*id)) {
+            self.lst.insert(id.clone(), next_index);
+            let _ig = sync_events_w.insert(id.clone(), Arc::new(Event::new()));
+        }
+        *ms_w = membership;
+        self.ctx.membership_event.notify(usize::MAX);
+    }
+
+    /// Check the state of the entry proposed to log[`index`] in `term`
+    /// Return `None` if it hasn't been applied yet
+    /// Return `Some(true)` if it has been applied, `Some(false)` if it has been overwritten by another leader
+    pub(super) fn entry_state(&self, index: LogIndex, term: u64) -> Option<bool> {
+        let log_r = self.log.read();
+        if log_r.get(index).map_or(false, |entry| entry.term != term) {
+            Some(false)
+        } else {
+            // a compacted entry must have been applied
+            (log_r.last_applied >= index).then_some(true)
+        }
+    }
+
+    /// Get session event
+    pub(super) fn session_event(&self) -> Arc<Event> {
+        Arc::clone(&self.ctx.session_event)
+    }
+
+    /// Cache the response of the command proposed in a client session once it's after synced
+    pub(super) fn record_session_response(&self, session: ClientSession, resp: CachedResponse<C>) {
+        self.ctx.sessions.lock().record(session, resp);
+    }
+
+    /// Get a copy of the client sessions, it's persisted before the log is compacted
+    pub(super) fn sessions(&self) -> SessionTable<C> {
+        self.ctx.sessions.lock().clone()
+    }
+
+    /// Reset the client sessions by the ones attached to an installed snapshot
+    pub(super) fn reset_sessions(&self, sessions: SessionTable<C>) {
+        let mut sessions_l = self.ctx.sessions.lock();
+        if sessions.applied_index() <= sessions_l.applied_index() {
+            return;
+        }
+        *sessions_l = sessions;
+    }
+
+    /// Get the cached response of the command `id`, return `None` if it's not proposed in a
+    /// client session or its response is not cached
+    pub(super) fn session_response(&self, id: &ProposeId) -> Option<CachedResponse<C>> {
+        let session = id.session()?;
+        self.ctx.sessions.lock().response(session)
+    }
+
+    /// The leader expires the client sessions that have been idle for `session_timeout`
+    /// Return the index of the log entry that expires them if there are any
+    pub(super) fn expire_sessions(&self) -> Option<LogIndex> {
+        let st_r = self.st.read();
+        if st_r.role != Role::Leader {
+            return None;
+        }
+        let client_ids = self
+            .ctx
+            .sessions
+            .lock()
+            .take_idle(Instant::now(), self.cfg().session_timeout);
+        if client_ids.is_empty() {
+            return None;
+        }
+        let mut log_w = self.log.write();
+        let index = match log_w.push_expire_sessions(st_r.term, client_ids) {
+            Ok(index) => index,
+            Err(e) => {
+                error!("failed to expire the client sessions, {e}");
+                return None;
+            }
+        };
+        debug!("{} gets new log[{index}] to expire sessions", self.id());
+        self.ctx
+            .sync_events
+            .read()
+            .values()
+            .for_each(|event| event.notify(1));
+        Some(index)
     }
 }
 
 // Utils
-// Don't grab lock in the following functions(except cb or sp's lock)
+// Don't grab lock in the following functions(except cb, sp or sessions' lock)
 impl<C: 'static + Command> RawCurp<C> {
     /// Server becomes a candidate
     fn become_candidate(&self, st: &mut State, cst: &mut CandidateState<C>, log: &Log<C>) -> Vote {
@@ -789,10 +1681,10 @@ impl<C: 'static + Command> RawCurp<C> {
         cst.votes_received = 1;
         cst.sps = HashMap::from([(self.id().clone(), self_sp)]);
 
-        if prev_role == Role::Follower {
-            debug!("Follower {} starts election", self.id());
-        } else {
-            debug!("Candidate {} restarts election", self.id());
+        match prev_role {
+            Role::Follower => debug!("Follower {} starts election", self.id()),
+            Role::PreCandidate => debug!("Pre-candidate {} starts election", self.id()),
+            Role::Candidate | Role::Leader => debug!("Candidate {} restarts election", self.id()),
         }
 
         Vote {
@@ -800,6 +1692,37 @@ impl<C: 'static + Command> RawCurp<C> {
             candidate_id: self.id().clone(),
             last_log_index: log.last_log_index(),
             last_log_term: log.last_log_term(),
+            is_pre_vote: false,
+            is_leader_transfer: false,
+        }
+    }
+
+    /// Server becomes a pre-candidate, the term won't be increased until the pre-vote is granted by the majority
+    fn become_pre_candidate(
+        &self,
+        st: &mut State,
+        cst: &mut CandidateState<C>,
+        log: &Log<C>,
+    ) -> Vote {
+        assert!(st.role != Role::Leader, "leader can't start election");
+
+        st.role = Role::PreCandidate;
+        st.leader_id = None;
+        let _ig = self.ctx.leader_tx.send(None).ok();
+        self.reset_election_tick();
+
+        cst.votes_received = 1;
+        cst.sps = HashMap::from([(self.id().clone(), vec![])]);
+
+        debug!("{} starts pre-vote", self.id());
+
+        Vote {
+            term: st.term + 1,
+            candidate_id: self.id().clone(),
+            last_log_index: log.last_log_index(),
+            last_log_term: log.last_log_term(),
+            is_pre_vote: true,
+            is_leader_transfer: false,
         }
     }
 
@@ -807,8 +1730,13 @@ impl<C: 'static + Command> RawCurp<C> {
     fn become_leader(&self, st: &mut State) {
         st.role = Role::Leader;
         st.leader_id = Some(self.id().clone());
+        st.leader_transferee = None;
         let _ig = self.ctx.leader_tx.send(Some(self.id().clone())).ok();
         self.ctx.leader_event.notify(usize::MAX);
+        // the quorum check and the lease start from a clean state
+        self.reset_election_tick();
+        let _ig_active = self.lst.take_recent_active();
+        self.lst.reset_lease();
 
         debug!("{} becomes the leader", self.id());
     }
@@ -829,7 +1757,10 @@ impl<C: 'static + Command> RawCurp<C> {
         st.role = Role::Follower;
         st.voted_for = None;
         st.leader_id = None;
+        st.leader_transferee = None;
         let _ig = self.ctx.leader_tx.send(None).ok();
+        // the entries waited to be persisted in the previous term may be replaced
+        self.ctx.persist_event.notify(usize::MAX);
         st.randomize_timeout_ticks(); // regenerate timeout ticks
         debug!(
             "{} updates to term {term} and becomes a follower",
@@ -842,25 +1773,33 @@ impl<C: 'static + Command> RawCurp<C> {
         self.ctx.election_tick.store(0, Ordering::Relaxed);
     }
 
-    /// Check whether `commit_index` can be updated to i
-    fn can_update_commit_index_to(&self, log: &Log<C>, i: LogIndex, cur_term: u64) -> bool {
+    /// Get the index that `commit_index` can be updated to, which is the highest index replicated
+    /// on the majority of voters. Return `None` if `commit_index` can't be updated
+    fn next_commit_index(&self, log: &Log<C>, cur_term: u64) -> Option<LogIndex> {
+        // only voters are counted, self is counted up to the persisted entries if it is a voter
+        let ms_r = self.ms.read();
+        let mut replicated: Vec<_> = ms_r
+            .voters()
+            .iter()
+            .map(|id| {
+                if id == self.id() {
+                    log.persisted_index
+                } else {
+                    self.lst.get_match_index(id)
+                }
+            })
+            .collect();
+        replicated.sort_unstable_by(|a, b| b.cmp(a));
+        let i = *replicated.get(ms_r.quorum() - 1)?;
+
         if log.commit_index >= i {
-            return false;
+            return None;
         }
-
         // don't commit log from previous term
         if log.get(i).map_or(true, |entry| entry.term != cur_term) {
-            return false;
+            return None;
         }
-
-        let replicated_cnt: u64 = self
-            .ctx
-            .others
-            .iter()
-            .filter(|&id| self.lst.get_match_index(id) >= i)
-            .count()
-            .numeric_cast();
-        replicated_cnt + 1 >= self.quorum()
+        Some(i)
     }
 
     /// Recover from all voter's spec pools
@@ -889,20 +1828,33 @@ impl<C: 'static + Command> RawCurp<C> {
 
         // get all possibly executed(fast path) commands
         let existing_log_ids = log.get_cmd_ids();
+        let sessions_l = self.ctx.sessions.lock();
         let recovered_cmds = cmd_cnt
             .into_values()
             // only cmds whose cnt >= 3/4 can be recovered
             .filter_map(|(cmd, cnt)| (cnt >= self.superquorum()).then_some(cmd))
-            // dedup in current logs
+            // dedup in current logs, and the ones applied in client sessions even if their
+            // entries have been compacted
             .filter(|cmd| {
-                // TODO: better dedup mechanism
                 !existing_log_ids.contains(cmd.id())
+                    && cmd
+                        .id()
+                        .session()
+                        .map_or(true, |session| !sessions_l.is_applied(session))
             })
             .collect_vec();
+        drop(sessions_l);
 
         let mut cb_w = self.ctx.cb.write();
         let mut sp_l = self.ctx.sp.lock();
 
+        // the unapplied cmds may be retried by the clients, they must not be appended twice
+        for i in (log.last_applied + 1)..=log.last_log_index() {
+            if let Some(cmd) = log.get(i).and_then(LogEntry::cmd) {
+                let _ig = cb_w.sync.insert(cmd.id().clone());
+            }
+        }
+
         let term = st.term;
         for cmd in recovered_cmds {
             let _ig_sync = cb_w.sync.insert(cmd.id().clone()); // may have been inserted before
@@ -920,6 +1872,20 @@ impl<C: 'static + Command> RawCurp<C> {
         }
     }
 
+    /// Get the meta of the applied entries to be compacted, return `None` if the log hasn't grown
+    /// beyond `log_entries_cap` or there are no entries to compact
+    fn compaction_meta(&self, log: &Log<C>) -> Option<SnapshotMeta> {
+        if log.len() <= self.cfg().log_entries_cap || log.last_applied <= log.base_index {
+            return None;
+        }
+        let (last_included_index, last_included_term) =
+            log.get_prev_entry_info(log.last_applied + 1);
+        Some(SnapshotMeta {
+            last_included_index,
+            last_included_term,
+        })
+    }
+
     /// Apply new logs
     fn apply(&self, log: &mut Log<C>) {
         for i in (log.last_applied + 1)..=log.commit_index {
@@ -929,7 +1895,27 @@ impl<C: 'static + Command> RawCurp<C> {
                     log.last_log_index()
                 )
             });
-            self.ctx.cmd_tx.send_after_sync(Arc::clone(&entry.cmd), i);
+            match entry.entry_data {
+                EntryData::Command(ref cmd) if self.cfg().is_witness => {
+                    // a witness never executes cmds, the synced ones are only removed from its spec pool
+                    self.ctx.sp.map_lock(|mut sp_l| sp_l.remove(cmd.id()));
+                }
+                EntryData::Command(ref cmd) => {
+                    // a retried cmd may be committed more than once, only the first one is executed
+                    if self.apply_session(entry) {
+                        self.ctx.cmd_tx.send_after_sync(Arc::clone(cmd), i);
+                    } else {
+                        self.serve_cached_response(cmd.as_ref());
+                    }
+                }
+                EntryData::ConfChange(ref change) => {
+                    self.apply_conf_change(change.clone(), i, log.last_log_index() + 1);
+                }
+                EntryData::RegisterSession | EntryData::ExpireSessions(_) => {
+                    let _applied = self.apply_session(entry);
+                }
+                EntryData::Empty => {}
+            }
             log.last_applied = i;
 
             debug!(
@@ -940,9 +1926,76 @@ impl<C: 'static + Command> RawCurp<C> {
         }
     }
 
-    /// Get quorum: the smallest number of servers who must be online for the cluster to work
+    /// Apply the entry to the client sessions
+    /// Return `false` if the entry is a cmd that has been applied in its session before
+    fn apply_session(&self, entry: &LogEntry<C>) -> bool {
+        let applied = self.ctx.sessions.lock().apply_entry(entry);
+        if matches!(entry.entry_data, EntryData::RegisterSession) {
+            self.ctx.session_event.notify(usize::MAX);
+        }
+        applied
+    }
+
+    /// Serve the retries of a cmd that has been applied in its session by the response cached
+    /// in the session, the cmd is dropped from the pools as it won't be after synced again
+    fn serve_cached_response(&self, cmd: &C) {
+        debug!("{} skips the applied cmd({})", self.id(), cmd.id());
+        self.ctx.sp.map_lock(|mut sp_l| sp_l.remove(cmd.id()));
+        let _ig = self.ctx.ucp.lock().remove(cmd.id());
+        let Some((er, asr)) = cmd
+            .id()
+            .session()
+            .and_then(|session| self.ctx.sessions.lock().response(session))
+        else {
+            // the response will be recorded once the first one is after synced
+            return;
+        };
+        let mut cb_w = self.ctx.cb.write();
+        if !cb_w.er_buffer.contains_key(cmd.id()) {
+            cb_w.insert_er(cmd.id(), er);
+        }
+        if let Some(asr) = asr {
+            if !cb_w.asr_buffer.contains_key(cmd.id()) {
+                cb_w.insert_asr(cmd.id(), asr);
+            }
+        }
+    }
+
+    /// Apply the conf change in log[`index`] to the membership, `next_index` is used to track the new follower
+    fn apply_conf_change(&self, change: ConfChange, index: LogIndex, next_index: LogIndex) {
+        let mut ms_w = self.ms.write();
+        if !ms_w.apply(self.id(), change.clone(), index) {
+            return;
+        }
+        match change {
+            ConfChange::AddLearner(ref id, _) => {
+                if id != self.id() {
+                    self.lst.insert(id.clone(), next_index);
+                    let _ig = self
+                        .ctx
+                        .sync_events
+                        .write()
+                        .insert(id.clone(), Arc::new(Event::new()));
+                }
+            }
+            ConfChange::RemoveNode(ref id) => {
+                self.lst.remove(id);
+                let _ig = self.ctx.sync_events.write().remove(id);
+            }
+            // the connection to the updated node is rebuilt by the membership task
+            ConfChange::PromoteLearner(_) | ConfChange::UpdateNode(..) => {}
+        }
+        drop(ms_w);
+        info!(
+            "{} applied conf change {change:?} in log[{index}]",
+            self.id()
+        );
+        self.ctx.membership_event.notify(usize::MAX);
+    }
+
+    /// Get quorum: the smallest number of voters who must be online for the cluster to work
     fn quorum(&self) -> u64 {
-        (self.ctx.others.len() / 2 + 1).numeric_cast()
+        self.ms.read().quorum().numeric_cast()
     }
 
     /// Get superquorum: the smallest number of servers who must contain a command in speculative pool for it to be recovered
@@ -953,24 +2006,119 @@ impl<C: 'static + Command> RawCurp<C> {
     /// When leader retires, it should reset state
     fn leader_retires(&self) {
         debug!("leader {} retires", self.id());
-
-        // when a leader retires, it should wipe up speculatively executed cmds by resetting and re-executing
-        let _ig = self.ctx.cmd_tx.send_reset(None);
+        // the pending reads can't be confirmed any more
+        self.ctx.read_event.notify(usize::MAX);
 
         let mut cb_w = self.ctx.cb.write();
         cb_w.clear();
 
+        // when a leader retires, it should wipe up speculatively executed cmds by resetting the
+        // command executor to the latest snapshot and re-executing the applied cmds after it
         let log_r = self.log.read();
-        for i in 1..=log_r.commit_index {
+        let Some(snapshot) = self.snapshot_to_rebuild(log_r.base_index) else {
+            // a server never campaigns without a snapshot to rebuild from, see `can_rebuild`,
+            // it must not go on serving with the speculatively executed cmds
+            unreachable!(
+                "{} retires without a snapshot of the compacted log to rebuild the command executor from",
+                self.id()
+            );
+        };
+        let (snapshot, mut sessions) = snapshot.map_or_else(
+            || (None, SessionTable::new()),
+            |(snapshot, sessions)| (Some(snapshot), sessions),
+        );
+        let next_index = snapshot
+            .as_ref()
+            .map_or(1, |snapshot| snapshot.meta.last_included_index + 1);
+        let _ig = self.ctx.cmd_tx.send_reset(snapshot);
+
+        // the applied entries are replayed through the sessions as `apply` does, so that the
+        // cmds committed more than once in their sessions are still executed only once
+        for i in next_index..=log_r.last_applied {
             let entry = log_r.get(i).unwrap_or_else(|| {
                 unreachable!(
                     "system corrupted, apply log[{i}] when we only have {} log entries",
                     log_r.last_log_index()
                 )
             });
-            self.ctx.cmd_tx.send_after_sync(Arc::clone(&entry.cmd), i);
+            if !sessions.apply_entry(entry) {
+                continue;
+            }
+            if let EntryData::Command(ref cmd) = entry.entry_data {
+                self.ctx.cmd_tx.send_after_sync(Arc::clone(cmd), i);
+            }
+        }
+    }
+
+    /// Take the snapshot that the command executor can be rebuilt from with the log after
+    /// `base_index` and the sessions at its index, return `Some(None)` if it can be rebuilt
+    /// from the initial state. The snapshot is consumed by the reset, so the cmd worker retains
+    /// a copy of it once the reset is done
+    fn snapshot_to_rebuild(
+        &self,
+        base_index: LogIndex,
+    ) -> Option<Option<(Snapshot, SessionTable<C>)>> {
+        let mut snapshot_l = self.ctx.snapshot.lock();
+        let Some(retained) = snapshot_l
+            .as_mut()
+            .filter(|retained| retained.meta.last_included_index >= base_index)
+        else {
+            return (base_index == 0).then_some(None);
+        };
+        let snapshot = retained.snapshot.take()?;
+        Some(Some((snapshot, retained.sessions.clone())))
+    }
+
+    /// Check if the command executor can be rebuilt when self retires, a server that can't
+    /// must not campaign, or the cmds it speculatively executes would never be wiped up
+    fn can_rebuild(&self) -> bool {
+        let base_index = self.log.read().base_index;
+        self.ctx
+            .snapshot
+            .lock()
+            .as_ref()
+            .map_or(base_index == 0, |retained| {
+                retained.snapshot.is_some() && retained.meta.last_included_index >= base_index
+            })
+    }
+
+    /// Check if self believes that the leader is still alive, a follower believes so if it
+    /// has heard from the leader within an election timeout
+    fn leader_alive(&self, st: &State) -> bool {
+        match st.role {
+            Role::Leader => true,
+            Role::Follower => {
+                st.leader_id.is_some()
+                    && self.ctx.election_tick.load(Ordering::Acquire)
+                        < self.cfg().follower_timeout_ticks
+            }
+            Role::PreCandidate | Role::Candidate => false,
         }
     }
+
+    /// Check if the leader still holds the lease at `now`, the lease is held if the majority of
+    /// voters have acked the heartbeats sent within `election_timeout - max_clock_drift`
+    fn in_lease(&self, now: Instant) -> bool {
+        let cfg = self.cfg();
+        // the election timer of a follower ticks every heartbeat interval, so it may time out
+        // one tick earlier than `heartbeat_interval * follower_timeout_ticks`
+        let lease = cfg
+            .heartbeat_interval
+            .saturating_mul(cfg.follower_timeout_ticks.saturating_sub(1).into())
+            .saturating_sub(cfg.max_clock_drift);
+        let ms_r = self.ms.read();
+        let held_cnt = ms_r
+            .voters()
+            .iter()
+            .filter(|&id| {
+                id == self.id()
+                    || self.lst.get_lease_acked(id).map_or(false, |sent_at| {
+                        now.saturating_duration_since(sent_at) < lease
+                    })
+            })
+            .count();
+        held_cnt >= ms_r.quorum()
+    }
 }
 
 #[cfg(test)]
@@ -1,10 +1,14 @@
 use std::{
-    collections::{HashMap, HashSet},
-    sync::Arc,
+    collections::{HashMap, HashSet, VecDeque},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Instant,
 };
 
 use madsim::rand::{thread_rng, Rng};
-use parking_lot::{Mutex, MutexGuard};
+use parking_lot::RwLock;
 use tracing::debug;
 
 use super::Role;
@@ -24,6 +28,8 @@ pub(super) struct State {
     pub(super) role: Role,
     /// Cached id of the leader.
     pub(super) leader_id: Option<ServerId>,
+    /// The server that the leader is transferring its leadership to
+    pub(super) leader_transferee: Option<ServerId>,
 
     /// Randomized follower timeout ticks
     pub(super) follower_timeout_ticks: u8,
@@ -45,6 +51,16 @@ pub(super) struct CandidateState<C> {
     pub(super) votes_received: u64,
 }
 
+/// Replication state of a follower, similar to the `Progress` state of etcd-raft
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum ProgressState {
+    /// The leader doesn't know where the follower's log matches its own, it sends one batch
+    /// at a time and waits for the response to probe it
+    Probe,
+    /// The follower's log matches, the leader sends batches optimistically without waiting for the responses
+    Replicate,
+}
+
 /// Status of a follower
 #[derive(Debug)]
 struct FollowerStatus {
@@ -52,13 +68,43 @@ struct FollowerStatus {
     next_index: LogIndex,
     /// Index of highest log entry known to be replicated on that follower
     match_index: LogIndex,
+    /// Replication state of that follower
+    state: ProgressState,
+    /// Last indexes of the batches sent to that follower but not acked yet
+    inflights: VecDeque<LogIndex>,
+    /// Whether the follower has responded since the last quorum check
+    recent_active: bool,
+    /// The latest read sequence number acked by that follower
+    read_acked: u64,
+    /// When the latest heartbeat acked by that follower was sent, used to hold the lease
+    lease_acked: Option<Instant>,
 }
 
 /// Additional state for the leader, all volatile
 #[derive(Debug)]
 pub(super) struct LeaderState {
     /// For each server, the leader maintains its status
-    statuses: HashMap<ServerId, Mutex<FollowerStatus>>,
+    statuses: RwLock<HashMap<ServerId, FollowerStatus>>,
+    /// Sequence number of the latest read, the leadership is confirmed for a read once
+    /// the majority acks a heartbeat sent after it
+    read_seq: AtomicU64,
+    /// The max number of batches sent to a follower but not acked yet
+    max_inflight_batches: usize,
+}
+
+impl FollowerStatus {
+    /// Create a new `FollowerStatus`, the follower is probed from `next_index`
+    fn new(next_index: LogIndex) -> Self {
+        Self {
+            next_index,
+            match_index: 0,
+            state: ProgressState::Probe,
+            inflights: VecDeque::new(),
+            recent_active: false,
+            read_acked: 0,
+            lease_acked: None,
+        }
+    }
 }
 
 impl State {
@@ -76,6 +122,7 @@ impl State {
             voted_for,
             role,
             leader_id,
+            leader_transferee: None,
             follower_timeout_ticks,
             candidate_timeout_ticks,
             follower_timeout_ticks_base: follower_timeout_ticks,
@@ -97,60 +144,212 @@ impl State {
 
 impl LeaderState {
     /// Create a `LeaderState`
-    pub(super) fn new(others: &HashSet<ServerId>) -> Self {
+    pub(super) fn new(others: &HashSet<ServerId>, max_inflight_batches: usize) -> Self {
         Self {
-            statuses: others
-                .iter()
-                .cloned()
-                .map(|o| {
-                    (
-                        o,
-                        Mutex::new(FollowerStatus {
-                            next_index: 1,
-                            match_index: 0,
-                        }),
-                    )
-                })
-                .collect(),
+            statuses: RwLock::new(
+                others
+                    .iter()
+                    .cloned()
+                    .map(|o| (o, FollowerStatus::new(1)))
+                    .collect(),
+            ),
+            read_seq: AtomicU64::new(0),
+            max_inflight_batches,
         }
     }
 
-    /// Get status for a server
-    fn get_status(&self, id: &ServerId) -> MutexGuard<'_, FollowerStatus> {
-        self.statuses
-            .get(id)
-            .unwrap_or_else(|| unreachable!("no status for {id}"))
-            .lock()
+    /// Start tracking a new follower
+    pub(super) fn insert(&self, id: ServerId, next_index: LogIndex) {
+        let _ig = self
+            .statuses
+            .write()
+            .insert(id, FollowerStatus::new(next_index));
+    }
+
+    /// Stop tracking a removed follower
+    pub(super) fn remove(&self, id: &ServerId) {
+        let _ig = self.statuses.write().remove(id);
     }
 
-    /// Get `next_index` for server
+    /// Get `next_index` for server, return 0 if the server is not tracked
     pub(super) fn get_next_index(&self, id: &ServerId) -> LogIndex {
-        self.get_status(id).next_index
+        self.statuses.read().get(id).map_or(0, |s| s.next_index)
     }
 
-    /// Get `match_index` for server
+    /// Get `match_index` for server, return 0 if the server is not tracked
     pub(super) fn get_match_index(&self, id: &ServerId) -> LogIndex {
-        self.get_status(id).match_index
+        self.statuses.read().get(id).map_or(0, |s| s.match_index)
+    }
+
+    /// Get the replication state of the follower, return `None` if the server is not tracked
+    pub(super) fn get_state(&self, id: &ServerId) -> Option<ProgressState> {
+        self.statuses.read().get(id).map(|s| s.state)
+    }
+
+    /// Forget the progress of the follower, it will be probed from `next_index`
+    pub(super) fn reset_progress(&self, id: &ServerId, next_index: LogIndex) {
+        if let Some(status) = self.statuses.write().get_mut(id) {
+            status.next_index = next_index;
+            status.match_index = 0;
+            status.state = ProgressState::Probe;
+            status.inflights.clear();
+        }
+    }
+
+    /// Check if no more batches can be sent to the follower until some inflight batches are acked
+    pub(super) fn is_paused(&self, id: &ServerId) -> bool {
+        self.statuses
+            .read()
+            .get(id)
+            .map_or(true, |s| match s.state {
+                ProgressState::Probe => !s.inflights.is_empty(),
+                ProgressState::Replicate => s.inflights.len() >= self.max_inflight_batches,
+            })
     }
 
-    /// Update `next_index` for server
-    pub(super) fn update_next_index(&self, id: &ServerId, index: LogIndex) {
-        self.get_status(id).next_index = index;
+    /// Record that a batch ending at `last_index` is sent to the follower, `next_index` will be
+    /// advanced optimistically if the follower is in the replicate state
+    pub(super) fn sent_batch(&self, id: &ServerId, last_index: LogIndex) {
+        if let Some(status) = self.statuses.write().get_mut(id) {
+            status.inflights.push_back(last_index);
+            if status.state == ProgressState::Replicate {
+                status.next_index = last_index + 1;
+            }
+        }
     }
 
     /// Update `match_index` for server, will update `next_index` if possible
+    /// The follower starts to be replicated once its `match_index` is updated in the probe state
     pub(super) fn update_match_index(&self, id: &ServerId, index: LogIndex) {
-        let mut status = self.get_status(id);
+        let mut statuses_w = self.statuses.write();
+        let Some(status) = statuses_w.get_mut(id) else {
+            return;
+        };
 
+        // the follower has responded to the probe, the next one can be sent
+        if status.state == ProgressState::Probe {
+            status.inflights.clear();
+        }
+        if status.next_index <= index {
+            status.next_index = index + 1;
+        }
         if status.match_index >= index {
             return;
         }
 
         status.match_index = index;
-        status.next_index = index + 1;
+        match status.state {
+            ProgressState::Probe => {
+                status.state = ProgressState::Replicate;
+                status.next_index = index + 1;
+                debug!("follower {id} starts to be replicated from {index}");
+            }
+            ProgressState::Replicate => status.inflights.retain(|&last| last > index),
+        }
 
         debug!("follower {id}'s match_index updated to {index}");
     }
+
+    /// Handle the rejection of the batch after `rejected`, `hint_index` is where the follower
+    /// suggests to retry from. Return `false` if the rejection is stale and ignored
+    pub(super) fn reject(&self, id: &ServerId, rejected: LogIndex, hint_index: LogIndex) -> bool {
+        let mut statuses_w = self.statuses.write();
+        let Some(status) = statuses_w.get_mut(id) else {
+            return false;
+        };
+
+        let stale = match status.state {
+            // the batches sent before the follower matches are rejected
+            ProgressState::Replicate => rejected <= status.match_index,
+            // only the latest probe matters
+            ProgressState::Probe => rejected + 1 != status.next_index,
+        };
+        if stale {
+            return false;
+        }
+
+        // the entry at `rejected` doesn't match, so the follower must be probed from or before it,
+        // but log[0] always matches
+        status.next_index = rejected.min(hint_index).max(1);
+        status.state = ProgressState::Probe;
+        status.inflights.clear();
+        debug!(
+            "follower {id} is probed from {} after a rejection",
+            status.next_index
+        );
+        true
+    }
+
+    /// The follower is unreachable, the inflight batches are considered lost and it
+    /// will be probed from `match_index + 1`
+    pub(super) fn report_unreachable(&self, id: &ServerId) {
+        if let Some(status) = self.statuses.write().get_mut(id) {
+            if status.state == ProgressState::Replicate {
+                status.next_index = status.match_index + 1;
+                status.state = ProgressState::Probe;
+            }
+            status.inflights.clear();
+        }
+    }
+
+    /// Mark the follower as recently active
+    pub(super) fn mark_active(&self, id: &ServerId) {
+        if let Some(status) = self.statuses.write().get_mut(id) {
+            status.recent_active = true;
+        }
+    }
+
+    /// Get the sequence number of the latest read
+    pub(super) fn read_seq(&self) -> u64 {
+        self.read_seq.load(Ordering::Acquire)
+    }
+
+    /// Start a new read, return its sequence number
+    pub(super) fn next_read_seq(&self) -> u64 {
+        self.read_seq.fetch_add(1, Ordering::AcqRel) + 1
+    }
+
+    /// Get the latest read sequence number acked by the follower, return 0 if it is not tracked
+    pub(super) fn get_read_acked(&self, id: &ServerId) -> u64 {
+        self.statuses.read().get(id).map_or(0, |s| s.read_acked)
+    }
+
+    /// Record that the follower has acked the heartbeat sent for read `seq`
+    pub(super) fn ack_read(&self, id: &ServerId, seq: u64) {
+        if let Some(status) = self.statuses.write().get_mut(id) {
+            status.read_acked = status.read_acked.max(seq);
+        }
+    }
+
+    /// Get when the latest heartbeat acked by the follower was sent
+    pub(super) fn get_lease_acked(&self, id: &ServerId) -> Option<Instant> {
+        self.statuses.read().get(id).and_then(|s| s.lease_acked)
+    }
+
+    /// Record that the follower has acked the heartbeat sent at `sent_at`
+    pub(super) fn ack_lease(&self, id: &ServerId, sent_at: Instant) {
+        if let Some(status) = self.statuses.write().get_mut(id) {
+            status.lease_acked = Some(status.lease_acked.map_or(sent_at, |t| t.max(sent_at)));
+        }
+    }
+
+    /// Forget all heartbeats acked before, the lease has to be held again by new heartbeats
+    pub(super) fn reset_lease(&self) {
+        for status in self.statuses.write().values_mut() {
+            status.lease_acked = None;
+        }
+    }
+
+    /// Get the followers that have been active since the last call, and reset their status
+    pub(super) fn take_recent_active(&self) -> HashSet<ServerId> {
+        self.statuses
+            .write()
+            .iter_mut()
+            .filter_map(|(id, status)| {
+                std::mem::take(&mut status.recent_active).then(|| id.clone())
+            })
+            .collect()
+    }
 }
 
 impl<C> CandidateState<C> {
@@ -1,9 +1,11 @@
-use std::time::Instant;
+use std::time::{Duration, Instant};
 
+use engine::memory_engine::MemorySnapshot;
 use tokio::{sync::oneshot, time::sleep};
 use tracing_test::traced_test;
 use utils::config::{
     default_candidate_timeout_ticks, default_follower_timeout_ticks, default_heartbeat_interval,
+    CurpConfigBuilder,
 };
 
 use super::*;
@@ -29,25 +31,30 @@ impl<C: 'static + Command> RawCurp<C> {
     }
 
     pub(crate) fn new_test<Tx: CEEventTxApi<C>>(n: u64, exe_tx: Tx) -> Self {
-        let others: HashSet<ServerId> = (1..n).map(|i| format!("S{i}")).collect();
+        Self::new_test_with_cfg(n, exe_tx, CurpConfig::default())
+    }
+
+    pub(crate) fn new_test_with_cfg<Tx: CEEventTxApi<C>>(
+        n: u64,
+        exe_tx: Tx,
+        cfg: CurpConfig,
+    ) -> Self {
+        let others: HashMap<ServerId, String> = (1..n)
+            .map(|i| (format!("S{i}"), format!("127.0.0.1:{i}")))
+            .collect();
         let cmd_board = Arc::new(RwLock::new(CommandBoard::new()));
         let spec_pool = Arc::new(Mutex::new(SpeculativePool::new()));
         let uncommitted_pool = Arc::new(Mutex::new(UncommittedPool::new()));
         let (log_tx, _log_rx) = mpsc::unbounded_channel();
-        let sync_events = others
-            .iter()
-            .map(|id| (id.clone(), Arc::new(Event::new())))
-            .collect();
         Self::new(
             "S0".to_owned(),
-            others,
+            Membership::new(&"S0".to_owned(), others, false),
             true,
             cmd_board,
             spec_pool,
             uncommitted_pool,
-            Arc::new(CurpConfig::default()),
+            Arc::new(cfg),
             Box::new(exe_tx),
-            sync_events,
             log_tx,
         )
     }
@@ -58,6 +65,14 @@ impl<C: 'static + Command> RawCurp<C> {
         let mut log_w = self.log.write();
         log_w.push_cmd(st_r.term, cmd).unwrap()
     }
+
+    /// Mark all log entries as persisted
+    pub(crate) fn persist_log(&self) {
+        let (index, term) = self
+            .log
+            .map_read(|log_r| (log_r.last_log_index(), log_r.last_log_term()));
+        self.on_log_persisted(index, term);
+    }
 }
 
 /*************** tests for propose **************/
@@ -181,7 +196,7 @@ fn heartbeat_will_calibrate_term() {
         RawCurp::new_test(3, exe_tx)
     };
 
-    let result = curp.handle_append_entries_resp(&"S1".to_owned(), None, 1, false, 1);
+    let result = curp.handle_append_entries_resp(&"S1".to_owned(), 0, 0, 1, false, 1);
     assert!(result.is_err());
 
     let st_r = curp.st.read();
@@ -194,7 +209,7 @@ fn heartbeat_will_calibrate_term() {
 fn heartbeat_will_calibrate_next_index() {
     let curp = RawCurp::new_test(3, MockCEEventTxApi::<TestCommand>::default());
 
-    let result = curp.handle_append_entries_resp(&"S1".to_owned(), None, 0, false, 1);
+    let result = curp.handle_append_entries_resp(&"S1".to_owned(), 0, 0, 0, false, 1);
     assert_eq!(result, Ok(false));
 
     let st_r = curp.st.read();
@@ -284,6 +299,198 @@ fn handle_ae_will_reject_wrong_log() {
     assert_eq!(result, Err((1, 1)));
 }
 
+#[traced_test]
+#[test]
+fn leader_will_send_snapshot_to_follower_behind_compacted_log() {
+    let curp = {
+        let mut exe_tx = MockCEEventTxApi::<TestCommand>::default();
+        exe_tx
+            .expect_send_snapshot()
+            .withf(|meta| meta.last_included_index == 3 && meta.last_included_term == 0)
+            .times(1)
+            .returning(|_| oneshot::channel().1);
+        RawCurp::new_test(3, exe_tx)
+    };
+    for _ in 0..5 {
+        let _index = curp.push_cmd(Arc::new(TestCommand::default()));
+    }
+    curp.log.map_write(|mut log_w| {
+        log_w.commit_index = 3;
+        log_w.last_applied = 3;
+        let _compacted = log_w.compact(3);
+    });
+
+    assert!(matches!(
+        curp.sync(&"S1".to_owned()),
+        Ok(SyncAction::Snapshot(..))
+    ));
+}
+
+#[traced_test]
+#[test]
+fn leader_will_pipeline_batches_to_replicating_follower() {
+    let curp = {
+        let mut exe_tx = MockCEEventTxApi::<TestCommand>::default();
+        exe_tx.expect_send_after_sync().returning(|_, _| {});
+        let cfg = CurpConfigBuilder::default()
+            .max_inflight_batches(2)
+            .build()
+            .unwrap();
+        RawCurp::new_test_with_cfg(3, exe_tx, cfg)
+    };
+    let s1_id = "S1".to_owned();
+    let sync_ae = || {
+        let Ok(SyncAction::AppendEntries(ae)) = curp.sync(&s1_id) else {
+            panic!("sync action should be append entries");
+        };
+        (
+            ae.prev_log_index,
+            ae.entries.iter().map(|e| e.index).collect::<Vec<_>>(),
+        )
+    };
+    for _ in 0..3 {
+        let _index = curp.push_cmd(Arc::new(TestCommand::default()));
+    }
+
+    // only one batch is sent at a time in the probe state
+    assert_eq!(sync_ae(), (0, vec![1, 2, 3]));
+    assert_eq!(sync_ae(), (0, vec![]));
+    assert_eq!(curp.lst.get_state(&s1_id), Some(ProgressState::Probe));
+    let result = curp.handle_append_entries_resp(&s1_id, 0, 3, 0, true, 0);
+    assert_eq!(result, Ok(true));
+    assert_eq!(curp.lst.get_state(&s1_id), Some(ProgressState::Replicate));
+
+    // batches are sent optimistically until the window is full
+    for _ in 0..2 {
+        let _index = curp.push_cmd(Arc::new(TestCommand::default()));
+    }
+    assert_eq!(sync_ae(), (3, vec![4, 5]));
+    let _index = curp.push_cmd(Arc::new(TestCommand::default()));
+    assert_eq!(sync_ae(), (5, vec![6]));
+    let _index = curp.push_cmd(Arc::new(TestCommand::default()));
+    // heartbeats are matched at `match_index` when the window is full
+    assert_eq!(sync_ae(), (3, vec![]));
+
+    let result = curp.handle_append_entries_resp(&s1_id, 3, 5, 0, true, 0);
+    assert_eq!(result, Ok(true));
+    assert_eq!(curp.lst.get_match_index(&s1_id), 5);
+    assert_eq!(sync_ae(), (6, vec![7]));
+}
+
+#[traced_test]
+#[test]
+fn leader_will_probe_follower_after_rejection() {
+    let curp = {
+        let mut exe_tx = MockCEEventTxApi::<TestCommand>::default();
+        exe_tx.expect_send_after_sync().returning(|_, _| {});
+        RawCurp::new_test(3, exe_tx)
+    };
+    let s1_id = "S1".to_owned();
+    for _ in 0..3 {
+        let _index = curp.push_cmd(Arc::new(TestCommand::default()));
+    }
+    let _action = curp.sync(&s1_id);
+    let _ig = curp.handle_append_entries_resp(&s1_id, 0, 3, 0, true, 0);
+    for _ in 0..3 {
+        let _index = curp.push_cmd(Arc::new(TestCommand::default()));
+        let _action = curp.sync(&s1_id);
+    }
+    assert_eq!(curp.lst.get_next_index(&s1_id), 7);
+
+    // the rejection of a batch sent before the follower matches is stale
+    let result = curp.handle_append_entries_resp(&s1_id, 2, 2, 0, false, 2);
+    assert_eq!(result, Ok(false));
+    assert_eq!(curp.lst.get_state(&s1_id), Some(ProgressState::Replicate));
+    assert_eq!(curp.lst.get_next_index(&s1_id), 7);
+
+    let result = curp.handle_append_entries_resp(&s1_id, 5, 6, 0, false, 4);
+    assert_eq!(result, Ok(false));
+    assert_eq!(curp.lst.get_state(&s1_id), Some(ProgressState::Probe));
+    assert_eq!(curp.lst.get_next_index(&s1_id), 4);
+
+    // only the rejection of the latest probe matters
+    let _ig = curp.handle_append_entries_resp(&s1_id, 4, 5, 0, false, 4);
+    assert_eq!(curp.lst.get_next_index(&s1_id), 4);
+    let Ok(SyncAction::AppendEntries(ae)) = curp.sync(&s1_id) else {
+        panic!("sync action should be append entries");
+    };
+    assert_eq!(ae.prev_log_index, 3);
+    assert_eq!(ae.entries.len(), 3);
+}
+
+#[traced_test]
+#[test]
+fn follower_commit_index_will_not_go_backwards() {
+    let curp = {
+        let mut exe_tx = MockCEEventTxApi::<TestCommand>::default();
+        exe_tx
+            .expect_send_reset()
+            .returning(|_| oneshot::channel().1);
+        exe_tx.expect_send_after_sync().returning(|_, _| {});
+        RawCurp::new_test(3, exe_tx)
+    };
+    curp.update_to_term_and_become_follower(&mut *curp.st.write(), 1);
+    let entries = (1..=2)
+        .map(|i| LogEntry::new(i, 1, Arc::new(TestCommand::default())))
+        .collect_vec();
+
+    let result = curp.handle_append_entries(1, "S2".to_owned(), 0, 0, entries, 2);
+    assert!(result.is_ok());
+    assert_eq!(curp.commit_index(), 2);
+
+    // a pipelined request sent earlier arrives late
+    let stale_entries = vec![LogEntry::new(1, 1, Arc::new(TestCommand::default()))];
+    let result = curp.handle_append_entries(1, "S2".to_owned(), 0, 0, stale_entries, 1);
+    assert!(result.is_ok());
+    assert_eq!(curp.commit_index(), 2);
+    assert_eq!(curp.log.read().last_log_index(), 2);
+}
+
+#[traced_test]
+#[test]
+fn leader_will_not_count_itself_before_entries_are_persisted() {
+    let curp = {
+        let mut exe_tx = MockCEEventTxApi::<TestCommand>::default();
+        exe_tx
+            .expect_send_after_sync()
+            .times(1)
+            .returning(|_, _| {});
+        RawCurp::new_test(3, exe_tx)
+    };
+    let index = curp.push_cmd(Arc::new(TestCommand::default()));
+    let _ig = curp.handle_append_entries_resp(&"S1".to_owned(), 0, index, 0, true, 0);
+    assert_eq!(curp.commit_index(), 0);
+
+    curp.persist_log();
+    assert_eq!(curp.commit_index(), index);
+}
+
+#[traced_test]
+#[test]
+fn follower_will_only_ack_persisted_entries() {
+    let curp = {
+        let mut exe_tx = MockCEEventTxApi::<TestCommand>::default();
+        exe_tx
+            .expect_send_reset()
+            .returning(|_| oneshot::channel().1);
+        RawCurp::new_test(3, exe_tx)
+    };
+    curp.update_to_term_and_become_follower(&mut *curp.st.write(), 1);
+    let entries = (1..=2)
+        .map(|i| LogEntry::new(i, 1, Arc::new(TestCommand::default())))
+        .collect_vec();
+    let result = curp.handle_append_entries(1, "S2".to_owned(), 0, 0, entries, 0);
+    assert_eq!(result, Ok(1));
+    assert_eq!(curp.log_persisted_to(1, 2), Ok(false));
+
+    curp.on_log_persisted(2, 1);
+    assert_eq!(curp.log_persisted_to(1, 2), Ok(true));
+
+    // the entries may be replaced once the term changes
+    curp.update_to_term_and_become_follower(&mut *curp.st.write(), 2);
+    assert!(curp.log_persisted_to(1, 2).is_err());
+}
+
 /*************** tests for election **************/
 
 #[traced_test]
@@ -370,7 +577,9 @@ fn handle_vote_will_calibrate_term() {
         Arc::new(RawCurp::new_test(3, exe_tx))
     };
 
-    let result = curp.handle_vote(1, "S1".to_owned(), 0, 0).unwrap();
+    let result = curp
+        .handle_vote(1, "S1".to_owned(), 0, 0, false, false)
+        .unwrap();
     assert_eq!(result.0, 1);
 
     assert_eq!(curp.term(), 1);
@@ -389,7 +598,7 @@ fn handle_vote_will_reject_smaller_term() {
     };
     curp.update_to_term_and_become_follower(&mut *curp.st.write(), 2);
 
-    let result = curp.handle_vote(1, "S1".to_owned(), 0, 0);
+    let result = curp.handle_vote(1, "S1".to_owned(), 0, 0, false, false);
     assert_eq!(result, Err(2));
 }
 
@@ -413,7 +622,7 @@ fn handle_vote_will_reject_outdated_candidate() {
     );
     assert!(result.is_ok());
 
-    let result = curp.handle_vote(3, "S1".to_owned(), 0, 0);
+    let result = curp.handle_vote(3, "S1".to_owned(), 0, 0, false, false);
     assert_eq!(result, Err(3));
 }
 
@@ -470,6 +679,105 @@ fn vote_will_calibrate_candidate_term() {
     assert_eq!(st_r.role, Role::Follower);
 }
 
+#[traced_test]
+#[test]
+fn leader_retires_after_compaction_will_rebuild_from_snapshot() {
+    let applied = Arc::new(Mutex::new(vec![]));
+    let curp = {
+        let mut exe_tx = MockCEEventTxApi::<TestCommand>::default();
+        let applied_c = Arc::clone(&applied);
+        exe_tx
+            .expect_send_after_sync()
+            .returning(move |_, index| applied_c.lock().push(index));
+        exe_tx
+            .expect_send_reset()
+            .withf(|snapshot| {
+                snapshot
+                    .as_ref()
+                    .map_or(false, |snapshot| snapshot.meta.last_included_index == 2)
+            })
+            .times(1)
+            .returning(|_| oneshot::channel().1);
+        RawCurp::new_test(3, exe_tx)
+    };
+    let s1_id = "S1".to_owned();
+    for _ in 0..2 {
+        let _index = curp.push_cmd(Arc::new(TestCommand::default()));
+    }
+    curp.persist_log();
+    assert_eq!(
+        curp.handle_append_entries_resp(&s1_id, 0, 2, 0, true, 0),
+        Ok(true)
+    );
+    let snapshot = Snapshot::new(
+        SnapshotMeta {
+            last_included_index: 2,
+            last_included_term: 0,
+        },
+        Box::new(MemorySnapshot::default()),
+    );
+    let (compacted, stale) = curp.compact_log(snapshot, curp.sessions());
+    assert_eq!(compacted.len(), 2);
+    assert!(stale.is_none());
+    assert_eq!(curp.log.read().base_index, 2);
+
+    let _index = curp.push_cmd(Arc::new(TestCommand::default()));
+    curp.persist_log();
+    assert_eq!(
+        curp.handle_append_entries_resp(&s1_id, 2, 3, 0, true, 0),
+        Ok(true)
+    );
+
+    // the command executor is reset to the snapshot, and only log[3] is re-applied
+    curp.update_to_term_and_become_follower(&mut *curp.st.write(), 1);
+    assert_eq!(*applied.lock(), vec![1, 2, 3, 3]);
+    assert_eq!(curp.log.read().last_applied, 3);
+
+    // a copy of the snapshot is retained once the reset is done, it can't campaign before that
+    assert!(curp.is_snapshot_expected(2));
+    assert!(!curp.can_rebuild());
+    let copy = Snapshot::new(
+        SnapshotMeta {
+            last_included_index: 2,
+            last_included_term: 0,
+        },
+        Box::new(MemorySnapshot::default()),
+    );
+    assert!(curp.retain_expected_snapshot(copy).is_none());
+    assert!(curp.can_rebuild());
+}
+
+#[traced_test]
+#[test]
+fn follower_without_snapshot_to_rebuild_will_not_campaign() {
+    let curp = {
+        let mut exe_tx = MockCEEventTxApi::<TestCommand>::default();
+        exe_tx
+            .expect_send_reset()
+            .returning(|_| oneshot::channel().1);
+        RawCurp::new_test(3, exe_tx)
+    };
+    curp.update_to_term_and_become_follower(&mut *curp.st.write(), 1);
+    // the log has been compacted, e.g. self restarts after a compaction
+    let meta = SnapshotMeta {
+        last_included_index: 2,
+        last_included_term: 1,
+    };
+    curp.log
+        .map_write(|mut log_w| log_w.reset_by_snapshot_meta(meta));
+    for _ in 0..=default_follower_timeout_ticks() * 2 {
+        assert!(curp.tick_election().is_none());
+    }
+
+    let snapshot = Snapshot::new(meta, Box::new(MemorySnapshot::default()));
+    assert!(curp
+        .retain_snapshot(snapshot, SessionTable::new())
+        .is_none());
+    let campaigned =
+        (0..=default_follower_timeout_ticks() * 2).any(|_| curp.tick_election().is_some());
+    assert!(campaigned);
+}
+
 /*************** tests for recovery **************/
 
 #[traced_test]
@@ -504,12 +812,866 @@ fn recover_from_spec_pools_will_pick_the_correct_cmds() {
     curp.recover_from_spec_pools(&mut *curp.st.write(), &mut *curp.log.write(), &spec_pools);
 
     curp.log.map_read(|log_r| {
-        assert_eq!(log_r[1].cmd.id(), cmd0.id());
-        assert_eq!(log_r[2].cmd.id(), cmd1.id());
+        assert_eq!(log_r[1].cmd().unwrap().id(), cmd0.id());
+        assert_eq!(log_r[2].cmd().unwrap().id(), cmd1.id());
         assert_eq!(log_r.last_log_index(), 2);
     });
 }
 
+/*************** tests for membership **************/
+
+#[traced_test]
+#[test]
+fn leader_handle_propose_conf_change_will_succeed() {
+    let curp = RawCurp::new_test(3, MockCEEventTxApi::<TestCommand>::default());
+    let change = ConfChange::AddLearner("S3".to_owned(), "127.0.0.1:3".to_owned());
+    let (_, result) = curp.handle_propose_conf_change(change.clone());
+    assert_eq!(result.unwrap(), Some(1));
+    assert_eq!(curp.entry_state(1, 0), None);
+
+    // only one conf change can be in progress
+    let (_, result) = curp.handle_propose_conf_change(ConfChange::RemoveNode("S2".to_owned()));
+    assert!(matches!(result, Err(ProposeError::InvalidConfChange(_))));
+
+    curp.persist_log();
+    let result = curp.handle_append_entries_resp(&"S1".to_owned(), 0, 1, 0, true, 0);
+    assert_eq!(result, Ok(true));
+    assert_eq!(curp.entry_state(1, 0), Some(true));
+    assert!(curp.is_member(&"S3".to_owned()));
+    assert!(curp.sync_event(&"S3".to_owned()).is_some());
+
+    // the change has been applied
+    let (_, result) = curp.handle_propose_conf_change(change);
+    assert!(matches!(result, Err(ProposeError::InvalidConfChange(_))));
+}
+
+#[traced_test]
+#[test]
+fn leader_will_promote_learner_after_it_catches_up() {
+    let curp = RawCurp::new_test(3, MockCEEventTxApi::<TestCommand>::default());
+    let (_, result) = curp.handle_propose_conf_change(ConfChange::AddLearner(
+        "S3".to_owned(),
+        "127.0.0.1:3".to_owned(),
+    ));
+    assert_eq!(result.unwrap(), Some(1));
+    curp.persist_log();
+    let result = curp.handle_append_entries_resp(&"S1".to_owned(), 0, 1, 0, true, 0);
+    assert_eq!(result, Ok(true));
+
+    let promote = ConfChange::PromoteLearner("S3".to_owned());
+    let (_, result) = curp.handle_propose_conf_change(promote.clone());
+    assert!(matches!(result, Err(ProposeError::LearnerNotCatchUp)));
+
+    let result = curp.handle_append_entries_resp(&"S3".to_owned(), 0, 1, 0, true, 0);
+    assert!(result.is_ok());
+    let (_, result) = curp.handle_propose_conf_change(promote);
+    assert_eq!(result.unwrap(), Some(2));
+}
+
+#[traced_test]
+#[test]
+fn follower_handle_propose_conf_change_will_redirect() {
+    let curp = {
+        let mut exe_tx = MockCEEventTxApi::<TestCommand>::default();
+        exe_tx
+            .expect_send_reset()
+            .returning(|_| oneshot::channel().1);
+        RawCurp::new_test(3, exe_tx)
+    };
+    curp.update_to_term_and_become_follower(&mut *curp.st.write(), 1);
+    let ((_, term), result) =
+        curp.handle_propose_conf_change(ConfChange::RemoveNode("S2".to_owned()));
+    assert_eq!(term, 1);
+    assert_eq!(result.unwrap(), None);
+}
+
+#[traced_test]
+#[test]
+fn learner_will_not_be_counted_in_quorum() {
+    let curp = {
+        let mut exe_tx = MockCEEventTxApi::<TestCommand>::default();
+        exe_tx
+            .expect_send_after_sync()
+            .times(1)
+            .returning(|_, _| {});
+        RawCurp::new_test(2, exe_tx)
+    };
+    let (_, result) = curp.handle_propose_conf_change(ConfChange::AddLearner(
+        "S2".to_owned(),
+        "127.0.0.1:2".to_owned(),
+    ));
+    assert_eq!(result.unwrap(), Some(1));
+    curp.persist_log();
+    let _ig = curp.handle_append_entries_resp(&"S1".to_owned(), 0, 1, 0, true, 0);
+    assert_eq!(curp.commit_index(), 1);
+
+    let index = curp.push_cmd(Arc::new(TestCommand::default()));
+    curp.persist_log();
+    let _ig = curp.handle_append_entries_resp(&"S2".to_owned(), 0, index, 0, true, 0);
+    assert_eq!(curp.commit_index(), 1);
+    let _ig = curp.handle_append_entries_resp(&"S1".to_owned(), 0, index, 0, true, 0);
+    assert_eq!(curp.commit_index(), index);
+}
+
+#[traced_test]
+#[test]
+fn non_voter_can_not_be_elected() {
+    let curp = RawCurp::new_test(3, MockCEEventTxApi::<TestCommand>::default());
+    let _ig = curp.handle_propose_conf_change(ConfChange::AddLearner(
+        "S3".to_owned(),
+        "127.0.0.1:3".to_owned(),
+    ));
+    curp.persist_log();
+    let _ig = curp.handle_append_entries_resp(&"S1".to_owned(), 0, 1, 0, true, 0);
+
+    let result = curp.handle_vote(1, "S3".to_owned(), 1, 0, false, false);
+    assert_eq!(result, Err(0));
+    assert_eq!(curp.role(), Role::Leader);
+}
+
+#[traced_test]
+#[test]
+fn removed_leader_will_step_down() {
+    let curp = {
+        let mut exe_tx = MockCEEventTxApi::<TestCommand>::default();
+        exe_tx
+            .expect_send_reset()
+            .returning(|_| oneshot::channel().1);
+        RawCurp::new_test(3, exe_tx)
+    };
+    let (_, result) = curp.handle_propose_conf_change(ConfChange::RemoveNode("S0".to_owned()));
+    assert_eq!(result.unwrap(), Some(1));
+
+    curp.persist_log();
+    let result = curp.handle_append_entries_resp(&"S1".to_owned(), 0, 1, 0, true, 0);
+    assert!(result.is_err());
+    assert_eq!(curp.role(), Role::Follower);
+    assert!(!curp.is_member(curp.id()));
+    assert!(curp.tick_election().is_none());
+}
+
+/*************** tests for leader transfer **************/
+
+#[traced_test]
+#[test]
+fn leader_will_reject_proposals_during_leader_transfer() {
+    let curp = RawCurp::new_test(3, MockCEEventTxApi::<TestCommand>::default());
+    assert!(curp.handle_move_leader(&"S1".to_owned()).unwrap());
+    assert!(matches!(
+        curp.handle_move_leader(&"S2".to_owned()),
+        Err(ProposeError::LeaderTransferring)
+    ));
+
+    let cmd = Arc::new(TestCommand::default());
+    let (_, result) = curp.handle_propose(Arc::clone(&cmd));
+    assert!(matches!(result, Err(ProposeError::LeaderTransferring)));
+    assert!(!curp.ctx.sp.lock().pool.contains_key(cmd.id()));
+    let (_, result) = curp.handle_propose_conf_change(ConfChange::RemoveNode("S2".to_owned()));
+    assert!(matches!(result, Err(ProposeError::LeaderTransferring)));
+
+    curp.abort_leader_transfer(&"S1".to_owned());
+    let (_, result) = curp.handle_propose_conf_change(ConfChange::RemoveNode("S2".to_owned()));
+    assert_eq!(result.unwrap(), Some(1));
+}
+
+#[traced_test]
+#[test]
+fn leader_will_send_timeout_now_after_transferee_caught_up() {
+    let curp = RawCurp::new_test(3, MockCEEventTxApi::<TestCommand>::default());
+    let index = curp.push_cmd(Arc::new(TestCommand::default()));
+    assert!(curp.handle_move_leader(&"S1".to_owned()).unwrap());
+    assert_eq!(curp.leader_transfer_ready(&"S1".to_owned()), None);
+
+    let _ig = curp.handle_append_entries_resp(&"S2".to_owned(), 0, index, 0, true, 0);
+    assert_eq!(curp.leader_transfer_ready(&"S2".to_owned()), None);
+    let _ig = curp.handle_append_entries_resp(&"S1".to_owned(), 0, index, 0, true, 0);
+    assert_eq!(curp.leader_transfer_ready(&"S1".to_owned()), Some(0));
+}
+
+#[traced_test]
+#[test]
+fn leader_transfer_to_non_voter_will_fail() {
+    let curp = RawCurp::new_test(3, MockCEEventTxApi::<TestCommand>::default());
+    assert!(matches!(
+        curp.handle_move_leader(&"S3".to_owned()),
+        Err(ProposeError::ProtocolError(_))
+    ));
+    assert!(!curp.handle_move_leader(curp.id()).unwrap());
+}
+
+#[traced_test]
+#[test]
+fn follower_handle_timeout_now_will_start_election() {
+    let curp = {
+        let mut exe_tx = MockCEEventTxApi::<TestCommand>::default();
+        exe_tx
+            .expect_send_reset()
+            .returning(|_| oneshot::channel().1);
+        RawCurp::new_test(3, exe_tx)
+    };
+    curp.handle_append_entries(1, "S2".to_owned(), 0, 0, vec![], 0)
+        .unwrap();
+
+    // only the current leader can ask self to start an election
+    assert!(curp.handle_timeout_now(1, &"S1".to_owned()).is_none());
+    assert!(curp.handle_timeout_now(0, &"S2".to_owned()).is_none());
+
+    let vote = curp.handle_timeout_now(1, &"S2".to_owned()).unwrap();
+    assert_eq!(vote.term, 2);
+    assert_eq!(curp.role(), Role::Candidate);
+}
+
+/*************** tests for pre-vote and check quorum **************/
+
+#[traced_test]
+#[test]
+fn pre_candidate_will_not_increase_term_until_pre_vote_succeeds() {
+    let curp = {
+        let mut exe_tx = MockCEEventTxApi::<TestCommand>::default();
+        exe_tx
+            .expect_send_reset()
+            .returning(|_| oneshot::channel().1);
+        let cfg = CurpConfigBuilder::default().pre_vote(true).build().unwrap();
+        RawCurp::new_test_with_cfg(3, exe_tx, cfg)
+    };
+    curp.update_to_term_and_become_follower(&mut *curp.st.write(), 1);
+
+    // tick till pre-vote starts
+    let vote = loop {
+        if let Some(vote) = curp.tick_election() {
+            break vote;
+        }
+    };
+    assert!(vote.is_pre_vote);
+    assert_eq!(vote.term, 2);
+    assert_eq!(curp.term(), 1);
+    assert_eq!(curp.role(), Role::PreCandidate);
+
+    let vote = curp
+        .handle_pre_vote_resp(&"S1".to_owned(), 2, true)
+        .unwrap()
+        .unwrap();
+    assert!(!vote.is_pre_vote);
+    assert_eq!(vote.term, 2);
+    assert_eq!(curp.term(), 2);
+    assert_eq!(curp.role(), Role::Candidate);
+}
+
+#[traced_test]
+#[test]
+fn pre_vote_rejection_will_calibrate_pre_candidate_term() {
+    let curp = {
+        let mut exe_tx = MockCEEventTxApi::<TestCommand>::default();
+        exe_tx
+            .expect_send_reset()
+            .returning(|_| oneshot::channel().1);
+        let cfg = CurpConfigBuilder::default().pre_vote(true).build().unwrap();
+        RawCurp::new_test_with_cfg(3, exe_tx, cfg)
+    };
+    curp.update_to_term_and_become_follower(&mut *curp.st.write(), 1);
+
+    // tick till pre-vote starts
+    while curp.role() != Role::PreCandidate {
+        let _ig = curp.tick_election();
+    }
+
+    let result = curp.handle_pre_vote_resp(&"S1".to_owned(), 3, false);
+    assert!(result.is_err());
+    assert_eq!(curp.term(), 3);
+    assert_eq!(curp.role(), Role::Follower);
+}
+
+#[traced_test]
+#[test]
+fn follower_will_reject_pre_vote_when_leader_is_alive() {
+    let curp = {
+        let mut exe_tx = MockCEEventTxApi::<TestCommand>::default();
+        exe_tx
+            .expect_send_reset()
+            .returning(|_| oneshot::channel().1);
+        RawCurp::new_test(3, exe_tx)
+    };
+    curp.handle_append_entries(1, "S2".to_owned(), 0, 0, vec![], 0)
+        .unwrap();
+
+    let result = curp.handle_vote(2, "S1".to_owned(), 0, 0, true, false);
+    assert_eq!(result, Err(1));
+
+    // the leader has not been heard from for an election timeout
+    curp.ctx
+        .election_tick
+        .store(default_follower_timeout_ticks(), Ordering::Relaxed);
+    let result = curp.handle_vote(2, "S1".to_owned(), 0, 0, true, false);
+    assert_eq!(result, Ok((2, vec![])));
+
+    // a pre-vote doesn't change the state of the voter
+    let st_r = curp.st.read();
+    assert_eq!(st_r.term, 1);
+    assert_eq!(st_r.voted_for, None);
+    assert_eq!(st_r.leader_id, Some("S2".to_owned()));
+}
+
+#[traced_test]
+#[test]
+fn leader_will_step_down_if_it_can_not_hear_from_the_majority() {
+    let curp = {
+        let mut exe_tx = MockCEEventTxApi::<TestCommand>::default();
+        exe_tx
+            .expect_send_reset()
+            .returning(|_| oneshot::channel().1);
+        let cfg = CurpConfigBuilder::default()
+            .check_quorum(true)
+            .build()
+            .unwrap();
+        RawCurp::new_test_with_cfg(3, exe_tx, cfg)
+    };
+
+    let _ig = curp.handle_append_entries_resp(&"S1".to_owned(), 0, 0, 0, true, 0);
+    for _ in 0..=default_follower_timeout_ticks() {
+        assert!(curp.tick_election().is_none());
+    }
+    assert_eq!(curp.role(), Role::Leader);
+
+    // no follower responds in the next election timeout
+    for _ in 0..=default_follower_timeout_ticks() {
+        assert!(curp.tick_election().is_none());
+    }
+    assert_eq!(curp.role(), Role::Follower);
+    assert_eq!(curp.term(), 0);
+}
+
+/*************** tests for read index **************/
+
+#[traced_test]
+#[test]
+fn follower_handle_fetch_read_state_will_return_none() {
+    let curp = {
+        let mut exe_tx = MockCEEventTxApi::<TestCommand>::default();
+        exe_tx
+            .expect_send_reset()
+            .returning(|_| oneshot::channel().1);
+        RawCurp::new_test(3, exe_tx)
+    };
+    curp.update_to_term_and_become_follower(&mut *curp.st.write(), 1);
+
+    let result = curp.handle_fetch_read_state(&TestCommand::default());
+    assert!(result.unwrap().is_none());
+}
+
+#[traced_test]
+#[test]
+fn leader_read_will_be_confirmed_after_the_majority_acks() {
+    let curp = {
+        let mut exe_tx = MockCEEventTxApi::<TestCommand>::default();
+        exe_tx
+            .expect_send_reset()
+            .returning(|_| oneshot::channel().1);
+        RawCurp::new_test(3, exe_tx)
+    };
+
+    let pending = curp
+        .handle_fetch_read_state(&TestCommand::default())
+        .unwrap()
+        .unwrap();
+    assert!(matches!(pending.state, ReadState::CommitIndex(0)));
+    assert_eq!(curp.read_confirmed(pending.term, pending.seq), Ok(false));
+
+    // the heartbeat sent after the read carries its sequence number
+    let Ok(SyncAction::AppendEntries(ae)) = curp.sync(&"S1".to_owned()) else {
+        panic!("sync action should be append entries");
+    };
+    assert_eq!(ae.read_seq, pending.seq);
+
+    // acks of the heartbeats sent before the read can't confirm the leadership
+    curp.ack_heartbeat(&"S1".to_owned(), pending.seq - 1, Instant::now());
+    assert_eq!(curp.read_confirmed(pending.term, pending.seq), Ok(false));
+    curp.ack_heartbeat(&"S1".to_owned(), pending.seq, Instant::now());
+    assert_eq!(curp.read_confirmed(pending.term, pending.seq), Ok(true));
+
+    curp.update_to_term_and_become_follower(&mut *curp.st.write(), 1);
+    assert!(curp.read_confirmed(pending.term, pending.seq).is_err());
+}
+
+#[traced_test]
+#[test]
+fn new_leader_will_append_an_empty_entry() {
+    let curp = {
+        let mut exe_tx = MockCEEventTxApi::<TestCommand>::default();
+        exe_tx
+            .expect_send_reset()
+            .returning(|_| oneshot::channel().1);
+        Arc::new(RawCurp::new_test(3, exe_tx))
+    };
+    curp.update_to_term_and_become_follower(&mut *curp.st.write(), 1);
+    let _ig = curp.push_cmd(Arc::new(TestCommand::default()));
+
+    // tick till election starts
+    while curp.role() != Role::Candidate {
+        let _ig = curp.tick_election();
+    }
+    let result = curp
+        .handle_vote_resp(&"S1".to_owned(), 2, true, vec![])
+        .unwrap();
+    assert!(result);
+
+    let log_r = curp.log.read();
+    assert_eq!(log_r.last_log_index(), 2);
+    assert_eq!(log_r.last_log_term(), 2);
+    assert!(matches!(log_r.get(2).unwrap().entry_data, EntryData::Empty));
+    drop(log_r);
+
+    // reads are rejected until the entry of the current term is committed
+    let result = curp.handle_fetch_read_state(&TestCommand::default());
+    assert!(result.unwrap().is_none());
+}
+
+/*************** tests for lease read **************/
+
+#[traced_test]
+#[test]
+fn deposed_leader_will_stop_serving_lease_reads_after_the_lease_expires() {
+    let curp = {
+        let mut exe_tx = MockCEEventTxApi::<TestCommand>::default();
+        exe_tx
+            .expect_send_reset()
+            .returning(|_| oneshot::channel().1);
+        // the lease is 10ms * (5 - 1) - 5ms = 35ms
+        let cfg = CurpConfigBuilder::default()
+            .heartbeat_interval(Duration::from_millis(10))
+            .lease_read(true)
+            .max_clock_drift(Duration::from_millis(5))
+            .build()
+            .unwrap();
+        RawCurp::new_test_with_cfg(3, exe_tx, cfg)
+    };
+
+    let pending = curp
+        .handle_fetch_read_state(&TestCommand::default())
+        .unwrap()
+        .unwrap();
+    assert!(!pending.in_lease);
+
+    curp.ack_heartbeat(&"S1".to_owned(), 0, Instant::now());
+    let pending = curp
+        .handle_fetch_read_state(&TestCommand::default())
+        .unwrap()
+        .unwrap();
+    assert!(pending.in_lease);
+
+    // the leader is partitioned from the majority, no heartbeat is acked since then
+    std::thread::sleep(Duration::from_millis(40));
+    let pending = curp
+        .handle_fetch_read_state(&TestCommand::default())
+        .unwrap()
+        .unwrap();
+    assert!(!pending.in_lease);
+}
+
+#[traced_test]
+#[test]
+fn leader_will_not_serve_lease_reads_during_leader_transfer() {
+    let curp = {
+        let exe_tx = MockCEEventTxApi::<TestCommand>::default();
+        let cfg = CurpConfigBuilder::default()
+            .lease_read(true)
+            .build()
+            .unwrap();
+        RawCurp::new_test_with_cfg(3, exe_tx, cfg)
+    };
+    curp.ack_heartbeat(&"S1".to_owned(), 0, Instant::now());
+
+    assert!(curp.handle_move_leader(&"S1".to_owned()).unwrap());
+    let pending = curp
+        .handle_fetch_read_state(&TestCommand::default())
+        .unwrap()
+        .unwrap();
+    assert!(!pending.in_lease);
+
+    // the acks before the transfer can't hold the lease any more
+    curp.abort_leader_transfer(&"S1".to_owned());
+    let pending = curp
+        .handle_fetch_read_state(&TestCommand::default())
+        .unwrap()
+        .unwrap();
+    assert!(!pending.in_lease);
+}
+
+#[traced_test]
+#[test]
+fn follower_will_reject_votes_in_the_lease_of_the_leader() {
+    let curp = {
+        let mut exe_tx = MockCEEventTxApi::<TestCommand>::default();
+        exe_tx
+            .expect_send_reset()
+            .returning(|_| oneshot::channel().1);
+        let cfg = CurpConfigBuilder::default()
+            .lease_read(true)
+            .build()
+            .unwrap();
+        RawCurp::new_test_with_cfg(3, exe_tx, cfg)
+    };
+    curp.handle_append_entries(1, "S2".to_owned(), 0, 0, vec![], 0)
+        .unwrap();
+
+    let result = curp.handle_vote(2, "S1".to_owned(), 0, 0, false, false);
+    assert_eq!(result, Err(1));
+    assert_eq!(curp.term(), 1);
+
+    // the leader transfers its leadership, so the lease is given up
+    let result = curp.handle_vote(2, "S1".to_owned(), 0, 0, false, true);
+    assert_eq!(result, Ok((2, vec![])));
+}
+
+/*************** tests for witness **************/
+
+#[traced_test]
+#[test]
+fn witness_will_not_start_election() {
+    let curp = {
+        let mut exe_tx = MockCEEventTxApi::<TestCommand>::default();
+        exe_tx
+            .expect_send_reset()
+            .returning(|_| oneshot::channel().1);
+        let cfg = CurpConfigBuilder::default()
+            .is_witness(true)
+            .build()
+            .unwrap();
+        RawCurp::new_test_with_cfg(3, exe_tx, cfg)
+    };
+    curp.handle_append_entries(1, "S2".to_owned(), 0, 0, vec![], 0)
+        .unwrap();
+
+    for _ in 0..=default_follower_timeout_ticks() * 2 {
+        assert!(curp.tick_election().is_none());
+    }
+    assert!(curp.handle_timeout_now(1, &"S2".to_owned()).is_none());
+    assert_eq!(curp.role(), Role::Follower);
+}
+
+#[traced_test]
+#[test]
+fn witness_will_answer_spec_pool_in_vote() {
+    let curp = {
+        let mut exe_tx = MockCEEventTxApi::<TestCommand>::default();
+        exe_tx
+            .expect_send_reset()
+            .returning(|_| oneshot::channel().1);
+        let cfg = CurpConfigBuilder::default()
+            .is_witness(true)
+            .build()
+            .unwrap();
+        RawCurp::new_test_with_cfg(3, exe_tx, cfg)
+    };
+    curp.update_to_term_and_become_follower(&mut *curp.st.write(), 1);
+    let cmd = Arc::new(TestCommand::default());
+    let (_, result) = curp.handle_propose(Arc::clone(&cmd));
+    assert!(matches!(result, Ok(false)));
+
+    let result = curp.handle_vote(2, "S1".to_owned(), 0, 0, false, false);
+    assert_eq!(result, Ok((2, vec![cmd])));
+}
+
+#[traced_test]
+#[test]
+fn witness_will_not_execute_synced_cmds() {
+    // the command executor will panic on `after_sync` since it's not expected
+    let curp = {
+        let mut exe_tx = MockCEEventTxApi::<TestCommand>::default();
+        exe_tx
+            .expect_send_reset()
+            .returning(|_| oneshot::channel().1);
+        let cfg = CurpConfigBuilder::default()
+            .is_witness(true)
+            .log_entries_cap(1)
+            .build()
+            .unwrap();
+        RawCurp::new_test_with_cfg(3, exe_tx, cfg)
+    };
+    curp.update_to_term_and_become_follower(&mut *curp.st.write(), 1);
+    let cmds = (0..2)
+        .map(|i| Arc::new(TestCommand::new_put(vec![i], i)))
+        .collect_vec();
+    for cmd in &cmds {
+        let (_, result) = curp.handle_propose(Arc::clone(cmd));
+        assert!(matches!(result, Ok(false)));
+    }
+    let entries = cmds
+        .iter()
+        .zip(1..)
+        .map(|(cmd, i)| LogEntry::new(i, 1, Arc::clone(cmd)))
+        .collect_vec();
+
+    let result = curp.handle_append_entries(1, "S2".to_owned(), 0, 0, entries, 2);
+    assert!(result.is_ok());
+    assert_eq!(curp.status().applied_index, 2);
+    assert!(curp.spec_pool().lock().pool.is_empty());
+
+    // the applied entries are compacted without a snapshot
+    let (meta, compacted) = curp.witness_compact_log().unwrap();
+    assert_eq!(meta.last_included_index, 2);
+    assert_eq!(meta.last_included_term, 1);
+    // the persisted spec pool cmds of the compacted entries will be removed
+    assert_eq!(
+        compacted,
+        cmds.iter().map(|cmd| cmd.id().clone()).collect_vec()
+    );
+}
+
+#[traced_test]
+#[test]
+fn fast_path_cmds_will_be_recovered_from_a_replica_and_a_witness() {
+    // two replicas S0, S1 and a witness S2, S1 crashes after a cmd succeeds in the fast path
+    let curp = {
+        let mut exe_tx = MockCEEventTxApi::<TestCommand>::default();
+        exe_tx
+            .expect_send_reset()
+            .returning(|_| oneshot::channel().1);
+        Arc::new(RawCurp::new_test(3, exe_tx))
+    };
+    curp.update_to_term_and_become_follower(&mut *curp.st.write(), 1);
+    let cmd = Arc::new(TestCommand::new_put(vec![1], 1));
+
+    // S0 is elected by the witness, their spec pools are all it can collect
+    let spec_pools = HashMap::from([
+        ("S0".to_owned(), vec![Arc::clone(&cmd)]),
+        ("S2".to_owned(), vec![Arc::clone(&cmd)]),
+    ]);
+    curp.recover_from_spec_pools(&mut *curp.st.write(), &mut *curp.log.write(), &spec_pools);
+
+    curp.log.map_read(|log_r| {
+        assert_eq!(log_r[1].cmd().unwrap().id(), cmd.id());
+        assert_eq!(log_r.last_log_index(), 1);
+    });
+}
+
+/*************** tests for client sessions **************/
+
+fn cmd_in_session(client_id: u64, seq: u64, first_incomplete: u64) -> Arc<TestCommand> {
+    let mut cmd = TestCommand::new_put(vec![1], 1);
+    cmd.id_mut().set_session(ClientSession {
+        client_id,
+        seq,
+        first_incomplete,
+    });
+    Arc::new(cmd)
+}
+
+#[traced_test]
+#[test]
+fn leader_handle_register_session_will_succeed() {
+    let curp = RawCurp::new_test(3, MockCEEventTxApi::<TestCommand>::default());
+    let (_, result) = curp.handle_register_session();
+    assert_eq!(result.unwrap(), Some(1));
+    assert_eq!(curp.entry_state(1, 0), None);
+
+    curp.persist_log();
+    let result = curp.handle_append_entries_resp(&"S1".to_owned(), 0, 1, 0, true, 0);
+    assert_eq!(result, Ok(true));
+    assert_eq!(curp.entry_state(1, 0), Some(true));
+
+    let cmd = cmd_in_session(1, 0, 0);
+    let (_, result) = curp.handle_propose_session(cmd.id().session().unwrap());
+    assert!(matches!(result, Ok(None)));
+}
+
+#[traced_test]
+#[test]
+fn follower_handle_register_session_will_redirect() {
+    let curp = {
+        let mut exe_tx = MockCEEventTxApi::<TestCommand>::default();
+        exe_tx
+            .expect_send_reset()
+            .returning(|_| oneshot::channel().1);
+        RawCurp::new_test(3, exe_tx)
+    };
+    curp.update_to_term_and_become_follower(&mut *curp.st.write(), 1);
+    let ((_, term), result) = curp.handle_register_session();
+    assert_eq!(term, 1);
+    assert!(matches!(result, Ok(None)));
+}
+
+#[traced_test]
+#[test]
+fn only_leader_will_reject_cmds_in_unknown_sessions() {
+    let curp = {
+        let mut exe_tx = MockCEEventTxApi::<TestCommand>::default();
+        exe_tx
+            .expect_send_reset()
+            .returning(|_| oneshot::channel().1);
+        RawCurp::new_test(3, exe_tx)
+    };
+    let session = cmd_in_session(1, 0, 0).id().session().unwrap();
+    let (_, result) = curp.handle_propose_session(session);
+    assert!(matches!(result, Err(ProposeError::SessionExpired)));
+
+    curp.update_to_term_and_become_follower(&mut *curp.st.write(), 1);
+    let (_, result) = curp.handle_propose_session(session);
+    assert!(matches!(result, Ok(None)));
+}
+
+#[traced_test]
+#[test]
+fn applied_cmd_in_session_will_not_be_proposed_again() {
+    let curp = {
+        let mut exe_tx = MockCEEventTxApi::<TestCommand>::default();
+        exe_tx.expect_send_after_sync().returning(|_, _| {});
+        RawCurp::new_test(3, exe_tx)
+    };
+    let (_, result) = curp.handle_register_session();
+    assert_eq!(result.unwrap(), Some(1));
+    let cmd = cmd_in_session(1, 0, 0);
+    let session = cmd.id().session().unwrap();
+    let _index = curp.push_cmd(Arc::clone(&cmd));
+    curp.persist_log();
+    let result = curp.handle_append_entries_resp(&"S1".to_owned(), 0, 2, 0, true, 0);
+    assert_eq!(result, Ok(true));
+
+    // the cmd is applied but not after synced yet
+    let (_, result) = curp.handle_propose_session(session);
+    assert!(matches!(result, Err(ProposeError::Duplicated)));
+    assert!(curp.session_response(cmd.id()).is_none());
+
+    curp.record_session_response(session, (Ok(vec![]), Some(Ok(2))));
+    let (_, result) = curp.handle_propose_session(session);
+    assert!(matches!(result, Ok(Some((Ok(_), Some(Ok(2)))))));
+    assert!(curp.session_response(cmd.id()).is_some());
+}
+
+#[traced_test]
+#[test]
+fn cmd_committed_twice_in_session_will_be_after_synced_once() {
+    let curp = {
+        let mut exe_tx = MockCEEventTxApi::<TestCommand>::default();
+        exe_tx
+            .expect_send_after_sync()
+            .times(1)
+            .returning(|_, _| {});
+        RawCurp::new_test(3, exe_tx)
+    };
+    let (_, result) = curp.handle_register_session();
+    assert_eq!(result.unwrap(), Some(1));
+    let cmd = cmd_in_session(1, 0, 0);
+    let session = cmd.id().session().unwrap();
+    let _index = curp.push_cmd(Arc::clone(&cmd));
+    curp.persist_log();
+    let result = curp.handle_append_entries_resp(&"S1".to_owned(), 0, 2, 0, true, 0);
+    assert_eq!(result, Ok(true));
+    curp.record_session_response(session, (Ok(vec![]), Some(Ok(2))));
+
+    // the retry of the cmd is committed again
+    let _index = curp.push_cmd(Arc::clone(&cmd));
+    curp.persist_log();
+    let result = curp.handle_append_entries_resp(&"S1".to_owned(), 2, 3, 0, true, 0);
+    assert_eq!(result, Ok(true));
+    assert_eq!(curp.log.read().last_applied, 3);
+
+    // the retries are served by the cached response
+    let cb_r = curp.ctx.cb.read();
+    assert!(matches!(cb_r.er_buffer.get(cmd.id()), Some(&Ok(_))));
+    assert!(matches!(cb_r.asr_buffer.get(cmd.id()), Some(&Ok(2))));
+}
+
+#[traced_test]
+#[test]
+fn leader_retires_will_re_execute_cmd_committed_twice_in_session_once() {
+    let applied = Arc::new(Mutex::new(vec![]));
+    let curp = {
+        let mut exe_tx = MockCEEventTxApi::<TestCommand>::default();
+        let applied_c = Arc::clone(&applied);
+        exe_tx
+            .expect_send_after_sync()
+            .returning(move |_, index| applied_c.lock().push(index));
+        exe_tx
+            .expect_send_reset()
+            .withf(Option::is_none)
+            .times(1)
+            .returning(|_| oneshot::channel().1);
+        RawCurp::new_test(3, exe_tx)
+    };
+    let (_, result) = curp.handle_register_session();
+    assert_eq!(result.unwrap(), Some(1));
+    let cmd = cmd_in_session(1, 0, 0);
+    for _ in 0..2 {
+        let _index = curp.push_cmd(Arc::clone(&cmd));
+    }
+    curp.persist_log();
+    let result = curp.handle_append_entries_resp(&"S1".to_owned(), 0, 3, 0, true, 0);
+    assert_eq!(result, Ok(true));
+    assert_eq!(curp.log.read().last_applied, 3);
+
+    // the retry in log[3] is skipped again when the command executor is rebuilt
+    curp.update_to_term_and_become_follower(&mut *curp.st.write(), 1);
+    assert_eq!(*applied.lock(), vec![2, 2]);
+}
+
+#[traced_test]
+#[test]
+fn recover_from_spec_pools_will_skip_applied_cmds_in_sessions() {
+    let curp = {
+        let mut exe_tx = MockCEEventTxApi::<TestCommand>::default();
+        exe_tx
+            .expect_send_reset()
+            .returning(|_| oneshot::channel().1);
+        Arc::new(RawCurp::new_test(5, exe_tx))
+    };
+    curp.update_to_term_and_become_follower(&mut *curp.st.write(), 1);
+
+    // cmd0 has been applied, but its entry has been compacted
+    let cmd0 = cmd_in_session(1, 0, 0);
+    curp.ctx.sessions.map_lock(|mut sessions_l| {
+        sessions_l.register(1);
+        let _applied = sessions_l.apply(cmd0.id().session().unwrap());
+    });
+    // cmd1 is in the log but not applied yet
+    let cmd1 = cmd_in_session(1, 1, 0);
+    let _index = curp.push_cmd(Arc::clone(&cmd1));
+
+    let spec_pools = HashMap::from([
+        ("S0".to_owned(), vec![Arc::clone(&cmd0), Arc::clone(&cmd1)]),
+        ("S1".to_owned(), vec![Arc::clone(&cmd0), Arc::clone(&cmd1)]),
+        ("S2".to_owned(), vec![Arc::clone(&cmd0), Arc::clone(&cmd1)]),
+        ("S3".to_owned(), vec![Arc::clone(&cmd0), Arc::clone(&cmd1)]),
+        ("S4".to_owned(), vec![]),
+    ]);
+
+    curp.recover_from_spec_pools(&mut *curp.st.write(), &mut *curp.log.write(), &spec_pools);
+
+    curp.log.map_read(|log_r| {
+        assert_eq!(log_r[1].cmd().unwrap().id(), cmd1.id());
+        assert_eq!(log_r.last_log_index(), 1);
+    });
+    // the retry of cmd1 will be rejected by the new leader
+    assert!(curp.ctx.cb.read().sync.contains(cmd1.id()));
+}
+
+#[traced_test]
+#[test]
+fn leader_will_expire_idle_sessions() {
+    let curp = {
+        let cfg = CurpConfigBuilder::default()
+            .session_timeout(Duration::ZERO)
+            .build()
+            .unwrap();
+        RawCurp::new_test_with_cfg(3, MockCEEventTxApi::<TestCommand>::default(), cfg)
+    };
+    assert!(curp.expire_sessions().is_none());
+
+    let (_, result) = curp.handle_register_session();
+    assert_eq!(result.unwrap(), Some(1));
+    curp.persist_log();
+    let result = curp.handle_append_entries_resp(&"S1".to_owned(), 0, 1, 0, true, 0);
+    assert_eq!(result, Ok(true));
+
+    assert_eq!(curp.expire_sessions(), Some(2));
+    curp.persist_log();
+    let result = curp.handle_append_entries_resp(&"S1".to_owned(), 1, 2, 0, true, 0);
+    assert_eq!(result, Ok(true));
+
+    let session = cmd_in_session(1, 0, 0).id().session().unwrap();
+    let (_, result) = curp.handle_propose_session(session);
+    assert!(matches!(result, Err(ProposeError::SessionExpired)));
+}
+
 /*************** tests for other small functions **************/
 
 #[traced_test]
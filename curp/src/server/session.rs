@@ -0,0 +1,323 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    time::{Duration, Instant},
+};
+
+use serde::{Deserialize, Serialize};
+use tracing::debug;
+
+use crate::{
+    cmd::{ClientSession, Command},
+    error::ProposeError,
+    log_entry::{EntryData, LogEntry},
+    LogIndex,
+};
+
+/// Response of a command cached in its session: the execution result and the after sync
+/// result, the latter is `None` if the execution fails
+pub(super) type CachedResponse<C> = (
+    Result<<C as Command>::ER, String>,
+    Option<Result<<C as Command>::ASR, String>>,
+);
+
+/// A registered client session
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Session<C: Command> {
+    /// Responses of the applied commands, a response is `None` until the command is after synced
+    responses: BTreeMap<u64, Option<CachedResponse<C>>>,
+    /// The client has received the responses of the commands before it, so they are discarded
+    first_incomplete: u64,
+    /// The commands before it may have been applied without being recorded, it's set when the
+    /// session is rebuilt from a command instead of its registration
+    known_from: u64,
+    /// When the session is used last time, only the leader expires the idle sessions by it,
+    /// a recovered session is counted as active from the recovery
+    #[serde(skip, default = "Instant::now")]
+    last_active: Instant,
+}
+
+impl<C: Command> Session<C> {
+    /// Create a new session, the commands before `known_from` are unknown to it
+    fn new(first_incomplete: u64, known_from: u64) -> Self {
+        Self {
+            responses: BTreeMap::new(),
+            first_incomplete,
+            known_from,
+            last_active: Instant::now(),
+        }
+    }
+}
+
+/// The client sessions of the cluster, they are registered and expired through the log, so
+/// every server keeps the same sessions as long as it has applied the same log entries. The
+/// sessions are persisted before the log is compacted and sent along with snapshots, so that
+/// the compacted entries are not needed to rebuild them. The entries after them are applied
+/// again on recovery, though the responses of those commands are lost
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(super) struct SessionTable<C: Command> {
+    /// Sessions indexed by the client id
+    sessions: HashMap<u64, Session<C>>,
+    /// Index of the last log entry applied to the sessions
+    applied_index: LogIndex,
+}
+
+impl<C: Command> SessionTable<C> {
+    /// Create an empty session table
+    pub(super) fn new() -> Self {
+        Self {
+            sessions: HashMap::new(),
+            applied_index: 0,
+        }
+    }
+
+    /// Index of the last log entry applied to the sessions
+    pub(super) fn applied_index(&self) -> LogIndex {
+        self.applied_index
+    }
+
+    /// Advance the applied index before log[`index`] is applied to the sessions
+    /// Return `false` if it has been applied, e.g. it's replayed on recovery
+    pub(super) fn advance(&mut self, index: LogIndex) -> bool {
+        if index <= self.applied_index {
+            return false;
+        }
+        self.applied_index = index;
+        true
+    }
+
+    /// Apply the log entry to the sessions
+    /// Return `false` if the entry is a cmd that has been applied in its session before
+    pub(super) fn apply_entry(&mut self, entry: &LogEntry<C>) -> bool {
+        if !self.advance(entry.index) {
+            return true;
+        }
+        match entry.entry_data {
+            EntryData::Command(ref cmd) => {
+                if let Some(session) = cmd.id().session() {
+                    return self.apply(session);
+                }
+            }
+            EntryData::RegisterSession => self.register(entry.index),
+            EntryData::ExpireSessions(ref client_ids) => self.expire(client_ids),
+            EntryData::ConfChange(_) | EntryData::Empty => {}
+        }
+        true
+    }
+
+    /// Register a new session for `client_id`
+    pub(super) fn register(&mut self, client_id: u64) {
+        debug!("client session {client_id} is registered");
+        let _ig = self.sessions.insert(client_id, Session::new(0, 0));
+    }
+
+    /// Expire the sessions, their cached responses are dropped
+    pub(super) fn expire(&mut self, client_ids: &[u64]) {
+        for client_id in client_ids {
+            if self.sessions.remove(client_id).is_some() {
+                debug!("client session {client_id} is expired");
+            }
+        }
+    }
+
+    /// Check a command proposed in `session`, it's also counted as an activity of the session
+    /// Return `Ok(None)` if the command has not been applied
+    /// Return `Ok(Some(response))` if the command has been applied and its response is cached
+    /// Return `Err(ProposeError::Duplicated)` if the command has been applied before
+    /// Return `Err(ProposeError::SessionExpired)` if the session is unknown or the command can't be told
+    pub(super) fn check(
+        &mut self,
+        session: ClientSession,
+    ) -> Result<Option<CachedResponse<C>>, ProposeError> {
+        let Some(s) = self.sessions.get_mut(&session.client_id) else {
+            return Err(ProposeError::SessionExpired);
+        };
+        s.last_active = Instant::now();
+        // the client has received the response, it's a stale retry
+        if session.seq < s.first_incomplete {
+            return Err(ProposeError::Duplicated);
+        }
+        match s.responses.get(&session.seq) {
+            Some(&Some(ref resp)) => Ok(Some(resp.clone())),
+            Some(&None) => Err(ProposeError::Duplicated),
+            None if session.seq < s.known_from => Err(ProposeError::SessionExpired),
+            None => Ok(None),
+        }
+    }
+
+    /// Check if the command proposed in `session` has been applied
+    pub(super) fn is_applied(&self, session: ClientSession) -> bool {
+        self.sessions
+            .get(&session.client_id)
+            .map_or(false, |s| s.responses.contains_key(&session.seq))
+    }
+
+    /// Record that the command proposed in `session` is applied, the responses the client has
+    /// received are discarded. A session unknown to self is rebuilt from the command
+    /// Return `false` if the command has been applied before, it must not be executed again
+    pub(super) fn apply(&mut self, session: ClientSession) -> bool {
+        let s = self.sessions.entry(session.client_id).or_insert_with(|| {
+            debug!(
+                "client session {} is rebuilt from seq {}",
+                session.client_id, session.seq
+            );
+            Session::new(session.first_incomplete, session.seq)
+        });
+        s.last_active = Instant::now();
+        let applied = session.seq < s.first_incomplete || s.responses.contains_key(&session.seq);
+        if session.first_incomplete > s.first_incomplete {
+            s.first_incomplete = session.first_incomplete;
+            s.responses = s.responses.split_off(&session.first_incomplete);
+        }
+        if session.seq >= s.first_incomplete {
+            let _ig = s.responses.entry(session.seq).or_insert(None);
+        }
+        !applied
+    }
+
+    /// Cache the response of the command proposed in `session` once it's after synced
+    pub(super) fn record(&mut self, session: ClientSession, resp: CachedResponse<C>) {
+        if let Some(cached) = self
+            .sessions
+            .get_mut(&session.client_id)
+            .and_then(|s| s.responses.get_mut(&session.seq))
+        {
+            *cached = Some(resp);
+        }
+    }
+
+    /// Get the cached response of the command proposed in `session`
+    pub(super) fn response(&self, session: ClientSession) -> Option<CachedResponse<C>> {
+        self.sessions
+            .get(&session.client_id)
+            .and_then(|s| s.responses.get(&session.seq))
+            .and_then(Clone::clone)
+    }
+
+    /// Get the sessions that have been idle for longer than `timeout` at `now`, they are
+    /// counted as active again so that they won't be expired twice
+    pub(super) fn take_idle(&mut self, now: Instant, timeout: Duration) -> Vec<u64> {
+        self.sessions
+            .iter_mut()
+            .filter(|&(_, ref s)| now.saturating_duration_since(s.last_active) >= timeout)
+            .map(|(&client_id, s)| {
+                s.last_active = now;
+                client_id
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::test_cmd::TestCommand;
+
+    fn session(client_id: u64, seq: u64, first_incomplete: u64) -> ClientSession {
+        ClientSession {
+            client_id,
+            seq,
+            first_incomplete,
+        }
+    }
+
+    #[allow(clippy::unwrap_used)]
+    #[test]
+    fn applied_cmd_will_get_cached_response() {
+        let mut table = SessionTable::<TestCommand>::new();
+        table.register(1);
+        assert!(table.check(session(1, 0, 0)).unwrap().is_none());
+
+        assert!(table.apply(session(1, 0, 0)));
+        assert!(matches!(
+            table.check(session(1, 0, 0)),
+            Err(ProposeError::Duplicated)
+        ));
+        // a retried cmd committed again won't be applied twice
+        assert!(!table.apply(session(1, 0, 0)));
+
+        table.record(session(1, 0, 0), (Ok(vec![]), Some(Ok(1))));
+        let (er, asr) = table.check(session(1, 0, 0)).unwrap().unwrap();
+        assert!(er.is_ok());
+        assert_eq!(asr.unwrap().unwrap(), 1);
+    }
+
+    #[test]
+    fn acked_responses_will_be_discarded() {
+        let mut table = SessionTable::<TestCommand>::new();
+        table.register(1);
+        assert!(table.apply(session(1, 0, 0)));
+        table.record(session(1, 0, 0), (Ok(vec![]), Some(Ok(1))));
+        assert!(table.apply(session(1, 1, 1)));
+
+        assert!(!table.is_applied(session(1, 0, 0)));
+        assert!(table.is_applied(session(1, 1, 1)));
+        assert!(matches!(
+            table.check(session(1, 0, 0)),
+            Err(ProposeError::Duplicated)
+        ));
+    }
+
+    #[test]
+    fn unknown_session_will_be_rejected() {
+        let mut table = SessionTable::<TestCommand>::new();
+        assert!(matches!(
+            table.check(session(1, 0, 0)),
+            Err(ProposeError::SessionExpired)
+        ));
+
+        table.register(1);
+        table.expire(&[1]);
+        assert!(matches!(
+            table.check(session(1, 0, 0)),
+            Err(ProposeError::SessionExpired)
+        ));
+    }
+
+    #[allow(clippy::unwrap_used)]
+    #[test]
+    fn rebuilt_session_will_reject_unknown_cmds() {
+        let mut table = SessionTable::<TestCommand>::new();
+        // self missed the registration and the cmds before seq 5
+        assert!(table.apply(session(1, 5, 3)));
+
+        assert!(matches!(
+            table.check(session(1, 4, 3)),
+            Err(ProposeError::SessionExpired)
+        ));
+        assert!(matches!(
+            table.check(session(1, 5, 3)),
+            Err(ProposeError::Duplicated)
+        ));
+        assert!(table.check(session(1, 6, 3)).unwrap().is_none());
+    }
+
+    #[test]
+    fn idle_sessions_will_be_taken_once() {
+        let mut table = SessionTable::<TestCommand>::new();
+        table.register(1);
+        let now = Instant::now();
+        assert!(table.take_idle(now, Duration::from_secs(1)).is_empty());
+
+        let later = now + Duration::from_secs(2);
+        assert_eq!(table.take_idle(later, Duration::from_secs(1)), vec![1]);
+        assert!(table.take_idle(later, Duration::from_secs(1)).is_empty());
+    }
+
+    #[allow(clippy::unwrap_used)]
+    #[test]
+    fn recovered_sessions_will_skip_applied_entries() {
+        let mut table = SessionTable::<TestCommand>::new();
+        assert!(table.advance(1));
+        table.register(1);
+        assert!(table.advance(2));
+        assert!(table.apply(session(1, 0, 0)));
+        table.record(session(1, 0, 0), (Ok(vec![]), Some(Ok(1))));
+
+        let mut recovered: SessionTable<TestCommand> =
+            bincode::deserialize(&bincode::serialize(&table).unwrap()).unwrap();
+        assert_eq!(recovered.applied_index(), 2);
+        assert!(!recovered.advance(2));
+        let (_, asr) = recovered.check(session(1, 0, 0)).unwrap().unwrap();
+        assert_eq!(asr.unwrap().unwrap(), 1);
+    }
+}
@@ -2,7 +2,14 @@ use async_trait::async_trait;
 use engine::{engine_api::SnapshotApi, error::EngineError};
 use thiserror::Error;
 
-use crate::{cmd::Command, log_entry::LogEntry, ServerId};
+use crate::{
+    cmd::{Command, ProposeId},
+    log_entry::LogEntry,
+    members::Membership,
+    server::session::SessionTable,
+    snapshot::SnapshotMeta,
+    ServerId,
+};
 
 /// Storage layer error
 #[derive(Error, Debug)]
@@ -13,8 +20,22 @@ pub(super) enum StorageError {
     /// Rocksdb error
     #[error("internal error, {0}")]
     Internal(#[from] EngineError),
+    /// IO error
+    #[error("io error, {0}")]
+    IoError(#[from] std::io::Error),
+    /// The persisted data is corrupted
+    #[error("corrupted data, {0}")]
+    Corrupted(String),
 }
 
+/// Data recovered from the persisted storage: `voted_for`, the membership, the log base and the log entries
+pub(super) type RecoverData<C> = (
+    Option<(u64, ServerId)>,
+    Option<Membership>,
+    Option<SnapshotMeta>,
+    Vec<LogEntry<C>>,
+);
+
 /// Curp storage api
 #[async_trait]
 pub(super) trait StorageApi: Send + Sync {
@@ -24,14 +45,47 @@ pub(super) trait StorageApi: Send + Sync {
     /// Put `voted_for` in storage, must be flushed on disk before returning
     async fn flush_voted_for(&self, term: u64, voted_for: ServerId) -> Result<(), StorageError>;
 
-    /// Put log entries in storage
-    async fn put_log_entry(&self, entry: LogEntry<Self::Command>) -> Result<(), StorageError>;
+    /// Put the membership in storage, must be flushed on disk before returning
+    async fn flush_membership(&self, membership: &Membership) -> Result<(), StorageError>;
 
-    /// Recover from persisted storage
-    /// Return `voted_for` and all log entries
-    async fn recover(
+    /// Put the client sessions in storage, must be flushed on disk before returning. They are
+    /// flushed before the log is compacted, since the compacted entries can't rebuild them
+    async fn flush_sessions(
+        &self,
+        sessions: &SessionTable<Self::Command>,
+    ) -> Result<(), StorageError>;
+
+    /// Put log entries in storage, they must be flushed on disk together before returning. The
+    /// stored entries after the first new one are replaced, so they must not be recovered
+    async fn put_log_entries(
         &self,
-    ) -> Result<(Option<(u64, ServerId)>, Vec<LogEntry<Self::Command>>), StorageError>;
+        entries: &[LogEntry<Self::Command>],
+    ) -> Result<(), StorageError>;
+
+    /// Compact the log entries up to `meta.last_included_index`(inclusive), the last
+    /// compacted entry will be recorded as the log base. The `synced` commands of the compacted
+    /// entries are removed from the speculative pool, and the removal must be flushed on disk
+    /// before returning, or they would be replayed and executed again on recovery since their
+    /// entries are gone
+    async fn compact(&self, meta: SnapshotMeta, synced: &[ProposeId]) -> Result<(), StorageError>;
+
+    /// Recover from persisted storage
+    /// Return `voted_for`, the membership, the log base and all log entries after it
+    async fn recover(&self) -> Result<RecoverData<Self::Command>, StorageError>;
+
+    /// Put a command of the speculative pool in storage, must be flushed on disk before returning
+    async fn put_spec_pool_cmd(&self, cmd: &Self::Command) -> Result<(), StorageError>;
+
+    /// Remove the commands of the speculative pool from storage, the removal needs not to be
+    /// flushed on disk: a lost removal will be retried, since a replayed command is removed
+    /// again once it leaves the speculative pool
+    async fn remove_spec_pool_cmds(&self, ids: &[ProposeId]) -> Result<(), StorageError>;
+
+    /// Get all persisted commands of the speculative pool
+    async fn spec_pool_cmds(&self) -> Result<Vec<Self::Command>, StorageError>;
+
+    /// Get the persisted client sessions
+    async fn sessions(&self) -> Result<Option<SessionTable<Self::Command>>, StorageError>;
 
     /// Initialize a new snapshot
     async fn new_snapshot(&self) -> Result<Box<dyn SnapshotApi>, StorageError>;
@@ -39,3 +93,6 @@ pub(super) trait StorageApi: Send + Sync {
 
 /// `RocksDB` storage implementation
 pub(super) mod rocksdb;
+
+/// Segmented WAL storage implementation
+pub(super) mod wal;
@@ -10,23 +10,46 @@ use engine::{
     rocksdb_engine::{RocksEngine, RocksSnapshot},
     StorageEngine, WriteOperation,
 };
+use parking_lot::Mutex;
 use uuid::Uuid;
 
-use super::{StorageApi, StorageError};
-use crate::{cmd::Command, log_entry::LogEntry, ServerId};
+use super::{RecoverData, StorageApi, StorageError};
+use crate::{
+    cmd::{Command, ProposeId},
+    log_entry::LogEntry,
+    members::Membership,
+    server::session::SessionTable,
+    snapshot::SnapshotMeta,
+    LogIndex, ServerId,
+};
 
 /// Key for persisted state
 const VOTE_FOR: &[u8] = b"VoteFor";
 
+/// Key for the last compacted log index and term
+const LOG_BASE: &[u8] = b"LogBase";
+
+/// Key for the cluster membership
+const MEMBERS: &[u8] = b"Members";
+
+/// Key for the client sessions
+const SESSIONS: &[u8] = b"Session";
+
 /// Column family name for curp storage
 const CF: &str = "curp";
 
+/// Column family name for the speculative pool, the commands are keyed by their ids
+const SP_CF: &str = "curp_spec_pool";
+
 /// `RocksDB` storage implementation
 pub(in crate::server) struct RocksDBStorage<C> {
     /// DB handle
     db: RocksEngine,
     /// Storage Path
     data_dir: PathBuf,
+    /// Index of the last log entry in storage, the entries after a new one are replaced by the
+    /// new ones, so they are removed along with it. It's known once the log is recovered
+    last_index: Mutex<LogIndex>,
     /// Phantom
     phantom: PhantomData<C>,
 }
@@ -44,41 +67,176 @@ impl<C: 'static + Command> StorageApi for RocksDBStorage<C> {
         Ok(())
     }
 
-    async fn put_log_entry(&self, entry: LogEntry<Self::Command>) -> Result<(), StorageError> {
-        let bytes = bincode::serialize(&entry)?;
-        let op = WriteOperation::new_put(CF, entry.index.to_be_bytes().to_vec(), bytes);
-        self.db.write_batch(vec![op], false)?;
+    async fn flush_membership(&self, membership: &Membership) -> Result<(), StorageError> {
+        let bytes = bincode::serialize(membership)?;
+        let op = WriteOperation::new_put(CF, MEMBERS.to_vec(), bytes);
+        self.db.write_batch(vec![op], true)?;
 
         Ok(())
     }
 
-    async fn recover(
+    async fn flush_sessions(
         &self,
-    ) -> Result<(Option<(u64, ServerId)>, Vec<LogEntry<Self::Command>>), StorageError> {
+        sessions: &SessionTable<Self::Command>,
+    ) -> Result<(), StorageError> {
+        let bytes = bincode::serialize(sessions)?;
+        let op = WriteOperation::new_put(CF, SESSIONS.to_vec(), bytes);
+        self.db.write_batch(vec![op], true)?;
+
+        Ok(())
+    }
+
+    async fn put_log_entries(
+        &self,
+        entries: &[LogEntry<Self::Command>],
+    ) -> Result<(), StorageError> {
+        let (Some(first), Some(last)) = (entries.first(), entries.last()) else {
+            return Ok(());
+        };
+        let mut ops = vec![];
+        let mut last_index = self.last_index.lock();
+        // the entries conflicting with the new ones are truncated from the log, the stale
+        // ones after the new entries must not be recovered
+        if first.index <= *last_index {
+            #[allow(clippy::integer_arithmetic)] // won't overflow
+            let to = (*last_index + 1).to_be_bytes();
+            ops.push(WriteOperation::new_delete_range(
+                CF,
+                &first.index.to_be_bytes(),
+                &to,
+            ));
+        }
+        for entry in entries {
+            let bytes = bincode::serialize(entry)?;
+            ops.push(WriteOperation::new_put(
+                CF,
+                entry.index.to_be_bytes().to_vec(),
+                bytes,
+            ));
+        }
+        self.db.write_batch(ops, true)?;
+        *last_index = last.index;
+
+        Ok(())
+    }
+
+    async fn compact(&self, meta: SnapshotMeta, synced: &[ProposeId]) -> Result<(), StorageError> {
+        let base = bincode::serialize(&(meta.last_included_index, meta.last_included_term))?;
+        let from = LogIndex::MIN.to_be_bytes();
+        #[allow(clippy::integer_arithmetic)] // won't overflow
+        let to = (meta.last_included_index + 1).to_be_bytes();
+        let keys = synced
+            .iter()
+            .map(bincode::serialize)
+            .collect::<Result<Vec<_>, _>>()?;
+        let mut ops = vec![
+            WriteOperation::new_put(CF, LOG_BASE.to_vec(), base),
+            WriteOperation::new_delete_range(CF, &from, &to),
+        ];
+        ops.extend(
+            keys.iter()
+                .map(|key| WriteOperation::new_delete(SP_CF, key)),
+        );
+        self.db.write_batch(ops, true)?;
+
+        Ok(())
+    }
+
+    async fn recover(&self) -> Result<RecoverData<Self::Command>, StorageError> {
         let voted_for = self
             .db
             .get(CF, VOTE_FOR)?
             .map(|bytes| bincode::deserialize::<(u64, ServerId)>(&bytes))
             .transpose()?;
+        let membership = self
+            .db
+            .get(CF, MEMBERS)?
+            .map(|bytes| bincode::deserialize::<Membership>(&bytes))
+            .transpose()?;
+        let log_base = self
+            .db
+            .get(CF, LOG_BASE)?
+            .map(|bytes| bincode::deserialize::<(LogIndex, u64)>(&bytes))
+            .transpose()?
+            .map(|(last_included_index, last_included_term)| SnapshotMeta {
+                last_included_index,
+                last_included_term,
+            });
 
         let mut entries = vec![];
-        let mut prev_index = 0;
+        let base_index = log_base.map_or(0, |meta| meta.last_included_index);
+        let mut prev_index = base_index;
+        let mut consistent = true;
+        let mut last_index = self.last_index.lock();
         for (k, v) in self.db.get_all(CF)? {
             // we can identify whether a kv is state or entry by the key length
-            if k.len() == VOTE_FOR.len() {
+            if k.len() == VOTE_FOR.len()
+                || k.len() == LOG_BASE.len()
+                || k.len() == MEMBERS.len()
+                || k.len() == SESSIONS.len()
+            {
+                continue;
+            }
+            let Ok(key) = <[u8; 8]>::try_from(k.as_slice()) else {
+                continue;
+            };
+            // keys are sorted, the entries not recovered are still removed once replaced
+            let index = LogIndex::from_be_bytes(key);
+            *last_index = index;
+            if index <= base_index || !consistent {
+                // the entry has been compacted, or it's after a gap
                 continue;
             }
-            let entry: LogEntry<C> = bincode::deserialize(&v)?;
             #[allow(clippy::integer_arithmetic)] // won't overflow
-            if entry.index != prev_index + 1 {
-                // break when logs are no longer consistent
-                break;
+            if index != prev_index + 1 {
+                // stop recovering when logs are no longer consistent
+                consistent = false;
+                continue;
             }
+            let entry: LogEntry<C> = bincode::deserialize(&v)?;
             prev_index = entry.index;
             entries.push(entry);
         }
 
-        Ok((voted_for, entries))
+        Ok((voted_for, membership, log_base, entries))
+    }
+
+    async fn put_spec_pool_cmd(&self, cmd: &Self::Command) -> Result<(), StorageError> {
+        let key = bincode::serialize(cmd.id())?;
+        let bytes = bincode::serialize(cmd)?;
+        let op = WriteOperation::new_put(SP_CF, key, bytes);
+        self.db.write_batch(vec![op], true)?;
+
+        Ok(())
+    }
+
+    async fn remove_spec_pool_cmds(&self, ids: &[ProposeId]) -> Result<(), StorageError> {
+        let keys = ids
+            .iter()
+            .map(bincode::serialize)
+            .collect::<Result<Vec<_>, _>>()?;
+        let ops = keys
+            .iter()
+            .map(|key| WriteOperation::new_delete(SP_CF, key))
+            .collect();
+        self.db.write_batch(ops, false)?;
+
+        Ok(())
+    }
+
+    async fn spec_pool_cmds(&self) -> Result<Vec<Self::Command>, StorageError> {
+        self.db
+            .get_all(SP_CF)?
+            .into_iter()
+            .map(|(_, v)| bincode::deserialize(&v).map_err(Into::into))
+            .collect()
+    }
+
+    async fn sessions(&self) -> Result<Option<SessionTable<Self::Command>>, StorageError> {
+        self.db
+            .get(CF, SESSIONS)?
+            .map(|bytes| bincode::deserialize(&bytes).map_err(Into::into))
+            .transpose()
     }
 
     async fn new_snapshot(&self) -> Result<Box<dyn SnapshotApi>, StorageError> {
@@ -95,10 +253,11 @@ impl<C: 'static + Command> StorageApi for RocksDBStorage<C> {
 impl<C> RocksDBStorage<C> {
     /// Create a new `RocksDBStorage`
     pub(in crate::server) fn new(dir: impl AsRef<Path>) -> Result<Self, StorageError> {
-        let db = RocksEngine::new(dir.as_ref(), &[CF])?;
+        let db = RocksEngine::new(dir.as_ref(), &[CF, SP_CF])?;
         Ok(Self {
             db,
             data_dir: dir.as_ref().into(),
+            last_index: Mutex::new(0),
             phantom: PhantomData,
         })
     }
@@ -106,7 +265,7 @@ impl<C> RocksDBStorage<C> {
 
 #[cfg(test)]
 mod tests {
-    use std::{error::Error, sync::Arc};
+    use std::{collections::HashMap, error::Error, sync::Arc};
 
     use tokio::fs::remove_dir_all;
 
@@ -121,19 +280,28 @@ mod tests {
             let s = RocksDBStorage::<TestCommand>::new(&db_dir)?;
             s.flush_voted_for(1, "S2".to_string()).await?;
             s.flush_voted_for(3, "S1".to_string()).await?;
+            s.flush_membership(&Membership::new(
+                &"S1".to_owned(),
+                HashMap::from([("S2".to_owned(), "127.0.0.1:2".to_owned())]),
+                false,
+            ))
+            .await?;
             let entry0 = LogEntry::new(1, 3, Arc::new(TestCommand::default()));
             let entry1 = LogEntry::new(2, 3, Arc::new(TestCommand::default()));
             let entry2 = LogEntry::new(3, 3, Arc::new(TestCommand::default()));
-            s.put_log_entry(entry0).await?;
-            s.put_log_entry(entry1).await?;
-            s.put_log_entry(entry2).await?;
+            s.put_log_entries(&[entry0]).await?;
+            s.put_log_entries(&[entry1, entry2]).await?;
             sleep_secs(2).await;
         }
 
         {
             let s = RocksDBStorage::<TestCommand>::new(&db_dir)?;
-            let (voted_for, entries) = s.recover().await?;
+            let (voted_for, membership, log_base, entries) = s.recover().await?;
             assert_eq!(voted_for, Some((3, "S1".to_string())));
+            let membership = membership.unwrap();
+            assert!(membership.is_voter(&"S1".to_owned()));
+            assert!(membership.is_voter(&"S2".to_owned()));
+            assert!(log_base.is_none());
             assert_eq!(entries[0].index, 1);
             assert_eq!(entries[1].index, 2);
             assert_eq!(entries[2].index, 3);
@@ -143,4 +311,116 @@ mod tests {
 
         Ok(())
     }
+
+    #[tokio::test]
+    async fn compact_and_recover() -> Result<(), Box<dyn Error>> {
+        let db_dir = format!("/tmp/curp-{}", random_id());
+
+        {
+            let s = RocksDBStorage::<TestCommand>::new(&db_dir)?;
+            s.flush_voted_for(1, "S1".to_string()).await?;
+            let entries = (1..=5)
+                .map(|index| LogEntry::new(index, 1, Arc::new(TestCommand::default())))
+                .collect::<Vec<_>>();
+            s.put_log_entries(&entries).await?;
+            s.compact(
+                SnapshotMeta {
+                    last_included_index: 3,
+                    last_included_term: 1,
+                },
+                &[],
+            )
+            .await?;
+            sleep_secs(2).await;
+        }
+
+        {
+            let s = RocksDBStorage::<TestCommand>::new(&db_dir)?;
+            let (voted_for, membership, log_base, entries) = s.recover().await?;
+            assert_eq!(voted_for, Some((1, "S1".to_string())));
+            assert!(membership.is_none());
+            let log_base = log_base.unwrap();
+            assert_eq!(log_base.last_included_index, 3);
+            assert_eq!(log_base.last_included_term, 1);
+            assert_eq!(entries.len(), 2);
+            assert_eq!(entries[0].index, 4);
+            assert_eq!(entries[1].index, 5);
+        }
+
+        remove_dir_all(db_dir).await?;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn stale_entries_after_the_conflicting_ones_will_not_be_recovered(
+    ) -> Result<(), Box<dyn Error>> {
+        let db_dir = format!("/tmp/curp-{}", random_id());
+        let terms = |entries: Vec<LogEntry<TestCommand>>| {
+            entries
+                .into_iter()
+                .map(|e| (e.index, e.term))
+                .collect::<Vec<_>>()
+        };
+
+        {
+            let s = RocksDBStorage::<TestCommand>::new(&db_dir)?;
+            let entries = (1..=7)
+                .map(|index| LogEntry::new(index, 1, Arc::new(TestCommand::default())))
+                .collect::<Vec<_>>();
+            s.put_log_entries(&entries).await?;
+            // the follower's log conflicts with the new leader's from index 3
+            let entries = (3..=5)
+                .map(|index| LogEntry::new(index, 2, Arc::new(TestCommand::default())))
+                .collect::<Vec<_>>();
+            s.put_log_entries(&entries).await?;
+        }
+
+        {
+            let s = RocksDBStorage::<TestCommand>::new(&db_dir)?;
+            let (_, _, _, entries) = s.recover().await?;
+            // the stale log[6] and log[7] are removed along with the conflicting ones
+            assert_eq!(terms(entries), vec![(1, 1), (2, 1), (3, 2), (4, 2), (5, 2)]);
+            // it conflicts again after a restart
+            let entry = LogEntry::new(4, 3, Arc::new(TestCommand::default()));
+            s.put_log_entries(&[entry]).await?;
+        }
+
+        {
+            let s = RocksDBStorage::<TestCommand>::new(&db_dir)?;
+            let (_, _, _, entries) = s.recover().await?;
+            assert_eq!(terms(entries), vec![(1, 1), (2, 1), (3, 2), (4, 3)]);
+        }
+
+        remove_dir_all(db_dir).await?;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn spec_pool_cmds_can_be_recovered() -> Result<(), Box<dyn Error>> {
+        let db_dir = format!("/tmp/curp-{}", random_id());
+        let cmd1 = TestCommand::new_put(vec![1], 1);
+        let cmd2 = TestCommand::new_put(vec![2], 2);
+
+        {
+            let s = RocksDBStorage::<TestCommand>::new(&db_dir)?;
+            s.put_spec_pool_cmd(&cmd1).await?;
+            s.put_spec_pool_cmd(&cmd2).await?;
+            s.remove_spec_pool_cmds(&[cmd1.id().clone()]).await?;
+            sleep_secs(2).await;
+        }
+
+        {
+            let s = RocksDBStorage::<TestCommand>::new(&db_dir)?;
+            assert_eq!(s.spec_pool_cmds().await?, vec![cmd2]);
+            // the spec pool cmds are not recovered as log entries
+            let (_, _, _, entries) = s.recover().await?;
+            assert!(entries.is_empty());
+        }
+
+        remove_dir_all(db_dir).await?;
+
+        Ok(())
+    }
 }
@@ -0,0 +1,771 @@
+use std::{
+    collections::VecDeque,
+    fs::{self, File, OpenOptions},
+    io::Write,
+    marker::PhantomData,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
+
+use async_trait::async_trait;
+use chrono::Local;
+use clippy_utilities::NumericCast;
+use engine::{engine_api::SnapshotApi, rocksdb_engine::RocksSnapshot};
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use tracing::{debug, warn};
+use uuid::Uuid;
+
+use self::{
+    segment::{decode_records, encode_record, sync_dir, Segment},
+    spec_pool::SpecPoolFile,
+};
+use super::{RecoverData, StorageApi, StorageError};
+use crate::{
+    cmd::{Command, ProposeId},
+    log_entry::LogEntry,
+    members::Membership,
+    server::session::SessionTable,
+    snapshot::SnapshotMeta,
+    LogIndex, ServerId,
+};
+
+/// WAL segment file format
+mod segment;
+
+/// Persisted speculative pool
+mod spec_pool;
+
+/// Directory of the WAL files, it's under the curp data dir
+const WAL_DIR: &str = "wal";
+
+/// Name of the metadata file
+const META_FILE: &str = "meta";
+
+/// Name of the temporary metadata file, it's renamed to `META_FILE` once it's fsynced
+const META_TMP_FILE: &str = "meta.tmp";
+
+/// Persisted states other than the log entries, they are small and rarely changed, so the
+/// metadata file is rewritten atomically on every change
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Meta {
+    /// The term and the candidate that received vote in it
+    voted_for: Option<(u64, ServerId)>,
+    /// Cluster membership
+    membership: Option<Membership>,
+    /// The last compacted log index and term
+    log_base: Option<(LogIndex, u64)>,
+    /// Serialized client sessions, they are flushed before the log is compacted
+    sessions: Option<Vec<u8>>,
+}
+
+/// The segment that records are appended to
+#[derive(Debug)]
+struct ActiveSegment {
+    /// The segment
+    segment: Segment,
+    /// Segment file, it's shared with the writer that fsyncs it
+    file: Arc<File>,
+    /// Size of the segment file
+    size: u64,
+}
+
+/// Appends records to the segments
+#[derive(Debug)]
+struct WalWriter {
+    /// Sealed segments, ordered by the sequence number, they have been fsynced
+    sealed: VecDeque<Segment>,
+    /// The active segment, it's created when the first record is appended
+    active: Option<ActiveSegment>,
+    /// Total bytes appended since the WAL is opened, used to track what has been fsynced
+    written: u64,
+}
+
+/// Segmented WAL storage implementation
+///
+/// Log entries are appended to segment files as records of `length | crc32 | payload`,
+/// and a new segment is created once the active one grows beyond `segment_size`. A torn
+/// record at the tail of the last segment is truncated on recovery, and the segments
+/// holding only compacted entries are removed. The speculative pool is persisted in a
+/// separate file, since its commands are removed out of order.
+pub(in crate::server) struct WalStorage<C> {
+    /// Curp storage path, snapshots are received here
+    data_dir: PathBuf,
+    /// Directory of the segments and the metadata file
+    wal_dir: PathBuf,
+    /// The max size of a segment
+    segment_size: u64,
+    /// Segments writer
+    writer: Mutex<WalWriter>,
+    /// Total bytes that have been fsynced, it lags behind `WalWriter::written`
+    synced: AtomicU64,
+    /// Only one writer fsyncs at a time, the others wait for it and usually find that their
+    /// records have been fsynced together, so concurrent writes share a single fsync
+    sync_lock: tokio::sync::Mutex<()>,
+    /// Persisted states other than the log entries
+    meta: Mutex<Meta>,
+    /// Persisted speculative pool
+    spec_pool: Mutex<SpecPoolFile>,
+    /// Phantom
+    phantom: PhantomData<C>,
+}
+
+#[async_trait]
+impl<C: 'static + Command> StorageApi for WalStorage<C> {
+    /// Command
+    type Command = C;
+
+    async fn flush_voted_for(&self, term: u64, voted_for: ServerId) -> Result<(), StorageError> {
+        let mut meta = self.meta.lock();
+        meta.voted_for = Some((term, voted_for));
+        self.write_meta(&meta)
+    }
+
+    async fn flush_membership(&self, membership: &Membership) -> Result<(), StorageError> {
+        let mut meta = self.meta.lock();
+        meta.membership = Some(membership.clone());
+        self.write_meta(&meta)
+    }
+
+    async fn flush_sessions(
+        &self,
+        sessions: &SessionTable<Self::Command>,
+    ) -> Result<(), StorageError> {
+        let bytes = bincode::serialize(sessions)?;
+        let mut meta = self.meta.lock();
+        meta.sessions = Some(bytes);
+        self.write_meta(&meta)
+    }
+
+    async fn put_log_entries(
+        &self,
+        entries: &[LogEntry<Self::Command>],
+    ) -> Result<(), StorageError> {
+        let records = entries
+            .iter()
+            .map(|entry| {
+                let mut record = vec![];
+                encode_record(&bincode::serialize(entry)?, &mut record)?;
+                Ok((entry.index, record))
+            })
+            .collect::<Result<Vec<_>, StorageError>>()?;
+        let written = {
+            let mut writer = self.writer.lock();
+            let mut written = writer.written;
+            for &(index, ref record) in &records {
+                written = writer.append(&self.wal_dir, self.segment_size, index, record)?;
+            }
+            written
+        };
+        self.sync(written).await
+    }
+
+    async fn compact(&self, meta: SnapshotMeta, synced: &[ProposeId]) -> Result<(), StorageError> {
+        {
+            let mut spec_pool = self.spec_pool.lock();
+            spec_pool.remove(synced)?;
+            spec_pool.sync()?;
+        }
+        {
+            let mut meta_l = self.meta.lock();
+            meta_l.log_base = Some((meta.last_included_index, meta.last_included_term));
+            self.write_meta(&meta_l)?;
+        }
+
+        // the log base is durable, the compacted entries will be skipped on recovery even
+        // if they are not removed
+        let mut writer = self.writer.lock();
+        let mut removed = false;
+        loop {
+            let next_first_index = writer
+                .sealed
+                .get(1)
+                .map(|s| s.first_index)
+                .or_else(|| writer.active.as_ref().map(|a| a.segment.first_index));
+            // a segment only holds live entries before the first entry of the next segment, the
+            // others have been overwritten by the latter, though a segment may also hold rewritten
+            // entries before its own first entry
+            #[allow(clippy::integer_arithmetic)] // won't overflow
+            let compacted =
+                next_first_index.map_or(false, |index| index <= meta.last_included_index + 1);
+            if !compacted {
+                break;
+            }
+            let Some(segment) = writer.sealed.pop_front() else {
+                break;
+            };
+            fs::remove_file(&segment.path)?;
+            debug!("WAL segment {} is removed", segment.path.display());
+            removed = true;
+        }
+        if removed {
+            sync_dir(&self.wal_dir)?;
+        }
+
+        Ok(())
+    }
+
+    async fn recover(&self) -> Result<RecoverData<Self::Command>, StorageError> {
+        let (voted_for, membership, log_base) = {
+            let meta = self.meta.lock();
+            (
+                meta.voted_for.clone(),
+                meta.membership.clone(),
+                meta.log_base,
+            )
+        };
+        let log_base = log_base.map(|(last_included_index, last_included_term)| SnapshotMeta {
+            last_included_index,
+            last_included_term,
+        });
+
+        let base_index = log_base.map_or(0, |meta| meta.last_included_index);
+        let mut entries: Vec<LogEntry<C>> = vec![];
+        let segments = Segment::list(&self.wal_dir)?;
+        let last_seq = segments.last().map(|s| s.seq);
+        for segment in segments {
+            let buf = fs::read(&segment.path)?;
+            let (payloads, valid_len) = decode_records(&buf);
+            // the torn tail of the last segment has been truncated when the WAL is opened
+            if valid_len != buf.len() && Some(segment.seq) != last_seq {
+                return Err(StorageError::Corrupted(format!(
+                    "WAL segment {} is corrupted at offset {valid_len}",
+                    segment.path.display()
+                )));
+            }
+            for payload in payloads {
+                let entry: LogEntry<C> = bincode::deserialize(payload)?;
+                // a rewritten entry replaces the conflicting entries after it, even if it has
+                // been compacted itself
+                while entries.last().map_or(false, |e| e.index >= entry.index) {
+                    let _ig = entries.pop();
+                }
+                if entry.index <= base_index {
+                    // the entry has been compacted
+                    continue;
+                }
+                entries.push(entry);
+            }
+        }
+
+        let mut prev_index = base_index;
+        let entries = entries
+            .into_iter()
+            .take_while(|entry| {
+                // break when logs are no longer consistent
+                #[allow(clippy::integer_arithmetic)] // won't overflow
+                let consistent = entry.index == prev_index + 1;
+                prev_index = entry.index;
+                consistent
+            })
+            .collect();
+
+        Ok((voted_for, membership, log_base, entries))
+    }
+
+    async fn put_spec_pool_cmd(&self, cmd: &Self::Command) -> Result<(), StorageError> {
+        let bytes = bincode::serialize(cmd)?;
+        self.spec_pool.lock().put(cmd.id().clone(), bytes)
+    }
+
+    async fn remove_spec_pool_cmds(&self, ids: &[ProposeId]) -> Result<(), StorageError> {
+        self.spec_pool.lock().remove(ids)
+    }
+
+    async fn spec_pool_cmds(&self) -> Result<Vec<Self::Command>, StorageError> {
+        self.spec_pool
+            .lock()
+            .cmds()
+            .map(|bytes| bincode::deserialize(bytes).map_err(Into::into))
+            .collect()
+    }
+
+    async fn sessions(&self) -> Result<Option<SessionTable<Self::Command>>, StorageError> {
+        self.meta
+            .lock()
+            .sessions
+            .as_ref()
+            .map(|bytes| bincode::deserialize(bytes).map_err(Into::into))
+            .transpose()
+    }
+
+    async fn new_snapshot(&self) -> Result<Box<dyn SnapshotApi>, StorageError> {
+        let dir = self
+            .data_dir
+            .join(format!("snapshot-{}-{}", Local::now(), Uuid::new_v4()));
+        let snapshot = RocksSnapshot::new_for_receiving(dir)?;
+        Ok(Box::new(snapshot))
+    }
+}
+
+impl<C> WalStorage<C> {
+    /// Open the `WalStorage` in `dir`, the torn tail of the last segment will be truncated
+    pub(in crate::server) fn new(
+        dir: impl AsRef<Path>,
+        segment_size: u64,
+    ) -> Result<Self, StorageError> {
+        let data_dir = dir.as_ref().to_path_buf();
+        let wal_dir = data_dir.join(WAL_DIR);
+        fs::create_dir_all(&wal_dir)?;
+        let meta = match fs::read(wal_dir.join(META_FILE)) {
+            Ok(buf) => {
+                let (payloads, _) = decode_records(&buf);
+                let Some(payload) = payloads.first() else {
+                    return Err(StorageError::Corrupted("WAL metadata file is corrupted".to_owned()));
+                };
+                bincode::deserialize(payload)?
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Meta::default(),
+            Err(e) => return Err(e.into()),
+        };
+        let writer = WalWriter::open(&wal_dir)?;
+        let spec_pool = SpecPoolFile::open(&wal_dir)?;
+
+        Ok(Self {
+            data_dir,
+            wal_dir,
+            segment_size,
+            writer: Mutex::new(writer),
+            synced: AtomicU64::new(0),
+            sync_lock: tokio::sync::Mutex::new(()),
+            meta: Mutex::new(meta),
+            spec_pool: Mutex::new(spec_pool),
+            phantom: PhantomData,
+        })
+    }
+
+    /// Rewrite the metadata file atomically
+    fn write_meta(&self, meta: &Meta) -> Result<(), StorageError> {
+        let mut buf = vec![];
+        encode_record(&bincode::serialize(meta)?, &mut buf)?;
+        let tmp_path = self.wal_dir.join(META_TMP_FILE);
+        let mut file = File::create(&tmp_path)?;
+        file.write_all(&buf)?;
+        file.sync_all()?;
+        fs::rename(tmp_path, self.wal_dir.join(META_FILE))?;
+        sync_dir(&self.wal_dir)?;
+        Ok(())
+    }
+
+    /// Wait until the records appended before `written` are fsynced
+    async fn sync(&self, written: u64) -> Result<(), StorageError> {
+        let _guard = self.sync_lock.lock().await;
+        if self.synced.load(Ordering::Acquire) >= written {
+            // the record has been fsynced by another writer
+            return Ok(());
+        }
+        // fsync all records appended so far, including the ones of the concurrent writers
+        let (file, written) = {
+            let writer = self.writer.lock();
+            (
+                writer.active.as_ref().map(|a| Arc::clone(&a.file)),
+                writer.written,
+            )
+        };
+        if let Some(file) = file {
+            file.sync_data()?;
+        }
+        let _ig = self.synced.fetch_max(written, Ordering::AcqRel);
+        Ok(())
+    }
+}
+
+impl WalWriter {
+    /// Open the segments in `wal_dir`, the last one becomes the active segment
+    #[allow(clippy::integer_arithmetic)] // won't overflow
+    fn open(wal_dir: &Path) -> Result<Self, StorageError> {
+        let mut sealed: VecDeque<_> = Segment::list(wal_dir)?.into();
+        let Some(last) = sealed.pop_back() else {
+            return Ok(Self {
+                sealed,
+                active: None,
+                written: 0,
+            });
+        };
+
+        // the records of the last segment may be torn by a crash, the segments before it
+        // were fsynced before it was created
+        let buf = fs::read(&last.path)?;
+        let (_, valid_len) = decode_records(&buf);
+        let file = OpenOptions::new().append(true).open(&last.path)?;
+        if valid_len != buf.len() {
+            warn!(
+                "truncate the torn tail of WAL segment {} from offset {valid_len}, {} bytes are discarded",
+                last.path.display(),
+                buf.len() - valid_len
+            );
+            file.set_len(valid_len.numeric_cast())?;
+            file.sync_all()?;
+        }
+
+        Ok(Self {
+            sealed,
+            active: Some(ActiveSegment {
+                segment: last,
+                file: Arc::new(file),
+                size: valid_len.numeric_cast(),
+            }),
+            written: 0,
+        })
+    }
+
+    /// Append a record of the entry at `index`, a new segment is created if the active one is full.
+    /// Return the total bytes appended after it
+    #[allow(clippy::integer_arithmetic)] // won't overflow
+    fn append(
+        &mut self,
+        wal_dir: &Path,
+        segment_size: u64,
+        index: LogIndex,
+        record: &[u8],
+    ) -> Result<u64, StorageError> {
+        let record_len = record.len().numeric_cast::<u64>();
+        let full = self
+            .active
+            .as_ref()
+            .map_or(true, |a| a.size > 0 && a.size + record_len > segment_size);
+        if full {
+            self.rotate(wal_dir, index)?;
+        }
+        let Some(active) = self.active.as_mut() else {
+            unreachable!("the active segment should be created");
+        };
+
+        if let Err(e) = (&*active.file).write_all(record) {
+            // remove the partially written record, or the records after it will be discarded on recovery
+            if let Err(err) = active.file.set_len(active.size) {
+                warn!("failed to truncate the partially written WAL record, {err}");
+            }
+            return Err(e.into());
+        }
+        active.size += record_len;
+        self.written += record_len;
+        Ok(self.written)
+    }
+
+    /// Seal the active segment and create a new one starting from `first_index`
+    #[allow(clippy::integer_arithmetic)] // won't overflow
+    fn rotate(&mut self, wal_dir: &Path, first_index: LogIndex) -> Result<(), StorageError> {
+        let seq = match self.active.take() {
+            Some(active) => {
+                // the sealed segments must be durable, only the tail of the last one may be torn
+                active.file.sync_data()?;
+                let seq = active.segment.seq + 1;
+                self.sealed.push_back(active.segment);
+                seq
+            }
+            None => 0,
+        };
+        let segment = Segment::new(wal_dir, seq, first_index);
+        let file = OpenOptions::new()
+            .append(true)
+            .create_new(true)
+            .open(&segment.path)?;
+        sync_dir(wal_dir)?;
+        debug!("WAL segment {} is created", segment.path.display());
+        self.active = Some(ActiveSegment {
+            segment,
+            file: Arc::new(file),
+            size: 0,
+        });
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, error::Error, sync::Arc};
+
+    use tokio::fs::remove_dir_all;
+
+    use super::*;
+    use crate::test_utils::{random_id, test_cmd::TestCommand};
+
+    fn segment_count(db_dir: &str) -> usize {
+        Segment::list(&Path::new(db_dir).join(WAL_DIR))
+            .unwrap()
+            .len()
+    }
+
+    #[tokio::test]
+    async fn create_and_recover() -> Result<(), Box<dyn Error>> {
+        let db_dir = format!("/tmp/curp-{}", random_id());
+
+        {
+            let s = WalStorage::<TestCommand>::new(&db_dir, 1024)?;
+            s.flush_voted_for(1, "S2".to_string()).await?;
+            s.flush_voted_for(3, "S1".to_string()).await?;
+            s.flush_membership(&Membership::new(
+                &"S1".to_owned(),
+                HashMap::from([("S2".to_owned(), "127.0.0.1:2".to_owned())]),
+                false,
+            ))
+            .await?;
+            for index in 1..=3 {
+                let entry = LogEntry::new(index, 3, Arc::new(TestCommand::default()));
+                s.put_log_entries(&[entry]).await?;
+            }
+        }
+
+        {
+            let s = WalStorage::<TestCommand>::new(&db_dir, 1024)?;
+            let (voted_for, membership, log_base, entries) = s.recover().await?;
+            assert_eq!(voted_for, Some((3, "S1".to_string())));
+            let membership = membership.unwrap();
+            assert!(membership.is_voter(&"S1".to_owned()));
+            assert!(membership.is_voter(&"S2".to_owned()));
+            assert!(log_base.is_none());
+            assert_eq!(entries.len(), 3);
+            assert_eq!(entries[0].index, 1);
+            assert_eq!(entries[1].index, 2);
+            assert_eq!(entries[2].index, 3);
+        }
+
+        remove_dir_all(db_dir).await?;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn rewritten_entries_will_replace_the_conflicting_ones() -> Result<(), Box<dyn Error>> {
+        let db_dir = format!("/tmp/curp-{}", random_id());
+
+        {
+            let s = WalStorage::<TestCommand>::new(&db_dir, 1024)?;
+            for index in 1..=5 {
+                let entry = LogEntry::new(index, 1, Arc::new(TestCommand::default()));
+                s.put_log_entries(&[entry]).await?;
+            }
+            // the follower's log conflicts with the new leader's from index 3
+            for index in 3..=4 {
+                let entry = LogEntry::new(index, 2, Arc::new(TestCommand::default()));
+                s.put_log_entries(&[entry]).await?;
+            }
+        }
+
+        {
+            let s = WalStorage::<TestCommand>::new(&db_dir, 1024)?;
+            let (_, _, _, entries) = s.recover().await?;
+            let entries = entries
+                .into_iter()
+                .map(|e| (e.index, e.term))
+                .collect::<Vec<_>>();
+            assert_eq!(entries, vec![(1, 1), (2, 1), (3, 2), (4, 2)]);
+        }
+
+        remove_dir_all(db_dir).await?;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn torn_tail_will_be_truncated_on_recovery() -> Result<(), Box<dyn Error>> {
+        let db_dir = format!("/tmp/curp-{}", random_id());
+
+        {
+            let s = WalStorage::<TestCommand>::new(&db_dir, 1024)?;
+            for index in 1..=3 {
+                let entry = LogEntry::new(index, 1, Arc::new(TestCommand::default()));
+                s.put_log_entries(&[entry]).await?;
+            }
+        }
+
+        // simulate a crash in the middle of appending the 4th record
+        let segments = Segment::list(&Path::new(&db_dir).join(WAL_DIR))?;
+        let last = segments.last().unwrap();
+        let mut record = vec![];
+        let entry = LogEntry::new(4, 1, Arc::new(TestCommand::default()));
+        encode_record(&bincode::serialize(&entry)?, &mut record)?;
+        let mut file = OpenOptions::new().append(true).open(&last.path)?;
+        file.write_all(&record[..record.len() - 1])?;
+
+        {
+            let s = WalStorage::<TestCommand>::new(&db_dir, 1024)?;
+            let (_, _, _, entries) = s.recover().await?;
+            assert_eq!(entries.len(), 3);
+            assert_eq!(entries[2].index, 3);
+            // the entries appended after the truncation can be recovered
+            let entry = LogEntry::new(4, 1, Arc::new(TestCommand::default()));
+            s.put_log_entries(&[entry]).await?;
+        }
+
+        {
+            let s = WalStorage::<TestCommand>::new(&db_dir, 1024)?;
+            let (_, _, _, entries) = s.recover().await?;
+            assert_eq!(entries.len(), 4);
+            assert_eq!(entries[3].index, 4);
+        }
+
+        remove_dir_all(db_dir).await?;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn compact_and_recover() -> Result<(), Box<dyn Error>> {
+        let db_dir = format!("/tmp/curp-{}", random_id());
+
+        {
+            // every segment holds only one entry
+            let s = WalStorage::<TestCommand>::new(&db_dir, 1)?;
+            s.flush_voted_for(1, "S1".to_string()).await?;
+            for index in 1..=5 {
+                let entry = LogEntry::new(index, 1, Arc::new(TestCommand::default()));
+                s.put_log_entries(&[entry]).await?;
+            }
+            assert_eq!(segment_count(&db_dir), 5);
+            s.compact(
+                SnapshotMeta {
+                    last_included_index: 3,
+                    last_included_term: 1,
+                },
+                &[],
+            )
+            .await?;
+            assert_eq!(segment_count(&db_dir), 2);
+        }
+
+        {
+            let s = WalStorage::<TestCommand>::new(&db_dir, 1)?;
+            let (voted_for, membership, log_base, entries) = s.recover().await?;
+            assert_eq!(voted_for, Some((1, "S1".to_string())));
+            assert!(membership.is_none());
+            let log_base = log_base.unwrap();
+            assert_eq!(log_base.last_included_index, 3);
+            assert_eq!(log_base.last_included_term, 1);
+            assert_eq!(entries.len(), 2);
+            assert_eq!(entries[0].index, 4);
+            assert_eq!(entries[1].index, 5);
+        }
+
+        remove_dir_all(db_dir).await?;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn compacted_rewrites_will_replace_the_conflicting_ones() -> Result<(), Box<dyn Error>> {
+        let db_dir = format!("/tmp/curp-{}", random_id());
+
+        {
+            // every segment holds only one entry
+            let s = WalStorage::<TestCommand>::new(&db_dir, 1)?;
+            for index in 1..=7 {
+                let entry = LogEntry::new(index, 1, Arc::new(TestCommand::default()));
+                s.put_log_entries(&[entry]).await?;
+            }
+            // the follower's log conflicts with the new leader's from index 3
+            for index in 3..=5 {
+                let entry = LogEntry::new(index, 2, Arc::new(TestCommand::default()));
+                s.put_log_entries(&[entry]).await?;
+            }
+            s.compact(
+                SnapshotMeta {
+                    last_included_index: 5,
+                    last_included_term: 2,
+                },
+                &[],
+            )
+            .await?;
+        }
+
+        {
+            let s = WalStorage::<TestCommand>::new(&db_dir, 1)?;
+            let (_, _, _, entries) = s.recover().await?;
+            // the stale log[6] and log[7] are overwritten by the compacted rewrites
+            assert!(entries.is_empty());
+            let entry = LogEntry::new(6, 2, Arc::new(TestCommand::default()));
+            s.put_log_entries(&[entry]).await?;
+        }
+
+        {
+            let s = WalStorage::<TestCommand>::new(&db_dir, 1)?;
+            let (_, _, log_base, entries) = s.recover().await?;
+            assert_eq!(log_base.unwrap().last_included_index, 5);
+            let entries = entries
+                .into_iter()
+                .map(|e| (e.index, e.term))
+                .collect::<Vec<_>>();
+            assert_eq!(entries, vec![(6, 2)]);
+        }
+
+        remove_dir_all(db_dir).await?;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn spec_pool_cmds_can_be_recovered() -> Result<(), Box<dyn Error>> {
+        let db_dir = format!("/tmp/curp-{}", random_id());
+        let cmds = (0..3)
+            .map(|i| TestCommand::new_put(vec![i], i))
+            .collect::<Vec<_>>();
+
+        {
+            let s = WalStorage::<TestCommand>::new(&db_dir, 1024)?;
+            for cmd in &cmds {
+                s.put_spec_pool_cmd(cmd).await?;
+            }
+            s.remove_spec_pool_cmds(&[cmds[0].id().clone()]).await?;
+        }
+
+        {
+            let s = WalStorage::<TestCommand>::new(&db_dir, 1024)?;
+            let mut recovered = s.spec_pool_cmds().await?;
+            recovered.sort_by_key(|cmd| cmd.keys().to_vec());
+            assert_eq!(recovered, cmds[1..]);
+            // the file is rewritten once most of its records are outdated
+            s.remove_spec_pool_cmds(&[cmds[1].id().clone()]).await?;
+        }
+
+        {
+            let s = WalStorage::<TestCommand>::new(&db_dir, 1024)?;
+            assert_eq!(s.spec_pool_cmds().await?, cmds[2..]);
+            let (_, _, _, entries) = s.recover().await?;
+            assert!(entries.is_empty());
+        }
+
+        remove_dir_all(db_dir).await?;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn compaction_removes_the_spec_pool_cmds_of_compacted_entries(
+    ) -> Result<(), Box<dyn Error>> {
+        let db_dir = format!("/tmp/curp-{}", random_id());
+        let cmds = (0..2)
+            .map(|i| Arc::new(TestCommand::new_put(vec![i], i)))
+            .collect::<Vec<_>>();
+
+        {
+            let s = WalStorage::<TestCommand>::new(&db_dir, 1024)?;
+            for cmd in &cmds {
+                s.put_spec_pool_cmd(cmd).await?;
+            }
+            let entry = LogEntry::new(1, 1, Arc::clone(&cmds[0]));
+            s.put_log_entries(&[entry]).await?;
+            s.compact(
+                SnapshotMeta {
+                    last_included_index: 1,
+                    last_included_term: 1,
+                },
+                &[cmds[0].id().clone()],
+            )
+            .await?;
+        }
+
+        {
+            // the cmd of the compacted entry won't be replayed
+            let s = WalStorage::<TestCommand>::new(&db_dir, 1024)?;
+            assert_eq!(s.spec_pool_cmds().await?, vec![cmds[1].as_ref().clone()]);
+        }
+
+        remove_dir_all(db_dir).await?;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,118 @@
+use std::{
+    fs::{self, File},
+    io,
+    path::{Path, PathBuf},
+};
+
+use clippy_utilities::NumericCast;
+
+use crate::LogIndex;
+
+/// Size of a record header, it's made up of the length and the crc32 of the payload,
+/// both are `u32` in little endian
+const HEADER_SIZE: usize = 8;
+
+/// Extension of the segment files
+const SEGMENT_EXT: &str = ".wal";
+
+/// A WAL segment file
+#[derive(Debug)]
+pub(super) struct Segment {
+    /// Sequence number of the segment, a segment is newer than the ones with smaller numbers
+    pub(super) seq: u64,
+    /// Index of the first entry written to the segment
+    pub(super) first_index: LogIndex,
+    /// Path of the segment file
+    pub(super) path: PathBuf,
+}
+
+impl Segment {
+    /// Create a `Segment` in `dir`
+    pub(super) fn new(dir: &Path, seq: u64, first_index: LogIndex) -> Self {
+        Self {
+            seq,
+            first_index,
+            path: dir.join(format!("{seq:016x}-{first_index:016x}{SEGMENT_EXT}")),
+        }
+    }
+
+    /// List all segments in `dir`, ordered by the sequence number
+    pub(super) fn list(dir: &Path) -> io::Result<Vec<Self>> {
+        let mut segments = vec![];
+        for dir_entry in fs::read_dir(dir)? {
+            let path = dir_entry?.path();
+            let Some((seq, first_index)) = path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .and_then(parse_segment_name) else {
+                continue;
+            };
+            segments.push(Self {
+                seq,
+                first_index,
+                path,
+            });
+        }
+        segments.sort_unstable_by_key(|s| s.seq);
+        Ok(segments)
+    }
+}
+
+/// Parse the name of a segment file, return `None` if it's not a segment
+fn parse_segment_name(name: &str) -> Option<(u64, LogIndex)> {
+    let (seq, first_index) = name.strip_suffix(SEGMENT_EXT)?.split_once('-')?;
+    Some((
+        u64::from_str_radix(seq, 16).ok()?,
+        u64::from_str_radix(first_index, 16).ok()?,
+    ))
+}
+
+/// Encode `payload` as a record and append it to `buf`
+pub(super) fn encode_record(payload: &[u8], buf: &mut Vec<u8>) -> io::Result<()> {
+    let len = u32::try_from(payload.len()).map_err(|_e| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("record of {} bytes is too large", payload.len()),
+        )
+    })?;
+    buf.extend_from_slice(&len.to_le_bytes());
+    buf.extend_from_slice(&crc32fast::hash(payload).to_le_bytes());
+    buf.extend_from_slice(payload);
+    Ok(())
+}
+
+/// Decode the records in `buf`, return their payloads and the length of the valid prefix of `buf`.
+/// Decoding stops at the first torn or corrupted record, the bytes after it can't be trusted
+#[allow(clippy::integer_arithmetic)] // won't overflow since the offsets are within `buf`
+pub(super) fn decode_records(buf: &[u8]) -> (Vec<&[u8]>, usize) {
+    let mut payloads = vec![];
+    let mut offset = 0;
+    while let Some(header) = buf.get(offset..offset + HEADER_SIZE) {
+        let (len, crc) = header.split_at(HEADER_SIZE / 2);
+        let len = le_u32(len).numeric_cast::<usize>();
+        let payload_start = offset + HEADER_SIZE;
+        let Some(payload) = buf.get(payload_start..payload_start + len) else {
+            // the record is torn
+            break;
+        };
+        // a zero-filled tail looks like an empty record
+        if len == 0 || crc32fast::hash(payload) != le_u32(crc) {
+            break;
+        }
+        payloads.push(payload);
+        offset = payload_start + len;
+    }
+    (payloads, offset)
+}
+
+/// Read a `u32` in little endian, `bytes` must be 4 bytes long
+fn le_u32(bytes: &[u8]) -> u32 {
+    let mut arr = [0; 4];
+    arr.copy_from_slice(bytes);
+    u32::from_le_bytes(arr)
+}
+
+/// Fsync the directory, so that the files created, renamed or removed in it are durable
+pub(super) fn sync_dir(dir: &Path) -> io::Result<()> {
+    File::open(dir)?.sync_all()
+}
@@ -0,0 +1,170 @@
+use std::{
+    collections::HashMap,
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+
+use clippy_utilities::NumericCast;
+use serde::{Deserialize, Serialize};
+use tracing::{debug, warn};
+
+use super::segment::{decode_records, encode_record, sync_dir};
+use crate::{cmd::ProposeId, server::storage::StorageError};
+
+/// Name of the speculative pool file
+const SPEC_POOL_FILE: &str = "spec_pool";
+
+/// Name of the temporary speculative pool file, it's renamed to `SPEC_POOL_FILE` once it's fsynced
+const SPEC_POOL_TMP_FILE: &str = "spec_pool.tmp";
+
+/// A change of the speculative pool
+#[derive(Debug, Serialize, Deserialize)]
+enum SpecPoolRecord {
+    /// A serialized command is put
+    Put(ProposeId, Vec<u8>),
+    /// The commands are removed
+    Remove(Vec<ProposeId>),
+}
+
+/// The persisted speculative pool
+///
+/// The changes are appended to a file as records, and the file is rewritten with only the
+/// live commands once most of its records are outdated.
+#[derive(Debug)]
+pub(super) struct SpecPoolFile {
+    /// Directory of the file
+    dir: PathBuf,
+    /// The file that records are appended to
+    file: File,
+    /// The live commands, they are serialized
+    cmds: HashMap<ProposeId, Vec<u8>>,
+    /// Number of records in the file
+    records: usize,
+}
+
+impl SpecPoolFile {
+    /// Open the speculative pool file in `dir`, its torn tail will be truncated
+    pub(super) fn open(dir: &Path) -> Result<Self, StorageError> {
+        let path = dir.join(SPEC_POOL_FILE);
+        let (buf, exists) = match fs::read(&path) {
+            Ok(buf) => (buf, true),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => (vec![], false),
+            Err(e) => return Err(e.into()),
+        };
+        let (payloads, valid_len) = decode_records(&buf);
+        let mut cmds = HashMap::new();
+        for payload in &payloads {
+            match bincode::deserialize(payload)? {
+                SpecPoolRecord::Put(id, cmd) => {
+                    let _ig = cmds.insert(id, cmd);
+                }
+                SpecPoolRecord::Remove(ids) => {
+                    for id in ids {
+                        let _ig = cmds.remove(&id);
+                    }
+                }
+            }
+        }
+
+        let file = OpenOptions::new().append(true).create(true).open(&path)?;
+        if !exists {
+            sync_dir(dir)?;
+        }
+        if valid_len != buf.len() {
+            warn!("truncate the torn tail of the spec pool file from offset {valid_len}");
+            file.set_len(valid_len.numeric_cast())?;
+            file.sync_all()?;
+        }
+
+        Ok(Self {
+            dir: dir.to_path_buf(),
+            file,
+            cmds,
+            records: payloads.len(),
+        })
+    }
+
+    /// Get the live commands
+    pub(super) fn cmds(&self) -> impl Iterator<Item = &[u8]> {
+        self.cmds.values().map(Vec::as_slice)
+    }
+
+    /// Put a serialized command, it's fsynced before returning
+    pub(super) fn put(&mut self, id: ProposeId, cmd: Vec<u8>) -> Result<(), StorageError> {
+        self.append(&SpecPoolRecord::Put(id.clone(), cmd.clone()))?;
+        self.file.sync_data()?;
+        let _ig = self.cmds.insert(id, cmd);
+        Ok(())
+    }
+
+    /// Remove the commands, the file is rewritten if most of its records are outdated
+    pub(super) fn remove(&mut self, ids: &[ProposeId]) -> Result<(), StorageError> {
+        let ids = ids
+            .iter()
+            .filter(|id| self.cmds.contains_key(id))
+            .cloned()
+            .collect::<Vec<_>>();
+        if ids.is_empty() {
+            return Ok(());
+        }
+        for id in &ids {
+            let _ig = self.cmds.remove(id);
+        }
+        self.append(&SpecPoolRecord::Remove(ids))?;
+
+        #[allow(clippy::integer_arithmetic)] // won't overflow
+        if self.records > self.cmds.len() * 2 {
+            self.rewrite()?;
+        }
+        Ok(())
+    }
+
+    /// Fsync the removals appended to the file
+    pub(super) fn sync(&self) -> Result<(), StorageError> {
+        self.file.sync_data()?;
+        Ok(())
+    }
+
+    /// Append a record to the file
+    #[allow(clippy::integer_arithmetic)] // won't overflow
+    fn append(&mut self, record: &SpecPoolRecord) -> Result<(), StorageError> {
+        let mut buf = vec![];
+        encode_record(&bincode::serialize(record)?, &mut buf)?;
+        let size = self.file.metadata()?.len();
+        if let Err(e) = self.file.write_all(&buf) {
+            // remove the partially written record, or the records after it will be discarded on recovery
+            if let Err(err) = self.file.set_len(size) {
+                warn!("failed to truncate the partially written spec pool record, {err}");
+            }
+            return Err(e.into());
+        }
+        self.records += 1;
+        Ok(())
+    }
+
+    /// Rewrite the file atomically with only the live commands
+    fn rewrite(&mut self) -> Result<(), StorageError> {
+        let mut buf = vec![];
+        for (id, cmd) in &self.cmds {
+            let record = SpecPoolRecord::Put(id.clone(), cmd.clone());
+            encode_record(&bincode::serialize(&record)?, &mut buf)?;
+        }
+        let tmp_path = self.dir.join(SPEC_POOL_TMP_FILE);
+        let path = self.dir.join(SPEC_POOL_FILE);
+        let mut file = File::create(&tmp_path)?;
+        file.write_all(&buf)?;
+        file.sync_all()?;
+        fs::rename(tmp_path, &path)?;
+        sync_dir(&self.dir)?;
+
+        self.file = OpenOptions::new().append(true).open(&path)?;
+        debug!(
+            "spec pool file is rewritten from {} records to {}",
+            self.records,
+            self.cmds.len()
+        );
+        self.records = self.cmds.len();
+        Ok(())
+    }
+}
@@ -22,7 +22,7 @@ use tracing::debug;
 use utils::config::{
     default_candidate_timeout_ticks, default_cmd_workers, default_follower_timeout_ticks,
     default_gc_interval, default_heartbeat_interval, default_retry_timeout, default_rpc_timeout,
-    default_server_wait_synced_timeout, ClientTimeout, CurpConfig, CurpConfigBuilder,
+    default_server_wait_synced_timeout, ClientTimeout, CurpConfig,
 };
 
 use crate::common::{
@@ -98,10 +98,16 @@ pub struct CurpGroup {
     pub nodes: HashMap<ServerId, CurpNode>,
     pub crashed_nodes: HashMap<ServerId, CrashedCurpNode>,
     pub all: HashMap<ServerId, String>,
+    pub config: CurpConfig,
 }
 
 impl CurpGroup {
     pub async fn new(n_nodes: usize) -> Self {
+        Self::new_with_config(n_nodes, CurpConfig::default()).await
+    }
+
+    /// Create a group whose nodes share the same `config`, except for the data dir
+    pub async fn new_with_config(n_nodes: usize, config: CurpConfig) -> Self {
         assert!(n_nodes >= 3);
         let listeners = join_all(
             iter::repeat_with(|| async { TcpListener::bind("0.0.0.0:0").await.unwrap() })
@@ -151,7 +157,10 @@ impl CurpGroup {
 
                 let id_c = id.clone();
                 let switch_c = Arc::clone(&switch);
-                let storage_path_c = storage_path.clone();
+                let config = Arc::new(CurpConfig {
+                    data_dir: PathBuf::from(storage_path.clone()),
+                    ..config.clone()
+                });
                 thread::spawn(move || {
                     handle.spawn(Rpc::run_from_listener(
                         id_c,
@@ -159,12 +168,7 @@ impl CurpGroup {
                         others.into_iter().collect(),
                         listener,
                         ce,
-                        Arc::new(
-                            CurpConfigBuilder::default()
-                                .data_dir(PathBuf::from(storage_path_c))
-                                .build()
-                                .unwrap(),
-                        ),
+                        config,
                         Some(Box::new(TestTxFilter::new(Arc::clone(&switch_c)))),
                         Some(reachable_layer),
                     ));
@@ -192,6 +196,7 @@ impl CurpGroup {
             nodes,
             all: all.into_iter().collect(),
             crashed_nodes: HashMap::new(),
+            config,
         }
     }
 
@@ -288,7 +293,10 @@ impl CurpGroup {
 
         let id_c = id.clone();
         let switch_c = Arc::clone(&switch);
-        let storage_path = crashed.storage_path.clone();
+        let config = Arc::new(CurpConfig {
+            data_dir: PathBuf::from(crashed.storage_path.clone()),
+            ..self.config.clone()
+        });
         thread::spawn(move || {
             handle.spawn(Rpc::run_from_listener(
                 id_c,
@@ -296,12 +304,7 @@ impl CurpGroup {
                 others.into_iter().collect(),
                 listener,
                 ce,
-                Arc::new(
-                    CurpConfigBuilder::default()
-                        .data_dir(PathBuf::from(storage_path))
-                        .build()
-                        .unwrap(),
-                ),
+                config,
                 Some(Box::new(TestTxFilter::new(Arc::clone(&switch_c)))),
                 Some(reachable_layer),
             ));
@@ -331,7 +334,7 @@ impl CurpGroup {
                 continue;
             };
 
-            let FetchLeaderResponse { leader_id, term } =
+            let FetchLeaderResponse { leader_id, term, .. } =
                 if let Ok(resp) = client.fetch_leader(FetchLeaderRequest {}).await {
                     resp.into_inner()
                 } else {
@@ -368,7 +371,7 @@ impl CurpGroup {
                 continue;
             };
 
-            let FetchLeaderResponse { leader_id, term } =
+            let FetchLeaderResponse { leader_id, term, .. } =
                 if let Ok(resp) = client.fetch_leader(FetchLeaderRequest {}).await {
                     resp.into_inner()
                 } else {
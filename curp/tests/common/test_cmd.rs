@@ -118,6 +118,10 @@ impl Command for TestCommand {
     fn id(&self) -> &ProposeId {
         &self.id
     }
+
+    fn id_mut(&mut self) -> &mut ProposeId {
+        &mut self.id
+    }
 }
 
 impl ConflictCheck for TestCommand {
@@ -1,7 +1,7 @@
 use std::time::Duration;
 
 use curp::{client::ReadState, cmd::Command};
-use utils::config::ClientTimeout;
+use utils::config::{ClientTimeout, CurpConfigBuilder};
 
 use crate::common::{curp_group::CurpGroup, init_logger, sleep_millis, test_cmd::TestCommand};
 
@@ -48,3 +48,34 @@ async fn read_state() {
     }
     group.stop();
 }
+
+#[tokio::test]
+async fn lease_read_state() {
+    init_logger();
+    let config = CurpConfigBuilder::default()
+        .lease_read(true)
+        .build()
+        .unwrap();
+    let group = CurpGroup::new_with_config(3, config).await;
+    let client = group.new_client(ClientTimeout::default()).await;
+    assert_eq!(
+        client
+            .propose(TestCommand::new_put(vec![0], 0))
+            .await
+            .unwrap(),
+        vec![]
+    );
+
+    // the leader holds the lease once the heartbeats are acked
+    sleep_millis(500).await;
+
+    let res = client
+        .fetch_read_state(&TestCommand::new_get(vec![0]))
+        .await
+        .unwrap();
+    assert!(
+        matches!(res, ReadState::CommitIndex(1)),
+        "expected result should be ReadState::CommitIndex(1), but received {res:?}"
+    );
+    group.stop();
+}
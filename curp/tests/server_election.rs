@@ -1,9 +1,13 @@
 use std::time::Duration;
 
 use madsim::time::sleep;
-use utils::config::ClientTimeout;
+use utils::config::{ClientTimeout, CurpConfigBuilder};
 
-use crate::common::{curp_group::CurpGroup, init_logger, test_cmd::TestCommand};
+use crate::common::{
+    curp_group::{proto::FetchLeaderRequest, CurpGroup},
+    init_logger,
+    test_cmd::TestCommand,
+};
 
 mod common;
 
@@ -124,3 +128,63 @@ async fn propose_after_reelect() {
 
     group.stop();
 }
+
+// A rejoining partitioned follower won't disrupt the cluster with pre-vote
+#[tokio::test]
+async fn pre_vote_prevents_disruption_from_rejoining_node() {
+    init_logger();
+
+    let config = CurpConfigBuilder::default().pre_vote(true).build().unwrap();
+    let group = CurpGroup::new_with_config(3, config).await;
+    let (leader1, term1) = group.get_leader().await;
+    let follower = group
+        .nodes
+        .keys()
+        .find(|&id| id != &leader1)
+        .unwrap()
+        .clone();
+
+    // the partitioned follower can't gather pre-votes, so it won't increase its term
+    group.disable_node(&follower);
+    wait_for_election().await;
+    group.enable_node(&follower);
+    wait_for_election().await;
+
+    let (leader2, term2) = group.get_leader().await;
+    assert_eq!(leader1, leader2);
+    assert_eq!(term1, term2);
+    assert_eq!(group.get_term_checked().await, term1);
+
+    group.stop();
+}
+
+// An isolated leader steps down with check quorum
+#[tokio::test]
+async fn check_quorum_makes_isolated_leader_step_down() {
+    init_logger();
+
+    let config = CurpConfigBuilder::default()
+        .check_quorum(true)
+        .build()
+        .unwrap();
+    let group = CurpGroup::new_with_config(5, config).await;
+    let leader1 = group.get_leader().await.0;
+    // connect before the leader is isolated, so that it can be asked as soon as it comes back
+    let mut leader1_connect = group.get_connect(&leader1).await;
+
+    group.disable_node(&leader1);
+    wait_for_election().await;
+    let leader2 = group.get_leader().await.0;
+    assert_ne!(leader1, leader2);
+
+    group.enable_node(&leader1);
+    let leader_id = leader1_connect
+        .fetch_leader(FetchLeaderRequest {})
+        .await
+        .unwrap()
+        .into_inner()
+        .leader_id;
+    assert_ne!(leader_id, Some(leader1));
+
+    group.stop();
+}
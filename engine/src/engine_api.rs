@@ -126,6 +126,12 @@ pub trait StorageEngine: Send + Sync + 'static + std::fmt::Debug {
         tables: &[&'static str],
     ) -> Result<Self::Snapshot, EngineError>;
 
+    /// Create an empty snapshot to receive the data of a snapshot taken by `get_snapshot`
+    ///
+    /// # Errors
+    /// Return `EngineError` if met some errors when creating the snapshot
+    fn new_snapshot(&self, path: impl AsRef<Path>) -> Result<Self::Snapshot, EngineError>;
+
     /// Apply a snapshot to the database
     ///
     /// # Errors
@@ -135,4 +141,26 @@ pub trait StorageEngine: Send + Sync + 'static + std::fmt::Debug {
         snapshot: Self::Snapshot,
         tables: &[&'static str],
     ) -> Result<(), EngineError>;
+
+    /// Get the size of the given tables in bytes, including the space which is
+    /// allocated but could be reclaimed
+    ///
+    /// # Errors
+    /// Return `EngineError::TableNotFound` if the given table does not exist
+    /// Return `EngineError` if met some errors
+    fn size(&self, tables: &[&'static str]) -> Result<u64, EngineError>;
+
+    /// Get the size of the live data of the given tables in bytes
+    ///
+    /// # Errors
+    /// Return `EngineError::TableNotFound` if the given table does not exist
+    /// Return `EngineError` if met some errors
+    fn size_in_use(&self, tables: &[&'static str]) -> Result<u64, EngineError>;
+
+    /// Reclaim the space which is allocated but no longer used by the given tables
+    ///
+    /// # Errors
+    /// Return `EngineError::TableNotFound` if the given table does not exist
+    /// Return `EngineError` if met some errors
+    fn defragment(&self, tables: &[&'static str]) -> Result<(), EngineError>;
 }
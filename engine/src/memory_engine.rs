@@ -6,7 +6,7 @@ use std::{
     sync::Arc,
 };
 
-use clippy_utilities::NumericCast;
+use clippy_utilities::{NumericCast, OverflowArithmetic};
 use parking_lot::RwLock;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 
@@ -177,6 +177,11 @@ impl StorageEngine for MemoryEngine {
         })
     }
 
+    #[inline]
+    fn new_snapshot(&self, _path: impl AsRef<Path>) -> Result<Self::Snapshot, EngineError> {
+        Ok(MemorySnapshot::default())
+    }
+
     #[inline]
     fn apply_snapshot(
         &self,
@@ -192,6 +197,39 @@ impl StorageEngine for MemoryEngine {
         *db = new_db;
         Ok(())
     }
+
+    #[inline]
+    fn size(&self, tables: &[&'static str]) -> Result<u64, EngineError> {
+        let inner = self.inner.read();
+        tables.iter().try_fold(0, |size, table| {
+            let table = inner
+                .get(*table)
+                .ok_or_else(|| EngineError::TableNotFound((*table).to_owned()))?;
+            Ok(table.iter().fold(size, |size, (key, value)| {
+                size.overflow_add(key.len().numeric_cast())
+                    .overflow_add(value.len().numeric_cast())
+            }))
+        })
+    }
+
+    #[inline]
+    fn size_in_use(&self, tables: &[&'static str]) -> Result<u64, EngineError> {
+        // all the space of memory engine is in use
+        self.size(tables)
+    }
+
+    #[inline]
+    fn defragment(&self, tables: &[&'static str]) -> Result<(), EngineError> {
+        // shrink the tables one by one, so that writes are only blocked for a short while
+        for table in tables {
+            let mut inner = self.inner.write();
+            let table = inner
+                .get_mut(*table)
+                .ok_or_else(|| EngineError::TableNotFound((*table).to_owned()))?;
+            table.shrink_to_fit();
+        }
+        Ok(())
+    }
 }
 
 #[cfg(test)]
@@ -258,6 +296,33 @@ mod test {
         assert!(engine.get("kv", &get_key_2).unwrap().is_none());
     }
 
+    #[test]
+    fn size_should_count_keys_and_values() {
+        let engine = MemoryEngine::new(&TESTTABLES).unwrap();
+        assert_eq!(engine.size(&TESTTABLES).unwrap(), 0);
+        let put = WriteOperation::new_put("kv", b"hello".to_vec(), b"world!".to_vec());
+        assert!(engine.write_batch(vec![put], false).is_ok());
+        assert_eq!(engine.size(&TESTTABLES).unwrap(), 11);
+        assert_eq!(engine.size_in_use(&TESTTABLES).unwrap(), 11);
+        assert!(engine.size(&["hello"]).is_err());
+    }
+
+    #[test]
+    fn defragment_should_keep_data() {
+        let engine = MemoryEngine::new(&TESTTABLES).unwrap();
+        let puts = (0u8..100u8)
+            .map(|i| WriteOperation::new_put("kv", vec![i], vec![i]))
+            .collect();
+        assert!(engine.write_batch(puts, false).is_ok());
+        let delete_range = WriteOperation::new_delete_range("kv", &[0], &[90]);
+        assert!(engine.write_batch(vec![delete_range], false).is_ok());
+
+        assert!(engine.defragment(&TESTTABLES).is_ok());
+        assert_eq!(engine.get_all("kv").unwrap().len(), 10);
+        assert_eq!(engine.size(&TESTTABLES).unwrap(), 20);
+        assert!(engine.defragment(&["hello"]).is_err());
+    }
+
     #[test]
     fn get_operation_should_success() {
         let engine = MemoryEngine::new(&TESTTABLES).unwrap();
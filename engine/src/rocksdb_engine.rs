@@ -12,8 +12,8 @@ use std::{
 
 use clippy_utilities::{NumericCast, OverflowArithmetic};
 use rocksdb::{
-    Error as RocksError, IteratorMode, Options, SstFileWriter, WriteBatchWithTransaction,
-    WriteOptions, DB,
+    BottommostLevelCompaction, CompactOptions, Error as RocksError, IteratorMode, Options,
+    SstFileWriter, WriteBatchWithTransaction, WriteOptions, DB,
 };
 use serde::{Deserialize, Serialize};
 use tokio::{
@@ -72,6 +72,22 @@ impl RocksEngine {
             inner: Arc::new(DB::open_cf(&db_opts, data_dir, tables)?),
         })
     }
+
+    /// Sum up the integer properties of the given tables
+    fn sum_properties(&self, tables: &[&'static str], names: &[&str]) -> Result<u64, EngineError> {
+        let mut sum: u64 = 0;
+        for table in tables {
+            let cf = self
+                .inner
+                .cf_handle(table)
+                .ok_or(EngineError::TableNotFound((*table).to_owned()))?;
+            for name in names {
+                let value = self.inner.property_int_value_cf(&cf, *name)?.unwrap_or(0);
+                sum = sum.overflow_add(value);
+            }
+        }
+        Ok(sum)
+    }
 }
 
 /// Human readable format for `RocksEngine`
@@ -300,7 +316,9 @@ impl RocksSnapshot {
                 .unwrap_or_else(|_e| unreachable!("infallible"));
             let meta = bincode::deserialize(&meta_bytes).map_err(|e| io::Error::new(Other, e))?;
 
-            self.meta.data = Cursor::new(meta_bytes);
+            // keep the length prefix so that the received snapshot can be read again
+            self.meta.data =
+                Cursor::new(next_buf[..meta_len.overflow_add(8).numeric_cast()].to_vec());
             self.apply_snap_meta(meta);
 
             self.meta.is_current = false;
@@ -552,6 +570,11 @@ impl StorageEngine for RocksEngine {
         RocksSnapshot::new_for_sending(path.as_ref())
     }
 
+    #[inline]
+    fn new_snapshot(&self, path: impl AsRef<Path>) -> Result<Self::Snapshot, EngineError> {
+        RocksSnapshot::new_for_receiving(path.as_ref())
+    }
+
     #[inline]
     fn apply_snapshot(
         &self,
@@ -571,6 +594,45 @@ impl StorageEngine for RocksEngine {
         }
         Ok(())
     }
+    #[inline]
+    fn size(&self, tables: &[&'static str]) -> Result<u64, EngineError> {
+        self.sum_properties(
+            tables,
+            &[
+                "rocksdb.total-sst-files-size",
+                "rocksdb.size-all-mem-tables",
+            ],
+        )
+    }
+
+    #[inline]
+    fn size_in_use(&self, tables: &[&'static str]) -> Result<u64, EngineError> {
+        self.sum_properties(
+            tables,
+            &[
+                "rocksdb.estimate-live-data-size",
+                "rocksdb.cur-size-all-mem-tables",
+            ],
+        )
+    }
+
+    #[inline]
+    fn defragment(&self, tables: &[&'static str]) -> Result<(), EngineError> {
+        let mut opts = CompactOptions::default();
+        // the bottommost level must be rewritten as well to drop the deleted keys
+        opts.set_bottommost_level_compaction(BottommostLevelCompaction::Force);
+        for table in tables {
+            let cf = self
+                .inner
+                .cf_handle(table)
+                .ok_or(EngineError::TableNotFound((*table).to_owned()))?;
+            self.inner.flush_cf(&cf)?;
+            // manual compaction runs alongside writes, so writes are not blocked
+            self.inner
+                .compact_range_cf_opt(&cf, None::<&[u8]>, None::<&[u8]>, &opts);
+        }
+        Ok(())
+    }
 }
 
 /// destroy will remove the db file. It's test only
@@ -657,6 +719,26 @@ mod test {
         destroy(&data_dir);
     }
 
+    #[test]
+    fn defragment_should_reclaim_space() {
+        let data_dir = PathBuf::from("/tmp/defragment_should_reclaim_space");
+        let engine = RocksEngine::new(&data_dir, &TESTTABLES).unwrap();
+        let puts = (0u16..1000u16)
+            .map(|i| WriteOperation::new_put("kv", i.to_be_bytes().to_vec(), vec![0; 1024]))
+            .collect();
+        assert!(engine.write_batch(puts, false).is_ok());
+        let delete_range = WriteOperation::new_delete_range("kv", &[0, 0], &[0xff, 0xff]);
+        assert!(engine.write_batch(vec![delete_range], false).is_ok());
+        let size = engine.size(&TESTTABLES).unwrap();
+
+        assert!(engine.defragment(&TESTTABLES).is_ok());
+        assert!(engine.size(&TESTTABLES).unwrap() < size);
+        assert!(engine.get_all("kv").unwrap().is_empty());
+        assert!(engine.defragment(&["hello"]).is_err());
+        drop(engine);
+        destroy(&data_dir);
+    }
+
     #[test]
     fn get_operation_should_success() {
         let data_dir = PathBuf::from("/tmp/get_operation_should_success");
@@ -713,6 +795,7 @@ mod test {
 
         let mut received_snapshot = RocksSnapshot::new_for_receiving(snapshot_bak_dir).unwrap();
         received_snapshot.write_all(&buf).await.unwrap();
+        assert_eq!(received_snapshot.size(), snapshot.size());
 
         let engine_2 = RocksEngine::new(&recover_data_dir, &TESTTABLES).unwrap();
         assert!(engine_2
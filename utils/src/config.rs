@@ -24,6 +24,10 @@ pub struct XlineServerConfig {
     /// auth configuration object
     #[getset(get = "pub")]
     auth: AuthConfig,
+    /// compact configuration object
+    #[getset(get = "pub")]
+    #[serde(default)]
+    compact: CompactConfig,
 }
 
 /// Cluster Range type alias
@@ -68,6 +72,51 @@ pub mod bytes_format {
     }
 }
 
+/// periodic auto compaction retention deserialization formatter
+pub mod periodic_retention_format {
+    use std::time::Duration;
+
+    use serde::{self, Deserialize, Deserializer};
+
+    use crate::parse_duration;
+
+    /// deserializes a positive retention period
+    #[allow(single_use_lifetimes)] //  the false positive case blocks us
+    pub(crate) fn deserialize<'de, D>(deserializer: D) -> Result<Duration, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let s = String::deserialize(deserializer)?;
+        let retention = parse_duration(&s).map_err(serde::de::Error::custom)?;
+        if retention.is_zero() {
+            return Err(serde::de::Error::custom(format!(
+                "the retention should be positive ({s})"
+            )));
+        }
+        Ok(retention)
+    }
+}
+
+/// revision auto compaction retention deserialization formatter
+pub mod revision_retention_format {
+    use serde::{self, Deserialize, Deserializer};
+
+    /// deserializes a positive number of revisions
+    #[allow(single_use_lifetimes)] //  the false positive case blocks us
+    pub(crate) fn deserialize<'de, D>(deserializer: D) -> Result<i64, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let revisions = i64::deserialize(deserializer)?;
+        if revisions <= 0 {
+            return Err(serde::de::Error::custom(format!(
+                "the retention should be positive ({revisions})"
+            )));
+        }
+        Ok(revisions)
+    }
+}
+
 /// Cluster configuration object, including cluster relevant configuration fields
 #[allow(clippy::module_name_repetitions)]
 #[derive(Clone, Debug, Deserialize, PartialEq, Eq, Getters)]
@@ -93,12 +142,25 @@ pub struct ClusterConfig {
     #[getset(get = "pub")]
     #[serde(with = "duration_format", default = "default_range_retry_timeout")]
     range_retry_timeout: Duration,
+    /// Max size of a watch response, events will be split into fragments if the watcher allows
+    #[getset(get = "pub")]
+    #[serde(with = "bytes_format", default = "default_watch_max_message_size")]
+    watch_max_message_size: u64,
+    /// How often should the leader check whether the members are corrupted, the check is disabled if it's zero
+    #[getset(get = "pub")]
+    #[serde(with = "duration_format", default = "default_corrupt_check_interval")]
+    corrupt_check_interval: Duration,
+    /// Whether the leader should transfer its leadership to another voter before it shuts down
+    #[getset(get = "pub")]
+    #[serde(default)]
+    transfer_leadership_on_shutdown: bool,
 }
 
 impl ClusterConfig {
     /// Generate a new `ClusterConfig` object
     #[must_use]
     #[inline]
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         name: String,
         members: HashMap<String, String>,
@@ -106,6 +168,9 @@ impl ClusterConfig {
         curp: CurpConfig,
         client_timeout: ClientTimeout,
         range_retry_timeout: Duration,
+        watch_max_message_size: u64,
+        corrupt_check_interval: Duration,
+        transfer_leadership_on_shutdown: bool,
     ) -> Self {
         Self {
             name,
@@ -114,6 +179,9 @@ impl ClusterConfig {
             curp_config: curp,
             client_timeout,
             range_retry_timeout,
+            watch_max_message_size,
+            corrupt_check_interval,
+            transfer_leadership_on_shutdown,
         }
     }
 }
@@ -184,6 +252,102 @@ pub struct CurpConfig {
     #[builder(default = "default_gc_interval()")]
     #[serde(with = "duration_format", default = "default_gc_interval")]
     pub gc_interval: Duration,
+
+    /// The max number of log entries kept in memory, the applied entries will be compacted
+    /// once the log grows beyond it
+    #[builder(default = "default_log_entries_cap()")]
+    #[serde(default = "default_log_entries_cap")]
+    pub log_entries_cap: usize,
+
+    /// How often should the log compaction task check the log size
+    #[builder(default = "default_log_compact_interval()")]
+    #[serde(with = "duration_format", default = "default_log_compact_interval")]
+    pub log_compact_interval: Duration,
+
+    /// Whether the node joins the cluster as a non-voting learner, it must be added by
+    /// a conf change before it starts
+    #[builder(default)]
+    #[serde(default)]
+    pub is_learner: bool,
+
+    /// Whether the node is a witness. A witness keeps the log and the speculative pool and
+    /// votes in elections, but it never executes commands, so it never becomes the leader
+    #[builder(default)]
+    #[serde(default)]
+    pub is_witness: bool,
+
+    /// Whether a node runs a pre-vote round before it increases its term and starts a real
+    /// election, it prevents a rejoining partitioned node from disrupting the cluster
+    #[builder(default)]
+    #[serde(default)]
+    pub pre_vote: bool,
+
+    /// Whether the leader steps down if it hasn't heard from the majority of voters within
+    /// an election timeout
+    #[builder(default)]
+    #[serde(default)]
+    pub check_quorum: bool,
+
+    /// Whether the leader serves reads locally within its lease, the lease is held while
+    /// the majority of voters acked its heartbeats within `election_timeout - max_clock_drift`
+    #[builder(default)]
+    #[serde(default)]
+    pub lease_read: bool,
+
+    /// The max clock drift between servers, the lease of the leader is shortened by it
+    #[builder(default = "default_max_clock_drift()")]
+    #[serde(with = "duration_format", default = "default_max_clock_drift")]
+    pub max_clock_drift: Duration,
+
+    /// The max number of log batches sent to a follower without waiting for the responses
+    #[builder(default = "default_max_inflight_batches()")]
+    #[serde(default = "default_max_inflight_batches")]
+    pub max_inflight_batches: usize,
+
+    /// Storage backend of the curp log and persisted states
+    #[builder(default = "default_curp_storage_backend()")]
+    #[serde(default = "default_curp_storage_backend")]
+    pub storage_backend: CurpStorageBackend,
+
+    /// The max size of a WAL segment file, a new segment is created once the active one
+    /// grows beyond it. Only used by the `wal` storage backend
+    #[builder(default = "default_wal_segment_size()")]
+    #[serde(default = "default_wal_segment_size")]
+    pub wal_segment_size: u64,
+
+    /// Whether the speculative pool is persisted, a command is acked in the fast path only
+    /// after it's flushed on disk, so that the witnessed commands survive crashes
+    #[builder(default)]
+    #[serde(default)]
+    pub persist_spec_pool: bool,
+
+    /// How long a client session lives without any proposal, the leader expires the idle
+    /// sessions and their cached responses through the log
+    #[builder(default = "default_session_timeout()")]
+    #[serde(with = "duration_format", default = "default_session_timeout")]
+    pub session_timeout: Duration,
+}
+
+/// Storage backend of curp
+#[non_exhaustive]
+#[allow(clippy::module_name_repetitions)]
+#[derive(Copy, Clone, Debug, Deserialize, PartialEq, Eq)]
+#[serde(rename_all(deserialize = "lowercase"))]
+pub enum CurpStorageBackend {
+    /// Store the log and states in `RocksDB`
+    RocksDB,
+    /// Store the log in segmented append-only WAL files
+    Wal,
+}
+
+impl std::fmt::Display for CurpStorageBackend {
+    #[inline]
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match *self {
+            CurpStorageBackend::RocksDB => write!(f, "rocksdb"),
+            CurpStorageBackend::Wal => write!(f, "wal"),
+        }
+    }
 }
 
 /// default heartbeat interval
@@ -278,6 +442,27 @@ pub const fn default_range_retry_timeout() -> Duration {
     Duration::from_secs(2)
 }
 
+/// default max size of a watch response
+#[must_use]
+#[inline]
+pub const fn default_watch_max_message_size() -> u64 {
+    1536 * 1024
+}
+
+/// default corrupt check interval, the check is disabled by default
+#[must_use]
+#[inline]
+pub const fn default_corrupt_check_interval() -> Duration {
+    Duration::ZERO
+}
+
+/// default backend quota
+#[must_use]
+#[inline]
+pub const fn default_quota_backend_bytes() -> u64 {
+    2 * 1024 * 1024 * 1024
+}
+
 /// default gc interval
 #[must_use]
 #[inline]
@@ -285,6 +470,56 @@ pub fn default_gc_interval() -> Duration {
     Duration::from_secs(20)
 }
 
+/// default log entries cap
+#[must_use]
+#[inline]
+pub const fn default_log_entries_cap() -> usize {
+    5000
+}
+
+/// default log compact interval
+#[must_use]
+#[inline]
+pub const fn default_log_compact_interval() -> Duration {
+    Duration::from_secs(10)
+}
+
+/// default max clock drift
+#[must_use]
+#[inline]
+pub const fn default_max_clock_drift() -> Duration {
+    Duration::from_millis(100)
+}
+
+/// default max inflight batches
+#[must_use]
+#[inline]
+pub const fn default_max_inflight_batches() -> usize {
+    8
+}
+
+/// default curp storage backend
+#[must_use]
+#[inline]
+pub const fn default_curp_storage_backend() -> CurpStorageBackend {
+    CurpStorageBackend::RocksDB
+}
+
+/// default WAL segment size
+#[must_use]
+#[inline]
+#[allow(clippy::integer_arithmetic)]
+pub const fn default_wal_segment_size() -> u64 {
+    64 * 1024 * 1024
+}
+
+/// default client session timeout
+#[must_use]
+#[inline]
+pub const fn default_session_timeout() -> Duration {
+    Duration::from_secs(600)
+}
+
 impl Default for CurpConfig {
     #[inline]
     fn default() -> Self {
@@ -300,6 +535,19 @@ impl Default for CurpConfig {
             data_dir: default_curp_data_dir(),
             cmd_workers: default_cmd_workers(),
             gc_interval: default_gc_interval(),
+            log_entries_cap: default_log_entries_cap(),
+            log_compact_interval: default_log_compact_interval(),
+            is_learner: false,
+            is_witness: false,
+            pre_vote: false,
+            check_quorum: false,
+            lease_read: false,
+            max_clock_drift: default_max_clock_drift(),
+            max_inflight_batches: default_max_inflight_batches(),
+            storage_backend: default_curp_storage_backend(),
+            wal_segment_size: default_wal_segment_size(),
+            persist_spec_pool: false,
+            session_timeout: default_session_timeout(),
         }
     }
 }
@@ -356,6 +604,33 @@ impl Default for ClientTimeout {
 
 /// Storage Configuration
 #[allow(clippy::module_name_repetitions)]
+#[derive(Debug, Clone, Deserialize, PartialEq, Eq, Getters)]
+pub struct StorageConfig {
+    /// Storage engine
+    #[getset(get = "pub")]
+    #[serde(flatten)]
+    engine: EngineConfig,
+    /// Backend quota in bytes, a `NOSPACE` alarm is raised when the database grows past it.
+    /// The quota is disabled if it's zero
+    #[getset(get = "pub")]
+    #[serde(default = "default_quota_backend_bytes")]
+    quota_backend_bytes: u64,
+}
+
+impl StorageConfig {
+    /// Create a new storage config
+    #[must_use]
+    #[inline]
+    pub fn new(engine: EngineConfig, quota_backend_bytes: u64) -> Self {
+        Self {
+            engine,
+            quota_backend_bytes,
+        }
+    }
+}
+
+/// Storage engine configuration
+#[allow(clippy::module_name_repetitions)]
 #[non_exhaustive]
 #[derive(Debug, Clone, Deserialize, PartialEq, Eq)]
 #[serde(
@@ -363,7 +638,7 @@ impl Default for ClientTimeout {
     content = "data_dir",
     rename_all(deserialize = "lowercase")
 )]
-pub enum StorageConfig {
+pub enum EngineConfig {
     /// Memory Storage Engine
     Memory,
     /// RocksDB Storage Engine
@@ -562,6 +837,45 @@ impl AuthConfig {
     }
 }
 
+/// Compaction configuration object
+#[allow(clippy::module_name_repetitions)]
+#[derive(Copy, Clone, Debug, Default, Deserialize, PartialEq, Eq, Getters)]
+pub struct CompactConfig {
+    /// The auto compactor config, auto compaction is disabled when it's `None`
+    #[getset(get = "pub")]
+    #[serde(default)]
+    auto_compact_config: Option<AutoCompactConfig>,
+}
+
+impl CompactConfig {
+    /// Generate a new `CompactConfig` object
+    #[must_use]
+    #[inline]
+    pub fn new(auto_compact_config: Option<AutoCompactConfig>) -> Self {
+        Self {
+            auto_compact_config,
+        }
+    }
+}
+
+/// Auto compactor configuration
+#[non_exhaustive]
+#[allow(clippy::module_name_repetitions)]
+#[derive(Copy, Clone, Debug, Deserialize, PartialEq, Eq)]
+#[serde(
+    tag = "mode",
+    content = "retention",
+    rename_all(deserialize = "lowercase")
+)]
+pub enum AutoCompactConfig {
+    /// Compact the revisions that are older than the retention period
+    #[serde(with = "periodic_retention_format")]
+    Periodic(Duration),
+    /// Only keep the latest `retention` revisions
+    #[serde(with = "revision_retention_format")]
+    Revision(i64),
+}
+
 impl XlineServerConfig {
     /// Generates a new `XlineServerConfig` object
     #[must_use]
@@ -572,6 +886,7 @@ impl XlineServerConfig {
         log: LogConfig,
         trace: TraceConfig,
         auth: AuthConfig,
+        compact: CompactConfig,
     ) -> Self {
         Self {
             cluster,
@@ -579,6 +894,7 @@ impl XlineServerConfig {
             log,
             trace,
             auth,
+            compact,
         }
     }
 }
@@ -595,6 +911,9 @@ mod tests {
             name = 'node1'
             is_leader = true
             range_retry_timeout = '3s'
+            watch_max_message_size = '1MB'
+            corrupt_check_interval = '60s'
+            transfer_leadership_on_shutdown = true
 
             [cluster.members]
             node1 = '127.0.0.1:2379'
@@ -612,6 +931,7 @@ mod tests {
 
             [storage]
             engine = 'memory'
+            quota_backend_bytes = 1073741824
 
             [log]
             path = '/var/log/xline'
@@ -624,7 +944,11 @@ mod tests {
             jaeger_output_dir = './jaeger_jsons'
             jaeger_level = 'info'
 
-            [auth]"#,
+            [auth]
+
+            [compact.auto_compact_config]
+            mode = 'periodic'
+            retention = '10s'"#,
         )
         .unwrap();
 
@@ -656,11 +980,17 @@ mod tests {
                 true,
                 curp_config,
                 client_timeout,
-                range_retry_timeout
+                range_retry_timeout,
+                1024 * 1024,
+                Duration::from_secs(60),
+                true
             )
         );
 
-        assert_eq!(config.storage, StorageConfig::Memory);
+        assert_eq!(
+            config.storage,
+            StorageConfig::new(EngineConfig::Memory, 1024 * 1024 * 1024)
+        );
 
         assert_eq!(
             config.log,
@@ -679,6 +1009,11 @@ mod tests {
                 LevelConfig::INFO
             )
         );
+
+        assert_eq!(
+            config.compact,
+            CompactConfig::new(Some(AutoCompactConfig::Periodic(Duration::from_secs(10))))
+        );
     }
 
     #[allow(clippy::unwrap_used)]
@@ -725,15 +1060,22 @@ mod tests {
                 true,
                 CurpConfig::default(),
                 ClientTimeout::default(),
-                default_range_retry_timeout()
+                default_range_retry_timeout(),
+                default_watch_max_message_size(),
+                default_corrupt_check_interval(),
+                false
             )
         );
 
-        if let StorageConfig::RocksDB(path) = config.storage {
-            assert_eq!(path, PathBuf::from("/usr/local/xline/data-dir"));
+        if let EngineConfig::RocksDB(ref path) = *config.storage.engine() {
+            assert_eq!(*path, PathBuf::from("/usr/local/xline/data-dir"));
         } else {
             unreachable!();
         }
+        assert_eq!(
+            *config.storage.quota_backend_bytes(),
+            default_quota_backend_bytes()
+        );
 
         assert_eq!(
             config.log,
@@ -752,5 +1094,40 @@ mod tests {
                 LevelConfig::INFO
             )
         );
+
+        assert_eq!(config.compact, CompactConfig::default());
+    }
+
+    #[allow(clippy::unwrap_used)]
+    #[test]
+    fn test_auto_revision_compactor_config_should_be_loaded() {
+        let config: CompactConfig = toml::from_str(
+            r#"[auto_compact_config]
+                mode = 'revision'
+                retention = 10000"#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            config,
+            CompactConfig::new(Some(AutoCompactConfig::Revision(10000)))
+        );
+    }
+
+    #[test]
+    fn test_non_positive_auto_compactor_retention_should_be_rejected() {
+        for (mode, retention) in [
+            ("periodic", "'0s'"),
+            ("periodic", "'0ms'"),
+            ("revision", "0"),
+            ("revision", "-1"),
+        ] {
+            let config = format!(
+                r#"[auto_compact_config]
+                mode = '{mode}'
+                retention = {retention}"#
+            );
+            assert!(toml::from_str::<CompactConfig>(&config).is_err());
+        }
     }
 }
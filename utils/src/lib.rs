@@ -130,7 +130,9 @@ use std::{collections::HashMap, time::Duration};
 
 use thiserror::Error;
 
-use crate::config::{ClusterRange, LevelConfig, RotationConfig};
+use crate::config::{
+    AutoCompactConfig, ClusterRange, CurpStorageBackend, LevelConfig, RotationConfig,
+};
 
 /// configuration
 pub mod config;
@@ -233,6 +235,31 @@ pub fn parse_duration(s: &str) -> Result<Duration, ConfigParseError> {
     }
 }
 
+/// Parse `AutoCompactConfig` from the auto compaction retention, it's a duration (eg: 3600s)
+/// in periodic mode, or a number of revisions in revision mode
+/// # Errors
+/// Return error when the given string is neither a positive duration nor a positive number
+#[inline]
+pub fn parse_auto_compact_retention(s: &str) -> Result<AutoCompactConfig, ConfigParseError> {
+    if let Ok(revisions) = s.parse::<i64>() {
+        return if revisions > 0 {
+            Ok(AutoCompactConfig::Revision(revisions))
+        } else {
+            Err(ConfigParseError::InvalidValue(format!(
+                "the retention should be positive ({s})"
+            )))
+        };
+    }
+    let retention = parse_duration(s)?;
+    if retention.is_zero() {
+        Err(ConfigParseError::InvalidValue(format!(
+            "the retention should be positive ({s})"
+        )))
+    } else {
+        Ok(AutoCompactConfig::Periodic(retention))
+    }
+}
+
 /// Parse `LevelConfig` from string
 /// # Errors
 /// Return error when parsing the given string to `LevelConfig` failed
@@ -265,6 +292,20 @@ pub fn parse_rotation(s: &str) -> Result<RotationConfig, ConfigParseError> {
     }
 }
 
+/// Parse `CurpStorageBackend` from string
+/// # Errors
+/// Return error when parsing the given string to `CurpStorageBackend` failed
+#[inline]
+pub fn parse_curp_storage_backend(s: &str) -> Result<CurpStorageBackend, ConfigParseError> {
+    match s {
+        "rocksdb" => Ok(CurpStorageBackend::RocksDB),
+        "wal" => Ok(CurpStorageBackend::Wal),
+        _ => Err(ConfigParseError::InvalidValue(format!(
+            "the curp storage backend should be one of 'rocksdb' or 'wal' ({s})"
+        ))),
+    }
+}
+
 /// Parse bytes from string
 /// # Errors
 /// Return error when parsing the given string to usize failed
@@ -354,6 +395,22 @@ mod test {
         assert!(parse_members(s4).is_err());
     }
 
+    #[allow(clippy::unwrap_used)]
+    #[test]
+    fn test_parse_auto_compact_retention() {
+        assert_eq!(
+            parse_auto_compact_retention("3600s").unwrap(),
+            AutoCompactConfig::Periodic(Duration::from_secs(3600))
+        );
+        assert_eq!(
+            parse_auto_compact_retention("100").unwrap(),
+            AutoCompactConfig::Revision(100)
+        );
+        for s in ["0", "-1", "0s", "0ms", "hello world", "5x"] {
+            assert!(parse_auto_compact_retention(s).is_err());
+        }
+    }
+
     #[allow(clippy::unwrap_used)]
     #[test]
     fn test_parse_log_level() {
@@ -376,6 +433,20 @@ mod test {
         assert!(res.is_err());
     }
 
+    #[allow(clippy::unwrap_used)]
+    #[test]
+    fn test_parse_curp_storage_backend() {
+        assert_eq!(
+            parse_curp_storage_backend("rocksdb").unwrap(),
+            CurpStorageBackend::RocksDB
+        );
+        assert_eq!(
+            parse_curp_storage_backend("wal").unwrap(),
+            CurpStorageBackend::Wal
+        );
+        assert!(parse_curp_storage_backend("sled").is_err());
+    }
+
     #[allow(clippy::unwrap_used)]
     #[test]
     fn test_parse_batch_size() {
@@ -113,7 +113,7 @@
 use std::{collections::HashMap, env, path::PathBuf, time::Duration};
 
 use anyhow::{anyhow, Result};
-use clap::Parser;
+use clap::{CommandFactory, ErrorKind, Parser};
 use jsonwebtoken::{DecodingKey, EncodingKey};
 use opentelemetry::{global, runtime::Tokio, sdk::propagation::TraceContextPropagator};
 use opentelemetry_contrib::trace::exporter::jaeger_json::JaegerJsonExporter;
@@ -124,14 +124,20 @@ use tracing_subscriber::{fmt::format, prelude::*};
 use utils::{
     config::{
         default_batch_max_size, default_batch_timeout, default_candidate_timeout_ticks,
-        default_client_wait_synced_timeout, default_cmd_workers, default_follower_timeout_ticks,
-        default_gc_interval, default_heartbeat_interval, default_log_level,
-        default_propose_timeout, default_range_retry_timeout, default_retry_timeout,
-        default_rotation, default_rpc_timeout, default_server_wait_synced_timeout, file_appender,
-        AuthConfig, ClientTimeout, ClusterConfig, CurpConfigBuilder, LevelConfig, LogConfig,
-        RotationConfig, StorageConfig, TraceConfig, XlineServerConfig,
+        default_client_wait_synced_timeout, default_cmd_workers, default_corrupt_check_interval,
+        default_curp_storage_backend, default_follower_timeout_ticks, default_gc_interval,
+        default_heartbeat_interval, default_log_compact_interval, default_log_entries_cap,
+        default_log_level, default_max_clock_drift, default_max_inflight_batches,
+        default_propose_timeout, default_quota_backend_bytes, default_range_retry_timeout,
+        default_retry_timeout, default_rotation, default_rpc_timeout,
+        default_server_wait_synced_timeout, default_session_timeout, default_wal_segment_size,
+        default_watch_max_message_size, file_appender, AuthConfig, AutoCompactConfig,
+        ClientTimeout, ClusterConfig, CompactConfig, CurpConfigBuilder, CurpStorageBackend,
+        EngineConfig, LevelConfig, LogConfig, RotationConfig, StorageConfig, TraceConfig,
+        XlineServerConfig,
     },
-    parse_batch_bytes, parse_duration, parse_log_level, parse_members, parse_rotation,
+    parse_auto_compact_retention, parse_batch_bytes, parse_curp_storage_backend, parse_duration,
+    parse_log_level, parse_members, parse_rotation,
 };
 use xline::{server::XlineServer, storage::db::DBProxy};
 
@@ -214,17 +220,74 @@ struct ServerArgs {
     /// Range request retry timeout [default: 2s]
     #[clap(long, value_parser = parse_duration)]
     range_retry_timeout: Option<Duration>,
+    /// Max size of a watch response, events will be split into fragments if the watcher allows [default: 1536KB]
+    #[clap(long, value_parser = parse_batch_bytes)]
+    watch_max_message_size: Option<u64>,
+    /// How often should the leader check whether the members are corrupted, the check is disabled if not set
+    #[clap(long, value_parser = parse_duration)]
+    corrupt_check_interval: Option<Duration>,
+    /// Transfer the leadership to another member before the leader shuts down
+    #[clap(long)]
+    transfer_leadership_on_shutdown: bool,
     /// Storage engine
     #[clap(long)]
     storage_engine: String,
     /// DB directory
     #[clap(long)]
     data_dir: PathBuf,
+    /// Backend quota in bytes, a NOSPACE alarm is raised when the database grows past it, 0 means no quota [default: 2GB]
+    #[clap(long)]
+    quota_backend_bytes: Option<u64>,
     /// Curp directory
     curp_dir: Option<PathBuf>,
     /// Curp command workers count
     #[clap(long, default_value_t = default_cmd_workers())]
     cmd_workers: u8,
+    /// The max number of log entries kept in memory
+    #[clap(long, default_value_t = default_log_entries_cap())]
+    log_entries_cap: usize,
+    /// How often should the log compaction task check the log size [default: 10s]
+    #[clap(long, value_parser = parse_duration)]
+    log_compact_interval: Option<Duration>,
+    /// If node joins the cluster as a learner
+    #[clap(long)]
+    is_learner: bool,
+    /// If node is a witness, it votes and keeps the spec pool but never executes commands
+    #[clap(long)]
+    is_witness: bool,
+    /// Run a pre-vote round before starting an election
+    #[clap(long)]
+    pre_vote: bool,
+    /// Leader steps down if it hasn't heard from the majority within an election timeout
+    #[clap(long)]
+    check_quorum: bool,
+    /// Leader serves reads locally within its lease
+    #[clap(long)]
+    lease_read: bool,
+    /// The max clock drift between servers, the lease of the leader is shortened by it [default: 100ms]
+    #[clap(long, value_parser = parse_duration)]
+    max_clock_drift: Option<Duration>,
+    /// The max number of log batches sent to a follower without waiting for the responses
+    #[clap(long, default_value_t = default_max_inflight_batches())]
+    max_inflight_batches: usize,
+    /// Storage backend of the curp log, eg: rocksdb, wal
+    #[clap(long, value_parser = parse_curp_storage_backend, default_value_t = default_curp_storage_backend())]
+    curp_storage_backend: CurpStorageBackend,
+    /// The max size of a curp WAL segment file in bytes, only used by the wal backend [default: 64MB]
+    #[clap(long, default_value_t = default_wal_segment_size())]
+    wal_segment_size: u64,
+    /// Persist the curp speculative pool, so that the witnessed commands survive crashes
+    #[clap(long)]
+    persist_spec_pool: bool,
+    /// How long an idle curp client session lives before it's expired [default: 600s]
+    #[clap(long, value_parser = parse_duration)]
+    session_timeout: Option<Duration>,
+    /// Auto compaction mode, eg: periodic, revision. Auto compaction is disabled if not set
+    #[clap(long, requires = "auto_compact_retention", value_parser = ["periodic", "revision"])]
+    auto_compact_mode: Option<String>,
+    /// Auto compaction retention, a duration (eg: 3600s) in periodic mode, or a number of revisions in revision mode
+    #[clap(long, value_parser = parse_auto_compact_retention)]
+    auto_compact_retention: Option<AutoCompactConfig>,
 }
 
 impl From<ServerArgs> for XlineServerConfig {
@@ -247,13 +310,32 @@ impl From<ServerArgs> for XlineServerConfig {
                 }))
             .gc_interval(args.gc_interval.unwrap_or_else(default_gc_interval))
             .cmd_workers(args.cmd_workers)
+            .log_entries_cap(args.log_entries_cap)
+            .log_compact_interval(args.log_compact_interval
+                .unwrap_or_else(default_log_compact_interval))
+            .is_learner(args.is_learner)
+            .is_witness(args.is_witness)
+            .pre_vote(args.pre_vote)
+            .check_quorum(args.check_quorum)
+            .lease_read(args.lease_read)
+            .max_clock_drift(args.max_clock_drift.unwrap_or_else(default_max_clock_drift))
+            .max_inflight_batches(args.max_inflight_batches)
+            .storage_backend(args.curp_storage_backend)
+            .wal_segment_size(args.wal_segment_size)
+            .persist_spec_pool(args.persist_spec_pool)
+            .session_timeout(args.session_timeout.unwrap_or_else(default_session_timeout))
             .build() else {unreachable!()};
 
-        let storage = match args.storage_engine.as_str() {
-            "memory" => StorageConfig::Memory,
-            "rocksdb" => StorageConfig::RocksDB(args.data_dir),
+        let engine = match args.storage_engine.as_str() {
+            "memory" => EngineConfig::Memory,
+            "rocksdb" => EngineConfig::RocksDB(args.data_dir),
             &_ => unreachable!(),
         };
+        let storage = StorageConfig::new(
+            engine,
+            args.quota_backend_bytes
+                .unwrap_or_else(default_quota_backend_bytes),
+        );
 
         let client_timeout = ClientTimeout::new(
             args.client_wait_synced_timeout
@@ -267,6 +349,12 @@ impl From<ServerArgs> for XlineServerConfig {
         let range_retry_timeout = args
             .range_retry_timeout
             .unwrap_or_else(default_range_retry_timeout);
+        let watch_max_message_size = args
+            .watch_max_message_size
+            .unwrap_or_else(default_watch_max_message_size);
+        let corrupt_check_interval = args
+            .corrupt_check_interval
+            .unwrap_or_else(default_corrupt_check_interval);
         let cluster = ClusterConfig::new(
             args.name,
             args.members,
@@ -274,6 +362,9 @@ impl From<ServerArgs> for XlineServerConfig {
             curp_config,
             client_timeout,
             range_retry_timeout,
+            watch_max_message_size,
+            corrupt_check_interval,
+            args.transfer_leadership_on_shutdown,
         );
         let log = LogConfig::new(args.log_file, args.log_rotate, args.log_level);
         let trace = TraceConfig::new(
@@ -283,7 +374,23 @@ impl From<ServerArgs> for XlineServerConfig {
             args.jaeger_level,
         );
         let auth = AuthConfig::new(args.auth_public_key, args.auth_private_key);
-        XlineServerConfig::new(cluster, storage, log, trace, auth)
+        let auto_compact_config = args.auto_compact_mode.map(|mode| {
+            let retention = args.auto_compact_retention.unwrap_or_else(|| {
+                unreachable!("auto_compact_retention is required by auto_compact_mode")
+            });
+            match (mode.as_str(), retention) {
+                ("periodic", AutoCompactConfig::Periodic(_))
+                | ("revision", AutoCompactConfig::Revision(_)) => retention,
+                _ => ServerArgs::command()
+                    .error(
+                        ErrorKind::InvalidValue,
+                        format!("invalid auto compaction retention for the {mode} mode"),
+                    )
+                    .exit(),
+            }
+        });
+        let compact = CompactConfig::new(auto_compact_config);
+        XlineServerConfig::new(cluster, storage, log, trace, auth, compact)
     }
 }
 
@@ -418,7 +525,7 @@ async fn main() -> Result<()> {
     debug!("server_addr = {:?}", self_addr);
     debug!("cluster_peers = {:?}", cluster_config.members());
 
-    let db_proxy = DBProxy::open(storage_config)?;
+    let db_proxy = DBProxy::open(storage_config.engine())?;
     let server = XlineServer::new(
         cluster_config.name().clone(),
         cluster_config.members().clone(),
@@ -427,6 +534,11 @@ async fn main() -> Result<()> {
         cluster_config.curp_config().clone(),
         *cluster_config.client_timeout(),
         *cluster_config.range_retry_timeout(),
+        *cluster_config.watch_max_message_size(),
+        *cluster_config.corrupt_check_interval(),
+        *cluster_config.transfer_leadership_on_shutdown(),
+        *storage_config.quota_backend_bytes(),
+        *config.compact(),
         db_proxy,
     )
     .await;
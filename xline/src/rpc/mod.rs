@@ -38,25 +38,29 @@ pub use self::etcdserverpb::range_request::{SortOrder, SortTarget};
 pub(crate) use self::{
     authpb::{permission::Type, Permission, Role, User},
     etcdserverpb::{
+        alarm_request::AlarmAction,
         auth_server::{Auth, AuthServer},
+        cluster_server::{Cluster, ClusterServer},
         compare::{CompareResult, CompareTarget, TargetUnion},
         kv_server::{Kv, KvServer},
         lease_client::LeaseClient,
         lease_server::{Lease, LeaseServer},
+        maintenance_client::MaintenanceClient,
         maintenance_server::{Maintenance, MaintenanceServer},
         request_op::Request,
         response_op::Response,
         watch_client::WatchClient,
         watch_request::RequestUnion,
         watch_server::{Watch, WatchServer},
-        AlarmRequest, AlarmResponse, AuthDisableRequest, AuthDisableResponse, AuthEnableRequest,
-        AuthEnableResponse, AuthRoleAddRequest, AuthRoleAddResponse, AuthRoleDeleteRequest,
-        AuthRoleDeleteResponse, AuthRoleGetRequest, AuthRoleGetResponse,
-        AuthRoleGrantPermissionRequest, AuthRoleGrantPermissionResponse, AuthRoleListRequest,
-        AuthRoleListResponse, AuthRoleRevokePermissionRequest, AuthRoleRevokePermissionResponse,
-        AuthStatusRequest, AuthStatusResponse, AuthUserAddRequest, AuthUserAddResponse,
-        AuthUserChangePasswordRequest, AuthUserChangePasswordResponse, AuthUserDeleteRequest,
-        AuthUserDeleteResponse, AuthUserGetRequest, AuthUserGetResponse, AuthUserGrantRoleRequest,
+        AlarmMember, AlarmRequest, AlarmResponse, AlarmType, AuthDisableRequest,
+        AuthDisableResponse, AuthEnableRequest, AuthEnableResponse, AuthRoleAddRequest,
+        AuthRoleAddResponse, AuthRoleDeleteRequest, AuthRoleDeleteResponse, AuthRoleGetRequest,
+        AuthRoleGetResponse, AuthRoleGrantPermissionRequest, AuthRoleGrantPermissionResponse,
+        AuthRoleListRequest, AuthRoleListResponse, AuthRoleRevokePermissionRequest,
+        AuthRoleRevokePermissionResponse, AuthStatusRequest, AuthStatusResponse,
+        AuthUserAddRequest, AuthUserAddResponse, AuthUserChangePasswordRequest,
+        AuthUserChangePasswordResponse, AuthUserDeleteRequest, AuthUserDeleteResponse,
+        AuthUserGetRequest, AuthUserGetResponse, AuthUserGrantRoleRequest,
         AuthUserGrantRoleResponse, AuthUserListRequest, AuthUserListResponse,
         AuthUserRevokeRoleRequest, AuthUserRevokeRoleResponse, AuthenticateRequest,
         AuthenticateResponse, CompactionRequest, CompactionResponse, Compare, DefragmentRequest,
@@ -64,11 +68,13 @@ pub(crate) use self::{
         DowngradeResponse, HashKvRequest, HashKvResponse, HashRequest, HashResponse,
         LeaseGrantRequest, LeaseGrantResponse, LeaseKeepAliveRequest, LeaseKeepAliveResponse,
         LeaseLeasesRequest, LeaseLeasesResponse, LeaseRevokeRequest, LeaseRevokeResponse,
-        LeaseStatus, LeaseTimeToLiveRequest, LeaseTimeToLiveResponse, MoveLeaderRequest,
-        MoveLeaderResponse, PutRequest, PutResponse, RangeRequest, RangeResponse, RequestOp,
-        ResponseHeader, ResponseOp, SnapshotRequest, SnapshotResponse, StatusRequest,
-        StatusResponse, TxnRequest, TxnResponse, WatchCancelRequest, WatchCreateRequest,
-        WatchRequest, WatchResponse,
+        LeaseStatus, LeaseTimeToLiveRequest, LeaseTimeToLiveResponse, Member, MemberAddRequest,
+        MemberAddResponse, MemberListRequest, MemberListResponse, MemberPromoteRequest,
+        MemberPromoteResponse, MemberRemoveRequest, MemberRemoveResponse, MemberUpdateRequest,
+        MemberUpdateResponse, MoveLeaderRequest, MoveLeaderResponse, PutRequest, PutResponse,
+        RangeRequest, RangeResponse, RequestOp, ResponseHeader, ResponseOp, SnapshotRequest,
+        SnapshotResponse, StatusRequest, StatusResponse, TxnRequest, TxnResponse,
+        WatchCancelRequest, WatchCreateRequest, WatchRequest, WatchResponse,
     },
     leasepb::Lease as PbLease,
     mvccpb::{event::EventType, Event, KeyValue},
@@ -146,6 +152,8 @@ pub(crate) enum RequestWrapper {
     LeaseGrantRequest(LeaseGrantRequest),
     /// `LeaseRevokeRequest`
     LeaseRevokeRequest(LeaseRevokeRequest),
+    /// `AlarmRequest`
+    AlarmRequest(AlarmRequest),
 }
 
 /// Wrapper for responses
@@ -200,6 +208,8 @@ pub(crate) enum ResponseWrapper {
     LeaseGrantResponse(LeaseGrantResponse),
     /// `LeaseRevokeResponse`
     LeaseRevokeResponse(LeaseRevokeResponse),
+    /// `AlarmResponse`
+    AlarmResponse(AlarmResponse),
 }
 
 impl ResponseWrapper {
@@ -230,6 +240,7 @@ impl ResponseWrapper {
             ResponseWrapper::AuthenticateResponse(ref mut resp) => &mut resp.header,
             ResponseWrapper::LeaseGrantResponse(ref mut resp) => &mut resp.header,
             ResponseWrapper::LeaseRevokeResponse(ref mut resp) => &mut resp.header,
+            ResponseWrapper::AlarmResponse(ref mut resp) => &mut resp.header,
         };
         if let Some(ref mut header) = *header {
             header.revision = revision;
@@ -246,6 +257,8 @@ pub(crate) enum RequestBackend {
     Auth,
     /// Lease backend
     Lease,
+    /// Alarm backend
+    Alarm,
 }
 
 impl RequestWrapper {
@@ -277,6 +290,7 @@ impl RequestWrapper {
             RequestWrapper::LeaseGrantRequest(_) | RequestWrapper::LeaseRevokeRequest(_) => {
                 RequestBackend::Lease
             }
+            RequestWrapper::AlarmRequest(_) => RequestBackend::Alarm,
         }
     }
 
@@ -306,6 +320,11 @@ impl RequestWrapper {
     pub(crate) fn is_lease_request(&self) -> bool {
         self.backend() == RequestBackend::Lease
     }
+
+    /// Check if this request is a alarm request
+    pub(crate) fn is_alarm_request(&self) -> bool {
+        self.backend() == RequestBackend::Alarm
+    }
 }
 
 /// impl `From` trait for all request types
@@ -376,7 +395,8 @@ impl_from_requests!(
     AuthUserRevokeRoleRequest,
     AuthenticateRequest,
     LeaseGrantRequest,
-    LeaseRevokeRequest
+    LeaseRevokeRequest,
+    AlarmRequest
 );
 
 impl_from_responses!(
@@ -403,7 +423,8 @@ impl_from_responses!(
     AuthUserRevokeRoleResponse,
     AuthenticateResponse,
     LeaseGrantResponse,
-    LeaseRevokeResponse
+    LeaseRevokeResponse,
+    AlarmResponse
 );
 
 impl From<RequestOp> for RequestWrapper {
@@ -0,0 +1,137 @@
+use std::{collections::VecDeque, sync::Arc, time::Duration};
+
+use clippy_utilities::OverflowArithmetic;
+use tokio::time::{self, Instant};
+use tracing::{info, warn};
+use utils::config::AutoCompactConfig;
+
+use super::kv_server::KvServer;
+use crate::{
+    rpc::{CompactionRequest, Kv},
+    state::State,
+    storage::{storage_api::StorageApi, AuthStore, KvStore},
+};
+
+/// How often the revision compactor checks the current revision
+const REVISION_COMPACTOR_INTERVAL: Duration = Duration::from_secs(300);
+
+/// How many revision samples the periodic compactor takes in one retention period
+const PERIODIC_COMPACTOR_SAMPLES: u32 = 10;
+
+/// Auto compactor, it proposes `CompactionRequest` periodically on the leader
+#[derive(Debug)]
+pub(crate) struct AutoCompactor<S>
+where
+    S: StorageApi,
+{
+    /// KV server, used to propose compaction
+    kv_server: Arc<KvServer<S>>,
+    /// KV storage
+    kv_storage: Arc<KvStore<S>>,
+    /// Auth storage
+    auth_storage: Arc<AuthStore<S>>,
+    /// State of current node
+    state: Arc<State>,
+}
+
+impl<S> AutoCompactor<S>
+where
+    S: StorageApi,
+{
+    /// New `AutoCompactor`
+    pub(crate) fn new(
+        kv_server: Arc<KvServer<S>>,
+        kv_storage: Arc<KvStore<S>>,
+        auth_storage: Arc<AuthStore<S>>,
+        state: Arc<State>,
+    ) -> Self {
+        Self {
+            kv_server,
+            kv_storage,
+            auth_storage,
+            state,
+        }
+    }
+
+    /// Run the auto compactor in the given mode
+    pub(crate) async fn run(self, config: AutoCompactConfig) {
+        match config {
+            AutoCompactConfig::Periodic(retention) => self.run_periodic(retention).await,
+            AutoCompactConfig::Revision(retention) => self.run_revision(retention).await,
+            _ => unreachable!("unknown auto compact config: {config:?}"),
+        }
+    }
+
+    /// Compact the revisions that are older than `retention`. The compactor samples
+    /// the current revision several times in each retention period, and compacts to
+    /// the latest sample that has been taken at least `retention` ago.
+    async fn run_periodic(self, retention: Duration) {
+        let interval = retention / PERIODIC_COMPACTOR_SAMPLES;
+        let mut samples: VecDeque<(Instant, i64)> = VecDeque::new();
+        loop {
+            // grab the listener before the check to prevent missing the leader change
+            let listener = self.state.leader_listener();
+            // only leader will propose compaction
+            if self.state.is_leader() {
+                samples.push_back((Instant::now(), self.kv_storage.revision()));
+                let mut target = None;
+                while let Some(&(sampled_at, revision)) = samples.front() {
+                    if sampled_at.elapsed() < retention {
+                        break;
+                    }
+                    target = Some(revision);
+                    let _ignore = samples.pop_front();
+                }
+                if let Some(revision) = target {
+                    self.compact(revision).await;
+                }
+            } else {
+                // samples taken in the previous term are stale
+                samples.clear();
+                listener.await;
+            }
+
+            time::sleep(interval).await;
+        }
+    }
+
+    /// Only keep the latest `retention` revisions
+    async fn run_revision(self, retention: i64) {
+        loop {
+            // grab the listener before the check to prevent missing the leader change
+            let listener = self.state.leader_listener();
+            // only leader will propose compaction
+            if self.state.is_leader() {
+                let target = self.kv_storage.revision().overflow_sub(retention);
+                self.compact(target).await;
+            } else {
+                listener.await;
+            }
+
+            time::sleep(REVISION_COMPACTOR_INTERVAL).await;
+        }
+    }
+
+    /// Propose a compaction at `revision`, skip it if `revision` has been compacted
+    async fn compact(&self, revision: i64) {
+        if revision <= 0 || revision <= self.kv_storage.compacted_revision() {
+            return;
+        }
+        let mut request = tonic::Request::new(CompactionRequest {
+            revision,
+            physical: false,
+        });
+        if let Ok(token) = self.auth_storage.root_token() {
+            let _ignore = request.metadata_mut().insert(
+                "token",
+                token
+                    .parse()
+                    .unwrap_or_else(|e| panic!("metadata value parse error: {e}")),
+            );
+        }
+        match self.kv_server.compact(request).await {
+            Ok(_) => info!("auto compactor compacted at revision {revision}"),
+            Err(e) => warn!("auto compactor failed to compact at revision {revision}: {e}"),
+        }
+    }
+}
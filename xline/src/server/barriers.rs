@@ -1,10 +1,14 @@
-use std::collections::{BTreeMap, HashMap};
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
 
 use clippy_utilities::OverflowArithmetic;
 use curp::cmd::ProposeId;
 use event_listener::Event;
 use parking_lot::Mutex;
 
+/// Number of the latest triggered ids kept by `IdBarrier`, an id may be triggered right
+/// before someone starts to wait for it
+const TRIGGERED_IDS_CAP: usize = 4096;
+
 /// Waiter for index
 #[derive(Debug)]
 pub(crate) struct IndexBarrier {
@@ -63,37 +67,66 @@ struct IndexWaiterInner {
 /// Waiter for id
 #[derive(Debug)]
 pub(crate) struct IdBarrier {
-    /// Waiters of id
-    waiters: Mutex<HashMap<ProposeId, Event>>,
+    /// Inner
+    inner: Mutex<IdWaiterInner>,
 }
 
 impl IdBarrier {
     /// Create a new id waiter
     pub(crate) fn new() -> Self {
         Self {
-            waiters: Mutex::new(HashMap::new()),
+            inner: Mutex::new(IdWaiterInner {
+                waiters: HashMap::new(),
+                triggered: HashSet::new(),
+                triggered_order: VecDeque::new(),
+            }),
         }
     }
 
     /// Wait for the id until it is triggered.
     pub(crate) async fn wait(&self, id: ProposeId) {
-        let listener = self
-            .waiters
-            .lock()
-            .entry(id)
-            .or_insert_with(Event::new)
-            .listen();
+        let listener = {
+            let mut inner_l = self.inner.lock();
+            if inner_l.triggered.contains(&id) {
+                return;
+            }
+            inner_l
+                .waiters
+                .entry(id)
+                .or_insert_with(Event::new)
+                .listen()
+        };
         listener.await;
     }
 
     /// Trigger the waiter of the given id.
     pub(crate) fn trigger(&self, id: &ProposeId) {
-        if let Some(event) = self.waiters.lock().remove(id) {
+        let mut inner_l = self.inner.lock();
+        if let Some(event) = inner_l.waiters.remove(id) {
             event.notify(usize::MAX);
         }
+        if inner_l.triggered.insert(id.clone()) {
+            inner_l.triggered_order.push_back(id.clone());
+        }
+        if inner_l.triggered_order.len() > TRIGGERED_IDS_CAP {
+            if let Some(oldest) = inner_l.triggered_order.pop_front() {
+                let _ignore = inner_l.triggered.remove(&oldest);
+            }
+        }
     }
 }
 
+/// Inner of id waiter.
+#[derive(Debug)]
+struct IdWaiterInner {
+    /// Waiters of id.
+    waiters: HashMap<ProposeId, Event>,
+    /// The latest triggered ids.
+    triggered: HashSet<ProposeId>,
+    /// The latest triggered ids in the order they are triggered.
+    triggered_order: VecDeque<ProposeId>,
+}
+
 #[cfg(test)]
 mod test {
     use std::{sync::Arc, time::Duration};
@@ -123,6 +156,18 @@ mod test {
             .unwrap();
     }
 
+    #[tokio::test]
+    async fn test_id_waiter_after_triggered() {
+        let id_waiter = IdBarrier::new();
+        id_waiter.trigger(&ProposeId::new("1".to_owned()));
+        timeout(
+            Duration::from_millis(100),
+            id_waiter.wait(ProposeId::new("1".to_owned())),
+        )
+        .await
+        .unwrap();
+    }
+
     #[tokio::test]
     async fn test_index_waiter() {
         let index_waiter = Arc::new(IndexBarrier::new());
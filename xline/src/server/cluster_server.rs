@@ -0,0 +1,243 @@
+use std::{collections::hash_map::DefaultHasher, hash::Hasher, sync::Arc};
+
+use curp::{client::Client, error::ProposeError, members::ConfChange, server::Rpc};
+use tracing::debug;
+use uuid::Uuid;
+
+use super::command::Command;
+use crate::{
+    header_gen::HeaderGenerator,
+    rpc::{
+        Cluster, Member, MemberAddRequest, MemberAddResponse, MemberListRequest,
+        MemberListResponse, MemberPromoteRequest, MemberPromoteResponse, MemberRemoveRequest,
+        MemberRemoveResponse, MemberUpdateRequest, MemberUpdateResponse,
+    },
+    state::State,
+};
+
+/// Calculate the member id from the member name, so that all servers agree on it
+pub(super) fn calc_member_id(name: &str, cluster_name: &str) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    hasher.write(name.as_bytes());
+    hasher.write(cluster_name.as_bytes());
+    hasher.finish()
+}
+
+/// Calculate the cluster id from the urls of the initial members
+pub(super) fn calc_cluster_id(member_urls: &[&str], cluster_name: &str) -> u64 {
+    let mut urls = member_urls.to_vec();
+    // urls are sorted so that the cluster id doesn't depend on their order
+    urls.sort_unstable();
+    let mut hasher = DefaultHasher::new();
+    for url in urls {
+        hasher.write(url.as_bytes());
+    }
+    hasher.write(cluster_name.as_bytes());
+    hasher.finish()
+}
+
+/// Cluster Server
+#[derive(Debug)]
+pub(crate) struct ClusterServer {
+    /// Consensus client
+    client: Arc<Client<Command>>,
+    /// Curp server, used to get the current membership
+    curp_server: Rpc<Command>,
+    /// State of current node
+    state: Arc<State>,
+    /// Header generator
+    header_gen: Arc<HeaderGenerator>,
+}
+
+impl ClusterServer {
+    /// New `ClusterServer`
+    pub(crate) fn new(
+        client: Arc<Client<Command>>,
+        curp_server: Rpc<Command>,
+        state: Arc<State>,
+        header_gen: Arc<HeaderGenerator>,
+    ) -> Self {
+        Self {
+            client,
+            curp_server,
+            state,
+            header_gen,
+        }
+    }
+
+    /// Get all members from the membership of the current node
+    fn members(&self) -> Vec<Member> {
+        self.curp_server
+            .members()
+            .into_iter()
+            .map(|info| {
+                // curp only knows the addresses of other members
+                let url = to_url(
+                    info.addr
+                        .as_deref()
+                        .unwrap_or_else(|| self.state.self_address()),
+                );
+                Member {
+                    id: calc_member_id(&info.id, ""),
+                    name: info.id,
+                    peer_ur_ls: vec![url.clone()],
+                    client_ur_ls: vec![url],
+                    is_learner: info.is_learner,
+                }
+            })
+            .collect()
+    }
+
+    /// Get the name of the member with the given id
+    fn member_name(&self, id: u64) -> Result<String, tonic::Status> {
+        self.curp_server
+            .members()
+            .into_iter()
+            .find(|info| calc_member_id(&info.id, "") == id)
+            .map(|info| info.id)
+            .ok_or_else(|| tonic::Status::not_found("etcdserver: member not found"))
+    }
+
+    /// Propose a conf change through curp and wait for it to be applied
+    async fn propose_conf_change(&self, change: ConfChange) -> Result<(), tonic::Status> {
+        debug!("propose conf change {change:?}");
+        self.client
+            .propose_conf_change(change)
+            .await
+            .map_err(|err| match err {
+                ProposeError::InvalidConfChange(e) => tonic::Status::failed_precondition(e),
+                ProposeError::LearnerNotCatchUp => tonic::Status::failed_precondition(
+                    "etcdserver: can only promote a learner member which is in sync with leader",
+                ),
+                ProposeError::SyncedError(e) => tonic::Status::unavailable(e),
+                _ => tonic::Status::internal(format!("propose conf change failed: {err}")),
+            })
+    }
+}
+
+/// Get the url of a member address. A member serves both peers and clients on the same
+/// address, so its peer urls and client urls are the same
+fn to_url(addr: &str) -> String {
+    if addr.starts_with("http://") || addr.starts_with("https://") {
+        addr.to_owned()
+    } else {
+        format!("http://{addr}")
+    }
+}
+
+#[tonic::async_trait]
+impl Cluster for ClusterServer {
+    /// A new member is always added as a learner, since it can't catch up with the leader
+    /// until it's started. A voting member should be promoted by `MemberPromote` once the
+    /// learner is in sync with the leader
+    async fn member_add(
+        &self,
+        request: tonic::Request<MemberAddRequest>,
+    ) -> Result<tonic::Response<MemberAddResponse>, tonic::Status> {
+        let req = request.into_inner();
+        let Some(addr) = req.peer_ur_ls.first().cloned() else {
+            return Err(tonic::Status::invalid_argument(
+                "etcdserver: member peer urls not provided",
+            ));
+        };
+        // the new member is named by the time it's added, the name should be used to start it
+        let name = Uuid::new_v4().simple().to_string();
+
+        self.propose_conf_change(ConfChange::AddLearner(name.clone(), addr))
+            .await?;
+        // the new member hasn't served clients yet
+        let member = Member {
+            id: calc_member_id(&name, ""),
+            name,
+            peer_ur_ls: req.peer_ur_ls,
+            client_ur_ls: vec![],
+            is_learner: true,
+        };
+        Ok(tonic::Response::new(MemberAddResponse {
+            header: Some(self.header_gen.gen_header_without_revision()),
+            member: Some(member),
+            members: self.members(),
+        }))
+    }
+
+    async fn member_remove(
+        &self,
+        request: tonic::Request<MemberRemoveRequest>,
+    ) -> Result<tonic::Response<MemberRemoveResponse>, tonic::Status> {
+        let name = self.member_name(request.into_inner().id)?;
+        self.propose_conf_change(ConfChange::RemoveNode(name))
+            .await?;
+        Ok(tonic::Response::new(MemberRemoveResponse {
+            header: Some(self.header_gen.gen_header_without_revision()),
+            members: self.members(),
+        }))
+    }
+
+    async fn member_update(
+        &self,
+        request: tonic::Request<MemberUpdateRequest>,
+    ) -> Result<tonic::Response<MemberUpdateResponse>, tonic::Status> {
+        let req = request.into_inner();
+        let name = self.member_name(req.id)?;
+        let Some(addr) = req.peer_ur_ls.into_iter().next() else {
+            return Err(tonic::Status::invalid_argument(
+                "etcdserver: member peer urls not provided",
+            ));
+        };
+        self.propose_conf_change(ConfChange::UpdateNode(name, addr))
+            .await?;
+        Ok(tonic::Response::new(MemberUpdateResponse {
+            header: Some(self.header_gen.gen_header_without_revision()),
+            members: self.members(),
+        }))
+    }
+
+    async fn member_list(
+        &self,
+        _request: tonic::Request<MemberListRequest>,
+    ) -> Result<tonic::Response<MemberListResponse>, tonic::Status> {
+        // the membership is served locally, it may lag behind the leader for a short while
+        Ok(tonic::Response::new(MemberListResponse {
+            header: Some(self.header_gen.gen_header_without_revision()),
+            members: self.members(),
+        }))
+    }
+
+    async fn member_promote(
+        &self,
+        request: tonic::Request<MemberPromoteRequest>,
+    ) -> Result<tonic::Response<MemberPromoteResponse>, tonic::Status> {
+        let name = self.member_name(request.into_inner().id)?;
+        self.propose_conf_change(ConfChange::PromoteLearner(name))
+            .await?;
+        Ok(tonic::Response::new(MemberPromoteResponse {
+            header: Some(self.header_gen.gen_header_without_revision()),
+            members: self.members(),
+        }))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn member_id_should_be_deterministic() {
+        assert_eq!(calc_member_id("node1", ""), calc_member_id("node1", ""));
+        assert_ne!(calc_member_id("node1", ""), calc_member_id("node2", ""));
+    }
+
+    #[test]
+    fn member_url_should_have_scheme() {
+        assert_eq!(to_url("127.0.0.1:2379"), "http://127.0.0.1:2379");
+        assert_eq!(to_url("http://127.0.0.1:2379"), "http://127.0.0.1:2379");
+    }
+
+    #[test]
+    fn cluster_id_should_not_depend_on_url_order() {
+        assert_eq!(
+            calc_cluster_id(&["127.0.0.1:2379", "127.0.0.1:2380"], ""),
+            calc_cluster_id(&["127.0.0.1:2380", "127.0.0.1:2379"], "")
+        );
+    }
+}
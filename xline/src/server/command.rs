@@ -12,17 +12,23 @@ use curp::{
 use engine::engine_api::SnapshotApi;
 use itertools::Itertools;
 use serde::{Deserialize, Serialize};
+use tracing::warn;
 
 use super::barriers::{IdBarrier, IndexBarrier};
 use crate::{
     rpc::{RequestBackend, RequestWithToken, RequestWrapper, ResponseWrapper},
-    storage::{db::WriteOp, storage_api::StorageApi, AuthStore, ExecuteError, KvStore, LeaseStore},
+    storage::{
+        db::WriteOp, storage_api::StorageApi, AlarmStore, AuthStore, ExecuteError, KvStore,
+        LeaseStore,
+    },
 };
 
 /// Meta table name
 pub(crate) const META_TABLE: &str = "meta";
 /// Key of applied index
 pub(crate) const APPLIED_INDEX_KEY: &str = "applied_index";
+/// Key of compacted revision
+pub(crate) const COMPACT_REVISION_KEY: &str = "compact_revision";
 
 /// Range start and end to get all keys
 const UNBOUNDED: &[u8] = &[0_u8];
@@ -209,6 +215,8 @@ where
     auth_storage: Arc<AuthStore<S>>,
     /// Lease Storage
     lease_storage: Arc<LeaseStore<S>>,
+    /// Alarm Storage
+    alarm_storage: Arc<AlarmStore<S>>,
     /// persistent storage
     persistent: Arc<S>,
     /// Barrier for applied index
@@ -226,6 +234,7 @@ where
         kv_storage: Arc<KvStore<S>>,
         auth_storage: Arc<AuthStore<S>>,
         lease_storage: Arc<LeaseStore<S>>,
+        alarm_storage: Arc<AlarmStore<S>>,
         persistent: Arc<S>,
         index_barrier: Arc<IndexBarrier>,
         id_barrier: Arc<IdBarrier>,
@@ -234,11 +243,21 @@ where
             kv_storage,
             auth_storage,
             lease_storage,
+            alarm_storage,
             persistent,
             index_barrier,
             id_barrier,
         }
     }
+
+    /// Rebuild the in-memory states of storages from the persistent storage
+    async fn recover(&self) -> Result<(), ExecuteError> {
+        // lease storage must recover before kv storage
+        self.lease_storage.recover()?;
+        self.kv_storage.recover().await?;
+        self.auth_storage.recover()?;
+        self.alarm_storage.recover()
+    }
 }
 
 #[async_trait::async_trait]
@@ -255,6 +274,7 @@ where
             RequestBackend::Kv => self.kv_storage.execute(wrapper),
             RequestBackend::Auth => self.auth_storage.execute(wrapper),
             RequestBackend::Lease => self.lease_storage.execute(wrapper),
+            RequestBackend::Alarm => self.alarm_storage.execute(wrapper),
         }
     }
 
@@ -273,6 +293,7 @@ where
                 RequestBackend::Kv => self.kv_storage.after_sync(wrapper).await?,
                 RequestBackend::Auth => self.auth_storage.after_sync(wrapper)?,
                 RequestBackend::Lease => self.lease_storage.after_sync(wrapper).await?,
+                RequestBackend::Alarm => self.alarm_storage.after_sync(wrapper)?,
             };
             ops.append(&mut wr_ops);
             res = sync_res;
@@ -283,22 +304,30 @@ where
         Ok(res)
     }
 
-    #[allow(clippy::todo)]
     async fn reset(
         &self,
         snapshot: Option<(Box<dyn SnapshotApi>, LogIndex)>,
     ) -> Result<(), Self::Error> {
-        if snapshot.is_none() {
-            self.persistent.reset()
-        } else {
-            todo!("apply snapshot");
+        let Some((mut snapshot, index)) = snapshot else {
+            self.persistent.reset()?;
+            return self.recover().await;
+        };
+        // the persistent storage is replaced by the snapshot, rather than wiped before it, so
+        // that it's left intact if the snapshot can't be applied
+        self.persistent.apply_snapshot(snapshot.as_mut()).await?;
+        if let Err(e) = snapshot.clean().await {
+            warn!("failed to clean snapshot files: {e}");
         }
+        // the applied index in the snapshot may lag behind the index the snapshot includes
+        self.persistent
+            .flush_ops(vec![WriteOp::PutAppliedIndex(index)])?;
+        self.recover().await?;
+        self.index_barrier.trigger(index);
+        Ok(())
     }
 
-    /// Todo
-    #[allow(clippy::todo)]
     async fn snapshot(&self) -> Result<Box<dyn SnapshotApi>, Self::Error> {
-        todo!("snapshot");
+        self.persistent.get_snapshot()
     }
 
     fn last_applied(&self) -> Result<LogIndex, ExecuteError> {
@@ -343,6 +372,11 @@ impl ConflictCheck for Command {
             return true;
         }
 
+        // alarms must be raised and disarmed in order
+        if this_req.is_alarm_request() && other_req.is_alarm_request() {
+            return true;
+        }
+
         if (this_req.is_lease_request()) && (other_req.is_lease_request()) {
             #[allow(clippy::wildcard_enum_match_arm)]
             let lease_id1 = match *this_req {
@@ -436,4 +470,117 @@ impl CurpCommand for Command {
     fn id(&self) -> &ProposeId {
         &self.id
     }
+
+    fn id_mut(&mut self) -> &mut ProposeId {
+        &mut self.id
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{collections::HashMap, path::PathBuf};
+
+    use tokio::sync::mpsc;
+    use utils::config::EngineConfig;
+
+    use super::*;
+    use crate::{
+        header_gen::HeaderGenerator,
+        rpc::{PutRequest, RangeRequest},
+        state::State,
+        storage::{db::DBProxy, index::Index},
+    };
+
+    fn init_executor(db: Arc<DBProxy>) -> CommandExecutor<DBProxy> {
+        let header_gen = Arc::new(HeaderGenerator::new(0, 0));
+        let state = Arc::new(State::new("node".to_owned(), None, HashMap::new()));
+        let (lease_cmd_tx, lease_cmd_rx) = mpsc::channel(128);
+        let index = Arc::new(Index::new());
+        let kv_storage = Arc::new(KvStore::new(
+            lease_cmd_tx.clone(),
+            Arc::clone(&header_gen),
+            Arc::clone(&db),
+            Arc::clone(&index),
+        ));
+        let lease_storage = Arc::new(LeaseStore::new(
+            lease_cmd_rx,
+            state,
+            Arc::clone(&header_gen),
+            Arc::clone(&db),
+            index,
+            kv_storage.kv_update_tx(),
+        ));
+        let auth_storage = Arc::new(AuthStore::new(
+            lease_cmd_tx,
+            None,
+            Arc::clone(&header_gen),
+            Arc::clone(&db),
+        ));
+        let alarm_storage = Arc::new(AlarmStore::new(header_gen, Arc::clone(&db)));
+        CommandExecutor::new(
+            kv_storage,
+            auth_storage,
+            lease_storage,
+            alarm_storage,
+            db,
+            Arc::new(IndexBarrier::new()),
+            Arc::new(IdBarrier::new()),
+        )
+    }
+
+    #[tokio::test]
+    async fn test_reset_with_snapshot() -> Result<(), ExecuteError> {
+        let origin_data_dir = PathBuf::from("/tmp/test_reset_with_snapshot_origin");
+        let recover_data_dir = PathBuf::from("/tmp/test_reset_with_snapshot_recover");
+        let origin_db = Arc::new(DBProxy::open(&EngineConfig::RocksDB(
+            origin_data_dir.clone(),
+        ))?);
+        let recover_db = Arc::new(DBProxy::open(&EngineConfig::RocksDB(
+            recover_data_dir.clone(),
+        ))?);
+        let origin_ce = init_executor(origin_db);
+        let recover_ce = init_executor(recover_db);
+
+        let put = Command::new(
+            vec![KeyRange::new("key", "")],
+            RequestWithToken::new(
+                PutRequest {
+                    key: "key".into(),
+                    value: "value".into(),
+                    ..Default::default()
+                }
+                .into(),
+            ),
+            ProposeId::new("put".to_owned()),
+        );
+        let _er = origin_ce.execute(&put).await?;
+        let _asr = origin_ce.after_sync(&put, 1, true).await?;
+
+        let snapshot = origin_ce.snapshot().await?;
+        recover_ce.reset(Some((snapshot, 3))).await?;
+
+        let range = Command::new(
+            vec![KeyRange::new("key", "")],
+            RequestWithToken::new(
+                RangeRequest {
+                    key: "key".into(),
+                    ..Default::default()
+                }
+                .into(),
+            ),
+            ProposeId::new("range".to_owned()),
+        );
+        let ResponseWrapper::RangeResponse(res) = recover_ce.execute(&range).await?.decode() else {
+            panic!("expect a range response");
+        };
+        assert_eq!(res.kvs.len(), 1);
+        assert_eq!(res.kvs[0].key, b"key");
+        assert_eq!(res.kvs[0].value, b"value");
+        // the applied index is the index that the snapshot includes
+        assert_eq!(recover_ce.last_applied()?, 3);
+
+        std::fs::remove_dir_all(origin_data_dir).unwrap();
+        std::fs::remove_dir_all(recover_data_dir).unwrap();
+        Ok(())
+    }
 }
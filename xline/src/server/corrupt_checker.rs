@@ -0,0 +1,131 @@
+use std::{sync::Arc, time::Duration};
+
+use curp::{client::Client, server::Rpc};
+use tokio::time;
+use tracing::{error, info, warn};
+
+use super::{cluster_server::calc_member_id, command::Command, maintenance::raise_alarm};
+use crate::{
+    rpc::{AlarmType, HashKvRequest, MaintenanceClient},
+    state::State,
+    storage::{storage_api::StorageApi, AuthStore, KvStore},
+};
+
+/// Corruption checker, it compares the `HashKV` of all members at the same revision periodically
+/// on the leader, and raises a `CORRUPT` alarm for the members whose hashes diverge from the leader's
+#[derive(Debug)]
+pub(crate) struct CorruptChecker<S>
+where
+    S: StorageApi,
+{
+    /// Consensus client, used to raise alarms
+    client: Arc<Client<Command>>,
+    /// Curp server, used to get the addresses of other members
+    curp_server: Rpc<Command>,
+    /// KV storage
+    kv_storage: Arc<KvStore<S>>,
+    /// Auth storage
+    auth_storage: Arc<AuthStore<S>>,
+    /// State of current node
+    state: Arc<State>,
+}
+
+impl<S> CorruptChecker<S>
+where
+    S: StorageApi,
+{
+    /// New `CorruptChecker`
+    pub(crate) fn new(
+        client: Arc<Client<Command>>,
+        curp_server: Rpc<Command>,
+        kv_storage: Arc<KvStore<S>>,
+        auth_storage: Arc<AuthStore<S>>,
+        state: Arc<State>,
+    ) -> Self {
+        Self {
+            client,
+            curp_server,
+            kv_storage,
+            auth_storage,
+            state,
+        }
+    }
+
+    /// Check the members every `interval`
+    pub(crate) async fn run(self, interval: Duration) {
+        loop {
+            // grab the listener before the check to prevent missing the leader change
+            let listener = self.state.leader_listener();
+            // only leader will check the members
+            if self.state.is_leader() {
+                self.check().await;
+            } else {
+                listener.await;
+            }
+
+            time::sleep(interval).await;
+        }
+    }
+
+    /// Compare the hashes of other members with the leader's at the leader's current revision
+    async fn check(&self) {
+        let revision = self.kv_storage.revision();
+        let (hash, compact_revision) = match self.kv_storage.hash_kv(revision) {
+            Ok(res) => res,
+            Err(e) => {
+                warn!("corruption checker failed to hash at revision {revision}: {e}");
+                return;
+            }
+        };
+        for member in self.curp_server.members() {
+            // curp doesn't know the address of the current node
+            let Some(addr) = member.addr else {
+                continue;
+            };
+            let resp = match MaintenanceClient::connect(format!("http://{addr}")).await {
+                Ok(mut client) => client.hash_kv(HashKvRequest { revision }).await,
+                Err(e) => {
+                    warn!("corruption checker failed to connect to {}: {e}", member.id);
+                    continue;
+                }
+            };
+            // the member may lag behind or the revision may be compacted, check it next time
+            let resp = match resp {
+                Ok(resp) => resp.into_inner(),
+                Err(e) => {
+                    warn!(
+                        "corruption checker failed to get hash of {} at revision {revision}: {e}",
+                        member.id
+                    );
+                    continue;
+                }
+            };
+            // hashes are only comparable if the same revisions are compacted
+            if resp.compact_revision != compact_revision || resp.hash == hash {
+                continue;
+            }
+            error!(
+                "the hash of {} at revision {revision} is {}, but the leader's is {hash}",
+                member.id, resp.hash
+            );
+            self.raise_corrupt_alarm(calc_member_id(&member.id, ""))
+                .await;
+        }
+    }
+
+    /// Raise a `CORRUPT` alarm for the member
+    async fn raise_corrupt_alarm(&self, member_id: u64) {
+        match raise_alarm(
+            &self.client,
+            &self.auth_storage,
+            self.state.id(),
+            member_id,
+            AlarmType::Corrupt,
+        )
+        .await
+        {
+            Ok(()) => info!("corruption checker raised CORRUPT alarm for member {member_id}"),
+            Err(e) => warn!("corruption checker failed to raise alarm for member {member_id}: {e}"),
+        }
+    }
+}
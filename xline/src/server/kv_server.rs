@@ -17,15 +17,18 @@ use super::{
 };
 use crate::{
     rpc::{
-        CompactionRequest, CompactionResponse, DeleteRangeRequest, DeleteRangeResponse, Kv,
-        PutRequest, PutResponse, RangeRequest, RangeResponse, Request, RequestOp, RequestWithToken,
-        RequestWrapper, Response, ResponseOp, SortOrder, SortTarget, TxnRequest, TxnResponse,
+        AlarmType, CompactionRequest, CompactionResponse, DeleteRangeRequest, DeleteRangeResponse,
+        Kv, PutRequest, PutResponse, RangeRequest, RangeResponse, Request, RequestOp,
+        RequestWithToken, RequestWrapper, Response, ResponseOp, ResponseWrapper, SortOrder,
+        SortTarget, TxnRequest, TxnResponse,
     },
-    storage::{storage_api::StorageApi, AuthStore, KvStore},
+    storage::{storage_api::StorageApi, AlarmStore, AuthStore, ExecuteError, KvStore},
 };
 
 /// Default max txn ops
 const DEFAULT_MAX_TXN_OPS: usize = 128;
+/// Error message of the writes rejected because of the `NOSPACE` alarm
+pub(super) const NOSPACE_ERROR: &str = "etcdserver: mvcc: database space exceeded";
 
 /// KV Server
 #[derive(Debug)]
@@ -37,6 +40,8 @@ where
     kv_storage: Arc<KvStore<S>>,
     /// Auth storage
     auth_storage: Arc<AuthStore<S>>,
+    /// Alarm storage
+    alarm_storage: Arc<AlarmStore<S>>,
     /// Barrier for applied index
     index_barrier: Arc<IndexBarrier>,
     /// Barrier for propose id
@@ -54,9 +59,11 @@ where
     S: StorageApi,
 {
     /// New `KvServer`
+    #[allow(clippy::too_many_arguments)]
     pub(crate) fn new(
         kv_storage: Arc<KvStore<S>>,
         auth_storage: Arc<AuthStore<S>>,
+        alarm_storage: Arc<AlarmStore<S>>,
         index_barrier: Arc<IndexBarrier>,
         id_barrier: Arc<IdBarrier>,
         range_retry_timeout: Duration,
@@ -66,6 +73,7 @@ where
         Self {
             kv_storage,
             auth_storage,
+            alarm_storage,
             index_barrier,
             id_barrier,
             range_retry_timeout,
@@ -99,6 +107,8 @@ where
                 .iter()
                 .map(|cmp| KeyRange::new(cmp.key.as_slice(), cmp.range_end.as_slice()))
                 .collect(),
+            // compaction affects all keys
+            RequestWrapper::CompactionRequest(_) => vec![KeyRange::new(vec![0], vec![0])],
             _ => unreachable!("Other request should not be sent to this store"),
         };
         Command::new(key_ranges, wrapper, propose_id)
@@ -113,10 +123,12 @@ where
             .check_permission(wrapper)
             .await
             .map_err(|err| tonic::Status::invalid_argument(err.to_string()))?;
-        let cmd_res = self
-            .kv_storage
-            .execute(wrapper)
-            .map_err(|e| tonic::Status::internal(format!("Execute failed: {e:?}")))?;
+        let cmd_res = self.kv_storage.execute(wrapper).map_err(|e| match e {
+            ExecuteError::RevisionTooLarge(_, _) | ExecuteError::RevisionCompacted(_, _) => {
+                tonic::Status::from(e)
+            }
+            _ => tonic::Status::internal(format!("Execute failed: {e:?}")),
+        })?;
         let res = Self::parse_response_op(cmd_res.decode().into());
         if let Response::ResponseRange(response) = res {
             Ok(tonic::Response::new(response))
@@ -125,6 +137,31 @@ where
         }
     }
 
+    /// Execute read-only `TxnRequest` in current node
+    async fn serializable_txn(
+        &self,
+        wrapper: &RequestWithToken,
+    ) -> Result<tonic::Response<TxnResponse>, tonic::Status> {
+        self.auth_storage
+            .check_permission(wrapper)
+            .await
+            .map_err(|err| tonic::Status::invalid_argument(err.to_string()))?;
+        let revision = self.kv_storage.revision();
+        let cmd_res = self.kv_storage.execute(wrapper).map_err(|e| match e {
+            ExecuteError::RevisionTooLarge(_, _) | ExecuteError::RevisionCompacted(_, _) => {
+                tonic::Status::from(e)
+            }
+            _ => tonic::Status::internal(format!("Execute failed: {e:?}")),
+        })?;
+        let mut res = Self::parse_response_op(cmd_res.decode().into());
+        Self::update_header_revision(&mut res, revision);
+        if let Response::ResponseTxn(response) = res {
+            Ok(tonic::Response::new(response))
+        } else {
+            panic!("Receive wrong response {res:?} for TxnRequest");
+        }
+    }
+
     /// Propose request and get result with fast/slow path
     #[instrument(skip(self))]
     async fn propose<T>(
@@ -270,6 +307,59 @@ where
         Ok(())
     }
 
+    /// Check if the txn request contains any put request, including the nested ones
+    fn txn_has_put(req: &TxnRequest) -> bool {
+        req.success
+            .iter()
+            .chain(req.failure.iter())
+            .any(|op| match op.request {
+                Some(Request::RequestPut(_)) => true,
+                Some(Request::RequestTxn(ref r)) => Self::txn_has_put(r),
+                Some(Request::RequestRange(_) | Request::RequestDeleteRange(_)) | None => false,
+            })
+    }
+
+    /// Check if the txn request only contains range requests, including the nested ones
+    fn txn_is_read_only(req: &TxnRequest) -> bool {
+        req.success
+            .iter()
+            .chain(req.failure.iter())
+            .all(|op| match op.request {
+                Some(Request::RequestRange(_)) | None => true,
+                Some(Request::RequestTxn(ref r)) => Self::txn_is_read_only(r),
+                Some(Request::RequestPut(_) | Request::RequestDeleteRange(_)) => false,
+            })
+    }
+
+    /// Get the key ranges read by a read-only txn request, including the compared keys
+    fn read_only_txn_key_ranges(req: &TxnRequest) -> Vec<KeyRange> {
+        let mut key_ranges: Vec<_> = req
+            .compare
+            .iter()
+            .map(|cmp| KeyRange::new(cmp.key.as_slice(), cmp.range_end.as_slice()))
+            .collect();
+        for op in req.success.iter().chain(req.failure.iter()) {
+            match op.request {
+                Some(Request::RequestRange(ref r)) => {
+                    key_ranges.push(KeyRange::new(r.key.as_slice(), r.range_end.as_slice()));
+                }
+                Some(Request::RequestTxn(ref r)) => {
+                    key_ranges.extend(Self::read_only_txn_key_ranges(r));
+                }
+                Some(Request::RequestPut(_) | Request::RequestDeleteRange(_)) | None => {}
+            }
+        }
+        key_ranges
+    }
+
+    /// Reject the writes that need more space if the `NOSPACE` alarm is raised
+    fn check_space(&self) -> Result<(), tonic::Status> {
+        if self.alarm_storage.has_alarm(AlarmType::Nospace) {
+            return Err(tonic::Status::resource_exhausted(NOSPACE_ERROR));
+        }
+        Ok(())
+    }
+
     /// Check if puts and deletes overlap
     fn check_intervals(
         ops: &[RequestOp],
@@ -344,34 +434,34 @@ where
         Ok((puts, dels))
     }
 
-    /// Wait current node's state machine apply the conflict commands
+    /// Wait current node's state machine apply the conflict commands, the leadership has
+    /// been confirmed when the read state is returned
     async fn wait_read_state(&self, cmd: &Command) -> Result<(), tonic::Status> {
-        loop {
-            let rd_state = self
-                .client
-                .fetch_read_state(cmd)
-                .await
-                .map_err(|e| tonic::Status::internal(e.to_string()))?;
-            let wait_future = async move {
-                match rd_state {
-                    ReadState::Ids(ids) => {
-                        let fus = ids
-                            .into_iter()
-                            .map(|id| self.id_barrier.wait(id))
-                            .collect::<Vec<_>>();
-                        let _ignore = join_all(fus).await;
-                    }
-                    ReadState::CommitIndex(index) => {
-                        self.index_barrier.wait(index).await;
-                    }
-                    _ => unreachable!(),
+        let rd_state = self
+            .client
+            .fetch_read_state(cmd)
+            .await
+            .map_err(|e| tonic::Status::internal(e.to_string()))?;
+        let wait_future = async move {
+            match rd_state {
+                ReadState::Ids(ids) => {
+                    let fus = ids
+                        .into_iter()
+                        .map(|id| self.id_barrier.wait(id))
+                        .collect::<Vec<_>>();
+                    let _ignore = join_all(fus).await;
                 }
-            };
-            if timeout(self.range_retry_timeout, wait_future).await.is_ok() {
-                break;
-            };
-        }
-        Ok(())
+                ReadState::CommitIndex(index) => {
+                    self.index_barrier.wait(index).await;
+                }
+                _ => unreachable!(),
+            }
+        };
+        timeout(self.range_retry_timeout, wait_future)
+            .await
+            .map_err(|_elapsed| {
+                tonic::Status::unavailable("timed out waiting for the read state to be applied")
+            })
     }
 }
 
@@ -412,6 +502,7 @@ where
     ) -> Result<tonic::Response<PutResponse>, tonic::Status> {
         debug!("Receive PutRequest {:?}", request);
         Self::check_put_request(request.get_ref())?;
+        self.check_space()?;
         let is_fast_path = true;
         let (cmd_res, sync_res) = self.propose(request, is_fast_path).await?;
 
@@ -465,6 +556,20 @@ where
     ) -> Result<tonic::Response<TxnResponse>, tonic::Status> {
         debug!("Receive TxnRequest {:?}", request);
         Self::check_txn_request(request.get_ref())?;
+        // a read-only txn is served locally once the read state is applied, like a range request
+        if Self::txn_is_read_only(request.get_ref()) {
+            let key_ranges = Self::read_only_txn_key_ranges(request.get_ref());
+            let wrapper = match get_token(request.metadata()) {
+                Some(token) => RequestWithToken::new_with_token(request.into_inner().into(), token),
+                None => RequestWithToken::new(request.into_inner().into()),
+            };
+            let cmd = Command::new(key_ranges, wrapper, self.generate_propose_id());
+            self.wait_read_state(&cmd).await?;
+            return self.serializable_txn(cmd.request()).await;
+        }
+        if Self::txn_has_put(request.get_ref()) {
+            self.check_space()?;
+        }
         let is_fast_path = false; // lock need revision of txn
         let (cmd_res, sync_res) = self.propose(request, is_fast_path).await?;
 
@@ -490,10 +595,24 @@ where
         request: tonic::Request<CompactionRequest>,
     ) -> Result<tonic::Response<CompactionResponse>, tonic::Status> {
         debug!("Receive CompactionRequest {:?}", request);
-        Err(tonic::Status::new(
-            tonic::Code::Unimplemented,
-            "Not Implemented".to_owned(),
-        ))
+        let req = request.get_ref();
+        let current_revision = self.kv_storage.revision();
+        if req.revision > current_revision {
+            return Err(ExecuteError::RevisionTooLarge(req.revision, current_revision).into());
+        }
+        let compacted_revision = self.kv_storage.compacted_revision();
+        if req.revision <= compacted_revision {
+            return Err(ExecuteError::RevisionCompacted(req.revision, compacted_revision).into());
+        }
+        // a physical compaction returns after the compacted revisions are removed from the backend
+        let is_fast_path = !req.physical;
+        let (cmd_res, _sync_res) = self.propose(request, is_fast_path).await?;
+        let res = cmd_res.decode();
+        if let ResponseWrapper::CompactionResponse(response) = res {
+            Ok(tonic::Response::new(response))
+        } else {
+            panic!("Receive wrong response {res:?} for CompactionRequest");
+        }
     }
 }
 
@@ -547,4 +666,46 @@ mod test {
         let result = KvServer::<DB<MemoryEngine>>::check_txn_request(&txn_req);
         assert!(result.is_ok());
     }
+
+    #[test]
+    fn txn_read_only_check() {
+        let range_op = RequestOp {
+            request: Some(Request::RequestRange(RangeRequest {
+                key: b"foo".to_vec(),
+                range_end: b"fop".to_vec(),
+                ..Default::default()
+            })),
+        };
+        let mut txn_req = TxnRequest {
+            compare: vec![],
+            success: vec![range_op.clone()],
+            failure: vec![RequestOp {
+                request: Some(Request::RequestTxn(TxnRequest {
+                    compare: vec![],
+                    success: vec![range_op],
+                    failure: vec![],
+                })),
+            }],
+        };
+        assert!(KvServer::<DB<MemoryEngine>>::txn_is_read_only(&txn_req));
+        assert_eq!(
+            KvServer::<DB<MemoryEngine>>::read_only_txn_key_ranges(&txn_req).len(),
+            2
+        );
+
+        txn_req.failure = vec![RequestOp {
+            request: Some(Request::RequestTxn(TxnRequest {
+                compare: vec![],
+                success: vec![RequestOp {
+                    request: Some(Request::RequestPut(PutRequest {
+                        key: b"foo".to_vec(),
+                        value: b"bar".to_vec(),
+                        ..Default::default()
+                    })),
+                }],
+                failure: vec![],
+            })),
+        }];
+        assert!(!KvServer::<DB<MemoryEngine>>::txn_is_read_only(&txn_req));
+    }
 }
@@ -10,17 +10,18 @@ use uuid::Uuid;
 use super::{
     auth_server::get_token,
     command::{Command, CommandResponse, KeyRange, SyncResponse},
+    kv_server::NOSPACE_ERROR,
 };
 use crate::{
     id_gen::IdGenerator,
     rpc::{
-        Lease, LeaseClient, LeaseGrantRequest, LeaseGrantResponse, LeaseKeepAliveRequest,
-        LeaseKeepAliveResponse, LeaseLeasesRequest, LeaseLeasesResponse, LeaseRevokeRequest,
-        LeaseRevokeResponse, LeaseStatus, LeaseTimeToLiveRequest, LeaseTimeToLiveResponse,
-        RequestWithToken, RequestWrapper,
+        AlarmType, Lease, LeaseClient, LeaseGrantRequest, LeaseGrantResponse,
+        LeaseKeepAliveRequest, LeaseKeepAliveResponse, LeaseLeasesRequest, LeaseLeasesResponse,
+        LeaseRevokeRequest, LeaseRevokeResponse, LeaseStatus, LeaseTimeToLiveRequest,
+        LeaseTimeToLiveResponse, RequestWithToken, RequestWrapper,
     },
     state::State,
-    storage::{storage_api::StorageApi, AuthStore, LeaseStore},
+    storage::{storage_api::StorageApi, AlarmStore, AuthStore, LeaseStore},
 };
 
 /// Default channel size
@@ -38,6 +39,8 @@ where
     lease_storage: Arc<LeaseStore<S>>,
     /// Auth storage
     auth_storage: Arc<AuthStore<S>>,
+    /// Alarm storage
+    alarm_storage: Arc<AlarmStore<S>>,
     /// Consensus client
     client: Arc<Client<Command>>,
     /// Server name
@@ -56,6 +59,7 @@ where
     pub(crate) fn new(
         lease_storage: Arc<LeaseStore<S>>,
         auth_storage: Arc<AuthStore<S>>,
+        alarm_storage: Arc<AlarmStore<S>>,
         client: Arc<Client<Command>>,
         name: String,
         state: Arc<State>,
@@ -64,6 +68,7 @@ where
         let lease_server = Arc::new(Self {
             lease_storage,
             auth_storage,
+            alarm_storage,
             client,
             name,
             state,
@@ -262,6 +267,9 @@ where
         mut request: tonic::Request<LeaseGrantRequest>,
     ) -> Result<tonic::Response<LeaseGrantResponse>, tonic::Status> {
         debug!("Receive LeaseGrantRequest {:?}", request);
+        if self.alarm_storage.has_alarm(AlarmType::Nospace) {
+            return Err(tonic::Status::resource_exhausted(NOSPACE_ERROR));
+        }
         let lease_grant_req = request.get_mut();
         if lease_grant_req.id == 0 {
             lease_grant_req.id = self.id_gen.next();
@@ -1,20 +1,24 @@
 use std::sync::Arc;
 
 use clippy_utilities::{Cast, OverflowArithmetic};
+use curp::{client::Client, cmd::ProposeId, error::ProposeError, server::Rpc};
 use sha2::{Digest, Sha256};
-use tokio::sync::mpsc;
+use tokio::{sync::mpsc, task};
 use tokio_stream::wrappers::ReceiverStream;
-use tracing::error;
+use tracing::{debug, error};
+use uuid::Uuid;
 
+use super::{auth_server::get_token, cluster_server::calc_member_id, command::Command};
 use crate::{
     header_gen::HeaderGenerator,
     rpc::{
-        AlarmRequest, AlarmResponse, DefragmentRequest, DefragmentResponse, DowngradeRequest,
-        DowngradeResponse, HashKvRequest, HashKvResponse, HashRequest, HashResponse, Maintenance,
-        MoveLeaderRequest, MoveLeaderResponse, SnapshotRequest, SnapshotResponse, StatusRequest,
+        AlarmAction, AlarmMember, AlarmRequest, AlarmResponse, AlarmType, DefragmentRequest,
+        DefragmentResponse, DowngradeRequest, DowngradeResponse, HashKvRequest, HashKvResponse,
+        HashRequest, HashResponse, Maintenance, MoveLeaderRequest, MoveLeaderResponse,
+        RequestWithToken, ResponseHeader, SnapshotRequest, SnapshotResponse, StatusRequest,
         StatusResponse,
     },
-    storage::storage_api::StorageApi,
+    storage::{db::XLINE_TABLES, storage_api::StorageApi, AlarmStore, AuthStore, KvStore},
 };
 
 /// Minimum page size
@@ -28,21 +32,44 @@ pub(crate) struct MaintenanceServer<S>
 where
     S: StorageApi,
 {
+    /// Kv storage
+    kv_storage: Arc<KvStore<S>>,
+    /// Alarm storage
+    alarm_storage: Arc<AlarmStore<S>>,
     /// persistent storage
     persistent: Arc<S>, // TODO: `persistent` is not a good name, rename it in a better way
     /// Header generator
     header_gen: Arc<HeaderGenerator>,
+    /// Curp server, used to get the raft status of the current node and transfer the leadership
+    curp_server: Rpc<Command>,
+    /// Consensus client
+    client: Arc<Client<Command>>,
+    /// Server name
+    name: String,
 }
 
 impl<S> MaintenanceServer<S>
 where
     S: StorageApi,
 {
-    /// New `LeaseServer`
-    pub(crate) fn new(persistent: Arc<S>, header_gen: Arc<HeaderGenerator>) -> Self {
+    /// New `MaintenanceServer`
+    pub(crate) fn new(
+        kv_storage: Arc<KvStore<S>>,
+        alarm_storage: Arc<AlarmStore<S>>,
+        persistent: Arc<S>,
+        header_gen: Arc<HeaderGenerator>,
+        curp_server: Rpc<Command>,
+        client: Arc<Client<Command>>,
+        name: String,
+    ) -> Self {
         Self {
+            kv_storage,
+            alarm_storage,
             persistent,
             header_gen,
+            curp_server,
+            client,
+            name,
         }
     }
 }
@@ -54,47 +81,111 @@ where
 {
     async fn alarm(
         &self,
-        _request: tonic::Request<AlarmRequest>,
+        request: tonic::Request<AlarmRequest>,
     ) -> Result<tonic::Response<AlarmResponse>, tonic::Status> {
-        Err(tonic::Status::unimplemented(
-            "alarm is unimplemented".to_owned(),
-        ))
+        debug!("Receive AlarmRequest {:?}", request);
+        let wrapper = match get_token(request.metadata()) {
+            Some(token) => RequestWithToken::new_with_token(request.into_inner().into(), token),
+            None => RequestWithToken::new(request.into_inner().into()),
+        };
+        let propose_id = ProposeId::new(format!("{}-{}", self.name, Uuid::new_v4()));
+        let cmd = Command::new(vec![], wrapper, propose_id);
+        // alarms are raised and disarmed in after sync, so the proposal must wait for it
+        let propose_res = self.client.propose_indexed(cmd).await;
+        let (cmd_res, _sync_res) = propose_res.map_err(|err| match err {
+            ProposeError::ExecutionError(e) => tonic::Status::invalid_argument(e),
+            _ => tonic::Status::internal(format!("propose alarm failed: {err}")),
+        })?;
+        Ok(tonic::Response::new(cmd_res.decode().into()))
     }
 
     async fn status(
         &self,
         _request: tonic::Request<StatusRequest>,
     ) -> Result<tonic::Response<StatusResponse>, tonic::Status> {
-        Err(tonic::Status::unimplemented(
-            "status is unimplemented".to_owned(),
-        ))
+        let db_size = self.persistent.size().map_err(|e| {
+            error!("get db size failed, {e}");
+            tonic::Status::internal("get db size failed")
+        })?;
+        let db_size_in_use = self.persistent.size_in_use().map_err(|e| {
+            error!("get db size in use failed, {e}");
+            tonic::Status::internal("get db size in use failed")
+        })?;
+        let status = self.curp_server.status();
+        Ok(tonic::Response::new(StatusResponse {
+            header: Some(self.header_gen.gen_header()),
+            version: env!("CARGO_PKG_VERSION").to_owned(),
+            db_size: db_size.cast(),
+            leader: status
+                .leader
+                .map_or(0, |leader| calc_member_id(&leader, "")),
+            raft_index: status.commit_index,
+            raft_term: status.term,
+            raft_applied_index: status.applied_index,
+            errors: self
+                .alarm_storage
+                .get_all_alarms()
+                .iter()
+                .map(alarm_message)
+                .collect(),
+            db_size_in_use: db_size_in_use.cast(),
+            is_learner: status.is_learner,
+        }))
     }
 
     async fn defragment(
         &self,
         _request: tonic::Request<DefragmentRequest>,
     ) -> Result<tonic::Response<DefragmentResponse>, tonic::Status> {
-        Err(tonic::Status::unimplemented(
-            "defragment is unimplemented".to_owned(),
-        ))
+        // defragment only reclaims the space of the current node, it's not proposed
+        let persistent = Arc::clone(&self.persistent);
+        task::spawn_blocking(move || persistent.defragment())
+            .await
+            .map_err(|e| {
+                error!("defragment task failed, {e}");
+                tonic::Status::internal("defragment failed")
+            })?
+            .map_err(|e| {
+                error!("defragment failed, {e}");
+                tonic::Status::internal("defragment failed")
+            })?;
+        Ok(tonic::Response::new(DefragmentResponse {
+            header: Some(self.header_gen.gen_header()),
+        }))
     }
 
     async fn hash(
         &self,
         _request: tonic::Request<HashRequest>,
     ) -> Result<tonic::Response<HashResponse>, tonic::Status> {
-        Err(tonic::Status::unimplemented(
-            "hash is unimplemented".to_owned(),
-        ))
+        let mut hasher = crc32fast::Hasher::new();
+        for table in XLINE_TABLES {
+            let mut kvs = self.persistent.get_all(table).map_err(|e| {
+                error!("get all key-values of table {table} failed, {e}");
+                tonic::Status::internal("get hash failed")
+            })?;
+            kvs.sort_unstable();
+            for (key, value) in kvs {
+                hasher.update(&key);
+                hasher.update(&value);
+            }
+        }
+        Ok(tonic::Response::new(HashResponse {
+            header: Some(self.header_gen.gen_header()),
+            hash: hasher.finalize(),
+        }))
     }
 
     async fn hash_kv(
         &self,
-        _request: tonic::Request<HashKvRequest>,
+        request: tonic::Request<HashKvRequest>,
     ) -> Result<tonic::Response<HashKvResponse>, tonic::Status> {
-        Err(tonic::Status::unimplemented(
-            "hash_kv is unimplemented".to_owned(),
-        ))
+        let (hash, compact_revision) = self.kv_storage.hash_kv(request.into_inner().revision)?;
+        Ok(tonic::Response::new(HashKvResponse {
+            header: Some(self.header_gen.gen_header()),
+            hash,
+            compact_revision,
+        }))
     }
 
     type SnapshotStream = ReceiverStream<Result<SnapshotResponse, tonic::Status>>;
@@ -103,77 +194,35 @@ where
         &self,
         _request: tonic::Request<SnapshotRequest>,
     ) -> Result<tonic::Response<Self::SnapshotStream>, tonic::Status> {
-        let mut snapshot = self.persistent.get_snapshot().map_err(|e| {
-            error!("get snapshot failed, {e}");
-            tonic::Status::internal("get snapshot failed")
-        })?;
-
-        let header = self.header_gen.gen_header();
-        let (tx, rx) = mpsc::channel(1);
-        let _ig = tokio::spawn(async move {
-            if let Err(e) = snapshot.rewind() {
-                error!("snapshot rewind failed, {e}");
-                return;
-            }
-
-            let mut remain_size = snapshot.size();
-            let mut checksum_gen = Sha256::new();
-            while remain_size > 0 {
-                let buf_size = std::cmp::min(MAINTENANCE_SNAPSHOT_CHUNK_SIZE, remain_size);
-                let mut buf = vec![0; buf_size.cast()];
-                remain_size = remain_size.overflow_sub(buf_size);
-                if snapshot.read_exact(&mut buf).await.is_err() {
-                    if let Err(e) = tx
-                        .send(Err(tonic::Status::internal("snapshot read failed")))
-                        .await
-                    {
-                        error!("snapshot send failed, {e}");
-                    }
-                    return;
-                }
-                // etcd client will use the size of the snapshot to determine whether checksum is included,
-                // and the check method size % 512 == sha256.size, So we need to pad snapshots to multiples
-                // of 512 bytes
-                let padding = MIN_PAGE_SIZE.overflow_sub(buf_size.overflow_rem(MIN_PAGE_SIZE));
-                if padding != 0 {
-                    buf.append(&mut vec![0; padding.cast()]);
-                }
-                checksum_gen.update(&buf);
-                let resp: SnapshotResponse = SnapshotResponse {
-                    header: Some(header.clone()),
-                    remaining_bytes: remain_size,
-                    blob: buf,
-                };
-                if let Err(e) = tx.send(Ok(resp)).await {
-                    error!("snapshot send failed, {e}");
-                    return;
-                }
-            }
-            let checksum = checksum_gen.finalize().to_vec();
-            let resp = SnapshotResponse {
-                header: Some(header),
-                remaining_bytes: 0,
-                blob: checksum,
-            };
-            if let Err(e) = tx.send(Ok(resp)).await {
-                error!("snapshot send failed, {e}");
-                return;
-            }
-            if let Err(e) = snapshot.clean().await {
-                error!("snapshot clean failed, {e}");
-            }
-        });
-
-        Ok(tonic::Response::new(ReceiverStream::new(rx)))
+        let stream = snapshot_stream(self.persistent.as_ref(), self.header_gen.gen_header())?;
+        Ok(tonic::Response::new(stream))
     }
 
     async fn move_leader(
         &self,
-        _request: tonic::Request<MoveLeaderRequest>,
+        request: tonic::Request<MoveLeaderRequest>,
     ) -> Result<tonic::Response<MoveLeaderResponse>, tonic::Status> {
-        Err(tonic::Status::unimplemented(
-            "move_leader is unimplemented".to_owned(),
-        ))
+        let target_id = request.into_inner().target_id;
+        // curp identifies the members by their names
+        let target = self
+            .curp_server
+            .members()
+            .into_iter()
+            .find(|info| !info.is_learner && calc_member_id(&info.id, "") == target_id)
+            .ok_or_else(|| {
+                tonic::Status::failed_precondition("etcdserver: bad leader transferee")
+            })?;
+        debug!("transfer the leadership to {}", target.id);
+        self.curp_server
+            .move_leader(&target.id)
+            .await
+            .map_err(|err| match err {
+                ProposeError::SyncedError(e) => tonic::Status::deadline_exceeded(e),
+                _ => tonic::Status::failed_precondition(err.to_string()),
+            })?;
+        Ok(tonic::Response::new(MoveLeaderResponse {
+            header: Some(self.header_gen.gen_header()),
+        }))
     }
 
     async fn downgrade(
@@ -186,12 +235,113 @@ where
     }
 }
 
+/// Raise the `alarm` for the member through consensus on behalf of the root user
+pub(super) async fn raise_alarm<S: StorageApi>(
+    client: &Client<Command>,
+    auth_storage: &AuthStore<S>,
+    name: &str,
+    member_id: u64,
+    alarm: AlarmType,
+) -> Result<(), ProposeError> {
+    let request = AlarmRequest {
+        action: AlarmAction::Activate.into(),
+        member_id,
+        alarm: alarm.into(),
+    };
+    let wrapper = match auth_storage.root_token() {
+        Ok(token) => RequestWithToken::new_with_token(request.into(), token),
+        Err(_) => RequestWithToken::new(request.into()),
+    };
+    let propose_id = ProposeId::new(format!("{name}-{}", Uuid::new_v4()));
+    let cmd = Command::new(vec![], wrapper, propose_id);
+    let _ig = client.propose_indexed(cmd).await?;
+    Ok(())
+}
+
+/// Describe a raised alarm in the same format as etcd
+fn alarm_message(alarm: &AlarmMember) -> String {
+    let alarm_type = match AlarmType::from_i32(alarm.alarm) {
+        Some(AlarmType::Nospace) => "NOSPACE",
+        Some(AlarmType::Corrupt) => "CORRUPT",
+        Some(AlarmType::None) | None => "NONE",
+    };
+    format!("memberID:{} alarm:{alarm_type}", alarm.member_id)
+}
+
+/// Generate a stream of the snapshot of `persistent`, the stream ends with a sha256 checksum
+fn snapshot_stream<S: StorageApi>(
+    persistent: &S,
+    header: ResponseHeader,
+) -> Result<ReceiverStream<Result<SnapshotResponse, tonic::Status>>, tonic::Status> {
+    let mut snapshot = persistent.get_snapshot().map_err(|e| {
+        error!("get snapshot failed, {e}");
+        tonic::Status::internal("get snapshot failed")
+    })?;
+
+    let (tx, rx) = mpsc::channel(1);
+    let _ig = tokio::spawn(async move {
+        if let Err(e) = snapshot.rewind() {
+            error!("snapshot rewind failed, {e}");
+            return;
+        }
+
+        let mut remain_size = snapshot.size();
+        let mut checksum_gen = Sha256::new();
+        while remain_size > 0 {
+            let buf_size = std::cmp::min(MAINTENANCE_SNAPSHOT_CHUNK_SIZE, remain_size);
+            let mut buf = vec![0; buf_size.cast()];
+            remain_size = remain_size.overflow_sub(buf_size);
+            if snapshot.read_exact(&mut buf).await.is_err() {
+                if let Err(e) = tx
+                    .send(Err(tonic::Status::internal("snapshot read failed")))
+                    .await
+                {
+                    error!("snapshot send failed, {e}");
+                }
+                return;
+            }
+            // etcd client will use the size of the snapshot to determine whether checksum is included,
+            // and the check method size % 512 == sha256.size, So we need to pad snapshots to multiples
+            // of 512 bytes
+            let padding = MIN_PAGE_SIZE.overflow_sub(buf_size.overflow_rem(MIN_PAGE_SIZE));
+            if padding != 0 {
+                buf.append(&mut vec![0; padding.cast()]);
+            }
+            checksum_gen.update(&buf);
+            let resp: SnapshotResponse = SnapshotResponse {
+                header: Some(header.clone()),
+                remaining_bytes: remain_size,
+                blob: buf,
+            };
+            if let Err(e) = tx.send(Ok(resp)).await {
+                error!("snapshot send failed, {e}");
+                return;
+            }
+        }
+        let checksum = checksum_gen.finalize().to_vec();
+        let resp = SnapshotResponse {
+            header: Some(header),
+            remaining_bytes: 0,
+            blob: checksum,
+        };
+        if let Err(e) = tx.send(Ok(resp)).await {
+            error!("snapshot send failed, {e}");
+            return;
+        }
+        if let Err(e) = snapshot.clean().await {
+            error!("snapshot clean failed, {e}");
+        }
+    });
+
+    Ok(ReceiverStream::new(rx))
+}
+
 #[cfg(test)]
 mod test {
     use std::{error::Error, path::PathBuf};
 
     use tokio_stream::StreamExt;
-    use utils::config::StorageConfig;
+    use utils::config::EngineConfig;
 
     use super::*;
     use crate::storage::db::DBProxy;
@@ -201,13 +351,9 @@ mod test {
         let dir = PathBuf::from("/tmp/test_snapshot_rpc");
         let db_path = dir.join("db");
 
-        let persistent = DBProxy::open(&StorageConfig::RocksDB(db_path.clone()))?;
-        let header_gen = Arc::new(HeaderGenerator::new(0, 0));
-        let maintenance_server = MaintenanceServer::new(persistent, header_gen);
-        let mut snap1_stream = maintenance_server
-            .snapshot(tonic::Request::new(SnapshotRequest {}))
-            .await?
-            .into_inner();
+        let persistent = DBProxy::open(&EngineConfig::RocksDB(db_path.clone()))?;
+        let header_gen = HeaderGenerator::new(0, 0);
+        let mut snap1_stream = snapshot_stream(persistent.as_ref(), header_gen.gen_header())?;
         let mut recv_data = Vec::new();
         while let Some(data) = snap1_stream.next().await {
             recv_data.append(&mut data?.blob);
@@ -217,7 +363,7 @@ mod test {
             Sha256::output_size()
         );
 
-        let mut snap2 = maintenance_server.persistent.get_snapshot().unwrap();
+        let mut snap2 = persistent.get_snapshot().unwrap();
         let size = snap2.size().cast();
         let mut snap2_data = vec![0; size];
         snap2.read_exact(&mut snap2_data).await.unwrap();
@@ -1,9 +1,15 @@
 /// Xline auth server
 mod auth_server;
+/// Auto compactor
+mod auto_compactor;
 /// Barriers for range requests
 mod barriers;
+/// Xline cluster server
+mod cluster_server;
 /// Command to be executed
 pub(crate) mod command;
+/// Corruption checker
+mod corrupt_checker;
 /// Xline kv server
 mod kv_server;
 /// Xline lease server
@@ -12,6 +18,8 @@ mod lease_server;
 mod lock_server;
 /// Xline maintenance client
 mod maintenance;
+/// Backend quota checker
+mod quota_checker;
 /// Xline watch server
 mod watch_server;
 /// Xline server
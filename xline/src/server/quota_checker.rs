@@ -0,0 +1,102 @@
+use std::{sync::Arc, time::Duration};
+
+use curp::client::Client;
+use tokio::time;
+use tracing::{info, warn};
+
+use super::{cluster_server::calc_member_id, command::Command, maintenance::raise_alarm};
+use crate::{
+    rpc::AlarmType,
+    state::State,
+    storage::{storage_api::StorageApi, AlarmStore, AuthStore},
+};
+
+/// How often should the quota checker check the db size
+const QUOTA_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Quota checker, it raises a `NOSPACE` alarm for the current member when its database grows past
+/// the backend quota. The alarm must be disarmed manually after the space is reclaimed
+#[derive(Debug)]
+pub(crate) struct QuotaChecker<S>
+where
+    S: StorageApi,
+{
+    /// Consensus client, used to raise alarms
+    client: Arc<Client<Command>>,
+    /// Alarm storage
+    alarm_storage: Arc<AlarmStore<S>>,
+    /// Auth storage
+    auth_storage: Arc<AuthStore<S>>,
+    /// Persistent storage
+    persistent: Arc<S>,
+    /// State of current node
+    state: Arc<State>,
+    /// Backend quota in bytes
+    quota: u64,
+}
+
+impl<S> QuotaChecker<S>
+where
+    S: StorageApi,
+{
+    /// New `QuotaChecker`
+    pub(crate) fn new(
+        client: Arc<Client<Command>>,
+        alarm_storage: Arc<AlarmStore<S>>,
+        auth_storage: Arc<AuthStore<S>>,
+        persistent: Arc<S>,
+        state: Arc<State>,
+        quota: u64,
+    ) -> Self {
+        Self {
+            client,
+            alarm_storage,
+            auth_storage,
+            persistent,
+            state,
+            quota,
+        }
+    }
+
+    /// Check the db size of the current member periodically
+    pub(crate) async fn run(self) {
+        // every member checks its own db, the sizes may differ among members
+        let member_id = calc_member_id(self.state.id(), "");
+        loop {
+            if !self.alarm_storage.is_raised(member_id, AlarmType::Nospace) {
+                self.check(member_id).await;
+            }
+            time::sleep(QUOTA_CHECK_INTERVAL).await;
+        }
+    }
+
+    /// Raise a `NOSPACE` alarm if the db size exceeds the quota
+    async fn check(&self, member_id: u64) {
+        let size = match self.persistent.size() {
+            Ok(size) => size,
+            Err(e) => {
+                warn!("quota checker failed to get db size: {e}");
+                return;
+            }
+        };
+        if size <= self.quota {
+            return;
+        }
+        warn!(
+            "db size {size} exceeds the backend quota {}, raising NOSPACE alarm",
+            self.quota
+        );
+        match raise_alarm(
+            &self.client,
+            &self.auth_storage,
+            self.state.id(),
+            member_id,
+            AlarmType::Nospace,
+        )
+        .await
+        {
+            Ok(()) => info!("quota checker raised NOSPACE alarm for member {member_id}"),
+            Err(e) => warn!("quota checker failed to raise alarm for member {member_id}: {e}"),
+        }
+    }
+}
@@ -1,25 +1,42 @@
-use std::{collections::HashSet, sync::Arc};
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+    time::Duration,
+};
 
-use clippy_utilities::OverflowArithmetic;
+use clippy_utilities::{Cast, OverflowArithmetic};
+use event_listener::EventListener;
+use prost::Message;
 use tokio::sync::mpsc;
-use tokio_stream::{wrappers::ReceiverStream, Stream, StreamExt};
+use tokio_stream::{wrappers::ReceiverStream, Stream, StreamExt, StreamMap};
 use tracing::{debug, warn};
 
-use super::command::KeyRange;
+use super::{auth_server::get_token, command::KeyRange};
 use crate::{
     rpc::{
-        RequestUnion, ResponseHeader, Watch, WatchCancelRequest, WatchCreateRequest, WatchRequest,
-        WatchResponse,
+        Event, RequestUnion, ResponseHeader, Watch, WatchCancelRequest, WatchCreateRequest,
+        WatchRequest, WatchResponse,
     },
     storage::{
         kvwatcher::{KvWatcher, KvWatcherOps, WatchEvent, WatchId},
         storage_api::StorageApi,
+        AuthStore, ExecuteError,
     },
 };
 
 /// Default channel size
 const CHANNEL_SIZE: usize = 128;
 
+/// Max number of pending events of a watcher, a watcher whose buffer is full won't
+/// block the other watchers of the same stream
+const WATCHER_BUFFER_SIZE: usize = 128;
+
+/// Default interval of progress notifications for idle watchers
+const DEFAULT_PROGRESS_NOTIFY_INTERVAL: Duration = Duration::from_secs(600);
+
+/// Watch id of the responses to `WatchProgressRequest`, which aren't sent to a specific watcher
+const PROGRESS_WATCH_ID: WatchId = -1;
+
 /// Watch Server
 #[derive(Debug)]
 pub(crate) struct WatchServer<S>
@@ -28,6 +45,12 @@ where
 {
     /// KV watcher
     watcher: Arc<KvWatcher<S>>,
+    /// Auth storage
+    auth_storage: Arc<AuthStore<S>>,
+    /// Interval of progress notifications
+    progress_notify_interval: Duration,
+    /// Max size of a watch response, it's used to split events into fragments
+    max_message_size: u64,
 }
 
 impl<S> WatchServer<S>
@@ -35,23 +58,45 @@ where
     S: StorageApi,
 {
     /// New `WatchServer`
-    pub(crate) fn new(watcher: Arc<KvWatcher<S>>) -> Self {
-        Self { watcher }
+    pub(crate) fn new(
+        watcher: Arc<KvWatcher<S>>,
+        auth_storage: Arc<AuthStore<S>>,
+        max_message_size: u64,
+    ) -> Self {
+        Self {
+            watcher,
+            auth_storage,
+            progress_notify_interval: DEFAULT_PROGRESS_NOTIFY_INTERVAL,
+            max_message_size,
+        }
     }
 
     /// bg task for handle watch connection
     #[allow(clippy::integer_arithmetic)] // Introduced by tokio::select!
     async fn task<ST, W>(
         kv_watcher: Arc<W>,
+        auth_storage: Arc<AuthStore<S>>,
+        token: Option<String>,
         res_tx: mpsc::Sender<Result<WatchResponse, tonic::Status>>,
         mut req_rx: ST,
+        progress_notify_interval: Duration,
+        max_message_size: u64,
     ) where
         ST: Stream<Item = Result<WatchRequest, tonic::Status>> + Unpin,
         W: KvWatcherOps,
     {
-        let (event_tx, event_rx) = mpsc::channel(CHANNEL_SIZE);
         let (stop_tx, stop_rx) = flume::bounded(0);
-        let mut watch_handle = WatchHandle::new(kv_watcher, res_tx, event_rx, event_tx, stop_tx);
+        let mut watch_handle = WatchHandle::new(
+            kv_watcher,
+            auth_storage,
+            token,
+            res_tx,
+            stop_tx,
+            max_message_size.cast(),
+        );
+        let mut ticker = tokio::time::interval(progress_notify_interval);
+        // the first tick completes immediately
+        let _now = ticker.tick().await;
         loop {
             tokio::select! {
                 req = req_rx.next() => {
@@ -70,13 +115,25 @@ where
                         break;
                     }
                 }
-                event = watch_handle.event_rx.recv() => {
+                event = watch_handle.watcher_events.next(),
+                    if !watch_handle.watcher_events.is_empty() => {
+                    if let Some((_watch_id, event)) = event {
+                        watch_handle.handle_watch_event(event).await;
+                    }
+                }
+                event = watch_handle.cancel_rx.recv() => {
                     if let Some(event) = event {
                         watch_handle.handle_watch_event(event).await;
                     } else {
-                        panic!("Watch event sender is closed");
+                        panic!("Watch cancel sender is closed");
                     }
                 }
+                _ = ticker.tick() => {
+                    watch_handle.handle_progress_notify().await;
+                }
+                _ = &mut watch_handle.permission_revoked => {
+                    watch_handle.handle_permission_revoked().await;
+                }
                 _ = stop_rx.recv_async() => {
                     break;
                 }
@@ -87,44 +144,75 @@ where
 
 /// Handler for one watch connection
 #[derive(Debug)]
-struct WatchHandle<W>
+struct WatchHandle<W, S>
 where
     W: KvWatcherOps,
+    S: StorageApi,
 {
     /// KV watcher
     kv_watcher: Arc<W>,
+    /// Auth storage
+    auth_storage: Arc<AuthStore<S>>,
+    /// Token of the watch connection
+    token: Option<String>,
+    /// Watchers created when auth is enabled, mapped to their users and key ranges,
+    /// they are rechecked once permissions are revoked
+    watch_users: HashMap<WatchId, (String, Vec<u8>, Vec<u8>)>,
+    /// Listener of the revocation of permissions
+    permission_revoked: EventListener,
     /// `WatchResponse` Sender
     response_tx: mpsc::Sender<Result<WatchResponse, tonic::Status>>,
-    /// Event receiver
-    event_rx: mpsc::Receiver<WatchEvent>,
-    /// Event sender
-    event_tx: mpsc::Sender<WatchEvent>,
+    /// Event receivers of the watchers, each watcher has its own bounded buffer
+    watcher_events: StreamMap<WatchId, ReceiverStream<WatchEvent>>,
+    /// Receiver of the cancellations by the KV watcher
+    cancel_rx: mpsc::UnboundedReceiver<WatchEvent>,
+    /// Sender of the cancellations by the KV watcher
+    cancel_tx: mpsc::UnboundedSender<WatchEvent>,
     /// Watch ID to watcher map
     active_watch_ids: HashSet<WatchId>,
+    /// Watchers that need progress notifications, mapped to whether they have
+    /// received events since the last notification
+    progress_watchers: HashMap<WatchId, bool>,
+    /// Watchers that allow their events to be split into fragments
+    fragment_watch_ids: HashSet<WatchId>,
+    /// Max size of a watch response
+    max_message_size: usize,
     /// Next available `WatchId`
     next_id: WatchId,
     /// Stop tx
     stop_tx: flume::Sender<()>,
 }
 
-impl<W> WatchHandle<W>
+impl<W, S> WatchHandle<W, S>
 where
     W: KvWatcherOps,
+    S: StorageApi,
 {
     /// New `WatchHandle`
     fn new(
         kv_watcher: Arc<W>,
+        auth_storage: Arc<AuthStore<S>>,
+        token: Option<String>,
         response_tx: mpsc::Sender<Result<WatchResponse, tonic::Status>>,
-        event_rx: mpsc::Receiver<WatchEvent>,
-        event_tx: mpsc::Sender<WatchEvent>,
         stop_tx: flume::Sender<()>,
+        max_message_size: usize,
     ) -> Self {
+        let (cancel_tx, cancel_rx) = mpsc::unbounded_channel();
+        let permission_revoked = auth_storage.permission_revoked();
         Self {
             kv_watcher,
+            auth_storage,
+            token,
+            watch_users: HashMap::new(),
+            permission_revoked,
             response_tx,
-            event_rx,
-            event_tx,
+            watcher_events: StreamMap::new(),
+            cancel_rx,
+            cancel_tx,
             active_watch_ids: HashSet::new(),
+            progress_watchers: HashMap::new(),
+            fragment_watch_ids: HashSet::new(),
+            max_message_size,
             next_id: 1, // watch_id starts from 1, 0 means auto-generating
             stop_tx,
         }
@@ -163,18 +251,58 @@ where
             return;
         };
 
-        let key_range = KeyRange::new(req.key, req.range_end);
-        let (events, revision) = self.kv_watcher.watch(
+        let user = match self.auth_storage.check_watch_permission(
+            self.token.as_deref(),
+            &req.key,
+            &req.range_end,
+        ) {
+            Ok(user) => user,
+            Err(e) => {
+                self.handle_watch_rejected(watch_id, 0, e.to_string()).await;
+                return;
+            }
+        };
+        let key_range = KeyRange::new(req.key.clone(), req.range_end.clone());
+        let (event_tx, event_rx) = mpsc::channel(WATCHER_BUFFER_SIZE);
+        let revision = match self.kv_watcher.watch(
             watch_id,
             key_range,
             req.start_revision,
             req.filters,
-            self.event_tx.clone(),
-        );
+            req.prev_kv,
+            event_tx,
+            self.cancel_tx.clone(),
+        ) {
+            Ok(revision) => revision,
+            Err(ExecuteError::RevisionCompacted(_, compacted_revision)) => {
+                self.handle_watch_rejected(
+                    watch_id,
+                    compacted_revision,
+                    "mvcc: required revision has been compacted".to_owned(),
+                )
+                .await;
+                return;
+            }
+            Err(e) => unreachable!("unexpected error when creating watcher: {e}"),
+        };
         assert!(
             self.active_watch_ids.insert(watch_id),
             "WatchId {watch_id} already exists in watcher_map",
         );
+        let _prev = self
+            .watcher_events
+            .insert(watch_id, ReceiverStream::new(event_rx));
+        if req.progress_notify {
+            let _prev = self.progress_watchers.insert(watch_id, false);
+        }
+        if req.fragment {
+            let _is_new = self.fragment_watch_ids.insert(watch_id);
+        }
+        if let Some(user) = user {
+            let _prev = self
+                .watch_users
+                .insert(watch_id, (user, req.key, req.range_end));
+        }
 
         let response = WatchResponse {
             header: Some(ResponseHeader {
@@ -190,21 +318,38 @@ where
                 warn!("failed to send stop signal: {}", e);
             });
         }
-        // send initial events
-        if !events.is_empty() {
-            let event_response = WatchResponse {
-                header: Some(ResponseHeader {
-                    revision,
-                    ..ResponseHeader::default()
-                }),
+    }
+
+    /// Reply a watcher that can't be created, e.g. its start revision has been compacted
+    /// or it's not permitted. The watcher will be created and canceled immediately
+    async fn handle_watch_rejected(
+        &mut self,
+        watch_id: WatchId,
+        compacted_revision: i64,
+        reason: String,
+    ) {
+        let responses = [
+            WatchResponse {
+                header: Some(ResponseHeader::default()),
                 watch_id,
-                events,
+                created: true,
                 ..WatchResponse::default()
-            };
-            if self.response_tx.send(Ok(event_response)).await.is_err() {
+            },
+            WatchResponse {
+                header: Some(ResponseHeader::default()),
+                watch_id,
+                canceled: true,
+                compact_revision: compacted_revision,
+                cancel_reason: reason,
+                ..WatchResponse::default()
+            },
+        ];
+        for response in responses {
+            if self.response_tx.send(Ok(response)).await.is_err() {
                 self.stop_tx.send(()).unwrap_or_else(|e| {
                     warn!("failed to send stop signal: {}", e);
                 });
+                break;
             }
         }
     }
@@ -214,7 +359,10 @@ where
         let watch_id = req.watch_id;
         let result = if self.active_watch_ids.remove(&watch_id) {
             let revision = self.kv_watcher.cancel(watch_id);
-            let _prev = self.active_watch_ids.remove(&watch_id);
+            let _events = self.watcher_events.remove(&watch_id);
+            let _prev = self.progress_watchers.remove(&watch_id);
+            let _prev = self.fragment_watch_ids.remove(&watch_id);
+            let _prev = self.watch_users.remove(&watch_id);
             let response = WatchResponse {
                 header: Some(ResponseHeader {
                     revision,
@@ -249,26 +397,101 @@ where
                     self.handle_watch_cancel(req).await;
                 }
                 RequestUnion::ProgressRequest(_req) => {
-                    panic!("Don't support ProgressRequest yet");
+                    self.handle_progress_request().await;
                 }
             }
         }
     }
 
+    /// Handle `WatchProgressRequest`, reply with the current revision
+    async fn handle_progress_request(&mut self) {
+        let response = WatchResponse {
+            header: Some(ResponseHeader {
+                revision: self.kv_watcher.revision(),
+                ..ResponseHeader::default()
+            }),
+            watch_id: PROGRESS_WATCH_ID,
+            ..WatchResponse::default()
+        };
+        if self.response_tx.send(Ok(response)).await.is_err() {
+            self.stop_tx.send(()).unwrap_or_else(|e| {
+                warn!("failed to send stop signal: {}", e);
+            });
+        }
+    }
+
+    /// Send empty responses with the current revision to the watchers which require progress
+    /// notifications and haven't received any events since the last notification
+    async fn handle_progress_notify(&mut self) {
+        let revision = self.kv_watcher.revision();
+        let mut idle_watchers = vec![];
+        for (&watch_id, has_events) in &mut self.progress_watchers {
+            if !*has_events {
+                idle_watchers.push(watch_id);
+            }
+            *has_events = false;
+        }
+        for watch_id in idle_watchers {
+            let response = WatchResponse {
+                header: Some(ResponseHeader {
+                    revision,
+                    ..ResponseHeader::default()
+                }),
+                watch_id,
+                ..WatchResponse::default()
+            };
+            if self.response_tx.send(Ok(response)).await.is_err() {
+                self.stop_tx.send(()).unwrap_or_else(|e| {
+                    warn!("failed to send stop signal: {}", e);
+                });
+                break;
+            }
+        }
+    }
+
     /// Handle watch event
     async fn handle_watch_event(&mut self, mut event: WatchEvent) {
         let watch_id = event.watch_id();
+        if let Some(reason) = event.take_cancel_reason() {
+            self.handle_watcher_canceled(watch_id, event.compact_revision(), reason)
+                .await;
+            return;
+        }
         let events = event.take_events();
         if events.is_empty() {
             return;
         }
+        if let Some(has_events) = self.progress_watchers.get_mut(&watch_id) {
+            *has_events = true;
+        }
+        self.send_events(watch_id, event.revision(), events).await;
+    }
+
+    /// Reply a watcher canceled by the server, e.g. its historical events have been
+    /// compacted while it's catching up, it's too slow to receive events or its
+    /// permission has been revoked. The watcher has been removed from the KV watcher
+    async fn handle_watcher_canceled(
+        &mut self,
+        watch_id: WatchId,
+        compacted_revision: i64,
+        reason: String,
+    ) {
+        if !self.active_watch_ids.remove(&watch_id) {
+            return;
+        }
+        let _events = self.watcher_events.remove(&watch_id);
+        let _prev = self.progress_watchers.remove(&watch_id);
+        let _prev = self.fragment_watch_ids.remove(&watch_id);
+        let _prev = self.watch_users.remove(&watch_id);
         let response = WatchResponse {
             header: Some(ResponseHeader {
-                revision: event.revision(),
+                revision: self.kv_watcher.revision(),
                 ..ResponseHeader::default()
             }),
             watch_id,
-            events,
+            canceled: true,
+            compact_revision: compacted_revision,
+            cancel_reason: reason,
             ..WatchResponse::default()
         };
         if self.response_tx.send(Ok(response)).await.is_err() {
@@ -277,11 +500,96 @@ where
             });
         }
     }
+
+    /// Recheck the watchers created when auth is enabled after permissions are revoked,
+    /// watchers whose users can't read their key ranges anymore are canceled
+    async fn handle_permission_revoked(&mut self) {
+        // listen again before rechecking so that no revocation is missed
+        self.permission_revoked = self.auth_storage.permission_revoked();
+        let denied_watch_ids: Vec<WatchId> = self
+            .watch_users
+            .iter()
+            .filter(|&(_, &(ref user, ref key, ref range_end))| {
+                self.auth_storage
+                    .check_read_permission(user, key, range_end)
+                    .is_err()
+            })
+            .map(|(watch_id, _)| *watch_id)
+            .collect();
+        for watch_id in denied_watch_ids {
+            debug!("watcher {watch_id} is canceled since its permission is revoked");
+            let _revision = self.kv_watcher.cancel(watch_id);
+            self.handle_watcher_canceled(watch_id, 0, ExecuteError::PermissionDenied.to_string())
+                .await;
+        }
+    }
+
+    /// Send events to a watcher, the events will be split into fragments if the watcher
+    /// allows and the response exceeds the max message size
+    async fn send_events(&mut self, watch_id: WatchId, revision: i64, events: Vec<Event>) {
+        let response = WatchResponse {
+            header: Some(ResponseHeader {
+                revision,
+                ..ResponseHeader::default()
+            }),
+            watch_id,
+            events,
+            ..WatchResponse::default()
+        };
+        let responses = if self.fragment_watch_ids.contains(&watch_id)
+            && response.encoded_len() > self.max_message_size
+        {
+            fragment(response, self.max_message_size)
+        } else {
+            vec![response]
+        };
+        for response in responses {
+            if self.response_tx.send(Ok(response)).await.is_err() {
+                self.stop_tx.send(()).unwrap_or_else(|e| {
+                    warn!("failed to send stop signal: {}", e);
+                });
+                break;
+            }
+        }
+    }
+}
+
+/// Split the events of a response into fragments, each fragment won't exceed `max_size` unless
+/// it only contains one event. All fragments except the last one are marked with `fragment`
+fn fragment(mut response: WatchResponse, max_size: usize) -> Vec<WatchResponse> {
+    let events = std::mem::take(&mut response.events);
+    let base_size = response.encoded_len();
+    let mut fragments = vec![];
+    let mut current_events = vec![];
+    let mut current_size = base_size;
+    for event in events {
+        // events are encoded as length-delimited fields with a one-byte tag
+        let event_len = event.encoded_len();
+        let event_size = prost::length_delimiter_len(event_len)
+            .overflow_add(event_len)
+            .overflow_add(1);
+        if !current_events.is_empty() && current_size.overflow_add(event_size) > max_size {
+            fragments.push(WatchResponse {
+                events: std::mem::take(&mut current_events),
+                fragment: true,
+                ..response.clone()
+            });
+            current_size = base_size;
+        }
+        current_size = current_size.overflow_add(event_size);
+        current_events.push(event);
+    }
+    fragments.push(WatchResponse {
+        events: current_events,
+        ..response
+    });
+    fragments
 }
 
-impl<W> Drop for WatchHandle<W>
+impl<W, S> Drop for WatchHandle<W, S>
 where
     W: KvWatcherOps,
+    S: StorageApi,
 {
     fn drop(&mut self) {
         for watch_id in &self.active_watch_ids {
@@ -308,9 +616,18 @@ where
         request: tonic::Request<tonic::Streaming<WatchRequest>>,
     ) -> Result<tonic::Response<Self::WatchStream>, tonic::Status> {
         debug!("Receive Watch Connection {:?}", request);
+        let token = get_token(request.metadata());
         let req_stream = request.into_inner();
         let (tx, rx) = mpsc::channel(CHANNEL_SIZE);
-        let _hd = tokio::spawn(Self::task(Arc::clone(&self.watcher), tx, req_stream));
+        let _hd = tokio::spawn(Self::task(
+            Arc::clone(&self.watcher),
+            Arc::clone(&self.auth_storage),
+            token,
+            tx,
+            req_stream,
+            self.progress_notify_interval,
+            self.max_message_size,
+        ));
         Ok(tonic::Response::new(ReceiverStream::new(rx)))
     }
 }
@@ -318,10 +635,21 @@ where
 #[cfg(test)]
 mod test {
 
-    use engine::memory_engine::MemoryEngine;
+    use utils::config::{default_watch_max_message_size, EngineConfig};
 
     use super::*;
-    use crate::storage::{db::DB, kvwatcher::MockKvWatcherOps};
+    use crate::{
+        header_gen::HeaderGenerator,
+        rpc::KeyValue,
+        storage::{db::DBProxy, kvwatcher::MockKvWatcherOps},
+    };
+
+    fn init_auth_store() -> Arc<AuthStore<DBProxy>> {
+        let db = DBProxy::open(&EngineConfig::Memory).unwrap();
+        let (lease_cmd_tx, _) = mpsc::channel(1);
+        let header_gen = Arc::new(HeaderGenerator::new(0, 0));
+        Arc::new(AuthStore::new(lease_cmd_tx, None, header_gen, db))
+    }
 
     #[tokio::test]
     async fn test_watch_client_closes_connection() -> Result<(), Box<dyn std::error::Error>> {
@@ -331,16 +659,17 @@ mod test {
             ReceiverStream::new(req_rx);
 
         let mut mock_watcher = MockKvWatcherOps::new();
-        let _ = mock_watcher
-            .expect_watch()
-            .times(1)
-            .return_const((vec![], 0));
+        let _ = mock_watcher.expect_watch().times(1).return_const(Ok(0));
         let _ = mock_watcher.expect_cancel().times(1).returning(move |_| 0);
         let watcher = Arc::new(mock_watcher);
-        let handle = tokio::spawn(WatchServer::<DB<MemoryEngine>>::task(
+        let handle = tokio::spawn(WatchServer::<DBProxy>::task(
             Arc::clone(&watcher),
+            init_auth_store(),
+            None,
             res_tx,
             req_stream,
+            DEFAULT_PROGRESS_NOTIFY_INTERVAL,
+            default_watch_max_message_size(),
         ));
         req_tx
             .send(Ok(WatchRequest {
@@ -358,4 +687,106 @@ mod test {
         tokio::time::timeout(std::time::Duration::from_secs(3), handle).await??;
         Ok(())
     }
+
+    #[tokio::test]
+    async fn test_watch_progress_request() -> Result<(), Box<dyn std::error::Error>> {
+        let (req_tx, req_rx) = mpsc::channel(CHANNEL_SIZE);
+        let (res_tx, mut res_rx) = mpsc::channel(CHANNEL_SIZE);
+        let req_stream: ReceiverStream<Result<WatchRequest, tonic::Status>> =
+            ReceiverStream::new(req_rx);
+
+        let mut mock_watcher = MockKvWatcherOps::new();
+        let _ = mock_watcher.expect_revision().return_const(3);
+        let handle = tokio::spawn(WatchServer::<DBProxy>::task(
+            Arc::new(mock_watcher),
+            init_auth_store(),
+            None,
+            res_tx,
+            req_stream,
+            DEFAULT_PROGRESS_NOTIFY_INTERVAL,
+            default_watch_max_message_size(),
+        ));
+        req_tx
+            .send(Ok(WatchRequest {
+                request_union: Some(RequestUnion::ProgressRequest(Default::default())),
+            }))
+            .await?;
+        let res = res_rx.recv().await.unwrap()?;
+        assert_eq!(res.watch_id, PROGRESS_WATCH_ID);
+        assert_eq!(res.header.unwrap().revision, 3);
+        drop(req_tx);
+        tokio::time::timeout(Duration::from_secs(3), handle).await??;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_watch_progress_notify() -> Result<(), Box<dyn std::error::Error>> {
+        let (req_tx, req_rx) = mpsc::channel(CHANNEL_SIZE);
+        let (res_tx, mut res_rx) = mpsc::channel(CHANNEL_SIZE);
+        let req_stream: ReceiverStream<Result<WatchRequest, tonic::Status>> =
+            ReceiverStream::new(req_rx);
+
+        let mut mock_watcher = MockKvWatcherOps::new();
+        let _ = mock_watcher.expect_watch().times(1).return_const(Ok(1));
+        let _ = mock_watcher.expect_cancel().times(1).returning(move |_| 1);
+        let _ = mock_watcher.expect_revision().return_const(1);
+        let handle = tokio::spawn(WatchServer::<DBProxy>::task(
+            Arc::new(mock_watcher),
+            init_auth_store(),
+            None,
+            res_tx,
+            req_stream,
+            Duration::from_millis(100),
+            default_watch_max_message_size(),
+        ));
+        req_tx
+            .send(Ok(WatchRequest {
+                request_union: Some(RequestUnion::CreateRequest(WatchCreateRequest {
+                    key: vec![0],
+                    progress_notify: true,
+                    ..Default::default()
+                })),
+            }))
+            .await?;
+        let res = res_rx.recv().await.unwrap()?;
+        assert!(res.created);
+        let res = tokio::time::timeout(Duration::from_secs(1), res_rx.recv())
+            .await?
+            .unwrap()?;
+        assert_eq!(res.watch_id, 1);
+        assert!(res.events.is_empty());
+        assert_eq!(res.header.unwrap().revision, 1);
+        drop(req_tx);
+        tokio::time::timeout(Duration::from_secs(3), handle).await??;
+        Ok(())
+    }
+
+    #[test]
+    fn test_watch_response_fragment() {
+        let events: Vec<Event> = (0..10)
+            .map(|i| Event {
+                kv: Some(KeyValue {
+                    key: vec![i],
+                    value: vec![0; 100],
+                    ..Default::default()
+                }),
+                ..Default::default()
+            })
+            .collect();
+        let response = WatchResponse {
+            watch_id: 1,
+            events: events.clone(),
+            ..Default::default()
+        };
+        let fragments = fragment(response, 300);
+        assert!(fragments.len() > 1);
+        let (last, others) = fragments.split_last().unwrap();
+        assert!(!last.fragment);
+        assert!(others.iter().all(|f| f.fragment));
+        assert!(fragments
+            .iter()
+            .all(|f| f.watch_id == 1 && f.encoded_len() <= 300));
+        let merged: Vec<Event> = fragments.into_iter().flat_map(|f| f.events).collect();
+        assert_eq!(merged, events);
+    }
 }
@@ -1,44 +1,45 @@
-use std::{
-    collections::{hash_map::DefaultHasher, HashMap},
-    future::Future,
-    hash::Hasher,
-    net::SocketAddr,
-    sync::Arc,
-    time::{Duration, SystemTime},
-};
+use std::{collections::HashMap, future::Future, net::SocketAddr, sync::Arc, time::Duration};
 
 use anyhow::Result;
 use curp::{client::Client, server::Rpc, ProtocolServer};
 use jsonwebtoken::{DecodingKey, EncodingKey};
 use tokio::{
     net::TcpListener,
+    signal::{
+        self,
+        unix::{signal as unix_signal, SignalKind},
+    },
     sync::{broadcast, mpsc},
 };
 use tokio_stream::wrappers::TcpListenerStream;
 use tonic::transport::Server;
-use tracing::info;
-use utils::config::{ClientTimeout, CurpConfig};
+use tracing::{info, warn};
+use utils::config::{ClientTimeout, CompactConfig, CurpConfig};
 
 use super::{
     auth_server::AuthServer,
+    auto_compactor::AutoCompactor,
     barriers::{IdBarrier, IndexBarrier},
+    cluster_server::{calc_cluster_id, calc_member_id, ClusterServer},
     command::{Command, CommandExecutor},
+    corrupt_checker::CorruptChecker,
     kv_server::KvServer,
     lease_server::LeaseServer,
     lock_server::LockServer,
     maintenance::MaintenanceServer,
+    quota_checker::QuotaChecker,
     watch_server::WatchServer,
 };
 use crate::{
     header_gen::HeaderGenerator,
     id_gen::IdGenerator,
     rpc::{
-        AuthServer as RpcAuthServer, KvServer as RpcKvServer, LeaseServer as RpcLeaseServer,
-        LockServer as RpcLockServer, MaintenanceServer as RpcMaintenanceServer,
-        WatchServer as RpcWatchServer,
+        AuthServer as RpcAuthServer, ClusterServer as RpcClusterServer, KvServer as RpcKvServer,
+        LeaseServer as RpcLeaseServer, LockServer as RpcLockServer,
+        MaintenanceServer as RpcMaintenanceServer, WatchServer as RpcWatchServer,
     },
     state::State,
-    storage::{index::Index, storage_api::StorageApi, AuthStore, KvStore, LeaseStore},
+    storage::{index::Index, storage_api::StorageApi, AlarmStore, AuthStore, KvStore, LeaseStore},
 };
 
 /// Default channel size
@@ -47,6 +48,23 @@ const CHANNEL_SIZE: usize = 128;
 /// Rpc Server of curp protocol
 type CurpServer = Rpc<Command>;
 
+/// Wait until the process receives `SIGINT` or `SIGTERM`
+#[allow(clippy::integer_arithmetic)] // tokio select internal triggered
+async fn shutdown_signal() {
+    let mut sigterm = match unix_signal(SignalKind::terminate()) {
+        Ok(sigterm) => sigterm,
+        Err(e) => {
+            warn!("failed to listen to SIGTERM, {e}");
+            let _ig = signal::ctrl_c().await;
+            return;
+        }
+    };
+    tokio::select! {
+        _ = signal::ctrl_c() => {}
+        _ = sigterm.recv() => {}
+    }
+}
+
 /// Xline server
 #[derive(Debug)]
 pub struct XlineServer<S>
@@ -61,6 +79,8 @@ where
     auth_storage: Arc<AuthStore<S>>,
     /// Lease storage
     lease_storage: Arc<LeaseStore<S>>,
+    /// Alarm storage
+    alarm_storage: Arc<AlarmStore<S>>,
     /// persistent storage
     persistent: Arc<S>,
     /// Consensus client
@@ -77,6 +97,16 @@ where
     id_barrier: Arc<IdBarrier>,
     /// Range request retry timeout
     range_retry_timeout: Duration,
+    /// Max size of a watch response
+    watch_max_message_size: u64,
+    /// How often should the leader check whether the members are corrupted
+    corrupt_check_interval: Duration,
+    /// Whether the leader should transfer its leadership to another voter before it shuts down
+    transfer_leadership_on_shutdown: bool,
+    /// Backend quota in bytes, the quota is disabled if it's zero
+    quota_backend_bytes: u64,
+    /// Compaction configuration
+    compact_cfg: CompactConfig,
 }
 
 impl<S> XlineServer<S>
@@ -102,18 +132,22 @@ where
         curp_config: CurpConfig,
         client_timeout: ClientTimeout,
         range_retry_timeout: Duration,
+        watch_max_message_size: u64,
+        corrupt_check_interval: Duration,
+        transfer_leadership_on_shutdown: bool,
+        quota_backend_bytes: u64,
+        compact_config: CompactConfig,
         persistent: Arc<S>,
     ) -> Self {
-        let url = all_members
-            .get(&name)
-            .unwrap_or_else(|| panic!("peer {} not found in peers {:?}", name, all_members.keys()));
-        let ts = SystemTime::now()
-            .duration_since(SystemTime::UNIX_EPOCH)
-            .unwrap_or_else(|e| panic!("SystemTime before UNIX EPOCH! {e}"))
-            .as_secs();
-        let member_id = Self::calc_member_id(url, "", ts);
+        assert!(
+            all_members.contains_key(&name),
+            "peer {} not found in peers {:?}",
+            name,
+            all_members.keys()
+        );
+        let member_id = calc_member_id(&name, "");
         let peer_urls = all_members.values().map(String::as_str).collect::<Vec<_>>();
-        let cluster_id = Self::calc_cluster_id(&peer_urls, "");
+        let cluster_id = calc_cluster_id(&peer_urls, "");
         let header_gen = Arc::new(HeaderGenerator::new(cluster_id, member_id));
         let id_gen = Arc::new(IdGenerator::new(member_id));
         let leader_id = is_leader.then(|| name.clone());
@@ -142,6 +176,10 @@ where
             Arc::clone(&header_gen),
             Arc::clone(&persistent),
         ));
+        let alarm_storage = Arc::new(AlarmStore::new(
+            Arc::clone(&header_gen),
+            Arc::clone(&persistent),
+        ));
         let client = Arc::new(Client::<Command>::new(all_members.clone(), client_timeout).await);
         let index_barrier = Arc::new(IndexBarrier::new());
         let id_barrier = Arc::new(IdBarrier::new());
@@ -150,6 +188,7 @@ where
             kv_storage,
             auth_storage,
             lease_storage,
+            alarm_storage,
             persistent,
             client,
             curp_cfg: curp_config,
@@ -158,28 +197,14 @@ where
             index_barrier,
             id_barrier,
             range_retry_timeout,
+            watch_max_message_size,
+            corrupt_check_interval,
+            transfer_leadership_on_shutdown,
+            quota_backend_bytes,
+            compact_cfg: compact_config,
         }
     }
 
-    /// calculate member id
-    fn calc_member_id(peer_url: &str, cluster_name: &str, now: u64) -> u64 {
-        let mut hasher = DefaultHasher::new();
-        hasher.write(peer_url.as_bytes());
-        hasher.write(cluster_name.as_bytes());
-        hasher.write_u64(now);
-        hasher.finish()
-    }
-
-    /// calculate cluster id
-    fn calc_cluster_id(member_urls: &[&str], cluster_name: &str) -> u64 {
-        let mut hasher = DefaultHasher::new();
-        for id in member_urls {
-            hasher.write(id.as_bytes());
-        }
-        hasher.write(cluster_name.as_bytes());
-        hasher.finish()
-    }
-
     /// Server id
     fn id(&self) -> String {
         self.state.id().to_owned()
@@ -201,6 +226,7 @@ where
         self.lease_storage.recover()?;
         self.kv_storage.recover().await?;
         self.auth_storage.recover()?;
+        self.alarm_storage.recover()?;
         let (
             kv_server,
             lock_server,
@@ -208,18 +234,31 @@ where
             auth_server,
             watch_server,
             maintenance_server,
+            cluster_server,
             curp_server,
         ) = self.init_servers().await;
-        Ok(Server::builder()
-            .add_service(RpcLockServer::new(lock_server))
-            .add_service(RpcKvServer::new(kv_server))
-            .add_service(RpcLeaseServer::from_arc(lease_server))
-            .add_service(RpcAuthServer::new(auth_server))
-            .add_service(RpcWatchServer::new(watch_server))
-            .add_service(RpcMaintenanceServer::new(maintenance_server))
-            .add_service(ProtocolServer::new(curp_server))
-            .serve(addr)
-            .await?)
+        // a witness never applies cmds, so it only serves the curp protocol
+        let serve_clients = !self.curp_cfg.is_witness;
+        let builder = Server::builder()
+            .add_service(ProtocolServer::new(curp_server.clone()))
+            .add_optional_service(serve_clients.then(|| RpcLockServer::new(lock_server)))
+            .add_optional_service(serve_clients.then(|| RpcKvServer::from_arc(kv_server)))
+            .add_optional_service(serve_clients.then(|| RpcLeaseServer::from_arc(lease_server)))
+            .add_optional_service(serve_clients.then(|| RpcAuthServer::new(auth_server)))
+            .add_optional_service(serve_clients.then(|| RpcWatchServer::new(watch_server)))
+            .add_optional_service(
+                serve_clients.then(|| RpcMaintenanceServer::new(maintenance_server)),
+            )
+            .add_optional_service(serve_clients.then(|| RpcClusterServer::new(cluster_server)));
+        if !self.transfer_leadership_on_shutdown {
+            return Ok(builder.serve(addr).await?);
+        }
+        // keep serving while transferring the leadership, so that the transferee can catch up
+        let signal = async {
+            shutdown_signal().await;
+            self.transfer_leadership(&curp_server).await;
+        };
+        Ok(builder.serve_with_shutdown(addr, signal).await?)
     }
 
     /// Start `XlineServer` from listeners
@@ -240,6 +279,7 @@ where
         self.lease_storage.recover()?;
         self.kv_storage.recover().await?;
         self.auth_storage.recover()?;
+        self.alarm_storage.recover()?;
         let (
             kv_server,
             lock_server,
@@ -247,20 +287,57 @@ where
             auth_server,
             watch_server,
             maintenance_server,
+            cluster_server,
             curp_server,
         ) = self.init_servers().await;
+        let signal = async {
+            signal.await;
+            self.transfer_leadership(&curp_server).await;
+        };
+        // a witness never applies cmds, so it only serves the curp protocol
+        let serve_clients = !self.curp_cfg.is_witness;
         Ok(Server::builder()
-            .add_service(RpcLockServer::new(lock_server))
-            .add_service(RpcKvServer::new(kv_server))
-            .add_service(RpcLeaseServer::from_arc(lease_server))
-            .add_service(RpcAuthServer::new(auth_server))
-            .add_service(RpcWatchServer::new(watch_server))
-            .add_service(RpcMaintenanceServer::new(maintenance_server))
-            .add_service(ProtocolServer::new(curp_server))
+            .add_service(ProtocolServer::new(curp_server.clone()))
+            .add_optional_service(serve_clients.then(|| RpcLockServer::new(lock_server)))
+            .add_optional_service(serve_clients.then(|| RpcKvServer::from_arc(kv_server)))
+            .add_optional_service(serve_clients.then(|| RpcLeaseServer::from_arc(lease_server)))
+            .add_optional_service(serve_clients.then(|| RpcAuthServer::new(auth_server)))
+            .add_optional_service(serve_clients.then(|| RpcWatchServer::new(watch_server)))
+            .add_optional_service(
+                serve_clients.then(|| RpcMaintenanceServer::new(maintenance_server)),
+            )
+            .add_optional_service(serve_clients.then(|| RpcClusterServer::new(cluster_server)))
             .serve_with_incoming_shutdown(TcpListenerStream::new(xline_listener), signal)
             .await?)
     }
 
+    /// Transfer the leadership to another voter before the current node shuts down,
+    /// so that the cluster doesn't have to wait for an election timeout
+    async fn transfer_leadership(&self, curp_server: &CurpServer) {
+        if !self.transfer_leadership_on_shutdown
+            || curp_server.status().leader.as_ref() != Some(&self.id())
+        {
+            return;
+        }
+        // the transfer fails if the transferee can't catch up in time, try the next one
+        let voters = curp_server
+            .members()
+            .into_iter()
+            .filter(|member| !member.is_learner && member.id != self.id());
+        for member in voters {
+            match curp_server.move_leader(&member.id).await {
+                Ok(()) => {
+                    info!(
+                        "transferred the leadership to {} before shutdown",
+                        member.id
+                    );
+                    return;
+                }
+                Err(e) => warn!("failed to transfer the leadership to {}: {e}", member.id),
+            }
+        }
+    }
+
     /// Leader change task
     async fn leader_change_task(
         mut rx: broadcast::Receiver<Option<String>>,
@@ -281,18 +358,19 @@ where
         }
     }
 
-    /// Init `KvServer`, `LockServer`, `LeaseServer`, `WatchServer` and `CurpServer`
-    /// for the Xline Server.
+    /// Init `KvServer`, `LockServer`, `LeaseServer`, `WatchServer`, `MaintenanceServer`,
+    /// `ClusterServer` and `CurpServer` for the Xline Server.
     #[allow(clippy::type_complexity)] // it is easy to read
     async fn init_servers(
         &self,
     ) -> (
-        KvServer<S>,
+        Arc<KvServer<S>>,
         LockServer,
         Arc<LeaseServer<S>>,
         AuthServer<S>,
         WatchServer<S>,
         MaintenanceServer<S>,
+        ClusterServer,
         CurpServer,
     ) {
         let curp_server = CurpServer::new(
@@ -303,6 +381,7 @@ where
                 Arc::clone(&self.kv_storage),
                 Arc::clone(&self.auth_storage),
                 Arc::clone(&self.lease_storage),
+                Arc::clone(&self.alarm_storage),
                 Arc::clone(&self.persistent),
                 Arc::clone(&self.index_barrier),
                 Arc::clone(&self.id_barrier),
@@ -317,16 +396,48 @@ where
             let rx = curp_server.leader_rx();
             Self::leader_change_task(rx, state, lease_storage)
         });
-        (
-            KvServer::new(
+        let kv_server = Arc::new(KvServer::new(
+            Arc::clone(&self.kv_storage),
+            Arc::clone(&self.auth_storage),
+            Arc::clone(&self.alarm_storage),
+            Arc::clone(&self.index_barrier),
+            Arc::clone(&self.id_barrier),
+            self.range_retry_timeout,
+            Arc::clone(&self.client),
+            self.id(),
+        ));
+        if let Some(auto_compact_config) = *self.compact_cfg.auto_compact_config() {
+            let auto_compactor = AutoCompactor::new(
+                Arc::clone(&kv_server),
                 Arc::clone(&self.kv_storage),
                 Arc::clone(&self.auth_storage),
-                Arc::clone(&self.index_barrier),
-                Arc::clone(&self.id_barrier),
-                self.range_retry_timeout,
+                Arc::clone(&self.state),
+            );
+            let _handle = tokio::spawn(auto_compactor.run(auto_compact_config));
+        }
+        if !self.corrupt_check_interval.is_zero() {
+            let corrupt_checker = CorruptChecker::new(
                 Arc::clone(&self.client),
-                self.id(),
-            ),
+                curp_server.clone(),
+                Arc::clone(&self.kv_storage),
+                Arc::clone(&self.auth_storage),
+                Arc::clone(&self.state),
+            );
+            let _handle = tokio::spawn(corrupt_checker.run(self.corrupt_check_interval));
+        }
+        if self.quota_backend_bytes > 0 {
+            let quota_checker = QuotaChecker::new(
+                Arc::clone(&self.client),
+                Arc::clone(&self.alarm_storage),
+                Arc::clone(&self.auth_storage),
+                Arc::clone(&self.persistent),
+                Arc::clone(&self.state),
+                self.quota_backend_bytes,
+            );
+            let _handle = tokio::spawn(quota_checker.run());
+        }
+        (
+            kv_server,
             LockServer::new(
                 Arc::clone(&self.client),
                 Arc::clone(&self.state),
@@ -336,6 +447,7 @@ where
             LeaseServer::new(
                 Arc::clone(&self.lease_storage),
                 Arc::clone(&self.auth_storage),
+                Arc::clone(&self.alarm_storage),
                 Arc::clone(&self.client),
                 self.id(),
                 Arc::clone(&self.state),
@@ -346,8 +458,26 @@ where
                 Arc::clone(&self.client),
                 self.id(),
             ),
-            WatchServer::new(self.kv_storage.kv_watcher()),
-            MaintenanceServer::new(Arc::clone(&self.persistent), Arc::clone(&self.header_gen)),
+            WatchServer::new(
+                self.kv_storage.kv_watcher(),
+                Arc::clone(&self.auth_storage),
+                self.watch_max_message_size,
+            ),
+            MaintenanceServer::new(
+                Arc::clone(&self.kv_storage),
+                Arc::clone(&self.alarm_storage),
+                Arc::clone(&self.persistent),
+                Arc::clone(&self.header_gen),
+                curp_server.clone(),
+                Arc::clone(&self.client),
+                self.id(),
+            ),
+            ClusterServer::new(
+                Arc::clone(&self.client),
+                curp_server.clone(),
+                Arc::clone(&self.state),
+                Arc::clone(&self.header_gen),
+            ),
             curp_server,
         )
     }
@@ -0,0 +1,286 @@
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+};
+
+use log::debug;
+use parking_lot::RwLock;
+use prost::Message;
+
+use super::{db::WriteOp, storage_api::StorageApi, ExecuteError};
+use crate::{
+    header_gen::HeaderGenerator,
+    rpc::{
+        AlarmAction, AlarmMember, AlarmRequest, AlarmResponse, AlarmType, RequestWithToken,
+        RequestWrapper,
+    },
+    server::command::{CommandResponse, SyncResponse},
+};
+
+/// Alarm table name
+pub(crate) const ALARM_TABLE: &str = "alarm";
+
+/// Alarm store, the alarms are raised and disarmed through consensus so that all members agree on them
+#[derive(Debug)]
+pub(crate) struct AlarmStore<DB>
+where
+    DB: StorageApi,
+{
+    /// Raised alarms, indexed by the alarm type
+    alarms: RwLock<HashMap<AlarmType, HashSet<u64>>>,
+    /// Header generator
+    header_gen: Arc<HeaderGenerator>,
+    /// DB to store the raised alarms
+    db: Arc<DB>,
+}
+
+impl<DB> AlarmStore<DB>
+where
+    DB: StorageApi,
+{
+    /// New `AlarmStore`
+    pub(crate) fn new(header_gen: Arc<HeaderGenerator>, db: Arc<DB>) -> Self {
+        Self {
+            alarms: RwLock::new(HashMap::new()),
+            header_gen,
+            db,
+        }
+    }
+
+    /// Recover the raised alarms from the db
+    pub(crate) fn recover(&self) -> Result<(), ExecuteError> {
+        let members = self
+            .db
+            .get_all(ALARM_TABLE)
+            .map_err(|e| ExecuteError::DbError(format!("Failed to get all alarms, error: {e}")))?
+            .into_iter()
+            .map(|(_, v)| {
+                AlarmMember::decode(v.as_slice()).map_err(|e| {
+                    ExecuteError::DbError(format!("Failed to decode alarm, error: {e}"))
+                })
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        let mut alarms = self.alarms.write();
+        alarms.clear();
+        for member in members {
+            if let Some(alarm) = AlarmType::from_i32(member.alarm) {
+                let _ig = alarms.entry(alarm).or_default().insert(member.member_id);
+            }
+        }
+        Ok(())
+    }
+
+    /// execute an alarm request
+    pub(crate) fn execute(
+        &self,
+        request: &RequestWithToken,
+    ) -> Result<CommandResponse, ExecuteError> {
+        #[allow(clippy::wildcard_enum_match_arm)]
+        let alarms = match request.request {
+            RequestWrapper::AlarmRequest(ref req) => {
+                debug!("Receive AlarmRequest {:?}", req);
+                self.handle_alarm_request(req)
+            }
+            _ => unreachable!("Other request should not be sent to this store"),
+        };
+        Ok(CommandResponse::new(
+            AlarmResponse {
+                header: Some(self.header_gen.gen_header()),
+                alarms,
+            }
+            .into(),
+        ))
+    }
+
+    /// sync an alarm request
+    pub(crate) fn after_sync(
+        &self,
+        request: &RequestWithToken,
+    ) -> Result<(SyncResponse, Vec<WriteOp>), ExecuteError> {
+        #[allow(clippy::wildcard_enum_match_arm)]
+        let ops = match request.request {
+            RequestWrapper::AlarmRequest(ref req) => {
+                debug!("Sync AlarmRequest {:?}", req);
+                self.sync_alarm_request(req)
+            }
+            _ => unreachable!("Other request should not be sent to this store"),
+        };
+        Ok((SyncResponse::new(self.header_gen.revision()), ops))
+    }
+
+    /// Get all raised alarms
+    pub(crate) fn get_all_alarms(&self) -> Vec<AlarmMember> {
+        self.get_alarms(AlarmType::None)
+    }
+
+    /// Check if any member has raised the alarm
+    pub(crate) fn has_alarm(&self, alarm: AlarmType) -> bool {
+        self.alarms.read().contains_key(&alarm)
+    }
+
+    /// Get raised alarms of the given type, get all alarms if `alarm` is `AlarmType::None`
+    fn get_alarms(&self, alarm: AlarmType) -> Vec<AlarmMember> {
+        let alarms = self.alarms.read();
+        let mut members = alarms
+            .iter()
+            .filter(|&(&ty, _)| alarm == AlarmType::None || ty == alarm)
+            .flat_map(|(&ty, member_ids)| {
+                member_ids.iter().map(move |&member_id| AlarmMember {
+                    member_id,
+                    alarm: ty.into(),
+                })
+            })
+            .collect::<Vec<_>>();
+        members.sort_unstable_by_key(|m| (m.alarm, m.member_id));
+        members
+    }
+
+    /// Check if the alarm of `member_id` has been raised
+    pub(crate) fn is_raised(&self, member_id: u64, alarm: AlarmType) -> bool {
+        self.alarms
+            .read()
+            .get(&alarm)
+            .map_or(false, |member_ids| member_ids.contains(&member_id))
+    }
+
+    /// Handle `AlarmRequest`, return the alarms affected by the request
+    fn handle_alarm_request(&self, req: &AlarmRequest) -> Vec<AlarmMember> {
+        let alarm = AlarmType::from_i32(req.alarm).unwrap_or(AlarmType::None);
+        let member = AlarmMember {
+            member_id: req.member_id,
+            alarm: req.alarm,
+        };
+        match AlarmAction::from_i32(req.action) {
+            Some(AlarmAction::Get) => self.get_alarms(alarm),
+            Some(AlarmAction::Activate) if alarm != AlarmType::None => vec![member],
+            Some(AlarmAction::Deactivate) if self.is_raised(req.member_id, alarm) => {
+                vec![member]
+            }
+            Some(AlarmAction::Activate | AlarmAction::Deactivate) | None => vec![],
+        }
+    }
+
+    /// Sync `AlarmRequest`
+    fn sync_alarm_request(&self, req: &AlarmRequest) -> Vec<WriteOp> {
+        let Some(alarm) = AlarmType::from_i32(req.alarm).filter(|&a| a != AlarmType::None) else {
+            return vec![];
+        };
+        let member = AlarmMember {
+            member_id: req.member_id,
+            alarm: req.alarm,
+        };
+        let mut alarms = self.alarms.write();
+        match AlarmAction::from_i32(req.action) {
+            Some(AlarmAction::Activate) => {
+                if alarms.entry(alarm).or_default().insert(req.member_id) {
+                    return vec![WriteOp::PutAlarm(member)];
+                }
+            }
+            Some(AlarmAction::Deactivate) => {
+                if let Some(member_ids) = alarms.get_mut(&alarm) {
+                    let removed = member_ids.remove(&req.member_id);
+                    if member_ids.is_empty() {
+                        let _ig = alarms.remove(&alarm);
+                    }
+                    if removed {
+                        return vec![WriteOp::DeleteAlarm(member)];
+                    }
+                }
+            }
+            Some(AlarmAction::Get) | None => {}
+        }
+        vec![]
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use utils::config::EngineConfig;
+
+    use super::*;
+    use crate::storage::db::DBProxy;
+
+    fn alarm_request(action: AlarmAction, member_id: u64, alarm: AlarmType) -> RequestWithToken {
+        RequestWithToken::new(
+            AlarmRequest {
+                action: action.into(),
+                member_id,
+                alarm: alarm.into(),
+            }
+            .into(),
+        )
+    }
+
+    fn sync_request<DB: StorageApi>(
+        store: &AlarmStore<DB>,
+        request: &RequestWithToken,
+    ) -> Result<(), ExecuteError> {
+        let (_, ops) = store.after_sync(request)?;
+        store.db.flush_ops(ops)
+    }
+
+    #[test]
+    fn test_alarm_activate_and_deactivate() -> Result<(), ExecuteError> {
+        let db = DBProxy::open(&EngineConfig::Memory)?;
+        let store = AlarmStore::new(Arc::new(HeaderGenerator::new(0, 0)), Arc::clone(&db));
+        let activate = alarm_request(AlarmAction::Activate, 1, AlarmType::Corrupt);
+        sync_request(&store, &activate)?;
+        sync_request(
+            &store,
+            &alarm_request(AlarmAction::Activate, 2, AlarmType::Nospace),
+        )?;
+        assert_eq!(store.get_all_alarms().len(), 2);
+        assert!(store.has_alarm(AlarmType::Nospace));
+
+        let get = alarm_request(AlarmAction::Get, 0, AlarmType::Corrupt);
+        let AlarmResponse { alarms, .. } = store.execute(&get)?.decode().into();
+        assert_eq!(
+            alarms,
+            vec![AlarmMember {
+                member_id: 1,
+                alarm: AlarmType::Corrupt.into(),
+            }]
+        );
+
+        let deactivate = alarm_request(AlarmAction::Deactivate, 1, AlarmType::Corrupt);
+        let AlarmResponse { alarms, .. } = store.execute(&deactivate)?.decode().into();
+        assert_eq!(alarms.len(), 1);
+        sync_request(&store, &deactivate)?;
+        let AlarmResponse { alarms, .. } = store.execute(&deactivate)?.decode().into();
+        assert!(alarms.is_empty());
+        assert!(!store.has_alarm(AlarmType::Corrupt));
+        assert_eq!(
+            store.get_all_alarms(),
+            vec![AlarmMember {
+                member_id: 2,
+                alarm: AlarmType::Nospace.into(),
+            }]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_alarm_recover() -> Result<(), ExecuteError> {
+        let db = DBProxy::open(&EngineConfig::Memory)?;
+        let store = AlarmStore::new(Arc::new(HeaderGenerator::new(0, 0)), Arc::clone(&db));
+        sync_request(
+            &store,
+            &alarm_request(AlarmAction::Activate, 1, AlarmType::Nospace),
+        )?;
+        sync_request(
+            &store,
+            &alarm_request(AlarmAction::Activate, 2, AlarmType::Corrupt),
+        )?;
+        sync_request(
+            &store,
+            &alarm_request(AlarmAction::Deactivate, 2, AlarmType::Corrupt),
+        )?;
+
+        let new_store = AlarmStore::new(Arc::new(HeaderGenerator::new(0, 0)), Arc::clone(&db));
+        new_store.recover()?;
+        assert_eq!(new_store.get_all_alarms(), store.get_all_alarms());
+        assert!(new_store.is_raised(1, AlarmType::Nospace));
+        assert!(!new_store.has_alarm(AlarmType::Corrupt));
+        Ok(())
+    }
+}
@@ -8,6 +8,7 @@ use std::{
 };
 
 use clippy_utilities::Cast;
+use event_listener::{Event, EventListener};
 use itertools::Itertools;
 use jsonwebtoken::{DecodingKey, EncodingKey};
 use log::debug;
@@ -71,6 +72,8 @@ where
     permission_cache: RwLock<PermissionCache>,
     /// The manager of token
     token_manager: Option<JwtTokenManager>,
+    /// Event of permissions being revoked
+    permission_revoked: Event,
 }
 
 impl<S> AuthStore<S>
@@ -96,6 +99,7 @@ where
             token_manager: key_pair.map(|(encoding_key, decoding_key)| {
                 JwtTokenManager::new(encoding_key, decoding_key)
             }),
+            permission_revoked: Event::new(),
         }
     }
 
@@ -592,6 +596,15 @@ where
                 unreachable!("Other request should not be sent to this store");
             }
         };
+        if matches!(
+            request.request,
+            RequestWrapper::AuthUserRevokeRoleRequest(_)
+                | RequestWrapper::AuthUserDeleteRequest(_)
+                | RequestWrapper::AuthRoleRevokePermissionRequest(_)
+                | RequestWrapper::AuthRoleDeleteRequest(_)
+        ) {
+            self.permission_revoked.notify(usize::MAX);
+        }
         Ok((SyncResponse::new(self.header_gen.revision()), ops))
     }
 
@@ -912,6 +925,7 @@ where
                 | RequestWrapper::AuthRoleDeleteRequest(_)
                 | RequestWrapper::AuthUserListRequest(_)
                 | RequestWrapper::AuthRoleListRequest(_)
+                | RequestWrapper::AlarmRequest(_)
         )
     }
 
@@ -994,6 +1008,47 @@ where
         Ok(())
     }
 
+    /// Check if the user of `token` can watch the key range, return the username
+    /// or `None` if auth is disabled
+    pub(crate) fn check_watch_permission(
+        &self,
+        token: Option<&str>,
+        key: &[u8],
+        range_end: &[u8],
+    ) -> Result<Option<String>, ExecuteError> {
+        if !self.is_enabled() {
+            return Ok(None);
+        }
+        let Some(token) = token else {
+            return Err(ExecuteError::token_not_provided());
+        };
+        let claims = self.verify_token(token)?;
+        if claims.revision < self.revision() {
+            return Err(ExecuteError::token_old_revision());
+        }
+        self.check_op_permission(&claims.username, key, range_end, Type::Read)?;
+        Ok(Some(claims.username))
+    }
+
+    /// Check if the user can still read the key range, it's used to recheck the
+    /// open watchers after permissions are revoked
+    pub(crate) fn check_read_permission(
+        &self,
+        username: &str,
+        key: &[u8],
+        range_end: &[u8],
+    ) -> Result<(), ExecuteError> {
+        if !self.is_enabled() {
+            return Ok(());
+        }
+        self.check_op_permission(username, key, range_end, Type::Read)
+    }
+
+    /// Listen to the revocation of permissions
+    pub(crate) fn permission_revoked(&self) -> EventListener {
+        self.permission_revoked.listen()
+    }
+
     /// check if range request is permitted
     fn check_range_permission(
         &self,
@@ -1143,9 +1198,7 @@ where
     /// Recover data from persistent storage
     pub(crate) fn recover(&self) -> Result<(), ExecuteError> {
         let enabled = self.backend.get_enable()?;
-        if enabled {
-            self.enabled.store(true, AtomicOrdering::Relaxed);
-        }
+        self.enabled.store(enabled, AtomicOrdering::Relaxed);
         let revision = self.backend.get_revision()?;
         self.revision.set(revision);
         self.create_permission_cache()?;
@@ -1158,7 +1211,7 @@ mod test {
     use std::collections::HashMap;
 
     use merged_range::MergedRange;
-    use utils::config::StorageConfig;
+    use utils::config::EngineConfig;
 
     use super::*;
     use crate::{
@@ -1175,7 +1228,7 @@ mod test {
 
     #[test]
     fn test_role_grant_permission() -> Result<(), ExecuteError> {
-        let db = DBProxy::open(&StorageConfig::Memory)?;
+        let db = DBProxy::open(&EngineConfig::Memory)?;
         let store = init_auth_store(db);
         let req = RequestWithToken::new(
             AuthRoleGrantPermissionRequest {
@@ -1211,7 +1264,7 @@ mod test {
 
     #[test]
     fn test_role_revoke_permission() -> Result<(), ExecuteError> {
-        let db = DBProxy::open(&StorageConfig::Memory)?;
+        let db = DBProxy::open(&EngineConfig::Memory)?;
         let store = init_auth_store(db);
         let req = RequestWithToken::new(
             AuthRoleRevokePermissionRequest {
@@ -1234,7 +1287,7 @@ mod test {
 
     #[test]
     fn test_role_delete() -> Result<(), ExecuteError> {
-        let db = DBProxy::open(&StorageConfig::Memory)?;
+        let db = DBProxy::open(&EngineConfig::Memory)?;
         let store = init_auth_store(db);
         let req = RequestWithToken::new(
             AuthRoleDeleteRequest {
@@ -1255,7 +1308,7 @@ mod test {
 
     #[test]
     fn test_user_delete() -> Result<(), ExecuteError> {
-        let db = DBProxy::open(&StorageConfig::Memory)?;
+        let db = DBProxy::open(&EngineConfig::Memory)?;
         let store = init_auth_store(db);
         let req = RequestWithToken::new(
             AuthUserDeleteRequest {
@@ -1276,7 +1329,7 @@ mod test {
 
     #[test]
     fn test_auth_enable_and_disable() {
-        let db = DBProxy::open(&StorageConfig::Memory).unwrap();
+        let db = DBProxy::open(&EngineConfig::Memory).unwrap();
         let store = init_auth_store(db);
         let revision = store.revision();
         assert!(!store.is_enabled());
@@ -1326,7 +1379,7 @@ mod test {
 
     #[test]
     fn test_recover() -> Result<(), ExecuteError> {
-        let db = DBProxy::open(&StorageConfig::Memory).unwrap();
+        let db = DBProxy::open(&EngineConfig::Memory).unwrap();
         let store = init_auth_store(Arc::clone(&db));
 
         let new_store = init_empty_store(db);
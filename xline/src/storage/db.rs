@@ -1,5 +1,6 @@
-use std::{collections::HashMap, sync::Arc};
+use std::{collections::HashMap, path::Path, sync::Arc};
 
+use clippy_utilities::{Cast, OverflowArithmetic};
 use engine::{
     engine_api::{SnapshotApi, StorageEngine},
     memory_engine::MemoryEngine,
@@ -7,9 +8,11 @@ use engine::{
     WriteOperation,
 };
 use prost::Message;
-use utils::config::StorageConfig;
+use tracing::warn;
+use utils::config::EngineConfig;
 
 use super::{
+    alarm_store::ALARM_TABLE,
     auth_store::{AUTH_ENABLE_KEY, AUTH_REVISION_KEY, AUTH_TABLE, ROLE_TABLE, USER_TABLE},
     kv_store::KV_TABLE,
     lease_store::LEASE_TABLE,
@@ -17,18 +20,22 @@ use super::{
     ExecuteError, Revision,
 };
 use crate::{
-    rpc::{PbLease, Role, User},
-    server::command::{APPLIED_INDEX_KEY, META_TABLE},
+    rpc::{AlarmMember, PbLease, Role, User},
+    server::{
+        command::{APPLIED_INDEX_KEY, COMPACT_REVISION_KEY, META_TABLE},
+        MAINTENANCE_SNAPSHOT_CHUNK_SIZE,
+    },
 };
 
 /// Xline Server Storage Table
-pub(crate) const XLINE_TABLES: [&str; 6] = [
+pub(crate) const XLINE_TABLES: [&str; 7] = [
     META_TABLE,
     KV_TABLE,
     LEASE_TABLE,
     AUTH_TABLE,
     USER_TABLE,
     ROLE_TABLE,
+    ALARM_TABLE,
 ];
 
 /// Database to store revision to kv mapping
@@ -50,8 +57,45 @@ where
             engine: Arc::new(engine),
         }
     }
+
+    /// Receive the snapshot into `path` and replace the storage by it, the storage is left
+    /// intact if the snapshot can't be received
+    async fn receive_and_apply_snapshot(
+        &self,
+        path: &str,
+        snapshot: &mut dyn SnapshotApi,
+    ) -> Result<(), ExecuteError> {
+        let mut received = self
+            .engine
+            .new_snapshot(path)
+            .map_err(|e| ExecuteError::DbError(format!("Failed to create snapshot: {e}")))?;
+        snapshot
+            .rewind()
+            .map_err(|e| ExecuteError::DbError(format!("Failed to rewind snapshot: {e}")))?;
+        // copy the snapshot into one that the engine can apply
+        let mut remain_size = snapshot.size();
+        while remain_size > 0 {
+            let buf_size = remain_size.min(MAINTENANCE_SNAPSHOT_CHUNK_SIZE);
+            let mut buf = vec![0; buf_size.cast()];
+            snapshot
+                .read_exact(&mut buf)
+                .await
+                .map_err(|e| ExecuteError::DbError(format!("Failed to read snapshot: {e}")))?;
+            received
+                .write_all(&buf)
+                .await
+                .map_err(|e| ExecuteError::DbError(format!("Failed to write snapshot: {e}")))?;
+            remain_size = remain_size.overflow_sub(buf_size);
+        }
+        // the engine only adds the data in the snapshot, so the stale data is removed first
+        self.reset()?;
+        self.engine
+            .apply_snapshot(received, &XLINE_TABLES)
+            .map_err(|e| ExecuteError::DbError(format!("Failed to apply snapshot: {e}")))
+    }
 }
 
+#[async_trait::async_trait]
 impl<S> StorageApi for DB<S>
 where
     S: StorageEngine,
@@ -100,6 +144,17 @@ where
         Ok(Box::new(snapshot))
     }
 
+    async fn apply_snapshot(&self, snapshot: &mut dyn SnapshotApi) -> Result<(), ExecuteError> {
+        let path = format!("/tmp/xline_snapshot_{}", uuid::Uuid::new_v4());
+        let result = self.receive_and_apply_snapshot(&path, snapshot).await;
+        if Path::new(&path).exists() {
+            if let Err(e) = tokio::fs::remove_dir_all(&path).await {
+                warn!("failed to remove snapshot files: {e}");
+            }
+        }
+        result
+    }
+
     fn reset(&self) -> Result<(), ExecuteError> {
         let start = vec![];
         let end = vec![0xff];
@@ -124,17 +179,48 @@ where
                 }
             })
             .collect::<HashMap<_, _>>();
+        let del_kv_key_buffer = ops
+            .iter()
+            .filter_map(|op| {
+                if let WriteOp::DeleteKeyValue(rev) = *op {
+                    Some((rev, rev.encode_to_vec()))
+                } else {
+                    None
+                }
+            })
+            .collect::<HashMap<_, _>>();
+        let del_alarm_key_buffer = ops
+            .iter()
+            .filter_map(|op| {
+                if let WriteOp::DeleteAlarm(ref member) = *op {
+                    Some(((member.member_id, member.alarm), member.encode_to_vec()))
+                } else {
+                    None
+                }
+            })
+            .collect::<HashMap<_, _>>();
         for op in ops {
             let wop = match op {
                 WriteOp::PutKeyValue(rev, value) => {
                     let key = rev.encode_to_vec();
                     WriteOperation::new_put(KV_TABLE, key, value.clone())
                 }
+                WriteOp::DeleteKeyValue(rev) => {
+                    let key = del_kv_key_buffer
+                        .get(&rev)
+                        .unwrap_or_else(|| panic!("revision({rev:?}) is not in del_kv_key_buffer"));
+                    WriteOperation::new_delete(KV_TABLE, key)
+                }
                 WriteOp::PutAppliedIndex(index) => WriteOperation::new_put(
                     META_TABLE,
                     APPLIED_INDEX_KEY.as_bytes().to_vec(),
                     index.to_le_bytes().to_vec(),
                 ),
+                WriteOp::PutCompactRevision(rev) => WriteOperation::new_put(
+                    META_TABLE,
+                    COMPACT_REVISION_KEY.as_bytes().to_vec(),
+                    rev.to_le_bytes().to_vec(),
+                ),
                 WriteOp::PutLease(lease) => WriteOperation::new_put(
                     LEASE_TABLE,
                     lease.id.encode_to_vec(),
@@ -170,6 +256,18 @@ where
                 WriteOp::DeleteRole(name) => {
                     WriteOperation::new_delete(ROLE_TABLE, name.as_bytes())
                 }
+                WriteOp::PutAlarm(member) => {
+                    let value = member.encode_to_vec();
+                    WriteOperation::new_put(ALARM_TABLE, value.clone(), value)
+                }
+                WriteOp::DeleteAlarm(member) => {
+                    let key = del_alarm_key_buffer
+                        .get(&(member.member_id, member.alarm))
+                        .unwrap_or_else(|| {
+                            panic!("alarm({member:?}) is not in del_alarm_key_buffer")
+                        });
+                    WriteOperation::new_delete(ALARM_TABLE, key)
+                }
             };
             wr_ops.push(wop);
         }
@@ -178,6 +276,24 @@ where
             .map_err(|e| ExecuteError::DbError(format!("Failed to flush ops, error: {e}")))?;
         Ok(())
     }
+
+    fn size(&self) -> Result<u64, ExecuteError> {
+        self.engine
+            .size(&XLINE_TABLES)
+            .map_err(|e| ExecuteError::DbError(format!("Failed to get db size, error: {e}")))
+    }
+
+    fn size_in_use(&self) -> Result<u64, ExecuteError> {
+        self.engine
+            .size_in_use(&XLINE_TABLES)
+            .map_err(|e| ExecuteError::DbError(format!("Failed to get db size in use, error: {e}")))
+    }
+
+    fn defragment(&self) -> Result<(), ExecuteError> {
+        self.engine
+            .defragment(&XLINE_TABLES)
+            .map_err(|e| ExecuteError::DbError(format!("Failed to defragment db, error: {e}")))
+    }
 }
 
 /// `DBProxy` is designed to mask the different type of `DB<MemoryEngine>` and `DB<RocksEngine>`
@@ -200,6 +316,7 @@ pub enum DBProxy {
     RocksDB(DB<RocksEngine>),
 }
 
+#[async_trait::async_trait]
 impl StorageApi for DBProxy {
     fn get_values<K>(
         &self,
@@ -239,6 +356,13 @@ impl StorageApi for DBProxy {
         }
     }
 
+    async fn apply_snapshot(&self, snapshot: &mut dyn SnapshotApi) -> Result<(), ExecuteError> {
+        match *self {
+            DBProxy::MemDB(ref inner_db) => inner_db.apply_snapshot(snapshot).await,
+            DBProxy::RocksDB(ref inner_db) => inner_db.apply_snapshot(snapshot).await,
+        }
+    }
+
     fn reset(&self) -> Result<(), ExecuteError> {
         match *self {
             DBProxy::MemDB(ref inner_db) => inner_db.reset(),
@@ -252,6 +376,27 @@ impl StorageApi for DBProxy {
             DBProxy::RocksDB(ref inner_db) => inner_db.flush_ops(ops),
         }
     }
+
+    fn size(&self) -> Result<u64, ExecuteError> {
+        match *self {
+            DBProxy::MemDB(ref inner_db) => inner_db.size(),
+            DBProxy::RocksDB(ref inner_db) => inner_db.size(),
+        }
+    }
+
+    fn size_in_use(&self) -> Result<u64, ExecuteError> {
+        match *self {
+            DBProxy::MemDB(ref inner_db) => inner_db.size_in_use(),
+            DBProxy::RocksDB(ref inner_db) => inner_db.size_in_use(),
+        }
+    }
+
+    fn defragment(&self) -> Result<(), ExecuteError> {
+        match *self {
+            DBProxy::MemDB(ref inner_db) => inner_db.defragment(),
+            DBProxy::RocksDB(ref inner_db) => inner_db.defragment(),
+        }
+    }
 }
 
 impl DBProxy {
@@ -261,14 +406,14 @@ impl DBProxy {
     ///
     /// Return `ExecuteError::DbError` when open db failed
     #[inline]
-    pub fn open(config: &StorageConfig) -> Result<Arc<DBProxy>, ExecuteError> {
+    pub fn open(config: &EngineConfig) -> Result<Arc<DBProxy>, ExecuteError> {
         match *config {
-            StorageConfig::Memory => {
+            EngineConfig::Memory => {
                 let engine = MemoryEngine::new(&XLINE_TABLES)
                     .map_err(|e| ExecuteError::DbError(format!("Cannot open database: {e}")))?;
                 Ok(Arc::new(DBProxy::MemDB(DB::new(engine))))
             }
-            StorageConfig::RocksDB(ref path) => {
+            EngineConfig::RocksDB(ref path) => {
                 let engine = RocksEngine::new(path, &XLINE_TABLES)
                     .map_err(|e| ExecuteError::DbError(format!("Cannot open database: {e}")))?;
                 Ok(Arc::new(DBProxy::RocksDB(DB::new(engine))))
@@ -284,8 +429,12 @@ impl DBProxy {
 pub enum WriteOp<'a> {
     /// Put a key-value pair to kv table
     PutKeyValue(Revision, Vec<u8>),
+    /// Delete a key-value pair from kv table
+    DeleteKeyValue(Revision),
     /// Put the applied index to meta table
     PutAppliedIndex(u64),
+    /// Put the compacted revision to meta table
+    PutCompactRevision(i64),
     /// Put a lease to lease table
     PutLease(PbLease),
     /// Delete a lease from lease table
@@ -302,6 +451,10 @@ pub enum WriteOp<'a> {
     PutRole(Role),
     /// Delete a role from role table
     DeleteRole(&'a str),
+    /// Put an alarm to alarm table
+    PutAlarm(AlarmMember),
+    /// Delete an alarm from alarm table
+    DeleteAlarm(AlarmMember),
 }
 
 #[cfg(test)]
@@ -312,7 +465,7 @@ mod test {
     #[test]
     fn test_reset() -> Result<(), ExecuteError> {
         let data_dir = PathBuf::from("/tmp/test_reset");
-        let db = DBProxy::open(&StorageConfig::RocksDB(data_dir.clone()))?;
+        let db = DBProxy::open(&EngineConfig::RocksDB(data_dir.clone()))?;
 
         let revision = Revision::new(1, 1);
         let key = revision.encode_to_vec();
@@ -332,14 +485,111 @@ mod test {
         Ok(())
     }
 
+    #[test]
+    fn test_delete_key_value() -> Result<(), ExecuteError> {
+        let db = DBProxy::open(&EngineConfig::Memory)?;
+        let revisions = [Revision::new(1, 0), Revision::new(2, 0)];
+        let ops = revisions
+            .iter()
+            .map(|rev| WriteOp::PutKeyValue(*rev, "value".into()))
+            .collect();
+        db.flush_ops(ops)?;
+        db.flush_ops(vec![
+            WriteOp::DeleteKeyValue(Revision::new(1, 0)),
+            WriteOp::PutCompactRevision(1),
+        ])?;
+
+        let keys = revisions
+            .iter()
+            .map(Revision::encode_to_vec)
+            .collect::<Vec<_>>();
+        let res = db.get_values(KV_TABLE, &keys)?;
+        assert_eq!(res, vec![None, Some("value".as_bytes().to_vec())]);
+        let res = db.get_value(META_TABLE, COMPACT_REVISION_KEY)?;
+        assert_eq!(res, Some(1_i64.to_le_bytes().to_vec()));
+        Ok(())
+    }
+
     #[tokio::test]
     async fn test_get_snapshot() -> Result<(), ExecuteError> {
         let data_dir = PathBuf::from("/tmp/test_get_snapshot");
-        let db = DBProxy::open(&StorageConfig::RocksDB(data_dir.clone()))?;
+        let db = DBProxy::open(&EngineConfig::RocksDB(data_dir.clone()))?;
         let mut res = db.get_snapshot()?;
         assert_ne!(res.size(), 0);
         res.clean().await.unwrap();
         std::fs::remove_dir_all(data_dir).unwrap();
         Ok(())
     }
+
+    #[tokio::test]
+    async fn test_apply_snapshot() -> Result<(), ExecuteError> {
+        let origin_data_dir = PathBuf::from("/tmp/test_apply_snapshot_origin");
+        let recover_data_dir = PathBuf::from("/tmp/test_apply_snapshot_recover");
+        let origin_db = DBProxy::open(&EngineConfig::RocksDB(origin_data_dir.clone()))?;
+        let recover_db = DBProxy::open(&EngineConfig::RocksDB(recover_data_dir.clone()))?;
+
+        let revision = Revision::new(1, 1);
+        let key = revision.encode_to_vec();
+        origin_db.flush_ops(vec![
+            WriteOp::PutKeyValue(revision, "value1".into()),
+            WriteOp::PutAppliedIndex(5),
+        ])?;
+
+        let mut snapshot = origin_db.get_snapshot()?;
+        recover_db.apply_snapshot(snapshot.as_mut()).await?;
+        snapshot.clean().await.unwrap();
+
+        let res = recover_db.get_value(KV_TABLE, &key)?;
+        assert_eq!(res, Some("value1".as_bytes().to_vec()));
+        let res = recover_db.get_value(META_TABLE, APPLIED_INDEX_KEY)?;
+        assert_eq!(res, Some(5_u64.to_le_bytes().to_vec()));
+
+        std::fs::remove_dir_all(origin_data_dir).unwrap();
+        std::fs::remove_dir_all(recover_data_dir).unwrap();
+        Ok(())
+    }
+
+    /// A snapshot that fails to be read
+    #[derive(Debug)]
+    struct BrokenSnapshot;
+
+    #[async_trait::async_trait]
+    impl SnapshotApi for BrokenSnapshot {
+        fn size(&self) -> u64 {
+            1
+        }
+
+        fn rewind(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+
+        async fn read_exact(&mut self, _buf: &mut [u8]) -> std::io::Result<()> {
+            Err(std::io::ErrorKind::UnexpectedEof.into())
+        }
+
+        async fn write_all(&mut self, _buf: &[u8]) -> std::io::Result<()> {
+            Ok(())
+        }
+
+        async fn clean(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_apply_broken_snapshot_will_keep_data() -> Result<(), ExecuteError> {
+        let data_dir = PathBuf::from("/tmp/test_apply_broken_snapshot_will_keep_data");
+        let db = DBProxy::open(&EngineConfig::RocksDB(data_dir.clone()))?;
+
+        let revision = Revision::new(1, 1);
+        let key = revision.encode_to_vec();
+        db.flush_ops(vec![WriteOp::PutKeyValue(revision, "value1".into())])?;
+
+        assert!(db.apply_snapshot(&mut BrokenSnapshot).await.is_err());
+        let res = db.get_value(KV_TABLE, &key)?;
+        assert_eq!(res, Some("value1".as_bytes().to_vec()));
+
+        std::fs::remove_dir_all(data_dir).unwrap();
+        Ok(())
+    }
 }
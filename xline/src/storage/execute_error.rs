@@ -18,6 +18,12 @@ pub enum ExecuteError {
     /// Permission denied
     #[error("permission denied")]
     PermissionDenied,
+    /// Revision is higher than current
+    #[error("required revision {0} is higher than current revision {1}")]
+    RevisionTooLarge(i64, i64),
+    /// Revision is compacted
+    #[error("required revision {0} has been compacted, compacted revision is {1}")]
+    RevisionCompacted(i64, i64),
 }
 
 impl ExecuteError {
@@ -135,3 +141,22 @@ impl ExecuteError {
         Self::AuthError("token's revision is older than current revision".to_owned())
     }
 }
+
+impl From<ExecuteError> for tonic::Status {
+    #[inline]
+    fn from(err: ExecuteError) -> Self {
+        match err {
+            ExecuteError::RevisionTooLarge(_, _) => tonic::Status::out_of_range(
+                "etcdserver: mvcc: required revision is a future revision",
+            ),
+            ExecuteError::RevisionCompacted(_, _) => tonic::Status::out_of_range(
+                "etcdserver: mvcc: required revision has been compacted",
+            ),
+            ExecuteError::PermissionDenied => tonic::Status::permission_denied(err.to_string()),
+            ExecuteError::DbError(_) => tonic::Status::internal(err.to_string()),
+            ExecuteError::KvError(_) | ExecuteError::LeaseError(_) | ExecuteError::AuthError(_) => {
+                tonic::Status::invalid_argument(err.to_string())
+            }
+        }
+    }
+}
@@ -21,6 +21,11 @@ impl Index {
         }
     }
 
+    /// Remove all keys from the index
+    pub(crate) fn clear(&self) {
+        self.index.lock().clear();
+    }
+
     /// Filter out `KeyRevision` that is less than one revision and convert to `Revision`
     fn filter_revision(revs: &[KeyRevision], revision: i64) -> Vec<Revision> {
         revs.iter()
@@ -49,6 +54,22 @@ impl Index {
         rev.filter(|kr| !kr.is_deleted())
             .map(KeyRevision::as_revision)
     }
+
+    /// Remove `KeyRevision`s that are older than `at_rev` and return the removed ones
+    ///
+    /// The latest `KeyRevision` which is less than or equal to `at_rev` will be kept
+    /// unless it represents a deletion.
+    fn compact_revision(revs: &mut Vec<KeyRevision>, at_rev: i64) -> Vec<KeyRevision> {
+        let Some(pos) = revs.iter().rposition(|rev| rev.mod_revision <= at_rev) else {
+            return vec![];
+        };
+        let end = if revs.get(pos).map_or(false, KeyRevision::is_deleted) {
+            pos.overflow_add(1)
+        } else {
+            pos
+        };
+        revs.drain(..end).collect()
+    }
 }
 
 /// Operations of Index
@@ -86,7 +107,8 @@ pub(crate) trait IndexOperate {
         version: i64,
     );
 
-    // TODO: fn compact(rev:i64)
+    /// Compact the index at the given revision and return the removed `Revision`s
+    fn compact(&self, at_rev: i64) -> Vec<Revision>;
 }
 
 impl IndexOperate for Index {
@@ -218,6 +240,20 @@ impl IndexOperate for Index {
         let new_rev = KeyRevision::new(create_revision, version, revision, sub_revision);
         index.entry(key).or_insert_with(Vec::new).push(new_rev);
     }
+
+    fn compact(&self, at_rev: i64) -> Vec<Revision> {
+        let mut index = self.index.lock();
+        let mut removed = Vec::new();
+        index.retain(|_k, revs| {
+            removed.extend(
+                Self::compact_revision(revs, at_rev)
+                    .iter()
+                    .map(KeyRevision::as_revision),
+            );
+            !revs.is_empty()
+        });
+        removed
+    }
 }
 
 #[cfg(test)]
@@ -278,6 +314,37 @@ mod test {
         );
     }
 
+    #[test]
+    fn test_compact() {
+        let index = init_and_test_insert();
+        index.insert_or_update_revision(b"foo", 4, 0);
+        index.delete(b"foo", b"", 5, 0);
+        index.insert_or_update_revision(b"bar", 6, 0);
+
+        assert_eq!(
+            index.compact(2),
+            vec![Revision::new(1, 3)],
+            "only the revisions before the latest one should be removed"
+        );
+        assert_eq!(
+            index.compact(5),
+            vec![
+                Revision::new(4, 0),
+                Revision::new(5, 0),
+                Revision::new(2, 2)
+            ]
+        );
+        assert_eq!(
+            *index.index.lock(),
+            BTreeMap::from_iter(vec![
+                (b"bar".to_vec(), vec![KeyRevision::new(6, 1, 6, 0)]),
+                (b"key".to_vec(), vec![KeyRevision::new(1, 3, 3, 1)]),
+            ])
+        );
+        assert_eq!(index.get(b"key", b"", 3), vec![Revision::new(3, 1)]);
+        assert!(index.get(b"foo", b"", 0).is_empty());
+    }
+
     #[test]
     fn test_restore() {
         let index = Index::new();
@@ -20,12 +20,12 @@ use crate::{
     header_gen::HeaderGenerator,
     revision_number::RevisionNumber,
     rpc::{
-        Compare, CompareResult, CompareTarget, DeleteRangeRequest, DeleteRangeResponse, Event,
-        EventType, KeyValue, PutRequest, PutResponse, RangeRequest, RangeResponse, Request,
-        RequestWithToken, RequestWrapper, ResponseWrapper, SortOrder, SortTarget, TargetUnion,
-        TxnRequest, TxnResponse,
+        CompactionRequest, CompactionResponse, Compare, CompareResult, CompareTarget,
+        DeleteRangeRequest, DeleteRangeResponse, Event, EventType, KeyValue, PutRequest,
+        PutResponse, RangeRequest, RangeResponse, Request, RequestWithToken, RequestWrapper,
+        ResponseWrapper, SortOrder, SortTarget, TargetUnion, TxnRequest, TxnResponse,
     },
-    server::command::{CommandResponse, KeyRange, SyncResponse},
+    server::command::{CommandResponse, KeyRange, SyncResponse, COMPACT_REVISION_KEY, META_TABLE},
     storage::{db::WriteOp, ExecuteError},
 };
 
@@ -58,6 +58,8 @@ where
     db: Arc<DB>,
     /// Revision
     revision: Arc<RevisionNumber>,
+    /// Compacted revision, -1 means the store has never been compacted
+    compacted_revision: RevisionNumber,
     /// Header generator
     header_gen: Arc<HeaderGenerator>,
     /// KV update sender
@@ -110,6 +112,22 @@ where
             .map(|(rev, ops)| (SyncResponse::new(rev), ops))
     }
 
+    /// Get revision of KV store
+    pub(crate) fn revision(&self) -> i64 {
+        self.inner.revision()
+    }
+
+    /// Get compacted revision of KV store
+    pub(crate) fn compacted_revision(&self) -> i64 {
+        self.inner.compacted_revision()
+    }
+
+    /// Compute the hash of the key-values whose revisions are not larger than `revision`,
+    /// return the hash and the compacted revision when the hash begins
+    pub(crate) fn hash_kv(&self, revision: i64) -> Result<(u32, i64), ExecuteError> {
+        self.inner.hash_kv(revision)
+    }
+
     /// Get KV watcher
     pub(crate) fn kv_watcher(&self) -> Arc<KvWatcher<DB>> {
         Arc::clone(&self.kv_watcher)
@@ -142,6 +160,7 @@ where
             index,
             db,
             revision: header_gen.revision_arc(),
+            compacted_revision: RevisionNumber::new(-1),
             header_gen,
             kv_update_tx,
             lease_cmd_tx,
@@ -153,6 +172,27 @@ where
         self.revision.get()
     }
 
+    /// Get compacted revision of KV store
+    pub(crate) fn compacted_revision(&self) -> i64 {
+        self.compacted_revision.get()
+    }
+
+    /// Check if the given revision is available for reading
+    fn check_revision(&self, revision: i64) -> Result<(), ExecuteError> {
+        let current_revision = self.revision();
+        if revision > current_revision {
+            return Err(ExecuteError::RevisionTooLarge(revision, current_revision));
+        }
+        let compacted_revision = self.compacted_revision();
+        if revision > 0 && revision < compacted_revision {
+            return Err(ExecuteError::RevisionCompacted(
+                revision,
+                compacted_revision,
+            ));
+        }
+        Ok(())
+    }
+
     /// Notify KV changes to KV watcher
     async fn notify_updates(&self, revision: i64, updates: Vec<Event>) {
         assert!(
@@ -340,12 +380,25 @@ where
 
     /// Recover data from current db
     async fn recover_from_current_db(&self) -> Result<(), ExecuteError> {
+        self.index.clear();
         let mut key_to_lease: HashMap<Vec<u8>, i64> = HashMap::new();
         let kvs = self.db.get_all(KV_TABLE)?;
+        let compacted_rev = match self.db.get_value(META_TABLE, COMPACT_REVISION_KEY)? {
+            Some(bytes) => {
+                let buf: [u8; 8] = bytes
+                    .try_into()
+                    .unwrap_or_else(|e| panic!("cannot decode compacted revision, {e:?}"));
+                i64::from_le_bytes(buf)
+            }
+            None => -1,
+        };
+        self.compacted_revision.set(compacted_rev);
 
+        // all key-value pairs may have been removed by compaction
         let current_rev = kvs
             .last()
-            .map_or(1, |pair| Revision::decode(&pair.0).revision());
+            .map_or(1, |pair| Revision::decode(&pair.0).revision())
+            .max(compacted_rev);
         self.revision.set(current_rev);
 
         for (key, value) in kvs {
@@ -374,6 +427,10 @@ where
 
         // compact Lock free
 
+        // let the watcher know where the recovered history ends, so that watchers
+        // asking for older revisions can catch up from the db
+        self.notify_updates(current_rev, vec![]).await;
+
         Ok(())
     }
 }
@@ -435,18 +492,69 @@ where
         Ok((kvs, total))
     }
 
-    /// Get `KeyValue` start from a revision and convert to `Event`
+    /// Compute the hash of the key-values whose revisions are not larger than `revision`,
+    /// the latest revision is used if `revision` is not positive
+    fn hash_kv(&self, revision: i64) -> Result<(u32, i64), ExecuteError> {
+        let compacted_revision = self.compacted_revision();
+        let revision = if revision <= 0 {
+            self.revision()
+        } else {
+            revision
+        };
+        self.check_revision(revision)?;
+        let mut revisions = self.index.get_from_rev(&[0], &[0], 1);
+        revisions.retain(|rev| rev.revision() <= revision);
+        // sort the revisions so that all members hash the key-values in the same order
+        revisions.sort_unstable_by_key(|rev| (rev.revision(), rev.sub_revision()));
+        let keys = revisions
+            .iter()
+            .map(Revision::encode_to_vec)
+            .collect::<Vec<_>>();
+        let values = self.db.get_values(KV_TABLE, &keys)?;
+        let mut hasher = crc32fast::Hasher::new();
+        for (key, value) in keys.iter().zip(values) {
+            // the index is updated before the ops are flushed, the hash is meaningless
+            // until all the revisions are persisted
+            let Some(value) = value else {
+                return Err(ExecuteError::DbError(format!(
+                    "key-values up to revision {revision} are not flushed yet"
+                )));
+            };
+            hasher.update(key);
+            hasher.update(&value);
+        }
+        Ok((hasher.finalize(), compacted_revision))
+    }
+
+    /// Get `KeyValue` between `revision` and `end_revision` (inclusive) and convert to `Event`,
+    /// the `prev_kv` of each `Event` will be filled from the previous revision if `prev_kv` is set
     pub(crate) fn get_event_from_revision(
         &self,
         key_range: KeyRange,
         revision: i64,
+        end_revision: i64,
+        prev_kv: bool,
     ) -> Result<Vec<Event>, ExecuteError> {
-        let revisions =
+        let compacted_revision = self.compacted_revision();
+        if revision < compacted_revision {
+            return Err(ExecuteError::RevisionCompacted(
+                revision,
+                compacted_revision,
+            ));
+        }
+        let mut revisions =
             self.index
                 .get_from_rev(key_range.range_start(), key_range.range_end(), revision);
-        let events = self
-            .get_values(&revisions)?
-            .into_iter()
+        revisions.retain(|rev| rev.revision() <= end_revision);
+        let kvs = self.get_values(&revisions)?;
+        // the index is updated before the ops are flushed, so a missing value means
+        // the revisions are not persisted yet and should be read later
+        if kvs.len() != revisions.len() {
+            return Err(ExecuteError::DbError(format!(
+                "events between revision {revision} and {end_revision} are not flushed yet"
+            )));
+        }
+        kvs.into_iter()
             .map(|kv| {
                 // Delete
                 #[allow(clippy::as_conversions)] // This cast is always valid
@@ -455,16 +563,22 @@ where
                 } else {
                     EventType::Put
                 };
+                // revision 0 means the latest revision in `get_range`
+                let prev_kv = if prev_kv && kv.mod_revision > 1 {
+                    self.get_range(&kv.key, &[], kv.mod_revision.overflow_sub(1))?
+                        .pop()
+                } else {
+                    None
+                };
                 let mut event = Event {
                     kv: Some(kv),
-                    prev_kv: None,
+                    prev_kv,
                     ..Default::default()
                 };
                 event.set_type(event_type);
-                event
+                Ok(event)
             })
-            .collect();
-        Ok(events)
+            .collect()
     }
 }
 
@@ -497,6 +611,10 @@ where
                 debug!("Receive TxnRequest {:?}", req);
                 self.handle_txn_request(req).map(Into::into)
             }
+            RequestWrapper::CompactionRequest(ref req) => {
+                debug!("Receive CompactionRequest {:?}", req);
+                self.handle_compaction_request(req).map(Into::into)
+            }
             _ => unreachable!("Other request should not be sent to this store"),
         };
         res
@@ -505,6 +623,7 @@ where
     /// Handle `RangeRequest`
     fn handle_range_request(&self, req: &RangeRequest) -> Result<RangeResponse, ExecuteError> {
         debug!("handle_range_request kvs");
+        self.check_revision(req.revision)?;
         let storage_fetch_limit = if (req.sort_order() != SortOrder::None)
             || (req.max_mod_revision != 0)
             || (req.min_mod_revision != 0)
@@ -612,11 +731,40 @@ where
         })
     }
 
+    /// Handle `CompactionRequest`
+    fn handle_compaction_request(
+        &self,
+        req: &CompactionRequest,
+    ) -> Result<CompactionResponse, ExecuteError> {
+        let current_revision = self.revision();
+        if req.revision > current_revision {
+            return Err(ExecuteError::RevisionTooLarge(
+                req.revision,
+                current_revision,
+            ));
+        }
+        let compacted_revision = self.compacted_revision();
+        if req.revision <= compacted_revision {
+            return Err(ExecuteError::RevisionCompacted(
+                req.revision,
+                compacted_revision,
+            ));
+        }
+        Ok(CompactionResponse {
+            header: Some(self.header_gen.gen_header()),
+        })
+    }
+
     /// Sync requests in kv store
     async fn sync_request(
         &self,
         wrapper: &RequestWrapper,
     ) -> Result<(i64, Vec<WriteOp>), ExecuteError> {
+        // compaction does not generate a new revision
+        if let RequestWrapper::CompactionRequest(ref req) = *wrapper {
+            debug!("sync compaction request: {:?}", req);
+            return Ok((self.revision(), self.sync_compaction(req.revision)));
+        }
         let next_revision = self.revision.next();
         #[allow(clippy::wildcard_enum_match_arm)] // only kv requests can be sent to kv store
         let (ops, events) = match *wrapper {
@@ -739,6 +887,24 @@ where
         Ok((ops, vec![event]))
     }
 
+    /// Sync `CompactionRequest`, remove the compacted revisions from index and
+    /// return the operations to delete them from DB
+    fn sync_compaction(&self, revision: i64) -> Vec<WriteOp> {
+        if revision <= self.compacted_revision() {
+            // the compaction has already been applied
+            return vec![];
+        }
+        let mut ops = self
+            .index
+            .compact(revision)
+            .into_iter()
+            .map(WriteOp::DeleteKeyValue)
+            .collect::<Vec<_>>();
+        ops.push(WriteOp::PutCompactRevision(revision));
+        self.compacted_revision.set(revision);
+        ops
+    }
+
     /// create events for a deletion
     fn new_deletion_events(revision: i64, prev_kvs: Vec<KeyValue>) -> Vec<Event> {
         prev_kvs
@@ -818,14 +984,14 @@ where
 #[cfg(test)]
 mod test {
 
-    use utils::config::StorageConfig;
+    use utils::config::EngineConfig;
 
     use super::*;
     use crate::{rpc::RequestOp, storage::db::DBProxy};
 
     #[tokio::test]
     async fn test_keys_only() -> Result<(), ExecuteError> {
-        let db = DBProxy::open(&StorageConfig::Memory)?;
+        let db = DBProxy::open(&EngineConfig::Memory)?;
         let store = init_store(db).await?;
 
         let request = RangeRequest {
@@ -845,7 +1011,7 @@ mod test {
 
     #[tokio::test]
     async fn test_range_empty() -> Result<(), ExecuteError> {
-        let db = DBProxy::open(&StorageConfig::Memory)?;
+        let db = DBProxy::open(&EngineConfig::Memory)?;
         let store = init_store(db).await?;
 
         let request = RangeRequest {
@@ -863,7 +1029,7 @@ mod test {
 
     #[tokio::test]
     async fn test_range_filter() -> Result<(), ExecuteError> {
-        let db = DBProxy::open(&StorageConfig::Memory)?;
+        let db = DBProxy::open(&EngineConfig::Memory)?;
         let store = init_store(db).await?;
 
         let request = RangeRequest {
@@ -886,7 +1052,7 @@ mod test {
 
     #[tokio::test]
     async fn test_range_sort() -> Result<(), ExecuteError> {
-        let db = DBProxy::open(&StorageConfig::Memory)?;
+        let db = DBProxy::open(&EngineConfig::Memory)?;
         let store = init_store(db).await?;
         let keys = ["a", "b", "c", "d", "e"];
         let reversed_keys = ["e", "d", "c", "b", "a"];
@@ -931,7 +1097,7 @@ mod test {
 
     #[tokio::test]
     async fn test_recover() -> Result<(), ExecuteError> {
-        let db = DBProxy::open(&StorageConfig::Memory)?;
+        let db = DBProxy::open(&EngineConfig::Memory)?;
         let _store = init_store(Arc::clone(&db)).await?;
 
         let new_store = init_empty_store(db);
@@ -993,7 +1159,7 @@ mod test {
             }
             .into(),
         );
-        let db = DBProxy::open(&StorageConfig::Memory)?;
+        let db = DBProxy::open(&EngineConfig::Memory)?;
         let store = init_store(db).await?;
         let (_ignore, ops) = store.after_sync(&txn_req).await?;
         store.inner.db.flush_ops(ops)?;
@@ -1010,6 +1176,94 @@ mod test {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn test_compaction() -> Result<(), ExecuteError> {
+        let db = DBProxy::open(&EngineConfig::Memory)?;
+        let store = init_store(Arc::clone(&db)).await?;
+        for val in ["a1", "a2"] {
+            let req = RequestWithToken::new(
+                PutRequest {
+                    key: "a".into(),
+                    value: val.into(),
+                    ..Default::default()
+                }
+                .into(),
+            );
+            let (_sync_res, ops) = store.after_sync(&req).await?;
+            db.flush_ops(ops)?;
+        }
+
+        let compact_req = RequestWithToken::new(
+            CompactionRequest {
+                revision: 7,
+                physical: true,
+            }
+            .into(),
+        );
+        let _cmd_res = store.execute(&compact_req)?;
+        let (sync_res, ops) = store.after_sync(&compact_req).await?;
+        assert_eq!(sync_res.revision(), 8);
+        db.flush_ops(ops)?;
+        assert_eq!(store.compacted_revision(), 7);
+        assert_eq!(db.get_all(KV_TABLE)?.len(), 6);
+
+        let range_req = |revision| RangeRequest {
+            key: "a".into(),
+            range_end: vec![],
+            revision,
+            ..Default::default()
+        };
+        assert!(matches!(
+            store.inner.handle_range_request(&range_req(6)),
+            Err(ExecuteError::RevisionCompacted(6, 7))
+        ));
+        assert!(matches!(
+            store.inner.handle_range_request(&range_req(9)),
+            Err(ExecuteError::RevisionTooLarge(9, 8))
+        ));
+        let res = store.inner.handle_range_request(&range_req(7))?;
+        assert_eq!(res.kvs[0].value, b"a1");
+        assert!(matches!(
+            store.execute(&compact_req),
+            Err(ExecuteError::RevisionCompacted(7, 7))
+        ));
+
+        let new_store = init_empty_store(db);
+        new_store.recover().await?;
+        assert_eq!(new_store.compacted_revision(), 7);
+        assert_eq!(new_store.revision(), 8);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_hash_kv() -> Result<(), ExecuteError> {
+        let db1 = DBProxy::open(&EngineConfig::Memory)?;
+        let store1 = init_store(db1).await?;
+        let db2 = DBProxy::open(&EngineConfig::Memory)?;
+        let store2 = init_store(db2).await?;
+
+        let (hash, compacted_revision) = store1.hash_kv(0)?;
+        assert_eq!(store2.hash_kv(0)?, (hash, compacted_revision));
+        assert_eq!(store1.hash_kv(store1.revision())?.0, hash);
+        assert_ne!(store1.hash_kv(4)?.0, hash);
+        assert_eq!(store1.hash_kv(4)?, store2.hash_kv(4)?);
+
+        // the put is synced but not flushed
+        let req = RequestWithToken::new(
+            PutRequest {
+                key: "a".into(),
+                value: "a1".into(),
+                ..Default::default()
+            }
+            .into(),
+        );
+        let (_sync_res, ops) = store1.after_sync(&req).await?;
+        assert!(matches!(store1.hash_kv(0), Err(ExecuteError::DbError(_))));
+        store1.inner.db.flush_ops(ops)?;
+        assert_ne!(store1.hash_kv(0)?.0, hash);
+        Ok(())
+    }
+
     fn sort_req(sort_order: SortOrder, sort_target: SortTarget) -> RangeRequest {
         RangeRequest {
             key: vec![0],
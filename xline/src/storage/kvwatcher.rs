@@ -2,20 +2,31 @@ use std::{
     collections::{HashMap, HashSet},
     hash::Hash,
     sync::Arc,
+    time::{Duration, Instant},
 };
 
-use futures::{stream::FuturesUnordered, StreamExt};
-use log::warn;
+use clippy_utilities::OverflowArithmetic;
+use log::{debug, warn};
 use parking_lot::RwLock;
-use tokio::sync::mpsc;
+use tokio::sync::mpsc::{self, error::TrySendError};
 use utils::parking_lot_lock::RwLockMap;
 
 use super::storage_api::StorageApi;
-use crate::{rpc::Event, server::command::KeyRange, storage::kv_store::KvStoreBackend};
+use crate::{
+    rpc::Event,
+    server::command::KeyRange,
+    storage::{kv_store::KvStoreBackend, ExecuteError},
+};
 
 /// Watch ID
 pub(crate) type WatchId = i64;
 
+/// Interval of catching up the unsynced watchers
+const SYNC_INTERVAL: Duration = Duration::from_millis(100);
+
+/// A victim whose events can't be delivered within this duration will be canceled
+const VICTIM_TIMEOUT: Duration = Duration::from_secs(30);
+
 /// Watcher
 #[derive(Debug)]
 struct Watcher {
@@ -27,8 +38,12 @@ struct Watcher {
     start_rev: i64,
     /// Event filters
     filters: Vec<i32>,
-    /// Sender of watch event
+    /// Whether the previous key-value pairs should be sent with events
+    prev_kv: bool,
+    /// Sender of watch event, it's a bounded buffer of this watcher only
     event_tx: mpsc::Sender<WatchEvent>,
+    /// Sender of the cancellation, it's not blocked by the events in `event_tx`
+    cancel_tx: mpsc::UnboundedSender<WatchEvent>,
 }
 
 impl PartialEq for Watcher {
@@ -52,14 +67,18 @@ impl Watcher {
         watch_id: WatchId,
         start_rev: i64,
         filters: Vec<i32>,
+        prev_kv: bool,
         event_tx: mpsc::Sender<WatchEvent>,
+        cancel_tx: mpsc::UnboundedSender<WatchEvent>,
     ) -> Self {
         Self {
             key_range,
             watch_id,
             start_rev,
             filters,
+            prev_kv,
             event_tx,
+            cancel_tx,
         }
     }
 
@@ -78,21 +97,58 @@ impl Watcher {
         self.start_rev
     }
 
-    /// Notify events
-    async fn notify(&self, (revision, mut events): (i64, Vec<Event>)) {
+    /// Notify events without blocking, the `WatchEvent` is given back if the receiver
+    /// is full so that a slow watcher won't block the others
+    fn notify(&self, (revision, mut events): (i64, Vec<Event>)) -> Result<(), WatchEvent> {
         if revision < self.start_rev() {
-            return;
+            return Ok(());
         }
         events.retain(|event| self.filters.iter().all(|filter| filter != &event.r#type));
+        if events.is_empty() {
+            return Ok(());
+        }
+        if !self.prev_kv {
+            for event in &mut events {
+                event.prev_kv = None;
+            }
+        }
         let watch_event = WatchEvent {
             id: self.watch_id(),
             events,
             revision,
+            compact_revision: 0,
+            cancel_reason: None,
         };
-        assert!(
-            self.event_tx.send(watch_event).await.is_ok(),
-            "WatchEvent receiver is closed"
-        );
+        self.try_send(watch_event)
+    }
+
+    /// Try to send a `WatchEvent`, the event is given back if the receiver is full
+    fn try_send(&self, watch_event: WatchEvent) -> Result<(), WatchEvent> {
+        match self.event_tx.try_send(watch_event) {
+            Ok(()) => Ok(()),
+            Err(TrySendError::Full(watch_event)) => Err(watch_event),
+            // the watcher may be canceled while its events are being sent
+            Err(TrySendError::Closed(_)) => {
+                debug!("receiver of watcher {} is closed", self.watch_id());
+                Ok(())
+            }
+        }
+    }
+
+    /// Tell the receiver that this watcher is canceled. The watcher has been removed
+    /// from the KV watcher, the cancellation is sent through `cancel_tx` so that it
+    /// won't wait for the pending events
+    fn notify_canceled(&self, compact_revision: i64, reason: String) {
+        let watch_event = WatchEvent {
+            id: self.watch_id(),
+            events: vec![],
+            revision: 0,
+            compact_revision,
+            cancel_reason: Some(reason),
+        };
+        if self.cancel_tx.send(watch_event).is_err() {
+            debug!("receiver of watcher {} is closed", self.watch_id());
+        }
     }
 }
 
@@ -114,8 +170,40 @@ where
 {
     /// KV storage
     storage: Arc<KvStoreBackend<S>>,
-    /// Watch indexes
-    watcher_map: RwLock<WatcherMap>,
+    /// Watchers grouped by whether they have caught up with the KV store
+    groups: RwLock<WatcherGroups>,
+    /// Trigger to catch up the unsynced watchers
+    sync_trigger: event_listener::Event,
+}
+
+/// Watchers grouped by whether they have caught up with the KV store
+///
+/// A synced watcher has received all events up to `dispatched_rev`, it will be notified
+/// by the following KV updates. An unsynced watcher still needs events from the history,
+/// it will be moved to the synced group once it has received all dispatched events.
+/// A victim is a watcher whose receiver is full, it keeps the events that can't be sent
+/// and becomes unsynced once they are delivered.
+#[derive(Debug)]
+struct WatcherGroups {
+    /// Synced watchers
+    synced: WatcherMap,
+    /// Unsynced watchers and the next revision they need
+    unsynced: HashMap<WatchId, (Arc<Watcher>, i64)>,
+    /// Victims
+    victims: HashMap<WatchId, Victim>,
+    /// The latest revision whose updates have been dispatched to synced watchers
+    dispatched_rev: i64,
+}
+
+/// A slow watcher whose events can't be sent
+#[derive(Debug)]
+struct Victim {
+    /// The watcher
+    watcher: Arc<Watcher>,
+    /// The events that are waiting to be sent
+    pending: WatchEvent,
+    /// When the watcher became a victim
+    since: Instant,
 }
 
 /// Store all watchers
@@ -187,7 +275,15 @@ where
         let inner_clone = Arc::clone(&inner);
         let _handle = tokio::spawn(async move {
             while let Some(updates) = kv_update_rx.recv().await {
-                inner_clone.handle_kv_updates(updates).await;
+                inner_clone.handle_kv_updates(updates);
+            }
+        });
+        let sync_inner = Arc::clone(&inner);
+        let _sync_handle = tokio::spawn(async move {
+            loop {
+                let listener = sync_inner.sync_trigger.listen();
+                let _elapsed = tokio::time::timeout(SYNC_INTERVAL, listener).await;
+                sync_inner.sync_watchers();
             }
         });
         Self { inner }
@@ -198,18 +294,31 @@ where
 #[allow(clippy::integer_arithmetic, clippy::indexing_slicing)] // Introduced by mockall::automock
 #[cfg_attr(test, mockall::automock)]
 pub(crate) trait KvWatcherOps {
-    /// Create a watch to KV store
+    /// Create a watch to KV store, return the current revision. Historical events since
+    /// `start_rev` are sent through `event_tx` before the following updates, and the
+    /// cancellation by the KV watcher is sent through `cancel_tx`. Events will carry the
+    /// previous key-value pairs if `prev_kv` is set
+    ///
+    /// # Errors
+    ///
+    /// Return `ExecuteError::RevisionCompacted` if `start_rev` has been compacted
+    #[allow(clippy::too_many_arguments)]
     fn watch(
         &self,
         id: WatchId,
         key_range: KeyRange,
         start_rev: i64,
         filters: Vec<i32>,
+        prev_kv: bool,
         event_tx: mpsc::Sender<WatchEvent>,
-    ) -> (Vec<Event>, i64);
+        cancel_tx: mpsc::UnboundedSender<WatchEvent>,
+    ) -> Result<i64, ExecuteError>;
 
     /// Cancel a watch from KV store
     fn cancel(&self, id: WatchId) -> i64;
+
+    /// Get the current revision of KV store
+    fn revision(&self) -> i64;
 }
 
 impl<S> KvWatcherOps for KvWatcher<S>
@@ -217,22 +326,31 @@ where
     S: StorageApi,
 {
     /// Create a watch to KV store
+    #[allow(clippy::too_many_arguments)]
     fn watch(
         &self,
         id: WatchId,
         key_range: KeyRange,
         start_rev: i64,
         filters: Vec<i32>,
+        prev_kv: bool,
         event_tx: mpsc::Sender<WatchEvent>,
-    ) -> (Vec<Event>, i64) {
-        self.inner
-            .watch(id, key_range, start_rev, filters, event_tx)
+        cancel_tx: mpsc::UnboundedSender<WatchEvent>,
+    ) -> Result<i64, ExecuteError> {
+        self.inner.watch(
+            id, key_range, start_rev, filters, prev_kv, event_tx, cancel_tx,
+        )
     }
 
     /// Cancel a watch from KV store
     fn cancel(&self, id: WatchId) -> i64 {
         self.inner.cancel(id)
     }
+
+    /// Get the current revision of KV store
+    fn revision(&self) -> i64 {
+        self.inner.storage.revision()
+    }
 }
 
 impl<S> KvWatcherInner<S>
@@ -243,49 +361,188 @@ where
     fn new(storage: Arc<KvStoreBackend<S>>) -> Self {
         Self {
             storage,
-            watcher_map: RwLock::new(WatcherMap::new()),
+            groups: RwLock::new(WatcherGroups {
+                synced: WatcherMap::new(),
+                unsynced: HashMap::new(),
+                victims: HashMap::new(),
+                dispatched_rev: 0,
+            }),
+            sync_trigger: event_listener::Event::new(),
         }
     }
 
     /// Create a watch to KV store
+    #[allow(clippy::too_many_arguments)]
     fn watch(
         &self,
         id: WatchId,
         key_range: KeyRange,
         start_rev: i64,
         filters: Vec<i32>,
+        prev_kv: bool,
         event_tx: mpsc::Sender<WatchEvent>,
-    ) -> (Vec<Event>, i64) {
-        let watcher = Watcher::new(key_range.clone(), id, start_rev, filters, event_tx);
+        cancel_tx: mpsc::UnboundedSender<WatchEvent>,
+    ) -> Result<i64, ExecuteError> {
+        let compacted_revision = self.storage.compacted_revision();
+        if start_rev > 0 && start_rev < compacted_revision {
+            return Err(ExecuteError::RevisionCompacted(
+                start_rev,
+                compacted_revision,
+            ));
+        }
+        let watcher = Arc::new(Watcher::new(
+            key_range, id, start_rev, filters, prev_kv, event_tx, cancel_tx,
+        ));
 
         let revision = self.storage.revision();
-        // TODO: handle racing that new event is generated before watcher is registered
-        let initial_events = if start_rev == 0 {
-            vec![]
+        let mut groups = self.groups.write();
+        if start_rev == 0 || start_rev > groups.dispatched_rev {
+            groups.synced.insert(watcher);
         } else {
-            self.storage
-                .get_event_from_revision(key_range, start_rev)
-                .unwrap_or_else(|e| {
-                    warn!("failed to get initial events for watcher: {:?}", e);
-                    vec![]
-                })
-        };
-
-        self.watcher_map.write().insert(Arc::new(watcher));
+            assert!(
+                groups.unsynced.insert(id, (watcher, start_rev)).is_none(),
+                "can't insert a watcher twice"
+            );
+            self.sync_trigger.notify(1);
+        }
 
-        (initial_events, revision)
+        Ok(revision)
     }
 
     /// Cancel a watch from KV store
     fn cancel(&self, watch_id: WatchId) -> i64 {
         let revision = self.storage.revision();
-        self.watcher_map.write().remove(watch_id);
+        let mut groups = self.groups.write();
+        // the watcher may have been removed from all groups if it's canceled by the KV watcher
+        if groups.unsynced.remove(&watch_id).is_none()
+            && groups.victims.remove(&watch_id).is_none()
+            && groups.synced.watchers.contains_key(&watch_id)
+        {
+            groups.synced.remove(watch_id);
+        }
         revision
     }
 
+    /// Catch up the unsynced watchers with the history in KV store. A watcher is moved
+    /// to the synced group if no updates have been dispatched while it's catching up,
+    /// otherwise it will continue from the last revision it has received.
+    fn sync_watchers(&self) {
+        self.retry_victims();
+        let (unsynced, target_rev) = self.groups.map_read(|groups| {
+            let unsynced = groups
+                .unsynced
+                .values()
+                .map(|&(ref watcher, next_rev)| (Arc::clone(watcher), next_rev))
+                .collect::<Vec<_>>();
+            (unsynced, groups.dispatched_rev)
+        });
+        for (watcher, next_rev) in unsynced {
+            let watch_id = watcher.watch_id();
+            let events = self.storage.get_event_from_revision(
+                watcher.key_range().clone(),
+                next_rev,
+                target_rev,
+                watcher.prev_kv,
+            );
+            let sent = match events {
+                Ok(events) => {
+                    if events.is_empty() {
+                        Ok(())
+                    } else {
+                        watcher.notify((target_rev, events))
+                    }
+                }
+                Err(ExecuteError::RevisionCompacted(_, compacted_rev)) => {
+                    let removed = self.groups.write().unsynced.remove(&watch_id);
+                    if let Some((watcher, _)) = removed {
+                        watcher.notify_canceled(
+                            compacted_rev,
+                            "mvcc: required revision has been compacted".to_owned(),
+                        );
+                    }
+                    continue;
+                }
+                Err(e) => {
+                    // retry in the next round
+                    debug!("failed to catch up watcher {watch_id}: {e:?}");
+                    continue;
+                }
+            };
+            let mut groups = self.groups.write();
+            // the watcher may have been canceled while catching up
+            if let Err(pending) = sent {
+                if let Some((watcher, _)) = groups.unsynced.remove(&watch_id) {
+                    Self::add_victim(&mut groups, watcher, pending);
+                }
+                continue;
+            }
+            let caught_up = groups.dispatched_rev == target_rev;
+            if caught_up {
+                if let Some((watcher, _)) = groups.unsynced.remove(&watch_id) {
+                    groups.synced.insert(watcher);
+                }
+            } else if let Some(&mut (_, ref mut rev)) = groups.unsynced.get_mut(&watch_id) {
+                *rev = target_rev.overflow_add(1);
+            }
+        }
+    }
+
+    /// Retry the pending events of victims. A victim becomes unsynced once its events are
+    /// sent, or it's canceled if its receiver has been full for too long
+    fn retry_victims(&self) {
+        let mut groups = self.groups.write();
+        let victims = std::mem::take(&mut groups.victims);
+        for (watch_id, victim) in victims {
+            let Victim {
+                watcher,
+                pending,
+                since,
+            } = victim;
+            let revision = pending.revision();
+            match watcher.try_send(pending) {
+                Ok(()) => {
+                    let _prev = groups
+                        .unsynced
+                        .insert(watch_id, (watcher, revision.overflow_add(1)));
+                }
+                Err(_pending) if since.elapsed() > VICTIM_TIMEOUT => {
+                    warn!("watcher {watch_id} is canceled since it's too slow");
+                    watcher.notify_canceled(0, "watcher is too slow to receive events".to_owned());
+                }
+                Err(pending) => {
+                    let _prev = groups.victims.insert(
+                        watch_id,
+                        Victim {
+                            watcher,
+                            pending,
+                            since,
+                        },
+                    );
+                }
+            }
+        }
+    }
+
+    /// Move a watcher whose receiver is full into victims
+    fn add_victim(groups: &mut WatcherGroups, watcher: Arc<Watcher>, pending: WatchEvent) {
+        debug!("watcher {} becomes a victim", watcher.watch_id());
+        let _prev = groups.victims.insert(
+            watcher.watch_id(),
+            Victim {
+                watcher,
+                pending,
+                since: Instant::now(),
+            },
+        );
+    }
+
     /// Handle KV store updates
-    async fn handle_kv_updates(&self, (revision, all_events): (i64, Vec<Event>)) {
-        let watcher_events = self.watcher_map.map_read(|watcher_map_r| {
+    fn handle_kv_updates(&self, (revision, all_events): (i64, Vec<Event>)) {
+        // updates are dispatched under the write lock, so that no watcher can be moved
+        // to the synced group in the meantime and miss them
+        self.groups.map_write(|mut groups| {
+            groups.dispatched_rev = revision;
+            let watcher_map_r = &groups.synced;
             let mut watcher_events: HashMap<Arc<Watcher>, Vec<Event>> = HashMap::new();
             for event in all_events {
                 // get related watchers
@@ -312,15 +569,13 @@ where
                         .push(event.clone());
                 }
             }
-            watcher_events
+            for (watcher, events) in watcher_events {
+                if let Err(pending) = watcher.notify((revision, events)) {
+                    groups.synced.remove(watcher.watch_id());
+                    Self::add_victim(&mut groups, watcher, pending);
+                }
+            }
         });
-
-        let _ig = watcher_events
-            .into_iter()
-            .map(|(watcher, events)| async move { watcher.notify((revision, events)).await })
-            .collect::<FuturesUnordered<_>>()
-            .collect::<Vec<_>>()
-            .await;
     }
 }
 
@@ -333,6 +588,11 @@ pub(crate) struct WatchEvent {
     events: Vec<Event>,
     /// Revision when this event is generated
     revision: i64,
+    /// Compacted revision if the watcher is canceled since its events have been compacted,
+    /// 0 otherwise
+    compact_revision: i64,
+    /// Reason of cancellation if the watcher is canceled by the KV watcher
+    cancel_reason: Option<String>,
 }
 
 impl WatchEvent {
@@ -341,6 +601,16 @@ impl WatchEvent {
         self.revision
     }
 
+    /// Get compacted revision
+    pub(crate) fn compact_revision(&self) -> i64 {
+        self.compact_revision
+    }
+
+    /// Take the reason of cancellation
+    pub(crate) fn take_cancel_reason(&mut self) -> Option<String> {
+        self.cancel_reason.take()
+    }
+
     /// Get `WatchId`
     pub(crate) fn watch_id(&self) -> WatchId {
         self.id
@@ -351,3 +621,178 @@ impl WatchEvent {
         std::mem::take(&mut self.events)
     }
 }
+
+#[cfg(test)]
+mod test {
+    use utils::config::EngineConfig;
+
+    use super::*;
+    use crate::{
+        header_gen::HeaderGenerator,
+        rpc::{KeyValue, PutRequest, RequestWithToken},
+        storage::{db::DBProxy, index::Index, lease_store::LeaseMessage, KvStore},
+    };
+
+    fn init_empty_store(db: Arc<DBProxy>) -> KvStore<DBProxy> {
+        let header_gen = Arc::new(HeaderGenerator::new(0, 0));
+        let (lease_cmd_tx, mut lease_cmd_rx) = mpsc::channel(128);
+        let index = Arc::new(Index::new());
+        let _handle = tokio::spawn(async move {
+            while let Some(LeaseMessage::GetLease(tx, _)) = lease_cmd_rx.recv().await {
+                assert!(tx.send(0).is_ok());
+            }
+        });
+        KvStore::new(lease_cmd_tx, header_gen, db, index)
+    }
+
+    async fn put(store: &KvStore<DBProxy>, db: &DBProxy, key: &str, value: &str) {
+        let req = RequestWithToken::new(
+            PutRequest {
+                key: key.into(),
+                value: value.into(),
+                ..Default::default()
+            }
+            .into(),
+        );
+        let _cmd_res = store.execute(&req).unwrap();
+        let (_sync_res, ops) = store.after_sync(&req).await.unwrap();
+        db.flush_ops(ops).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_watch_history_without_gaps_or_duplicates() {
+        let db = DBProxy::open(&EngineConfig::Memory).unwrap();
+        let store = init_empty_store(Arc::clone(&db));
+        for i in 0..5 {
+            put(&store, &db, "foo", &i.to_string()).await;
+        }
+
+        let (event_tx, mut event_rx) = mpsc::channel(128);
+        let (cancel_tx, _cancel_rx) = mpsc::unbounded_channel();
+        let _revision = store
+            .kv_watcher()
+            .watch(
+                1,
+                KeyRange::new_one_key("foo"),
+                1,
+                vec![],
+                false,
+                event_tx,
+                cancel_tx,
+            )
+            .unwrap();
+        // new events are generated while the watcher is catching up
+        for i in 5..10 {
+            put(&store, &db, "foo", &i.to_string()).await;
+        }
+
+        let mut revisions = vec![];
+        while revisions.len() < 10 {
+            let mut event = tokio::time::timeout(Duration::from_secs(3), event_rx.recv())
+                .await
+                .unwrap()
+                .unwrap();
+            revisions.extend(
+                event
+                    .take_events()
+                    .into_iter()
+                    .map(|e| e.kv.unwrap().mod_revision),
+            );
+        }
+        assert_eq!(revisions, (1..=10).collect::<Vec<_>>());
+        assert!(
+            tokio::time::timeout(Duration::from_millis(300), event_rx.recv())
+                .await
+                .is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_slow_watcher_will_not_block_others() {
+        let db = DBProxy::open(&EngineConfig::Memory).unwrap();
+        let store = init_empty_store(Arc::clone(&db));
+        let (slow_tx, mut slow_rx) = mpsc::channel(1);
+        let (fast_tx, mut fast_rx) = mpsc::channel(128);
+        let (cancel_tx, _cancel_rx) = mpsc::unbounded_channel();
+        let kv_watcher = store.kv_watcher();
+        let _revision = kv_watcher
+            .watch(
+                1,
+                KeyRange::new_one_key("foo"),
+                0,
+                vec![],
+                false,
+                slow_tx,
+                cancel_tx.clone(),
+            )
+            .unwrap();
+        let _revision = kv_watcher
+            .watch(
+                2,
+                KeyRange::new_one_key("foo"),
+                0,
+                vec![],
+                false,
+                fast_tx,
+                cancel_tx,
+            )
+            .unwrap();
+        for i in 0..5 {
+            put(&store, &db, "foo", &i.to_string()).await;
+        }
+
+        for revision in 1..=5 {
+            let event = tokio::time::timeout(Duration::from_secs(3), fast_rx.recv())
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(event.revision(), revision);
+        }
+
+        // the slow watcher catches up once it starts receiving
+        let mut revisions = vec![];
+        while revisions.len() < 5 {
+            let mut event = tokio::time::timeout(Duration::from_secs(3), slow_rx.recv())
+                .await
+                .unwrap()
+                .unwrap();
+            revisions.extend(
+                event
+                    .take_events()
+                    .into_iter()
+                    .map(|e| e.kv.unwrap().mod_revision),
+            );
+        }
+        assert_eq!(revisions, (1..=5).collect::<Vec<_>>());
+    }
+
+    #[tokio::test]
+    async fn test_cancellation_will_not_wait_for_full_buffer() {
+        let (event_tx, mut event_rx) = mpsc::channel(1);
+        let (cancel_tx, mut cancel_rx) = mpsc::unbounded_channel();
+        let watcher = Watcher::new(
+            KeyRange::new_one_key("foo"),
+            1,
+            0,
+            vec![],
+            false,
+            event_tx,
+            cancel_tx,
+        );
+        let event = Event {
+            kv: Some(KeyValue {
+                key: b"foo".to_vec(),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        assert!(watcher.notify((1, vec![event.clone()])).is_ok());
+        assert!(watcher.notify((2, vec![event])).is_err());
+
+        watcher.notify_canceled(0, "watcher is too slow to receive events".to_owned());
+        let mut canceled = cancel_rx.try_recv().unwrap();
+        assert_eq!(canceled.watch_id(), 1);
+        assert!(canceled.take_cancel_reason().is_some());
+        assert_eq!(event_rx.recv().await.unwrap().revision(), 1);
+    }
+}
@@ -401,11 +401,10 @@ where
     /// Recover data form persistent storage
     fn recover_from_current_db(&self) -> Result<(), ExecuteError> {
         let leases = self.get_all()?;
+        let mut lease_collection = self.lease_collection.write();
+        *lease_collection = LeaseCollection::new();
         for lease in leases {
-            let _ignore = self
-                .lease_collection
-                .write()
-                .grant(lease.id, lease.ttl, false);
+            let _ignore = lease_collection.grant(lease.id, lease.ttl, false);
         }
         Ok(())
     }
@@ -599,14 +598,14 @@ where
 mod test {
     use std::{error::Error, time::Duration};
 
-    use utils::config::StorageConfig;
+    use utils::config::EngineConfig;
 
     use super::*;
     use crate::storage::db::DBProxy;
 
     #[tokio::test(flavor = "multi_thread", worker_threads = 10)]
     async fn test_lease_storage() -> Result<(), Box<dyn Error>> {
-        let db = DBProxy::open(&StorageConfig::Memory)?;
+        let db = DBProxy::open(&EngineConfig::Memory)?;
         let lease_store = init_store(db);
 
         let req1 = RequestWithToken::new(LeaseGrantRequest { ttl: 10, id: 1 }.into());
@@ -633,7 +632,7 @@ mod test {
 
     #[tokio::test]
     async fn test_recover() -> Result<(), ExecuteError> {
-        let db = DBProxy::open(&StorageConfig::Memory)?;
+        let db = DBProxy::open(&EngineConfig::Memory)?;
         let store = init_store(Arc::clone(&db));
 
         let req1 = RequestWithToken::new(LeaseGrantRequest { ttl: 10, id: 1 }.into());
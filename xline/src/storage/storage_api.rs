@@ -3,6 +3,7 @@ use engine::engine_api::SnapshotApi;
 use super::{db::WriteOp, ExecuteError};
 
 /// The Stable Storage Api
+#[async_trait::async_trait]
 pub trait StorageApi: Send + Sync + 'static + std::fmt::Debug {
     /// Get values by keys from storage
     ///
@@ -44,6 +45,35 @@ pub trait StorageApi: Send + Sync + 'static + std::fmt::Debug {
     /// Get the snapshot of the storage
     fn get_snapshot(&self) -> Result<Box<dyn SnapshotApi>, ExecuteError>;
 
+    /// Apply a snapshot taken by `get_snapshot` to the storage, the data in the storage is
+    /// replaced by the snapshot, and it's left intact if the snapshot can't be received
+    ///
+    /// # Errors
+    ///
+    /// if error occurs in storage, return `Err(error)`
+    async fn apply_snapshot(&self, snapshot: &mut dyn SnapshotApi) -> Result<(), ExecuteError>;
+
     /// Flush the operations to storage
     fn flush_ops(&self, ops: Vec<WriteOp>) -> Result<(), ExecuteError>;
+
+    /// Get the size of the storage in bytes, including the space which could be reclaimed
+    ///
+    /// # Errors
+    ///
+    /// if error occurs in storage, return `Err(error)`
+    fn size(&self) -> Result<u64, ExecuteError>;
+
+    /// Get the size of the storage in use in bytes
+    ///
+    /// # Errors
+    ///
+    /// if error occurs in storage, return `Err(error)`
+    fn size_in_use(&self) -> Result<u64, ExecuteError>;
+
+    /// Reclaim the space which is no longer used by the storage
+    ///
+    /// # Errors
+    ///
+    /// if error occurs in storage, return `Err(error)`
+    fn defragment(&self) -> Result<(), ExecuteError>;
 }
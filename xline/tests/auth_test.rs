@@ -196,6 +196,41 @@ async fn test_auth_wrong_password() -> Result<(), Box<dyn Error>> {
     Ok(())
 }
 
+#[tokio::test(flavor = "multi_thread", worker_threads = 10)]
+async fn test_watch_authorization() -> Result<(), Box<dyn Error>> {
+    let mut cluster = Cluster::new(3).await;
+    cluster.start().await;
+    let client = cluster.client().await;
+    let mut auth_client = client.auth_client();
+
+    set_user(&mut auth_client, "u1", "123", "r1", b"foo", &[]).await?;
+    enable_auth(&mut auth_client).await?;
+    let mut u1_client = etcd_client::Client::connect(
+        vec![cluster.addrs()["server0"].to_string()],
+        Some(ConnectOptions::new().with_user("u1", "123")),
+    )
+    .await?;
+    let mut root_client = etcd_client::Client::connect(
+        vec![cluster.addrs()["server0"].to_string()],
+        Some(ConnectOptions::new().with_user("root", "123")),
+    )
+    .await?;
+
+    let (_watcher, mut denied_stream) = u1_client.watch("bar", None).await?;
+    let res = denied_stream.message().await?.unwrap();
+    assert!(res.canceled());
+
+    let (_watcher, mut stream) = u1_client.watch("foo", None).await?;
+    root_client
+        .role_revoke_permission("r1", "foo", None)
+        .await?;
+    let res = stream.message().await?.unwrap();
+    assert!(res.canceled());
+    assert!(res.cancel_reason().contains("permission denied"));
+
+    Ok(())
+}
+
 async fn set_user(
     client: &mut AuthClient,
     name: &str,
@@ -0,0 +1,48 @@
+use std::net::TcpListener;
+
+use common::Cluster;
+use etcd_client::MemberAddOptions;
+
+mod common;
+
+#[tokio::test]
+async fn test_member_add_and_list() -> Result<(), Box<dyn std::error::Error>> {
+    let mut cluster = Cluster::new(3).await;
+    cluster.start().await;
+    let addr = cluster.addrs()["server0"].clone();
+    let mut client = etcd_client::Client::connect([addr], None).await?;
+
+    let res = client.member_list().await?;
+    assert_eq!(res.members().len(), 3);
+    assert!(res.members().iter().all(|m| !m.is_learner()));
+
+    let port = TcpListener::bind("127.0.0.1:0")?.local_addr()?.port();
+    let peer_url = format!("http://127.0.0.1:{port}");
+    let res = client
+        .member_add(
+            [peer_url.clone()],
+            Some(MemberAddOptions::new().with_is_learner()),
+        )
+        .await?;
+    let added = res.member().unwrap();
+    assert!(added.is_learner());
+    assert_eq!(added.peer_urls(), [peer_url.clone()]);
+
+    let res = client.member_list().await?;
+    assert_eq!(res.members().len(), 4);
+    let member = res
+        .members()
+        .iter()
+        .find(|m| m.name() == added.name())
+        .unwrap();
+    assert!(member.is_learner());
+    assert_eq!(member.peer_urls(), [peer_url]);
+
+    // a voting member is added as a learner until it's promoted
+    let port = TcpListener::bind("127.0.0.1:0")?.local_addr()?.port();
+    let res = client
+        .member_add([format!("http://127.0.0.1:{port}")], None)
+        .await?;
+    assert!(res.member().unwrap().is_learner());
+    Ok(())
+}
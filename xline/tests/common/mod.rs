@@ -10,7 +10,10 @@ use tokio::{
     sync::broadcast::{self, Sender},
     time::{self, Duration},
 };
-use utils::config::{default_range_retry_timeout, ClientTimeout, CurpConfig, StorageConfig};
+use utils::config::{
+    default_corrupt_check_interval, default_quota_backend_bytes, default_range_retry_timeout,
+    default_watch_max_message_size, ClientTimeout, CompactConfig, CurpConfig, EngineConfig,
+};
 use xline::{client::Client, server::XlineServer, storage::db::DBProxy};
 
 /// Cluster
@@ -27,6 +30,12 @@ pub struct Cluster {
     size: usize,
     /// storage paths
     paths: Vec<PathBuf>,
+    /// Compaction configuration
+    compact_config: CompactConfig,
+    /// Backend quota in bytes
+    quota_backend_bytes: u64,
+    /// How often should the leader check whether the members are corrupted
+    corrupt_check_interval: Duration,
 }
 
 impl Cluster {
@@ -48,6 +57,9 @@ impl Cluster {
             stop_tx: None,
             size,
             paths: vec![],
+            compact_config: CompactConfig::default(),
+            quota_backend_bytes: default_quota_backend_bytes(),
+            corrupt_check_interval: default_corrupt_check_interval(),
         }
     }
 
@@ -56,6 +68,21 @@ impl Cluster {
         self.paths = paths;
     }
 
+    #[allow(dead_code)] // used in tests but get warning
+    pub(crate) fn set_compact_config(&mut self, compact_config: CompactConfig) {
+        self.compact_config = compact_config;
+    }
+
+    #[allow(dead_code)] // used in tests but get warning
+    pub(crate) fn set_quota_backend_bytes(&mut self, quota_backend_bytes: u64) {
+        self.quota_backend_bytes = quota_backend_bytes;
+    }
+
+    #[allow(dead_code)] // used in tests but get warning
+    pub(crate) fn set_corrupt_check_interval(&mut self, corrupt_check_interval: Duration) {
+        self.corrupt_check_interval = corrupt_check_interval;
+    }
+
     /// Start `Cluster`
     pub(crate) async fn start(&mut self) {
         let (stop_tx, _) = broadcast::channel(1);
@@ -74,7 +101,10 @@ impl Cluster {
                 path
             };
             #[allow(clippy::unwrap_used)]
-            let db = DBProxy::open(&StorageConfig::RocksDB(path.clone())).unwrap();
+            let db = DBProxy::open(&EngineConfig::RocksDB(path.clone())).unwrap();
+            let compact_config = self.compact_config;
+            let quota_backend_bytes = self.quota_backend_bytes;
+            let corrupt_check_interval = self.corrupt_check_interval;
             tokio::spawn(async move {
                 let server = XlineServer::new(
                     name,
@@ -87,6 +117,11 @@ impl Cluster {
                     },
                     ClientTimeout::default(),
                     default_range_retry_timeout(),
+                    default_watch_max_message_size(),
+                    corrupt_check_interval,
+                    false,
+                    quota_backend_bytes,
+                    compact_config,
                     db,
                 )
                 .await;
@@ -2,7 +2,8 @@ mod common;
 
 use std::{error::Error, time::Duration};
 
-use etcd_client::Client;
+use etcd_client::{Client, CompactionOptions, GetOptions, WatchOptions};
+use utils::config::{AutoCompactConfig, CompactConfig};
 use xline::client::kv_types::{
     DeleteRangeRequest, PutRequest, RangeRequest, SortOrder, SortTarget,
 };
@@ -246,3 +247,82 @@ async fn test_kv_delete() -> Result<(), Box<dyn Error>> {
 
     Ok(())
 }
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 10)]
+async fn test_kv_compact() -> Result<(), Box<dyn Error>> {
+    let mut cluster = Cluster::new(3).await;
+    cluster.start().await;
+    let client = cluster.client().await;
+    let mut kv_client = client.kv_client();
+
+    let mut revisions = vec![];
+    for value in ["bar0", "bar1", "bar2"] {
+        let res = kv_client.put("foo", value, None).await?;
+        revisions.push(res.header().unwrap().revision());
+    }
+
+    let _ignore = kv_client
+        .compact(revisions[1], Some(CompactionOptions::new().with_physical()))
+        .await?;
+    let err = kv_client
+        .compact(revisions[1], None)
+        .await
+        .expect_err("compact a compacted revision should fail");
+    assert!(err.to_string().contains("compacted"));
+    let err = kv_client
+        .compact(revisions[2] + 1, None)
+        .await
+        .expect_err("compact a future revision should fail");
+    assert!(err.to_string().contains("future revision"));
+
+    let err = kv_client
+        .get("foo", Some(GetOptions::new().with_revision(revisions[0])))
+        .await
+        .expect_err("range a compacted revision should fail");
+    assert!(err.to_string().contains("compacted"));
+    let res = kv_client
+        .get("foo", Some(GetOptions::new().with_revision(revisions[1])))
+        .await?;
+    assert_eq!(res.kvs()[0].value(), b"bar1");
+
+    let mut watch_client = client.watch_client();
+    let (_watcher, mut stream) = watch_client
+        .watch(
+            "foo",
+            Some(WatchOptions::new().with_start_revision(revisions[0])),
+        )
+        .await?;
+    let res = stream.message().await?.unwrap();
+    assert!(res.canceled());
+    assert_eq!(res.compact_revision(), revisions[1]);
+
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 10)]
+async fn test_kv_auto_compact_periodic() -> Result<(), Box<dyn Error>> {
+    let mut cluster = Cluster::new(3).await;
+    cluster.set_compact_config(CompactConfig::new(Some(AutoCompactConfig::Periodic(
+        Duration::from_secs(1),
+    ))));
+    cluster.start().await;
+    let client = cluster.client().await;
+    let mut kv_client = client.kv_client();
+
+    let mut revisions = vec![];
+    for value in ["bar0", "bar1"] {
+        let res = kv_client.put("foo", value, None).await?;
+        revisions.push(res.header().unwrap().revision());
+    }
+    tokio::time::sleep(Duration::from_secs(3)).await;
+
+    let err = kv_client
+        .get("foo", Some(GetOptions::new().with_revision(revisions[0])))
+        .await
+        .expect_err("range a compacted revision should fail");
+    assert!(err.to_string().contains("compacted"));
+    let res = kv_client.get("foo", None).await?;
+    assert_eq!(res.kvs()[0].value(), b"bar1");
+
+    Ok(())
+}
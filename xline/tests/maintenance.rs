@@ -42,3 +42,182 @@ async fn test_snapshot_and_restore() -> Result<(), Box<dyn std::error::Error>> {
     tokio::fs::remove_dir_all(&dir).await?;
     Ok(())
 }
+
+#[tokio::test]
+async fn test_status() -> Result<(), Box<dyn std::error::Error>> {
+    let mut cluster = Cluster::new(3).await;
+    cluster.start().await;
+    let client = cluster.client().await;
+    let _ignore = client.put(PutRequest::new("key", "value")).await?;
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    let mut maintenance_client = client.maintenance_client();
+    let res = maintenance_client.status().await?;
+    assert_eq!(res.version(), env!("CARGO_PKG_VERSION"));
+    assert!(res.db_size() > 0);
+    assert_ne!(res.leader(), 0);
+    assert!(res.raft_term() >= 1);
+    assert!(res.raft_index() >= 1);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_hash_kv_should_be_consistent_among_members() -> Result<(), Box<dyn std::error::Error>>
+{
+    let mut cluster = Cluster::new(3).await;
+    cluster.start().await;
+    let client = cluster.client().await;
+    for i in 0..10 {
+        let _ignore = client
+            .put(PutRequest::new(format!("key{i}"), format!("value{i}")))
+            .await?;
+    }
+    let revision = client
+        .range(RangeRequest::new("key0"))
+        .await?
+        .header
+        .unwrap()
+        .revision;
+    tokio::time::sleep(Duration::from_millis(500)).await;
+    let mut hashes = vec![];
+    for addr in cluster.addrs().values() {
+        let mut member_client = etcd_client::Client::connect([addr], None).await?;
+        let res = member_client.hash_kv(revision).await?;
+        hashes.push(res.hash());
+    }
+    assert!(hashes.windows(2).all(|w| w[0] == w[1]));
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_corrupt_checker_should_raise_alarm_on_hash_mismatch(
+) -> Result<(), Box<dyn std::error::Error>> {
+    let dir = PathBuf::from("/tmp/test_corrupt_checker_should_raise_alarm_on_hash_mismatch");
+    tokio::fs::create_dir_all(&dir).await?;
+    // snapshots at the same revision but with different values
+    let mut snapshot_paths = vec![];
+    for value in ["value0", "value1"] {
+        let snapshot_path = dir.join(format!("snapshot_{value}"));
+        let mut cluster = Cluster::new(3).await;
+        cluster.start().await;
+        let client = cluster.client().await;
+        let _ignore = client.put(PutRequest::new("key", value)).await?;
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let mut maintenance_client = client.maintenance_client();
+        let mut stream = maintenance_client.snapshot().await?;
+        let mut snapshot = tokio::fs::File::create(&snapshot_path).await?;
+        while let Some(chunk) = stream.message().await? {
+            snapshot.write_all(chunk.blob()).await?;
+        }
+        snapshot_paths.push(snapshot_path);
+    }
+    // server1 diverges from the others
+    let restore_dirs: Vec<PathBuf> = (0..3).map(|i| dir.join(format!("restore_{i}"))).collect();
+    for (i, restore_dir) in restore_dirs.iter().enumerate() {
+        let snapshot_path = if i == 1 {
+            &snapshot_paths[1]
+        } else {
+            &snapshot_paths[0]
+        };
+        restore(snapshot_path, restore_dir).await?;
+    }
+    let mut cluster = Cluster::new(3).await;
+    cluster.set_paths(restore_dirs);
+    cluster.set_corrupt_check_interval(Duration::from_millis(500));
+    cluster.start().await;
+    tokio::time::sleep(Duration::from_secs(2)).await;
+    let addr = cluster.addrs()["server0"].clone();
+    let mut client = etcd_client::Client::connect([addr], None).await?;
+
+    let res = client
+        .alarm(
+            etcd_client::AlarmAction::Get,
+            etcd_client::AlarmType::None,
+            None,
+        )
+        .await?;
+    assert_eq!(res.alarms().len(), 1);
+    assert_eq!(res.alarms()[0].alarm(), etcd_client::AlarmType::Corrupt);
+    tokio::fs::remove_dir_all(&dir).await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_defragment() -> Result<(), Box<dyn std::error::Error>> {
+    let mut cluster = Cluster::new(3).await;
+    cluster.start().await;
+    let addr = cluster.addrs()["server0"].clone();
+    let mut client = etcd_client::Client::connect([addr], None).await?;
+    for i in 0..10 {
+        let _ignore = client.put("key", format!("value{i}"), None).await?;
+    }
+    let revision = client.get("key", None).await?.header().unwrap().revision();
+    let _ignore = client
+        .compact(
+            revision,
+            Some(etcd_client::CompactionOptions::new().with_physical()),
+        )
+        .await?;
+    let _ignore = client.defragment().await?;
+    let res = client.get("key", None).await?;
+    assert_eq!(res.kvs().len(), 1);
+    assert_eq!(res.kvs()[0].value(), b"value9");
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_nospace_alarm_should_reject_writes() -> Result<(), Box<dyn std::error::Error>> {
+    let mut cluster = Cluster::new(3).await;
+    // any database exceeds the quota
+    cluster.set_quota_backend_bytes(1);
+    cluster.start().await;
+    tokio::time::sleep(Duration::from_secs(2)).await;
+    let addr = cluster.addrs()["server0"].clone();
+    let mut client = etcd_client::Client::connect([addr], None).await?;
+
+    let res = client
+        .alarm(
+            etcd_client::AlarmAction::Get,
+            etcd_client::AlarmType::None,
+            None,
+        )
+        .await?;
+    assert_eq!(res.alarms().len(), 3);
+    assert!(res
+        .alarms()
+        .iter()
+        .all(|m| m.alarm() == etcd_client::AlarmType::Nospace));
+
+    let err = client.put("key", "value", None).await.unwrap_err();
+    assert!(err.to_string().contains("database space exceeded"));
+    let err = client.lease_grant(10, None).await.unwrap_err();
+    assert!(err.to_string().contains("database space exceeded"));
+    // reads and deletes are still allowed
+    let _ignore = client.get("key", None).await?;
+    let _ignore = client.delete("key", None).await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_move_leader() -> Result<(), Box<dyn std::error::Error>> {
+    let mut cluster = Cluster::new(3).await;
+    cluster.start().await;
+    let addr = cluster.addrs()["server0"].clone();
+    let mut client = etcd_client::Client::connect([addr], None).await?;
+    let target = client
+        .member_list()
+        .await?
+        .members()
+        .iter()
+        .find(|m| m.name() == "server1")
+        .unwrap()
+        .id();
+
+    let _ignore = client.move_leader(target).await?;
+    let res = client.status().await?;
+    assert_eq!(res.leader(), target);
+    // the cluster still accepts writes after the transfer
+    let _ignore = client.put("key", "value", None).await?;
+    let res = client.get("key", None).await?;
+    assert_eq!(res.kvs()[0].value(), b"value");
+    Ok(())
+}
@@ -2,7 +2,7 @@ mod common;
 
 use std::error::Error;
 
-use etcd_client::EventType;
+use etcd_client::{EventType, WatchOptions};
 use xline::client::kv_types::{DeleteRangeRequest, PutRequest};
 
 use crate::common::Cluster;
@@ -38,3 +38,83 @@ async fn test_watch() -> Result<(), Box<dyn Error>> {
     handle.await?;
     Ok(())
 }
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 10)]
+async fn test_watch_filter_noput() -> Result<(), Box<dyn Error>> {
+    let mut cluster = Cluster::new(3).await;
+    cluster.start().await;
+    let client = cluster.client().await;
+    let mut watch_client = client.watch_client();
+
+    let (_watcher, mut stream) = watch_client
+        .watch("foo", Some(WatchOptions::new().with_filter_put()))
+        .await?;
+    let handle = tokio::spawn(async move {
+        if let Ok(Some(res)) = stream.message().await {
+            assert_eq!(res.events().len(), 1);
+            let event = res.events().get(0).unwrap();
+            assert_eq!(event.event_type(), EventType::Delete);
+            assert_eq!(event.kv().unwrap().key(), b"foo");
+        }
+    });
+
+    client.put(PutRequest::new("foo", "bar")).await?;
+    client.delete(DeleteRangeRequest::new("foo")).await?;
+
+    handle.await?;
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 10)]
+async fn test_watch_filter_nodelete() -> Result<(), Box<dyn Error>> {
+    let mut cluster = Cluster::new(3).await;
+    cluster.start().await;
+    let client = cluster.client().await;
+    let mut watch_client = client.watch_client();
+
+    let (_watcher, mut stream) = watch_client
+        .watch("foo", Some(WatchOptions::new().with_filter_delete()))
+        .await?;
+    let handle = tokio::spawn(async move {
+        for value in [b"bar1", b"bar2"] {
+            if let Ok(Some(res)) = stream.message().await {
+                assert_eq!(res.events().len(), 1);
+                let event = res.events().get(0).unwrap();
+                assert_eq!(event.event_type(), EventType::Put);
+                assert_eq!(event.kv().unwrap().value(), value);
+            }
+        }
+    });
+
+    client.put(PutRequest::new("foo", "bar1")).await?;
+    client.delete(DeleteRangeRequest::new("foo")).await?;
+    client.put(PutRequest::new("foo", "bar2")).await?;
+
+    handle.await?;
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 10)]
+async fn test_watch_prev_kv() -> Result<(), Box<dyn Error>> {
+    let mut cluster = Cluster::new(3).await;
+    cluster.start().await;
+    let client = cluster.client().await;
+    let mut watch_client = client.watch_client();
+
+    client.put(PutRequest::new("foo", "bar1")).await?;
+    let (_watcher, mut stream) = watch_client
+        .watch("foo", Some(WatchOptions::new().with_prev_key()))
+        .await?;
+    let handle = tokio::spawn(async move {
+        if let Ok(Some(res)) = stream.message().await {
+            let event = res.events().get(0).unwrap();
+            assert_eq!(event.kv().unwrap().value(), b"bar2");
+            assert_eq!(event.prev_kv().unwrap().value(), b"bar1");
+        }
+    });
+
+    client.put(PutRequest::new("foo", "bar2")).await?;
+
+    handle.await?;
+    Ok(())
+}